@@ -40,10 +40,42 @@ where
     }
 }
 
+// Like `from_bytes`, but the input may contain more data after the decoded
+// value, which is returned alongside it. Some protocols (e.g. the ut_metadata
+// extension) append raw bytes right after a bencoded dictionary.
+pub fn from_bytes_partial<'a, T>(s: &'a [u8]) -> Result<(T, &'a [u8])>
+where
+    T: Deserialize<'a>,
+{
+    let mut deserializer = Deserializer::from_bytes(s);
+    let t = T::deserialize(&mut deserializer)?;
+    Ok((t, deserializer.input))
+}
+
+// Returns the encoded value of `key` in the dictionary at the start of `s`,
+// without decoding it. Hashes must be computed over the original bytes, e.g.
+// the info hash over the `info` dictionary of a torrent, since decoding and
+// encoding again drops the keys the decoded type does not know.
+pub fn raw_dict_value<'a>(s: &'a [u8], key: &[u8]) -> Result<Option<&'a [u8]>> {
+    let mut deserializer = Deserializer::from_bytes(s);
+    if deserializer.next_byte()? != b'd' {
+        return Err(Error::ExpectedMap);
+    }
+    while deserializer.peek_byte()? != b'e' {
+        let k = deserializer.parse_byte_string()?;
+        let value = deserializer.input;
+        de::IgnoredAny::deserialize(&mut deserializer)?;
+        if k == key {
+            return Ok(Some(&value[..value.len() - deserializer.input.len()]));
+        }
+    }
+    Ok(None)
+}
+
 impl<'de> Deserializer<'de> {
     // Look at the first character in the input without consuming it.
     fn peek_byte(&mut self) -> Result<u8> {
-        if self.input.len() == 0 {
+        if self.input.is_empty() {
             Err(Error::Eof)
         } else {
             Ok(self.input[0])
@@ -191,7 +223,7 @@ impl<'de> Deserializer<'de> {
     }
 }
 
-impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
     type Error = Error;
 
     // Look at the input data to decide what Serde data model type to
@@ -202,7 +234,15 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
         V: Visitor<'de>,
     {
         match self.peek_byte()? {
-            b'0'..=b'9' => self.deserialize_str(visitor),
+            // Byte strings are not required to be valid utf-8 (e.g. `pieces`), so they
+            // are handed to the visitor as raw bytes when they are not.
+            b'0'..=b'9' => {
+                let byte_string = self.parse_byte_string()?;
+                match str::from_utf8(byte_string) {
+                    Ok(string) => visitor.visit_borrowed_str(string),
+                    Err(_) => visitor.visit_borrowed_bytes(byte_string),
+                }
+            }
             b'i' => match self.peek_two_bytes()? {
                 (_, b'-') => self.deserialize_i64(visitor),
                 _ => self.deserialize_u64(visitor),
@@ -423,7 +463,7 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
     // Deserialization of compound types like sequences and maps happens by
     // passing the visitor an "Access" object that gives it the ability to
     // iterate through the data contained in the sequence.
-    fn deserialize_seq<V>(mut self, visitor: V) -> Result<V::Value>
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
@@ -431,7 +471,7 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
         // Parse the opening bracket of the sequence.
         if self.next_byte()? == b'l' {
             // Give the visitor access to each element of the sequence.
-            let value = visitor.visit_seq(Values::new(&mut self))?;
+            let value = visitor.visit_seq(Values::new(self))?;
             // Parse the closing bracket of the sequence.
             if self.next_byte()? == b'e' {
                 Ok(value)
@@ -474,7 +514,7 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
     // Much like `deserialize_seq` but calls the visitors `visit_map` method
     // with a `MapAccess` implementation, rather than the visitor's `visit_seq`
     // method with a `SeqAccess` implementation.
-    fn deserialize_map<V>(mut self, visitor: V) -> Result<V::Value>
+    fn deserialize_map<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
@@ -482,7 +522,7 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
         // Parse the opening brace of the map.
         if self.next_byte()? == b'd' {
             // Give the visitor access to each entry of the map.
-            let value = visitor.visit_map(Values::new(&mut self))?;
+            let value = visitor.visit_map(Values::new(self))?;
             // Parse the closing brace of the map.
             if self.next_byte()? == b'e' {
                 Ok(value)
@@ -711,6 +751,42 @@ fn test_struct_deserialization() {
     assert_eq!(expected, from_bytes(j).unwrap());
 }
 
+#[test]
+fn test_ignores_unknown_binary_fields() {
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct Test {
+        int: u32,
+    }
+
+    let j = b"d3:inti1e7:unknown2:\xff\xfee";
+    assert_eq!(Test { int: 1 }, from_bytes(j).unwrap());
+}
+
+#[test]
+fn test_partial_deserialization() {
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct Test {
+        int: u32,
+    }
+
+    let j = b"d3:inti7eeraw bytes";
+    let (t, rest): (Test, _) = from_bytes_partial(j).unwrap();
+    assert_eq!(Test { int: 7 }, t);
+    assert_eq!(rest, b"raw bytes");
+}
+
+#[test]
+fn test_raw_dict_value() {
+    let j = b"d1:ai1e4:infod4:name1:x7:unknownli1ei2eee1:zlee";
+    assert_eq!(
+        raw_dict_value(j, b"info").unwrap(),
+        Some(&b"d4:name1:x7:unknownli1ei2eee"[..])
+    );
+    assert_eq!(raw_dict_value(j, b"z").unwrap(), Some(&b"le"[..]));
+    assert_eq!(raw_dict_value(j, b"missing").unwrap(), None);
+    assert!(raw_dict_value(b"li1ee", b"info").is_err());
+}
+
 #[test]
 fn test_enum_deserialization() {
     #[derive(Deserialize, PartialEq, Debug)]
@@ -5,6 +5,6 @@ mod de;
 mod error;
 mod ser;
 
-pub use de::from_bytes;
+pub use de::{from_bytes, from_bytes_partial, raw_dict_value};
 pub use error::{Error, Result};
 pub use ser::to_bytes;
@@ -43,12 +43,12 @@ where
     value.serialize(&mut serializer)?;
 
     if let Some(map_state) = serializer.map_state {
-        serializer.output.write(&map_state.output).unwrap();
+        serializer.output.write_all(&map_state.output).unwrap();
     }
     Ok(serializer.output)
 }
 
-impl<'a> ser::Serializer for &'a mut Serializer {
+impl ser::Serializer for &mut Serializer {
     type Ok = ();
     type Error = Error;
     type SerializeSeq = Self;
@@ -82,7 +82,8 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     fn serialize_i64(self, v: i64) -> Result<()> {
         // TODO: probably not that efficient
         trace!("Serializing i64: {}", v);
-        Ok(utils::write_integer(&mut self.output, v))
+        utils::write_integer(&mut self.output, v);
+        Ok(())
     }
 
     fn serialize_u8(self, v: u8) -> Result<()> {
@@ -102,7 +103,8 @@ impl<'a> ser::Serializer for &'a mut Serializer {
 
     fn serialize_u64(self, v: u64) -> Result<()> {
         trace!("Serializing u64: {}", v);
-        Ok(utils::write_unsigned(&mut self.output, v))
+        utils::write_unsigned(&mut self.output, v);
+        Ok(())
     }
 
     fn serialize_f32(self, _v: f32) -> Result<()> {
@@ -126,7 +128,7 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     fn serialize_bytes(self, v: &[u8]) -> Result<()> {
         trace!("Serializing bytes");
         write!(&mut self.output, "{}:", v.len()).unwrap();
-        self.output.write(v).unwrap();
+        self.output.write_all(v).unwrap();
         Ok(())
     }
 
@@ -194,14 +196,14 @@ impl<'a> ser::Serializer for &'a mut Serializer {
 
     fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
         trace!("Serializing seq");
-        self.output.write(b"l").unwrap();
+        self.output.write_all(b"l").unwrap();
         trace!("seq: main: {}", String::from_utf8_lossy(&self.output));
         Ok(self)
     }
 
     fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
         trace!("Serializing tuple");
-        self.output.write(b"l").unwrap();
+        self.output.write_all(b"l").unwrap();
         Ok(self)
     }
 
@@ -225,9 +227,9 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         _len: usize,
     ) -> Result<Self::SerializeTupleVariant> {
         trace!("Serializing tuple variant");
-        self.output.write(b"d").unwrap();
+        self.output.write_all(b"d").unwrap();
         variant.serialize(&mut *self)?;
-        self.output.write(b"l").unwrap();
+        self.output.write_all(b"l").unwrap();
         Ok(self)
     }
 
@@ -252,13 +254,13 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         len: usize,
     ) -> Result<Self::SerializeStructVariant> {
         trace!("Serializing struct variant: {}", variant);
-        self.output.write(b"d").unwrap();
+        self.output.write_all(b"d").unwrap();
         variant.serialize(&mut *self)?;
         self.serialize_map(Some(len))
     }
 }
 
-impl<'a> ser::SerializeSeq for &'a mut Serializer {
+impl ser::SerializeSeq for &mut Serializer {
     // Must match the `Ok` type of the serializer.
     type Ok = ();
     // Must match the `Error` type of the serializer.
@@ -274,7 +276,7 @@ impl<'a> ser::SerializeSeq for &'a mut Serializer {
         if let Some(map_state) = self.map_state.take() {
             // A map/struct was serialized to map_state.output,
             // move it into the serialized value variable.
-            self.output.write(&map_state.output).unwrap();
+            self.output.write_all(&map_state.output).unwrap();
         }
 
         Ok(())
@@ -282,12 +284,12 @@ impl<'a> ser::SerializeSeq for &'a mut Serializer {
 
     // Close the sequence.
     fn end(self) -> Result<()> {
-        self.output.write(b"e").unwrap();
+        self.output.write_all(b"e").unwrap();
         Ok(())
     }
 }
 
-impl<'a> ser::SerializeTuple for &'a mut Serializer {
+impl ser::SerializeTuple for &mut Serializer {
     type Ok = ();
     type Error = Error;
 
@@ -299,12 +301,12 @@ impl<'a> ser::SerializeTuple for &'a mut Serializer {
     }
 
     fn end(self) -> Result<()> {
-        self.output.write(b"e").unwrap();
+        self.output.write_all(b"e").unwrap();
         Ok(())
     }
 }
 
-impl<'a> ser::SerializeTupleStruct for &'a mut Serializer {
+impl ser::SerializeTupleStruct for &mut Serializer {
     type Ok = ();
     type Error = Error;
 
@@ -316,12 +318,12 @@ impl<'a> ser::SerializeTupleStruct for &'a mut Serializer {
     }
 
     fn end(self) -> Result<()> {
-        self.output.write(b"e").unwrap();
+        self.output.write_all(b"e").unwrap();
         Ok(())
     }
 }
 
-impl<'a> ser::SerializeTupleVariant for &'a mut Serializer {
+impl ser::SerializeTupleVariant for &mut Serializer {
     type Ok = ();
     type Error = Error;
 
@@ -333,7 +335,7 @@ impl<'a> ser::SerializeTupleVariant for &'a mut Serializer {
     }
 
     fn end(self) -> Result<()> {
-        self.output.write(b"ee").unwrap();
+        self.output.write_all(b"ee").unwrap();
         Ok(())
     }
 }
@@ -341,7 +343,7 @@ impl<'a> ser::SerializeTupleVariant for &'a mut Serializer {
 ////////////////////////////////////////////////////////////////////
 /// Map Serializer and similar ones
 ////////////////////////////////////////////////////////////////////
-impl<'a> ser::SerializeMap for &'a mut Serializer {
+impl ser::SerializeMap for &mut Serializer {
     type Ok = ();
     type Error = Error;
 
@@ -431,7 +433,7 @@ impl<'a> ser::SerializeMap for &'a mut Serializer {
     }
 }
 
-impl<'a> ser::SerializeStruct for &'a mut Serializer {
+impl ser::SerializeStruct for &mut Serializer {
     type Ok = ();
     type Error = Error;
 
@@ -509,7 +511,7 @@ impl<'a> ser::SerializeStruct for &'a mut Serializer {
     }
 }
 
-impl<'a> ser::SerializeStructVariant for &'a mut Serializer {
+impl ser::SerializeStructVariant for &mut Serializer {
     type Ok = ();
     type Error = Error;
 
@@ -525,7 +527,7 @@ impl<'a> ser::SerializeStructVariant for &'a mut Serializer {
         let map_state: &mut MapState = self.map_state.as_mut().unwrap();
 
         write_dict_with_ordered_pairs(&mut map_state.ordered_pairs, &mut map_state.output)?;
-        map_state.output.write(b"e").unwrap();
+        map_state.output.write_all(b"e").unwrap();
         trace!(
             "[struct_variant] result: {}",
             String::from_utf8_lossy(&map_state.output),
@@ -548,15 +550,15 @@ fn write_dict_with_ordered_pairs(
         new_key.to_owned()
     });
 
-    output.write(b"d").unwrap();
+    output.write_all(b"d").unwrap();
     for (key, val) in ordered_pairs.iter() {
         trace!("writing key {}", unsafe {
             std::str::from_utf8_unchecked(key)
         });
-        output.write(&key).unwrap();
-        output.write(&val).unwrap();
+        output.write_all(key).unwrap();
+        output.write_all(val).unwrap();
     }
-    output.write(b"e").unwrap();
+    output.write_all(b"e").unwrap();
     ordered_pairs.clear();
     Ok(())
 }
@@ -0,0 +1,98 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use structopt::StructOpt;
+use thor::tracker_server::http::HttpTracker;
+use thor::tracker_server::udp::UdpTracker;
+use thor::tracker_server::{ServerConfig, Swarms};
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "thor-tracker", about = "A BitTorrent tracker")]
+struct Args {
+    /// Address of the UDP tracker
+    #[structopt(long, default_value = "0.0.0.0:6969")]
+    udp: SocketAddr,
+    /// Does not serve announces over UDP
+    #[structopt(long)]
+    no_udp: bool,
+    /// Address of the HTTP tracker, not started by default
+    #[structopt(long)]
+    http: Option<SocketAddr>,
+    /// Seconds peers should wait between announces
+    #[structopt(long, default_value = "1800")]
+    interval: u64,
+    /// File of the only info hashes served, one in hex per line
+    #[structopt(long, parse(from_os_str))]
+    whitelist: Option<PathBuf>,
+    /// Requires HTTP announces to /<passkey>/announce with one of these passkeys, which
+    /// disables the UDP tracker
+    #[structopt(long = "passkey", number_of_values = 1)]
+    passkeys: Vec<String>,
+}
+
+/// Reads a whitelist file, skipping empty lines and lines starting with `#`.
+fn read_whitelist(path: &Path) -> Result<HashSet<[u8; 20]>, String> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut whitelist = HashSet::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let bytes = thor::magnet::decode_hex(line)
+            .filter(|bytes| bytes.len() == 20)
+            .ok_or_else(|| format!("{}: invalid info hash {}", path.display(), line))?;
+        let mut info_hash = [0u8; 20];
+        info_hash.copy_from_slice(&bytes);
+        whitelist.insert(info_hash);
+    }
+    Ok(whitelist)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), String> {
+    env_logger::init();
+
+    let args = Args::from_args();
+    let whitelist = match args.whitelist.as_ref() {
+        Some(path) => Some(read_whitelist(path)?),
+        None => None,
+    };
+    let config = ServerConfig {
+        interval: Duration::from_secs(args.interval),
+        whitelist,
+        passkeys: Some(args.passkeys.iter().cloned().collect())
+            .filter(|p: &HashSet<_>| !p.is_empty()),
+    };
+    // UDP announces cannot carry a passkey
+    let udp_enabled = !args.no_udp && config.passkeys.is_none();
+    if !udp_enabled && args.http.is_none() {
+        return Err("nothing to serve, use --http".to_owned());
+    }
+    let swarms = Arc::new(Mutex::new(Swarms::new(config)));
+
+    let _udp = if udp_enabled {
+        let udp = UdpTracker::bind(args.udp, swarms.clone())
+            .await
+            .map_err(|e| format!("{}: {}", args.udp, e))?;
+        println!("udp tracker listening on {}", udp.local_addr());
+        Some(udp)
+    } else {
+        None
+    };
+    let _http = match args.http {
+        Some(addr) => {
+            let http = HttpTracker::bind(addr, swarms)
+                .await
+                .map_err(|e| format!("{}: {}", addr, e))?;
+            println!("http tracker listening on {}", http.local_addr());
+            Some(http)
+        }
+        None => None,
+    };
+
+    let _ = tokio::signal::ctrl_c().await;
+    Ok(())
+}
@@ -0,0 +1,103 @@
+/// The set of pieces a peer has, in the wire format of the bitfield message: the high
+/// bit of the first byte is piece 0, spare bits at the end are zero.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Bitfield {
+        Bitfield {
+            bytes: vec![0u8; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// A bitfield with every piece set, e.g. for a seed.
+    pub fn full(len: usize) -> Bitfield {
+        let mut bitfield = Bitfield::new(len);
+        for i in 0..len {
+            bitfield.set(i, true);
+        }
+        bitfield
+    }
+
+    /// Parses a received bitfield, `None` if its size does not match or spare bits are set.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Option<Bitfield> {
+        let mut bitfield = Bitfield::new(len);
+        if bytes.len() != bitfield.bytes.len() {
+            return None;
+        }
+        bitfield.bytes.copy_from_slice(bytes);
+        if (len..bitfield.bytes.len() * 8).any(|i| bitfield.get_unchecked(i)) {
+            return None;
+        }
+        Some(bitfield)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.get_unchecked(index)
+    }
+
+    pub fn set(&mut self, index: usize, value: bool) {
+        assert!(index < self.len, "piece {} out of range", index);
+        let mask = 0x80 >> (index % 8);
+        if value {
+            self.bytes[index / 8] |= mask;
+        } else {
+            self.bytes[index / 8] &= !mask;
+        }
+    }
+
+    /// Number of pieces set.
+    pub fn count(&self) -> usize {
+        self.bytes.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    pub fn iter_set(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(move |i| self.get_unchecked(*i))
+    }
+
+    fn get_unchecked(&self, index: usize) -> bool {
+        self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_bitfield() {
+    let mut bitfield = Bitfield::new(10);
+    assert_eq!(bitfield.as_bytes(), &[0, 0]);
+
+    bitfield.set(0, true);
+    bitfield.set(9, true);
+    assert_eq!(bitfield.as_bytes(), &[0x80, 0x40]);
+    assert_eq!(bitfield.count(), 2);
+    assert_eq!(bitfield.iter_set().collect::<Vec<_>>(), vec![0, 9]);
+    assert!(!bitfield.get(10));
+
+    assert_eq!(Bitfield::from_bytes(&[0x80, 0x40], 10), Some(bitfield));
+    // spare bits must be cleared
+    assert_eq!(Bitfield::from_bytes(&[0x80, 0x20], 10), None);
+    assert_eq!(Bitfield::from_bytes(&[0x80], 10), None);
+}
@@ -0,0 +1,380 @@
+use crate::dht::DEFAULT_BOOTSTRAP_NODES;
+use crate::error::Error;
+use crate::peer::choker::DEFAULT_UPLOAD_SLOTS;
+use crate::peer::listener::DEFAULT_PORTS;
+use crate::peer::mse::EncryptionPolicy;
+use serde::{Deserialize, Deserializer};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Prefix of the environment variables overriding settings, e.g. `THOR_MAX_PEERS` or
+/// `THOR_TRACKER_TIMEOUT` for `tracker.timeout`.
+pub const ENV_PREFIX: &str = "THOR_";
+
+/// Every setting that can be overridden, sections are separated by a dot.
+pub const KEYS: &[&str] = &[
+    "download_dir",
+    "listen_ports",
+    "max_peers",
+    "max_peers_per_torrent",
+    "upload_slots",
+    "dht",
+    "dht_bootstrap_nodes",
+    "dht_state",
+    "lsd",
+    "utp",
+    "download_rate",
+    "upload_rate",
+    "peer.connect_timeout",
+    "peer.handshake_timeout",
+    "peer.idle_timeout",
+    "peer.keep_alive_interval",
+    "peer.pipeline_len",
+    "peer.encryption",
+    "tracker.timeout",
+    "tracker.num_want",
+    "tracker.recv_buf_size",
+];
+
+/// Settings of a session. Read from a TOML file with `Config::load`, where missing settings
+/// keep their default, then overridden from the environment and the command line:
+///
+/// ```toml
+/// download_dir = "/srv/torrents"
+/// listen_ports = "6881-6889"
+/// upload_rate = 102400
+///
+/// [tracker]
+/// timeout = 5
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Where the files of the torrents are stored.
+    pub download_dir: PathBuf,
+    /// Ports tried in order for incoming peers, a single port or a range like "6881-6889".
+    #[serde(deserialize_with = "deserialize_ports")]
+    pub listen_ports: RangeInclusive<u16>,
+    /// Connections of all torrents together.
+    pub max_peers: usize,
+    pub max_peers_per_torrent: usize,
+    /// Peers each torrent uploads to at once.
+    pub upload_slots: usize,
+    /// Looks up and announces the public torrents on the DHT.
+    pub dht: bool,
+    /// `host:port` of the nodes the DHT is joined through, comma separated when set from
+    /// the environment or the command line.
+    pub dht_bootstrap_nodes: Vec<String>,
+    /// File keeping the DHT node id and routing table between sessions, "none" to start
+    /// afresh every time.
+    #[serde(deserialize_with = "deserialize_state_path")]
+    pub dht_state: Option<PathBuf>,
+    /// Announces the public torrents to the peers of the LAN, and finds theirs.
+    pub lsd: bool,
+    /// Connects to peers over uTP before TCP, and accepts uTP connections on the port.
+    pub utp: bool,
+    /// Bytes per second of all torrents together, `None` means unlimited.
+    pub download_rate: Option<u64>,
+    pub upload_rate: Option<u64>,
+    pub peer: PeerConfig,
+    pub tracker: TrackerConfig,
+}
+
+/// Settings of the peer connections, durations are in seconds in the file.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PeerConfig {
+    /// Time for an outgoing connection to be opened and to exchange handshakes.
+    #[serde(deserialize_with = "deserialize_secs")]
+    pub connect_timeout: Duration,
+    /// Incoming connections that do not send their handshake in time are dropped.
+    #[serde(deserialize_with = "deserialize_secs")]
+    pub handshake_timeout: Duration,
+    /// Connections that receive nothing for this long are closed.
+    #[serde(deserialize_with = "deserialize_secs")]
+    pub idle_timeout: Duration,
+    #[serde(deserialize_with = "deserialize_secs")]
+    pub keep_alive_interval: Duration,
+    /// Requests kept outstanding with an unchoking peer.
+    pub pipeline_len: usize,
+    /// "disabled", "enabled" or "forced".
+    pub encryption: EncryptionPolicy,
+}
+
+/// Settings of the announces to UDP trackers, durations are in seconds in the file.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrackerConfig {
+    /// Time for a tracker to answer each request.
+    #[serde(deserialize_with = "deserialize_secs")]
+    pub timeout: Duration,
+    /// Peers asked for in an announce.
+    pub num_want: u32,
+    /// Largest response read from a tracker, longer responses are truncated.
+    pub recv_buf_size: usize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            download_dir: PathBuf::from("."),
+            listen_ports: DEFAULT_PORTS,
+            max_peers: 200,
+            max_peers_per_torrent: 50,
+            upload_slots: DEFAULT_UPLOAD_SLOTS,
+            dht: true,
+            dht_bootstrap_nodes: DEFAULT_BOOTSTRAP_NODES
+                .iter()
+                .map(|n| n.to_string())
+                .collect(),
+            dht_state: Some(std::env::temp_dir().join("thor-dht.dat")),
+            lsd: true,
+            utp: true,
+            download_rate: None,
+            upload_rate: None,
+            peer: PeerConfig::default(),
+            tracker: TrackerConfig::default(),
+        }
+    }
+}
+
+impl Default for PeerConfig {
+    fn default() -> PeerConfig {
+        PeerConfig {
+            connect_timeout: Duration::from_secs(10),
+            handshake_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(3 * 60),
+            keep_alive_interval: Duration::from_secs(2 * 60),
+            pipeline_len: 16,
+            encryption: EncryptionPolicy::Enabled,
+        }
+    }
+}
+
+impl Default for TrackerConfig {
+    fn default() -> TrackerConfig {
+        TrackerConfig {
+            timeout: Duration::from_secs(2),
+            num_want: 30,
+            recv_buf_size: 1024,
+        }
+    }
+}
+
+impl Config {
+    /// Reads a TOML file, missing settings keep their default.
+    pub fn load(path: &Path) -> Result<Config, Error> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?;
+        toml::from_str(&contents).map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))
+    }
+
+    /// `$XDG_CONFIG_HOME/thor/config.toml`, or `~/.config/thor/config.toml`.
+    pub fn default_path() -> Option<PathBuf> {
+        let dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+        Some(dir.join("thor").join("config.toml"))
+    }
+
+    /// Overrides the settings named by the variables starting with `ENV_PREFIX`, e.g. from
+    /// `std::env::vars()`. Other variables of the prefix are ignored.
+    pub fn apply_env<I>(&mut self, vars: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        for (name, value) in vars {
+            let name = match name.strip_prefix(ENV_PREFIX) {
+                Some(name) => name.to_lowercase(),
+                None => continue,
+            };
+            if let Some(key) = KEYS.iter().find(|k| k.replace('.', "_") == name) {
+                self.set(key, &value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Overrides one of `KEYS`. Rates of 0 or "unlimited" remove the limit.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        let value = value.trim();
+        match key {
+            "download_dir" => self.download_dir = PathBuf::from(value),
+            "listen_ports" => self.listen_ports = parse_ports(value)?,
+            "max_peers" => self.max_peers = parse(key, value)?,
+            "max_peers_per_torrent" => self.max_peers_per_torrent = parse(key, value)?,
+            "upload_slots" => self.upload_slots = parse(key, value)?,
+            "dht" => self.dht = parse(key, value)?,
+            "dht_bootstrap_nodes" => {
+                self.dht_bootstrap_nodes = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|n| !n.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            }
+            "dht_state" => self.dht_state = parse_state_path(value),
+            "lsd" => self.lsd = parse(key, value)?,
+            "utp" => self.utp = parse(key, value)?,
+            "download_rate" => self.download_rate = parse_rate(key, value)?,
+            "upload_rate" => self.upload_rate = parse_rate(key, value)?,
+            "peer.connect_timeout" => self.peer.connect_timeout = parse_secs(key, value)?,
+            "peer.handshake_timeout" => self.peer.handshake_timeout = parse_secs(key, value)?,
+            "peer.idle_timeout" => self.peer.idle_timeout = parse_secs(key, value)?,
+            "peer.keep_alive_interval" => self.peer.keep_alive_interval = parse_secs(key, value)?,
+            "peer.pipeline_len" => self.peer.pipeline_len = parse(key, value)?,
+            "peer.encryption" => self.peer.encryption = value.parse()?,
+            "tracker.timeout" => self.tracker.timeout = parse_secs(key, value)?,
+            "tracker.num_want" => self.tracker.num_want = parse(key, value)?,
+            "tracker.recv_buf_size" => self.tracker.recv_buf_size = parse(key, value)?,
+            _ => return Err(Error::Config(format!("unknown setting {}", key))),
+        }
+        Ok(())
+    }
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, Error> {
+    value
+        .parse()
+        .map_err(|_| Error::Config(format!("invalid value {:?} for {}", value, key)))
+}
+
+fn parse_secs(key: &str, value: &str) -> Result<Duration, Error> {
+    parse(key, value).map(Duration::from_secs)
+}
+
+fn parse_rate(key: &str, value: &str) -> Result<Option<u64>, Error> {
+    if value == "unlimited" {
+        return Ok(None);
+    }
+    parse(key, value).map(|rate: u64| Some(rate).filter(|r| *r > 0))
+}
+
+/// A path, or `None` for "none" or an empty value.
+fn parse_state_path(value: &str) -> Option<PathBuf> {
+    Some(value)
+        .filter(|v| !v.is_empty() && *v != "none")
+        .map(PathBuf::from)
+}
+
+/// Parses a single port or an inclusive range like "6881-6889".
+fn parse_ports(value: &str) -> Result<RangeInclusive<u16>, Error> {
+    let (start, end) = match value.split_once('-') {
+        Some((start, end)) => (start.trim(), end.trim()),
+        None => (value, value),
+    };
+    let start = parse("listen_ports", start)?;
+    let end = parse("listen_ports", end)?;
+    if start > end {
+        return Err(Error::Config(format!("invalid port range {}", value)));
+    }
+    Ok(start..=end)
+}
+
+fn deserialize_ports<'de, D>(deserializer: D) -> Result<RangeInclusive<u16>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Ports {
+        Single(u16),
+        Range(String),
+    }
+
+    match Ports::deserialize(deserializer)? {
+        Ports::Single(port) => Ok(port..=port),
+        Ports::Range(range) => parse_ports(&range).map_err(serde::de::Error::custom),
+    }
+}
+
+fn deserialize_state_path<'de, D>(deserializer: D) -> Result<Option<PathBuf>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    String::deserialize(deserializer).map(|value| parse_state_path(&value))
+}
+
+fn deserialize_secs<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_file_then_env_then_cli() {
+    let mut config: Config = toml::from_str(
+        r#"
+        download_dir = "/srv/torrents"
+        listen_ports = "7000-7010"
+        upload_rate = 1024
+        dht_state = "/var/lib/thor/dht.dat"
+        dht_bootstrap_nodes = ["router.example:6881"]
+
+        [peer]
+        encryption = "forced"
+
+        [tracker]
+        timeout = 5
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.download_dir, PathBuf::from("/srv/torrents"));
+    assert_eq!(config.listen_ports, 7000..=7010);
+    assert_eq!(config.upload_rate, Some(1024));
+    assert_eq!(config.tracker.timeout, Duration::from_secs(5));
+    assert_eq!(
+        config.dht_state,
+        Some(PathBuf::from("/var/lib/thor/dht.dat"))
+    );
+    assert_eq!(config.dht_bootstrap_nodes, vec!["router.example:6881"]);
+    // missing settings keep their default
+    assert_eq!(config.tracker.num_want, 30);
+    assert_eq!(config.peer.encryption, EncryptionPolicy::Forced);
+    assert_eq!(config.peer.pipeline_len, 16);
+
+    let vars = vec![
+        ("THOR_UPLOAD_RATE".to_owned(), "unlimited".to_owned()),
+        ("THOR_TRACKER_NUM_WANT".to_owned(), "50".to_owned()),
+        ("THOR_LSD".to_owned(), "false".to_owned()),
+        ("THOR_CONFIG".to_owned(), "ignored.toml".to_owned()),
+        ("MAX_PEERS".to_owned(), "1".to_owned()),
+    ];
+    config.apply_env(vars).unwrap();
+    assert_eq!(config.upload_rate, None);
+    assert_eq!(config.tracker.num_want, 50);
+    assert!(!config.lsd);
+    assert_eq!(config.max_peers, 200);
+
+    config.set("listen_ports", "6900").unwrap();
+    config.set("peer.idle_timeout", "60").unwrap();
+    config.set("utp", "false").unwrap();
+    assert!(!config.utp);
+    config
+        .set("dht_bootstrap_nodes", "10.0.0.1:6881, node.example:6881")
+        .unwrap();
+    config.set("dht_state", "none").unwrap();
+    assert_eq!(
+        config.dht_bootstrap_nodes,
+        vec!["10.0.0.1:6881", "node.example:6881"]
+    );
+    assert_eq!(config.dht_state, None);
+    assert_eq!(config.listen_ports, 6900..=6900);
+    assert_eq!(config.peer.idle_timeout, Duration::from_secs(60));
+
+    config.set("peer.encryption", "disabled").unwrap();
+    assert_eq!(config.peer.encryption, EncryptionPolicy::Disabled);
+
+    assert!(config.set("max_peers", "many").is_err());
+    assert!(config.set("peer.encryption", "always").is_err());
+    assert!(config.set("listen_ports", "7000-6000").is_err());
+    assert!(config.set("colour", "blue").is_err());
+    assert!(toml::from_str::<Config>("colour = \"blue\"").is_err());
+}
@@ -0,0 +1,342 @@
+use crate::error::Error;
+use crate::model::{FileInfo, InfoDict, MetaInfo};
+use log::debug;
+use rayon::prelude::*;
+use sha1::Digest;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MIN_PIECE_LENGTH: u64 = 16 * 1024;
+const MAX_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+/// The automatic piece length is chosen so that the torrent has at most this many pieces.
+const TARGET_NUM_PIECES: u64 = 1500;
+
+/// Builds a `MetaInfo` out of a file or a directory tree on disk.
+///
+/// ```no_run
+/// let meta_info = thor::create::TorrentBuilder::new("/data/ubuntu.iso")
+///     .tier(vec!["udp://tracker.example.com:6969/announce".to_owned()])
+///     .comment("ubuntu image")
+///     .build()
+///     .unwrap();
+/// let bytes = bencoding::to_bytes(&meta_info).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct TorrentBuilder {
+    path: PathBuf,
+    piece_length: Option<u64>,
+    tiers: Vec<Vec<String>>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<u64>,
+    private: bool,
+    source: Option<String>,
+}
+
+/// A file that will be part of the torrent, with its absolute location on disk.
+#[derive(Debug)]
+struct InputFile {
+    disk_path: PathBuf,
+    length: u64,
+    /// Path components relative to the torrent root.
+    path: Vec<String>,
+}
+
+impl TorrentBuilder {
+    pub fn new<P: Into<PathBuf>>(path: P) -> TorrentBuilder {
+        TorrentBuilder {
+            path: path.into(),
+            piece_length: None,
+            tiers: vec![],
+            comment: None,
+            created_by: Some(format!("thor {}", env!("CARGO_PKG_VERSION"))),
+            creation_date: Some(unix_timestamp()),
+            private: false,
+            source: None,
+        }
+    }
+
+    /// Sets an explicit piece length. When not set, a piece length is computed from the total size.
+    pub fn piece_length(mut self, piece_length: u64) -> TorrentBuilder {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    /// Adds a tier of trackers. The first tracker of the first tier becomes the `announce` url.
+    pub fn tier(mut self, trackers: Vec<String>) -> TorrentBuilder {
+        if !trackers.is_empty() {
+            self.tiers.push(trackers);
+        }
+        self
+    }
+
+    pub fn comment<S: Into<String>>(mut self, comment: S) -> TorrentBuilder {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    /// Overrides the `created by` field, `None` omits it from the torrent.
+    pub fn created_by(mut self, created_by: Option<String>) -> TorrentBuilder {
+        self.created_by = created_by;
+        self
+    }
+
+    /// Overrides the `creation date` field, `None` omits it from the torrent.
+    pub fn creation_date(mut self, creation_date: Option<u64>) -> TorrentBuilder {
+        self.creation_date = creation_date;
+        self
+    }
+
+    pub fn private(mut self, private: bool) -> TorrentBuilder {
+        self.private = private;
+        self
+    }
+
+    /// Sets the `source` tag of the info dict, which is commonly used by private trackers
+    /// to change the info hash of cross-seeded torrents.
+    pub fn source<S: Into<String>>(mut self, source: S) -> TorrentBuilder {
+        self.source = Some(source.into());
+        self
+    }
+
+    pub fn build(self) -> Result<MetaInfo, Error> {
+        let name = self
+            .path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .ok_or(Error::EmptyTorrent)?;
+
+        let is_dir = std::fs::metadata(&self.path)?.is_dir();
+        let files = if is_dir {
+            let mut files = vec![];
+            collect_files(&self.path, &mut vec![], &mut files)?;
+            files
+        } else {
+            let length = std::fs::metadata(&self.path)?.len();
+            vec![InputFile {
+                disk_path: self.path.clone(),
+                length,
+                path: vec![name.clone()],
+            }]
+        };
+
+        let total_length: u64 = files.iter().map(|f| f.length).sum();
+        if files.is_empty() || total_length == 0 {
+            return Err(Error::EmptyTorrent);
+        }
+
+        let piece_length = match self.piece_length {
+            Some(l) if l < MIN_PIECE_LENGTH || !l.is_power_of_two() => {
+                return Err(Error::InvalidPieceLength(l))
+            }
+            Some(l) => l,
+            None => auto_piece_length(total_length),
+        };
+
+        debug!(
+            "hashing {} files ({} bytes) with piece length {}",
+            files.len(),
+            total_length,
+            piece_length
+        );
+        let pieces = hash_pieces(&files, piece_length, total_length)?;
+
+        let info = InfoDict {
+            file_tree: None,
+            files: if is_dir {
+                Some(
+                    files
+                        .into_iter()
+                        .map(|f| FileInfo {
+                            attr: None,
+                            length: f.length,
+                            md5sum: None,
+                            path: f.path,
+                            sha1: None,
+                            symlink_path: None,
+                        })
+                        .collect(),
+                )
+            } else {
+                None
+            },
+            length: if is_dir {
+                None
+            } else {
+                Some(total_length as usize)
+            },
+            md5sum: None,
+            meta_version: None,
+            name,
+            piece_length,
+            pieces,
+            private: if self.private { Some(1) } else { None },
+            source: self.source,
+        };
+
+        let announce = self.tiers.first().map(|t| t[0].clone());
+        let num_trackers: usize = self.tiers.iter().map(|t| t.len()).sum();
+
+        Ok(MetaInfo {
+            announce,
+            announce_list: if num_trackers > 1 {
+                Some(self.tiers)
+            } else {
+                None
+            },
+            comment: self.comment,
+            created_by: self.created_by,
+            creation_date: self.creation_date,
+            encoding: None,
+            info,
+            piece_layers: None,
+            url_list: None,
+            raw_info: None,
+        })
+    }
+}
+
+/// Picks the smallest power of two piece length that keeps the number of pieces
+/// under `TARGET_NUM_PIECES`.
+pub fn auto_piece_length(total_length: u64) -> u64 {
+    let mut piece_length = MIN_PIECE_LENGTH;
+    while piece_length < MAX_PIECE_LENGTH && total_length / piece_length > TARGET_NUM_PIECES {
+        piece_length *= 2;
+    }
+    piece_length
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Walks `dir` recursively, appending every regular file to `files` sorted by path
+/// so that the same tree always yields the same info hash.
+fn collect_files(
+    dir: &Path,
+    prefix: &mut Vec<String>,
+    files: &mut Vec<InputFile>,
+) -> Result<(), Error> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let file_type = entry.file_type()?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        prefix.push(name);
+        if file_type.is_dir() {
+            collect_files(&entry.path(), prefix, files)?;
+        } else if file_type.is_file() {
+            files.push(InputFile {
+                disk_path: entry.path(),
+                length: entry.metadata()?.len(),
+                path: prefix.clone(),
+            });
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+/// Hashes every piece in parallel, returning the concatenated SHA-1 digests.
+fn hash_pieces(
+    files: &[InputFile],
+    piece_length: u64,
+    total_length: u64,
+) -> Result<Vec<u8>, Error> {
+    let num_pieces = total_length.div_ceil(piece_length);
+
+    let hashes = (0..num_pieces)
+        .into_par_iter()
+        .map(|index| {
+            let start = index * piece_length;
+            let end = std::cmp::min(start + piece_length, total_length);
+            hash_range(files, start, end)
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    Ok(hashes.concat())
+}
+
+/// Hashes the bytes in `[start, end)` of the concatenation of all files.
+fn hash_range(files: &[InputFile], start: u64, end: u64) -> Result<Vec<u8>, Error> {
+    let mut hasher = sha1::Sha1::new();
+    let mut buf = vec![0u8; (end - start) as usize];
+    let mut filled = 0usize;
+    let mut file_start = 0u64;
+
+    for f in files {
+        let file_end = file_start + f.length;
+        if file_end > start && file_start < end {
+            let offset = start.saturating_sub(file_start);
+            let len = (std::cmp::min(end, file_end) - file_start - offset) as usize;
+
+            let mut file = File::open(&f.disk_path)?;
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut buf[filled..filled + len])?;
+            filled += len;
+        }
+        file_start = file_end;
+    }
+
+    assert!(filled == buf.len());
+    hasher.update(&buf);
+    Ok(hasher.finalize().to_vec())
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_auto_piece_length() {
+    assert_eq!(auto_piece_length(1), MIN_PIECE_LENGTH);
+    assert_eq!(auto_piece_length(1500 * MIN_PIECE_LENGTH), MIN_PIECE_LENGTH);
+    assert_eq!(
+        auto_piece_length(1501 * MIN_PIECE_LENGTH),
+        2 * MIN_PIECE_LENGTH
+    );
+    assert_eq!(auto_piece_length(u64::MAX), MAX_PIECE_LENGTH);
+}
+
+#[test]
+fn test_build_directory() {
+    let dir = std::env::temp_dir().join(format!("thor-create-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    std::fs::write(dir.join("b.txt"), vec![1u8; 20_000]).unwrap();
+    std::fs::write(dir.join("sub").join("a.txt"), vec![2u8; 30_000]).unwrap();
+
+    let meta_info = TorrentBuilder::new(&dir)
+        .tier(vec!["udp://a:1".to_owned(), "udp://b:2".to_owned()])
+        .private(true)
+        .source("TEST")
+        .creation_date(None)
+        .build()
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(meta_info.announce.as_deref(), Some("udp://a:1"));
+    assert!(meta_info.announce_list.is_some());
+
+    let files = meta_info.info.files.as_ref().unwrap();
+    assert_eq!(files[0].path, vec!["b.txt"]);
+    assert_eq!(files[1].path, vec!["sub", "a.txt"]);
+    assert_eq!(meta_info.info.piece_length, MIN_PIECE_LENGTH);
+
+    // the second piece spans both files
+    let mut expected = vec![1u8; 20_000 - 16384];
+    expected.extend(vec![2u8; 2 * 16384 - 20_000]);
+    let digest = sha1::Sha1::digest(&expected);
+    assert_eq!(meta_info.info.pieces.len(), 4 * 20);
+    assert_eq!(&meta_info.info.pieces[20..40], &digest[..]);
+
+    let bytes = bencoding::to_bytes(&meta_info).unwrap();
+    let decoded: MetaInfo = bencoding::from_bytes(&bytes).unwrap();
+    assert_eq!(decoded.info.private, Some(1));
+    assert_eq!(decoded.info.source.as_deref(), Some("TEST"));
+}
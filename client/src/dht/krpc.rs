@@ -0,0 +1,326 @@
+use super::routing::{NodeId, NodeInfo};
+use crate::error::Error;
+use crate::peer;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::net::SocketAddr;
+
+pub const ERROR_GENERIC: i64 = 201;
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+/// A KRPC query (BEP 5).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: [u8; 20],
+    },
+    AnnouncePeer {
+        info_hash: [u8; 20],
+        /// Ignored by the receiver when `implied_port` is set, it uses the source port instead.
+        port: u16,
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+}
+
+impl Query {
+    fn method(&self) -> &'static str {
+        match self {
+            Query::Ping => "ping",
+            Query::FindNode { .. } => "find_node",
+            Query::GetPeers { .. } => "get_peers",
+            Query::AnnouncePeer { .. } => "announce_peer",
+        }
+    }
+}
+
+/// The arguments of every response type, only `id` is always present.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+    pub values: Vec<SocketAddr>,
+    pub token: Option<Vec<u8>>,
+}
+
+impl Response {
+    pub fn new(id: NodeId) -> Response {
+        Response {
+            id,
+            nodes: vec![],
+            values: vec![],
+            token: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Body {
+    Query { id: NodeId, query: Query },
+    Response(Response),
+    Error { code: i64, message: String },
+}
+
+/// A message exchanged between DHT nodes, `transaction_id` pairs responses with queries.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KrpcMessage {
+    pub transaction_id: Vec<u8>,
+    pub body: Body,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct RawMessage {
+    #[serde(with = "serde_bytes")]
+    t: Vec<u8>,
+    y: String,
+    q: Option<String>,
+    a: Option<RawArguments>,
+    r: Option<RawArguments>,
+    e: Option<(i64, String)>,
+}
+
+/// Query arguments and response values share the same dictionary layout.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct RawArguments {
+    #[serde(with = "serde_bytes")]
+    id: Vec<u8>,
+    #[serde(with = "serde_bytes", default)]
+    target: Option<Vec<u8>>,
+    #[serde(with = "serde_bytes", default)]
+    info_hash: Option<Vec<u8>>,
+    port: Option<u16>,
+    implied_port: Option<u8>,
+    #[serde(with = "serde_bytes", default)]
+    token: Option<Vec<u8>>,
+    #[serde(with = "serde_bytes", default)]
+    nodes: Option<Vec<u8>>,
+    #[serde(with = "serde_bytes", default)]
+    nodes6: Option<Vec<u8>>,
+    values: Option<Vec<ByteBuf>>,
+}
+
+impl KrpcMessage {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut raw = RawMessage {
+            t: self.transaction_id.clone(),
+            ..Default::default()
+        };
+
+        match &self.body {
+            Body::Query { id, query } => {
+                raw.y = "q".to_owned();
+                raw.q = Some(query.method().to_owned());
+                let mut args = RawArguments {
+                    id: id.0.to_vec(),
+                    ..Default::default()
+                };
+                match query {
+                    Query::Ping => {}
+                    Query::FindNode { target } => args.target = Some(target.0.to_vec()),
+                    Query::GetPeers { info_hash } => args.info_hash = Some(info_hash.to_vec()),
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        implied_port,
+                        token,
+                    } => {
+                        args.info_hash = Some(info_hash.to_vec());
+                        args.port = Some(*port);
+                        args.implied_port = Some(*implied_port as u8);
+                        args.token = Some(token.clone());
+                    }
+                }
+                raw.a = Some(args);
+            }
+            Body::Response(response) => {
+                raw.y = "r".to_owned();
+                let mut values = RawArguments {
+                    id: response.id.0.to_vec(),
+                    token: response.token.clone(),
+                    ..Default::default()
+                };
+                for node in response.nodes.iter() {
+                    let nodes = if node.addr.is_ipv4() {
+                        &mut values.nodes
+                    } else {
+                        &mut values.nodes6
+                    };
+                    node.write_compact(nodes.get_or_insert_with(Vec::new));
+                }
+                if !response.values.is_empty() {
+                    values.values = Some(
+                        response
+                            .values
+                            .iter()
+                            .map(|addr| {
+                                let mut buf = vec![];
+                                peer::write_compact(addr, &mut buf);
+                                ByteBuf::from(buf)
+                            })
+                            .collect(),
+                    );
+                }
+                raw.r = Some(values);
+            }
+            Body::Error { code, message } => {
+                raw.y = "e".to_owned();
+                raw.e = Some((*code, message.clone()));
+            }
+        }
+
+        bencoding::to_bytes(&raw).expect("krpc message should not fail to encode")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<KrpcMessage, Error> {
+        let raw: RawMessage = bencoding::from_bytes(bytes)?;
+        let invalid = |reason: &str| Error::Dht(reason.to_owned());
+
+        let body = match raw.y.as_str() {
+            "q" => {
+                let args = raw.a.ok_or_else(|| invalid("query without arguments"))?;
+                let id = NodeId::from_slice(&args.id).ok_or_else(|| invalid("invalid node id"))?;
+                let info_hash = || {
+                    args.info_hash
+                        .as_deref()
+                        .and_then(NodeId::from_slice)
+                        .map(|h| h.0)
+                        .ok_or_else(|| invalid("invalid info_hash"))
+                };
+                let query = match raw.q.as_deref() {
+                    Some("ping") => Query::Ping,
+                    Some("find_node") => Query::FindNode {
+                        target: args
+                            .target
+                            .as_deref()
+                            .and_then(NodeId::from_slice)
+                            .ok_or_else(|| invalid("invalid target"))?,
+                    },
+                    Some("get_peers") => Query::GetPeers {
+                        info_hash: info_hash()?,
+                    },
+                    Some("announce_peer") => Query::AnnouncePeer {
+                        info_hash: info_hash()?,
+                        port: args.port.unwrap_or(0),
+                        implied_port: args.implied_port.unwrap_or(0) != 0,
+                        token: args.token.clone().ok_or_else(|| invalid("missing token"))?,
+                    },
+                    _ => return Err(Error::Dht(format!("unknown method {:?}", raw.q))),
+                };
+                Body::Query { id, query }
+            }
+            "r" => {
+                let values = raw.r.ok_or_else(|| invalid("response without values"))?;
+                let mut nodes =
+                    NodeInfo::parse_compact_v4(values.nodes.as_deref().unwrap_or_default());
+                nodes.extend(NodeInfo::parse_compact_v6(
+                    values.nodes6.as_deref().unwrap_or_default(),
+                ));
+                let peers = values
+                    .values
+                    .unwrap_or_default()
+                    .iter()
+                    .flat_map(|v| match v.len() {
+                        peer::COMPACT_V4_LEN => peer::parse_compact_v4(v),
+                        peer::COMPACT_V6_LEN => peer::parse_compact_v6(v),
+                        _ => vec![],
+                    })
+                    .collect();
+                Body::Response(Response {
+                    id: NodeId::from_slice(&values.id).ok_or_else(|| invalid("invalid node id"))?,
+                    nodes,
+                    values: peers,
+                    token: values.token,
+                })
+            }
+            "e" => {
+                let (code, message) = raw.e.ok_or_else(|| invalid("error without details"))?;
+                Body::Error { code, message }
+            }
+            y => return Err(Error::Dht(format!("unknown message type {}", y))),
+        };
+
+        Ok(KrpcMessage {
+            transaction_id: raw.t,
+            body,
+        })
+    }
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_encode_bep5_examples() {
+    let ping = KrpcMessage {
+        transaction_id: b"aa".to_vec(),
+        body: Body::Query {
+            id: NodeId(*b"abcdefghij0123456789"),
+            query: Query::Ping,
+        },
+    };
+    let bytes = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+    assert_eq!(ping.to_bytes(), bytes.to_vec());
+    assert_eq!(KrpcMessage::from_bytes(bytes).unwrap(), ping);
+
+    let error = KrpcMessage {
+        transaction_id: b"aa".to_vec(),
+        body: Body::Error {
+            code: ERROR_GENERIC,
+            message: "A Generic Error Ocurred".to_owned(),
+        },
+    };
+    let bytes = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+    assert_eq!(error.to_bytes(), bytes.to_vec());
+    assert_eq!(KrpcMessage::from_bytes(bytes).unwrap(), error);
+
+    let announce = b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e\
+        9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe\
+        1:q13:announce_peer1:t2:aa1:y1:qe";
+    assert_eq!(
+        KrpcMessage::from_bytes(announce).unwrap().body,
+        Body::Query {
+            id: NodeId(*b"abcdefghij0123456789"),
+            query: Query::AnnouncePeer {
+                info_hash: *b"mnopqrstuvwxyz123456",
+                port: 6881,
+                implied_port: true,
+                token: b"aoeusnth".to_vec(),
+            },
+        }
+    );
+}
+
+#[test]
+fn test_response_roundtrip() {
+    let response = KrpcMessage {
+        transaction_id: vec![0, 1],
+        body: Body::Response(Response {
+            id: NodeId([1u8; 20]),
+            nodes: vec![
+                NodeInfo {
+                    id: NodeId([2u8; 20]),
+                    addr: "10.0.0.1:6881".parse().unwrap(),
+                },
+                NodeInfo {
+                    id: NodeId([3u8; 20]),
+                    addr: "[2001:db8::1]:6881".parse().unwrap(),
+                },
+            ],
+            values: vec![
+                "10.0.0.2:51413".parse().unwrap(),
+                "[2001:db8::2]:1".parse().unwrap(),
+            ],
+            token: Some(b"token".to_vec()),
+        }),
+    };
+    assert_eq!(
+        KrpcMessage::from_bytes(&response.to_bytes()).unwrap(),
+        response
+    );
+}
@@ -0,0 +1,730 @@
+use crate::error::Error;
+use futures_util::future::join_all;
+use krpc::{Body, KrpcMessage, Query, Response};
+use log::{debug, info, warn};
+use routing::{NodeId, NodeInfo, RoutingTable, K};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use token::TokenManager;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::timeout;
+
+pub mod krpc;
+pub mod routing;
+mod token;
+
+/// Well known nodes used to join the DHT when the routing table is empty.
+pub const DEFAULT_BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "router.utorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+];
+/// Number of queries sent in parallel during a lookup.
+const ALPHA: usize = 3;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Announced peers are forgotten after this long unless they announce again.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+/// How often the announced peers past `PEER_TTL` are forgotten.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60);
+/// Torrents we keep announced peers for, announces of other torrents are ignored.
+const MAX_TORRENTS: usize = 2000;
+/// Announced peers kept per torrent, a new one replaces the oldest.
+const MAX_PEERS_PER_TORRENT: usize = 200;
+/// Maximum number of peers returned in a get_peers response.
+const MAX_VALUES: usize = 50;
+const RECV_BUF_SIZE: usize = 4096;
+/// How often the routing table should be refreshed with `refresh`.
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    pub bind_addr: SocketAddr,
+    /// `host:port` of the nodes contacted by `bootstrap`.
+    pub bootstrap_nodes: Vec<String>,
+    /// File the node id and routing table are loaded from and saved to.
+    pub state_path: Option<PathBuf>,
+}
+
+impl Default for DhtConfig {
+    fn default() -> DhtConfig {
+        DhtConfig {
+            bind_addr: SocketAddr::from(([0, 0, 0, 0], 6881)),
+            bootstrap_nodes: DEFAULT_BOOTSTRAP_NODES
+                .iter()
+                .map(|n| n.to_string())
+                .collect(),
+            state_path: None,
+        }
+    }
+}
+
+/// The routing table as saved to disk, nodes are in compact form.
+#[derive(Serialize, Deserialize, Debug)]
+struct SavedState {
+    #[serde(with = "serde_bytes")]
+    id: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    nodes: Vec<u8>,
+    #[serde(with = "serde_bytes", default)]
+    nodes6: Option<Vec<u8>>,
+}
+
+type PendingQuery = (SocketAddr, oneshot::Sender<Result<Response, Error>>);
+
+#[derive(Debug)]
+struct State {
+    table: RoutingTable,
+    tokens: TokenManager,
+    /// Peers announced to us, by info hash.
+    peers: HashMap<[u8; 20], HashMap<SocketAddr, Instant>>,
+    /// Queries waiting for a response, by transaction id.
+    pending: HashMap<u16, PendingQuery>,
+    next_transaction_id: u16,
+}
+
+impl State {
+    fn handle_query(&mut self, from: SocketAddr, id: NodeId, query: Query) -> Body {
+        self.table.insert(NodeInfo { id, addr: from });
+
+        let mut response = Response::new(self.table.id());
+        match query {
+            Query::Ping => {}
+            Query::FindNode { target } => response.nodes = self.table.closest(&target, K),
+            Query::GetPeers { info_hash } => {
+                response.token = Some(self.tokens.generate(from.ip()));
+                response.nodes = self.table.closest(&NodeId(info_hash), K);
+                if let Some(peers) = self.peers.get_mut(&info_hash) {
+                    peers.retain(|_, announced| announced.elapsed() < PEER_TTL);
+                    response.values = peers.keys().take(MAX_VALUES).copied().collect();
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => {
+                if !self.tokens.validate(from.ip(), &token) {
+                    return Body::Error {
+                        code: krpc::ERROR_PROTOCOL,
+                        message: "bad token".to_owned(),
+                    };
+                }
+                let port = if implied_port { from.port() } else { port };
+                debug!("{} announced {:02x?}", from, info_hash);
+                self.add_peer(info_hash, SocketAddr::new(from.ip(), port), Instant::now());
+            }
+        }
+        Body::Response(response)
+    }
+
+    /// Keeps an announced peer within the limits on torrents and peers per torrent.
+    fn add_peer(&mut self, info_hash: [u8; 20], addr: SocketAddr, now: Instant) {
+        if !self.peers.contains_key(&info_hash) && self.peers.len() >= MAX_TORRENTS {
+            debug!("too many announced torrents, ignoring {:02x?}", info_hash);
+            return;
+        }
+        let peers = self.peers.entry(info_hash).or_default();
+        if !peers.contains_key(&addr) && peers.len() >= MAX_PEERS_PER_TORRENT {
+            let oldest = peers
+                .iter()
+                .min_by_key(|(_, announced)| **announced)
+                .map(|(addr, _)| *addr);
+            if let Some(oldest) = oldest {
+                peers.remove(&oldest);
+            }
+        }
+        peers.insert(addr, now);
+    }
+
+    /// Forgets the peers that did not announce again within `PEER_TTL`.
+    fn expire_peers(&mut self) {
+        for peers in self.peers.values_mut() {
+            peers.retain(|_, announced| announced.elapsed() < PEER_TTL);
+        }
+        self.peers.retain(|_, peers| !peers.is_empty());
+    }
+
+    fn handle_reply(
+        &mut self,
+        from: SocketAddr,
+        transaction_id: &[u8],
+        result: Result<Response, Error>,
+    ) {
+        let transaction_id = match transaction_id {
+            [a, b] => u16::from_be_bytes([*a, *b]),
+            _ => return,
+        };
+        match self.pending.get(&transaction_id) {
+            Some((addr, _)) if *addr == from => {}
+            _ => {
+                debug!("unexpected reply from {}", from);
+                return;
+            }
+        }
+        let (_, sender) = self.pending.remove(&transaction_id).unwrap();
+        if let Ok(response) = result.as_ref() {
+            self.table.insert(NodeInfo {
+                id: response.id,
+                addr: from,
+            });
+        }
+        let _ = sender.send(result);
+    }
+}
+
+/// A node of the mainline DHT (BEP 5), used to find peers without a tracker.
+///
+/// Incoming queries are answered by a background task for as long as the `Dht` lives.
+pub struct Dht {
+    socket: Arc<UdpSocket>,
+    state: Arc<Mutex<State>>,
+    config: DhtConfig,
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl Dht {
+    /// Binds the node, restoring its id and routing table from `config.state_path` if the
+    /// file exists. Call `bootstrap` afterwards to join the network.
+    pub async fn bind(config: DhtConfig) -> Result<Dht, Error> {
+        let socket = Arc::new(UdpSocket::bind(config.bind_addr).await?);
+        Dht::start(config, socket, Incoming::Socket)
+    }
+
+    /// Starts the node on a socket shared with another protocol, e.g. uTP on the peer
+    /// port, which hands over the datagrams it does not handle as `packets`.
+    /// `config.bind_addr` is not used.
+    pub fn with_socket(
+        config: DhtConfig,
+        socket: Arc<UdpSocket>,
+        packets: mpsc::Receiver<(Vec<u8>, SocketAddr)>,
+    ) -> Result<Dht, Error> {
+        Dht::start(config, socket, Incoming::Shared(packets))
+    }
+
+    fn start(config: DhtConfig, socket: Arc<UdpSocket>, incoming: Incoming) -> Result<Dht, Error> {
+        let table = match config.state_path.as_ref().filter(|p| p.exists()) {
+            Some(path) => load_routing_table(path)?,
+            None => RoutingTable::new(NodeId::random()),
+        };
+        info!(
+            "dht node {:02x?} listening on {} with {} known nodes",
+            table.id().0,
+            socket.local_addr()?,
+            table.len()
+        );
+
+        let state = Arc::new(Mutex::new(State {
+            table,
+            tokens: TokenManager::new(),
+            peers: HashMap::new(),
+            pending: HashMap::new(),
+            next_transaction_id: 0,
+        }));
+
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        tokio::spawn(receive_loop(
+            socket.clone(),
+            incoming,
+            state.clone(),
+            shutdown_rx,
+        ));
+
+        Ok(Dht {
+            socket,
+            state,
+            config,
+            _shutdown: shutdown_tx,
+        })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.state.lock().unwrap().table.id()
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub fn num_nodes(&self) -> usize {
+        self.state.lock().unwrap().table.len()
+    }
+
+    /// Contacts the configured bootstrap nodes, then looks up our own id to fill the
+    /// routing table with our neighbours.
+    pub async fn bootstrap(&self) -> Result<(), Error> {
+        let mut addrs = vec![];
+        for node in self.config.bootstrap_nodes.iter() {
+            match tokio::net::lookup_host(node.as_str()).await {
+                Ok(resolved) => addrs.extend(resolved.filter(|a| a.is_ipv4())),
+                Err(e) => warn!("failed to resolve bootstrap node {}: {}", node, e),
+            }
+        }
+        join_all(addrs.into_iter().map(|addr| self.ping(addr))).await;
+
+        self.find_node(self.id()).await;
+        if self.num_nodes() == 0 {
+            return Err(Error::Dht("no node answered during bootstrap".to_owned()));
+        }
+        info!("dht bootstrapped with {} nodes", self.num_nodes());
+        Ok(())
+    }
+
+    /// Looks up a random id, to keep the routing table populated.
+    pub async fn refresh(&self) {
+        self.find_node(NodeId::random()).await;
+    }
+
+    pub async fn ping(&self, addr: SocketAddr) -> Result<NodeId, Error> {
+        Ok(self.query(addr, Query::Ping).await?.id)
+    }
+
+    /// Returns the nodes closest to `target` found by an iterative lookup.
+    pub async fn find_node(&self, target: NodeId) -> Vec<NodeInfo> {
+        let (nodes, _) = self.lookup(target, Query::FindNode { target }).await;
+        nodes.into_iter().map(|(node, _)| node).collect()
+    }
+
+    /// Returns the peers of a torrent found by an iterative lookup.
+    pub async fn get_peers(&self, info_hash: [u8; 20]) -> Vec<SocketAddr> {
+        let (_, peers) = self
+            .lookup(NodeId(info_hash), Query::GetPeers { info_hash })
+            .await;
+        peers
+    }
+
+    /// Looks up the peers of a torrent and announces ourselves to the closest nodes.
+    /// Without a `port` the nodes use the source port of the query, which is our DHT port.
+    pub async fn announce(&self, info_hash: [u8; 20], port: Option<u16>) -> Vec<SocketAddr> {
+        let (nodes, peers) = self
+            .lookup(NodeId(info_hash), Query::GetPeers { info_hash })
+            .await;
+
+        let announces = nodes.into_iter().filter_map(|(node, token)| {
+            let query = Query::AnnouncePeer {
+                info_hash,
+                port: port.unwrap_or(0),
+                implied_port: port.is_none(),
+                token: token?,
+            };
+            Some(self.query(node.addr, query))
+        });
+        let accepted = join_all(announces)
+            .await
+            .into_iter()
+            .filter(Result::is_ok)
+            .count();
+        debug!("announced {:02x?} to {} nodes", info_hash, accepted);
+        peers
+    }
+
+    /// Writes the node id and routing table to `config.state_path`, if set.
+    pub fn save(&self) -> Result<(), Error> {
+        let path = match self.config.state_path.as_ref() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let state = self.state.lock().unwrap();
+        let mut saved = SavedState {
+            id: state.table.id().0.to_vec(),
+            nodes: vec![],
+            nodes6: None,
+        };
+        for node in state.table.nodes() {
+            if node.addr.is_ipv4() {
+                node.write_compact(&mut saved.nodes);
+            } else {
+                node.write_compact(saved.nodes6.get_or_insert_with(Vec::new));
+            }
+        }
+        std::fs::write(path, bencoding::to_bytes(&saved)?)?;
+        Ok(())
+    }
+
+    /// Sends `query` to `addr` and waits for the response.
+    async fn query(&self, addr: SocketAddr, query: Query) -> Result<Response, Error> {
+        let (sender, receiver) = oneshot::channel();
+        let (transaction_id, id) = {
+            let mut state = self.state.lock().unwrap();
+            let transaction_id = state.next_transaction_id;
+            state.next_transaction_id = transaction_id.wrapping_add(1);
+            state.pending.insert(transaction_id, (addr, sender));
+            (transaction_id, state.table.id())
+        };
+
+        let message = KrpcMessage {
+            transaction_id: transaction_id.to_be_bytes().to_vec(),
+            body: Body::Query { id, query },
+        };
+        let result = match self.socket.send_to(&message.to_bytes(), addr).await {
+            Ok(_) => match timeout(QUERY_TIMEOUT, receiver).await {
+                Ok(Ok(result)) => result,
+                Ok(Err(_)) => Err(Error::Dht("node shut down".to_owned())),
+                Err(_) => Err(Error::Timeout),
+            },
+            Err(e) => Err(e.into()),
+        };
+
+        self.state.lock().unwrap().pending.remove(&transaction_id);
+        result
+    }
+
+    /// Iteratively queries the nodes closest to `target`, until the `K` closest nodes
+    /// found have all answered. Returns those nodes with the token they sent, and the
+    /// peers found along the way.
+    async fn lookup(
+        &self,
+        target: NodeId,
+        query: Query,
+    ) -> (Vec<(NodeInfo, Option<Vec<u8>>)>, Vec<SocketAddr>) {
+        struct Candidate {
+            node: NodeInfo,
+            queried: bool,
+            response: Option<Response>,
+        }
+
+        let own_id = self.id();
+        let mut seen = HashSet::new();
+        let mut candidates: Vec<Candidate> = self
+            .state
+            .lock()
+            .unwrap()
+            .table
+            .closest(&target, K)
+            .into_iter()
+            .map(|node| Candidate {
+                node,
+                queried: false,
+                response: None,
+            })
+            .collect();
+        seen.extend(candidates.iter().map(|c| c.node.id));
+        let mut peers = HashSet::new();
+
+        loop {
+            candidates.sort_by_key(|c| c.node.id.distance(&target));
+            let batch: Vec<NodeInfo> = candidates
+                .iter_mut()
+                .take(K)
+                .filter(|c| !c.queried)
+                .take(ALPHA)
+                .map(|c| {
+                    c.queried = true;
+                    c.node
+                })
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            let results = join_all(batch.iter().map(|n| self.query(n.addr, query.clone()))).await;
+            for (node, result) in batch.into_iter().zip(results) {
+                match result {
+                    Ok(response) => {
+                        peers.extend(response.values.iter().copied());
+                        for n in response.nodes.iter() {
+                            if n.id != own_id && seen.insert(n.id) {
+                                candidates.push(Candidate {
+                                    node: *n,
+                                    queried: false,
+                                    response: None,
+                                });
+                            }
+                        }
+                        if let Some(c) = candidates.iter_mut().find(|c| c.node.id == node.id) {
+                            c.response = Some(response);
+                        }
+                    }
+                    Err(e) => {
+                        debug!("query to {} failed: {}", node.addr, e);
+                        self.state.lock().unwrap().table.failed(&node.id);
+                        candidates.retain(|c| c.node.id != node.id);
+                    }
+                }
+            }
+        }
+
+        let nodes = candidates
+            .into_iter()
+            .filter_map(|c| Some((c.node, c.response?.token)))
+            .take(K)
+            .collect();
+        (nodes, peers.into_iter().collect())
+    }
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        if let Err(e) = self.save() {
+            warn!("failed to save the dht routing table: {}", e);
+        }
+    }
+}
+
+fn load_routing_table(path: &std::path::Path) -> Result<RoutingTable, Error> {
+    let saved: SavedState = bencoding::from_bytes(&std::fs::read(path)?)?;
+    let id = NodeId::from_slice(&saved.id)
+        .ok_or_else(|| Error::Dht("invalid node id in saved state".to_owned()))?;
+
+    let mut table = RoutingTable::new(id);
+    let mut nodes = NodeInfo::parse_compact_v4(&saved.nodes);
+    nodes.extend(NodeInfo::parse_compact_v6(
+        saved.nodes6.as_deref().unwrap_or_default(),
+    ));
+    for node in nodes {
+        table.insert(node);
+    }
+    Ok(table)
+}
+
+/// Where the node reads its messages from.
+enum Incoming {
+    Socket,
+    /// Handed over by the protocol sharing the socket.
+    Shared(mpsc::Receiver<(Vec<u8>, SocketAddr)>),
+}
+
+impl Incoming {
+    /// The next datagram and its sender, `None` once the shared socket is gone.
+    async fn recv(
+        &mut self,
+        socket: &UdpSocket,
+        buf: &mut [u8],
+    ) -> Option<io::Result<(Vec<u8>, SocketAddr)>> {
+        match self {
+            Incoming::Socket => Some(
+                socket
+                    .recv_from(buf)
+                    .await
+                    .map(|(len, from)| (buf[..len].to_vec(), from)),
+            ),
+            Incoming::Shared(packets) => packets.recv().await.map(Ok),
+        }
+    }
+}
+
+/// Answers queries and hands responses to the pending queries, until `shutdown` resolves.
+/// Expires the announced peers in between.
+async fn receive_loop(
+    socket: Arc<UdpSocket>,
+    mut incoming: Incoming,
+    state: Arc<Mutex<State>>,
+    mut shutdown: oneshot::Receiver<()>,
+) {
+    let mut buf = vec![0u8; RECV_BUF_SIZE];
+    let mut expire_tick = tokio::time::interval(EXPIRE_INTERVAL);
+    loop {
+        let (packet, from) = tokio::select! {
+            _ = &mut shutdown => break,
+            _ = expire_tick.tick() => {
+                state.lock().unwrap().expire_peers();
+                continue;
+            }
+            received = incoming.recv(&socket, &mut buf) => match received {
+                Some(Ok(received)) => received,
+                None => break,
+                Some(Err(e)) => {
+                    // e.g. ICMP port unreachable reported by the previous send
+                    debug!("dht socket error: {}", e);
+                    continue;
+                }
+            },
+        };
+
+        let message = match KrpcMessage::from_bytes(&packet) {
+            Ok(message) => message,
+            Err(e) => {
+                debug!("invalid krpc message from {}: {}", from, e);
+                continue;
+            }
+        };
+
+        let reply = {
+            let mut state = state.lock().unwrap();
+            match message.body {
+                Body::Query { id, query } => Some(state.handle_query(from, id, query)),
+                Body::Response(response) => {
+                    state.handle_reply(from, &message.transaction_id, Ok(response));
+                    None
+                }
+                Body::Error { code, message: m } => {
+                    let error = Error::Dht(format!("error {}: {}", code, m));
+                    state.handle_reply(from, &message.transaction_id, Err(error));
+                    None
+                }
+            }
+        };
+
+        if let Some(body) = reply {
+            let reply = KrpcMessage {
+                transaction_id: message.transaction_id,
+                body,
+            };
+            if let Err(e) = socket.send_to(&reply.to_bytes(), from).await {
+                debug!("failed to reply to {}: {}", from, e);
+            }
+        }
+    }
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_local_network() {
+    let first = local_node(None).await;
+    let first_addr = first.local_addr().unwrap();
+
+    let mut nodes = vec![];
+    for _ in 0..12 {
+        let node = local_node(Some(first_addr)).await;
+        node.bootstrap().await.unwrap();
+        nodes.push(node);
+    }
+    assert!(first.num_nodes() >= K);
+
+    let info_hash = [0x42u8; 20];
+    assert!(nodes[0].get_peers(info_hash).await.is_empty());
+
+    let seeder = &nodes[3];
+    assert!(seeder.announce(info_hash, Some(51413)).await.is_empty());
+    let peers = nodes[9].get_peers(info_hash).await;
+    assert_eq!(peers, vec![SocketAddr::from(([127, 0, 0, 1], 51413))]);
+
+    // implied port uses the port the announce was sent from
+    nodes[5].announce(info_hash, None).await;
+    let mut peers = nodes[11].get_peers(info_hash).await;
+    peers.sort();
+    let mut expected = vec![
+        SocketAddr::from(([127, 0, 0, 1], 51413)),
+        nodes[5].local_addr().unwrap(),
+    ];
+    expected.sort();
+    assert_eq!(peers, expected);
+}
+
+#[tokio::test]
+async fn test_bad_token_and_timeout() {
+    let a = local_node(None).await;
+    let b = local_node(None).await;
+    let b_addr = b.local_addr().unwrap();
+
+    assert_eq!(a.ping(b_addr).await.unwrap(), b.id());
+    assert_eq!(a.num_nodes(), 1);
+    assert_eq!(b.num_nodes(), 1);
+
+    let query = Query::AnnouncePeer {
+        info_hash: [1u8; 20],
+        port: 1,
+        implied_port: false,
+        token: b"forged".to_vec(),
+    };
+    assert!(matches!(a.query(b_addr, query).await, Err(Error::Dht(_))));
+
+    drop(b);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(a.ping(b_addr).await.is_err());
+}
+
+#[tokio::test]
+async fn test_persisted_routing_table() {
+    let dir = std::env::temp_dir().join(format!("thor-dht-{}", rand::random::<u32>()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("dht.dat");
+
+    let other = local_node(None).await;
+    let config = DhtConfig {
+        bind_addr: "127.0.0.1:0".parse().unwrap(),
+        bootstrap_nodes: vec![],
+        state_path: Some(path.clone()),
+    };
+
+    let node = Dht::bind(config.clone()).await.unwrap();
+    node.ping(other.local_addr().unwrap()).await.unwrap();
+    let id = node.id();
+    drop(node);
+
+    let restored = Dht::bind(config).await.unwrap();
+    assert_eq!(restored.id(), id);
+    assert_eq!(restored.num_nodes(), 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_shared_socket() {
+    let (utp, packets) = crate::utp::UtpSocket::bind_shared("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let config = DhtConfig {
+        bootstrap_nodes: vec![],
+        state_path: None,
+        ..Default::default()
+    };
+    let shared = Dht::with_socket(config, utp.udp_socket(), packets).unwrap();
+    assert_eq!(shared.local_addr().unwrap(), utp.local_addr());
+
+    // queries are answered and replies received next to the uTP connections
+    let other = local_node(None).await;
+    other.ping(utp.local_addr()).await.unwrap();
+    shared.ping(other.local_addr().unwrap()).await.unwrap();
+}
+
+#[test]
+fn test_announced_peers_limits() {
+    let mut state = State {
+        table: RoutingTable::new(NodeId::random()),
+        tokens: TokenManager::new(),
+        peers: HashMap::new(),
+        pending: HashMap::new(),
+        next_transaction_id: 0,
+    };
+    let now = Instant::now();
+    let peer = |i: usize| SocketAddr::from(([10, 0, (i / 256) as u8, (i % 256) as u8], 6881));
+
+    // the oldest peer makes room for a new one
+    for i in 0..MAX_PEERS_PER_TORRENT + 1 {
+        state.add_peer([0u8; 20], peer(i), now + Duration::from_secs(i as u64));
+    }
+    let peers = &state.peers[&[0u8; 20]];
+    assert_eq!(peers.len(), MAX_PEERS_PER_TORRENT);
+    assert!(!peers.contains_key(&peer(0)));
+    assert!(peers.contains_key(&peer(MAX_PEERS_PER_TORRENT)));
+
+    // announces of new torrents are ignored once full
+    for i in 1..MAX_TORRENTS + 1 {
+        let mut info_hash = [0u8; 20];
+        info_hash[..8].copy_from_slice(&(i as u64).to_be_bytes());
+        state.add_peer(info_hash, peer(0), now);
+    }
+    assert_eq!(state.peers.len(), MAX_TORRENTS);
+
+    // expired peers and their torrents are forgotten
+    if let Some(expired) = now.checked_sub(PEER_TTL) {
+        state.peers.clear();
+        state.add_peer([1u8; 20], peer(0), expired);
+        state.add_peer([2u8; 20], peer(0), expired);
+        state.add_peer([2u8; 20], peer(1), now);
+        state.expire_peers();
+        assert_eq!(state.peers.len(), 1);
+        assert_eq!(state.peers[&[2u8; 20]].len(), 1);
+    }
+}
+
+#[cfg(test)]
+async fn local_node(bootstrap: Option<SocketAddr>) -> Dht {
+    Dht::bind(DhtConfig {
+        bind_addr: "127.0.0.1:0".parse().unwrap(),
+        bootstrap_nodes: bootstrap.iter().map(|a| a.to_string()).collect(),
+        state_path: None,
+    })
+    .await
+    .unwrap()
+}
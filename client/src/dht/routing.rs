@@ -0,0 +1,269 @@
+use crate::peer;
+use rand::Rng;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Number of nodes per bucket.
+pub const K: usize = 8;
+/// Size of a node in compact IPv4 form: the id followed by the address.
+pub const COMPACT_NODE_V4_LEN: usize = 26;
+/// Size of a node in compact IPv6 form.
+pub const COMPACT_NODE_V6_LEN: usize = 38;
+/// Nodes that failed to answer this many queries in a row are replaced first.
+const MAX_FAILURES: u32 = 2;
+/// Nodes not heard from in this long are questionable and can be replaced.
+const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// A 160 bit identifier of a DHT node, in the same space as info hashes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId {
+    pub fn random() -> NodeId {
+        NodeId(rand::thread_rng().gen())
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Option<NodeId> {
+        if bytes.len() != 20 {
+            return None;
+        }
+        let mut id = [0u8; 20];
+        id.copy_from_slice(bytes);
+        Some(NodeId(id))
+    }
+
+    pub fn distance(&self, other: &NodeId) -> [u8; 20] {
+        let mut distance = [0u8; 20];
+        for (i, d) in distance.iter_mut().enumerate() {
+            *d = self.0[i] ^ other.0[i];
+        }
+        distance
+    }
+
+    /// Index of the bucket `other` belongs to in the routing table of `self`, which is
+    /// the length of the common prefix of both ids. `None` if both ids are equal.
+    fn bucket_index(&self, other: &NodeId) -> Option<usize> {
+        let distance = self.distance(other);
+        let mut zeros = 0;
+        for byte in distance.iter() {
+            if *byte == 0 {
+                zeros += 8;
+            } else {
+                return Some(zeros + byte.leading_zeros() as usize);
+            }
+        }
+        None
+    }
+}
+
+/// The contact information of a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+impl NodeInfo {
+    pub fn write_compact(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.id.0);
+        peer::write_compact(&self.addr, buf);
+    }
+
+    /// Parses a list of compact IPv4 nodes, ignoring trailing bytes.
+    pub fn parse_compact_v4(bytes: &[u8]) -> Vec<NodeInfo> {
+        parse_compact(bytes, COMPACT_NODE_V4_LEN, peer::parse_compact_v4)
+    }
+
+    /// Parses a list of compact IPv6 nodes, ignoring trailing bytes.
+    pub fn parse_compact_v6(bytes: &[u8]) -> Vec<NodeInfo> {
+        parse_compact(bytes, COMPACT_NODE_V6_LEN, peer::parse_compact_v6)
+    }
+}
+
+fn parse_compact(
+    bytes: &[u8],
+    len: usize,
+    parse_addr: fn(&[u8]) -> Vec<SocketAddr>,
+) -> Vec<NodeInfo> {
+    bytes
+        .chunks_exact(len)
+        .filter_map(|c| {
+            Some(NodeInfo {
+                id: NodeId::from_slice(&c[..20])?,
+                addr: parse_addr(&c[20..]).pop()?,
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    node: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+}
+
+impl Entry {
+    fn is_replaceable(&self) -> bool {
+        self.failures >= MAX_FAILURES || self.last_seen.elapsed() > QUESTIONABLE_AFTER
+    }
+}
+
+/// Kademlia routing table, with one bucket of `K` nodes for each possible length of
+/// the common prefix between a node id and our own.
+#[derive(Debug)]
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> RoutingTable {
+        RoutingTable {
+            id,
+            buckets: vec![vec![]; 160],
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Records that `node` answered or sent us a query. Returns false if its bucket is full
+    /// of good nodes and it was not added.
+    pub fn insert(&mut self, node: NodeInfo) -> bool {
+        let index = match self.id.bucket_index(&node.id) {
+            Some(i) => i,
+            None => return false,
+        };
+        let bucket = &mut self.buckets[index];
+
+        if let Some(pos) = bucket.iter().position(|e| e.node.id == node.id) {
+            let mut entry = bucket.remove(pos);
+            entry.node.addr = node.addr;
+            entry.last_seen = Instant::now();
+            entry.failures = 0;
+            bucket.push(entry);
+            return true;
+        }
+
+        let entry = Entry {
+            node,
+            last_seen: Instant::now(),
+            failures: 0,
+        };
+        if bucket.len() < K {
+            bucket.push(entry);
+            return true;
+        }
+
+        // buckets are sorted from least to most recently seen
+        if let Some(pos) = bucket.iter().position(|e| e.is_replaceable()) {
+            bucket.remove(pos);
+            bucket.push(entry);
+            return true;
+        }
+        false
+    }
+
+    /// Records a query to `id` that timed out.
+    pub fn failed(&mut self, id: &NodeId) {
+        if let Some(index) = self.id.bucket_index(id) {
+            if let Some(entry) = self.buckets[index].iter_mut().find(|e| e.node.id == *id) {
+                entry.failures += 1;
+            }
+        }
+    }
+
+    pub fn remove(&mut self, id: &NodeId) {
+        if let Some(index) = self.id.bucket_index(id) {
+            self.buckets[index].retain(|e| e.node.id != *id);
+        }
+    }
+
+    /// Returns up to `count` known nodes closest to `target`, excluding failing nodes.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|e| e.failures < MAX_FAILURES)
+            .map(|e| e.node)
+            .collect();
+        nodes.sort_by_key(|n| n.id.distance(target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = NodeInfo> + '_ {
+        self.buckets.iter().flatten().map(|e| e.node)
+    }
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_routing_table() {
+    let own = NodeId([0u8; 20]);
+    let mut table = RoutingTable::new(own);
+    let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+
+    // our own id is never added
+    assert!(!table.insert(NodeInfo { id: own, addr }));
+
+    // all these ids have the first bit set, so they go to the same bucket
+    for i in 0..K as u8 {
+        let mut id = [0u8; 20];
+        id[0] = 0x80;
+        id[19] = i;
+        assert!(table.insert(NodeInfo {
+            id: NodeId(id),
+            addr
+        }));
+    }
+    let mut id = [0u8; 20];
+    id[0] = 0xff;
+    let extra = NodeInfo {
+        id: NodeId(id),
+        addr,
+    };
+    assert!(!table.insert(extra));
+
+    // a failing node gets replaced
+    let mut failing = [0u8; 20];
+    failing[0] = 0x80;
+    for _ in 0..MAX_FAILURES {
+        table.failed(&NodeId(failing));
+    }
+    assert!(table.insert(extra));
+    assert_eq!(table.len(), K);
+
+    let mut target = [0u8; 20];
+    target[0] = 0x80;
+    target[19] = 3;
+    let closest = table.closest(&NodeId(target), 2);
+    assert_eq!(closest[0].id, NodeId(target));
+    assert_eq!(closest[1].id.0[19], 2);
+}
+
+#[test]
+fn test_compact_nodes() {
+    let node = NodeInfo {
+        id: NodeId([7u8; 20]),
+        addr: "10.1.2.3:6881".parse().unwrap(),
+    };
+    let mut buf = vec![];
+    node.write_compact(&mut buf);
+    assert_eq!(buf.len(), COMPACT_NODE_V4_LEN);
+    assert_eq!(NodeInfo::parse_compact_v4(&buf), vec![node]);
+}
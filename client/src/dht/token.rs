@@ -0,0 +1,78 @@
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Tokens are built from a secret changed this often. Tokens of the previous secret are
+/// still accepted, so a token is valid for up to twice this long.
+const ROTATE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Hands out the tokens returned by get_peers and checks them in announce_peer, so only
+/// nodes that recently asked us for peers from their own ip can announce.
+#[derive(Debug)]
+pub struct TokenManager {
+    secret: [u8; 20],
+    previous: [u8; 20],
+    rotated_at: Instant,
+}
+
+impl TokenManager {
+    pub fn new() -> TokenManager {
+        let secret = rand::thread_rng().gen();
+        TokenManager {
+            secret,
+            previous: secret,
+            rotated_at: Instant::now(),
+        }
+    }
+
+    pub fn generate(&mut self, ip: IpAddr) -> Vec<u8> {
+        self.rotate();
+        make_token(&self.secret, ip)
+    }
+
+    pub fn validate(&mut self, ip: IpAddr, token: &[u8]) -> bool {
+        self.rotate();
+        token == &make_token(&self.secret, ip)[..] || token == &make_token(&self.previous, ip)[..]
+    }
+
+    fn rotate(&mut self) {
+        if self.rotated_at.elapsed() >= ROTATE_INTERVAL {
+            self.previous = self.secret;
+            self.secret = rand::thread_rng().gen();
+            self.rotated_at = Instant::now();
+        }
+    }
+}
+
+fn make_token(secret: &[u8; 20], ip: IpAddr) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.update(secret);
+    match ip {
+        IpAddr::V4(ip) => hasher.update(ip.octets()),
+        IpAddr::V6(ip) => hasher.update(ip.octets()),
+    }
+    // the first 8 bytes are plenty and keep get_peers responses small
+    hasher.finalize()[..8].to_vec()
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_tokens() {
+    let ip: IpAddr = "10.0.0.1".parse().unwrap();
+    let other: IpAddr = "10.0.0.2".parse().unwrap();
+    let mut tokens = TokenManager::new();
+
+    let token = tokens.generate(ip);
+    assert!(tokens.validate(ip, &token));
+    assert!(!tokens.validate(other, &token));
+
+    // still valid right after a rotation, but not after two
+    tokens.rotated_at -= ROTATE_INTERVAL;
+    assert!(tokens.validate(ip, &token));
+    tokens.rotated_at -= ROTATE_INTERVAL;
+    assert!(!tokens.validate(ip, &token));
+}
@@ -15,9 +15,78 @@ pub enum Error {
     #[error("timeout")]
     Timeout,
 
+    #[error("cancelled")]
+    Cancelled,
+
     #[error("addr parsing: {0}")]
     AddrParsing(#[from] std::net::AddrParseError),
 
     #[error("server: {0}")]
     Server(String),
+
+    #[error("invalid tracker request: {0}")]
+    InvalidRequest(&'static str),
+
+    #[error("bencoding: {0}")]
+    Bencoding(#[from] bencoding::Error),
+
+    #[error("invalid piece length {0}, expected a power of two of at least 16 KiB")]
+    InvalidPieceLength(u64),
+
+    #[error("invalid torrent: {0}")]
+    InvalidTorrent(String),
+
+    #[error("no files to add to the torrent")]
+    EmptyTorrent,
+
+    #[error("invalid magnet link: {0}")]
+    InvalidMagnet(String),
+
+    #[error("invalid handshake")]
+    InvalidHandshake,
+
+    #[error("invalid peer message")]
+    InvalidMessage,
+
+    #[error("peer closed the connection")]
+    ConnectionClosed,
+
+    #[error("encryption: {0}")]
+    Encryption(&'static str),
+
+    #[error("peer does not support {0}")]
+    Unsupported(&'static str),
+
+    #[error("metadata: {0}")]
+    Metadata(String),
+
+    #[error("utp: {0}")]
+    Utp(&'static str),
+
+    #[error("dht: {0}")]
+    Dht(String),
+
+    #[error("invalid block request")]
+    InvalidBlock,
+
+    #[error("http: {0}")]
+    Http(String),
+
+    #[error("unsupported tracker {0}")]
+    UnsupportedTracker(String),
+
+    #[error("unknown torrent")]
+    UnknownTorrent,
+
+    #[error("torrent already added")]
+    DuplicateTorrent,
+
+    #[error("config: {0}")]
+    Config(String),
+
+    #[error("invalid priority {0}, expected skip, low, normal or high")]
+    InvalidPriority(String),
+
+    #[error("expected {expected} file priorities, got {got}")]
+    FileCount { expected: usize, got: usize },
 }
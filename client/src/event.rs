@@ -0,0 +1,145 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// Events kept for subscribers that are slow to receive them, older events are dropped.
+const EVENT_QUEUE_LEN: usize = 1024;
+
+/// Something that happened in a session.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    TorrentAdded {
+        info_hash: [u8; 20],
+        name: String,
+    },
+    /// The info dict of a magnet link was downloaded from peers.
+    MetadataReceived {
+        info_hash: [u8; 20],
+        name: String,
+    },
+    TorrentRemoved {
+        info_hash: [u8; 20],
+    },
+    PieceVerified {
+        info_hash: [u8; 20],
+        index: u32,
+    },
+    /// A downloaded piece did not match its hash and is downloaded again.
+    PieceFailed {
+        info_hash: [u8; 20],
+        index: u32,
+    },
+    PeerConnected {
+        info_hash: [u8; 20],
+        addr: SocketAddr,
+    },
+    PeerDisconnected {
+        info_hash: [u8; 20],
+        addr: SocketAddr,
+    },
+    /// A tracker answered an announce with this many peers.
+    Announced {
+        info_hash: [u8; 20],
+        tracker: String,
+        peers: usize,
+    },
+    AnnounceFailed {
+        info_hash: [u8; 20],
+        tracker: String,
+        error: String,
+    },
+    /// Every piece was downloaded and verified.
+    TorrentCompleted {
+        info_hash: [u8; 20],
+    },
+    /// An error that does not stop the torrent, e.g. failing to check its files.
+    Error {
+        info_hash: Option<[u8; 20]>,
+        message: String,
+    },
+}
+
+/// Receives the events of a session as they are emitted. Called from the tasks of the
+/// session, so it must return quickly.
+pub trait EventHandler: Send + Sync {
+    fn on_event(&self, event: &Event);
+}
+
+/// Hands the events of a session to its handlers and subscribers.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+    handlers: Arc<Mutex<Vec<Arc<dyn EventHandler>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        let (sender, _) = broadcast::channel(EVENT_QUEUE_LEN);
+        EventBus {
+            sender,
+            handlers: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    /// A receiver of the events emitted from now on. A receiver lagging behind by more
+    /// than `EVENT_QUEUE_LEN` events misses the oldest ones.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    pub fn add_handler(&self, handler: Arc<dyn EventHandler>) {
+        self.handlers.lock().unwrap().push(handler);
+    }
+
+    pub fn emit(&self, event: Event) {
+        for handler in self.handlers.lock().unwrap().iter() {
+            handler.on_event(&event);
+        }
+        // fails when nobody subscribed
+        let _ = self.sender.send(event);
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> EventBus {
+        EventBus::new()
+    }
+}
+
+impl std::fmt::Debug for EventBus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventBus")
+            .field("subscribers", &self.sender.receiver_count())
+            .field("handlers", &self.handlers.lock().unwrap().len())
+            .finish()
+    }
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_subscribers_and_handlers() {
+    struct Counter(Mutex<usize>);
+    impl EventHandler for Counter {
+        fn on_event(&self, _: &Event) {
+            *self.0.lock().unwrap() += 1;
+        }
+    }
+
+    let bus = EventBus::new();
+    bus.emit(Event::TorrentCompleted { info_hash: [0; 20] });
+
+    let mut receiver = bus.subscribe();
+    let counter = Arc::new(Counter(Mutex::new(0)));
+    bus.add_handler(counter.clone());
+    let event = Event::PieceVerified {
+        info_hash: [1; 20],
+        index: 3,
+    };
+    bus.emit(event.clone());
+
+    assert_eq!(receiver.recv().await.unwrap(), event);
+    assert_eq!(*counter.0.lock().unwrap(), 1);
+}
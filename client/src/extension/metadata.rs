@@ -0,0 +1,370 @@
+use super::{ExtendedHandshake, Extension, Extensions};
+use crate::error::Error;
+use crate::model::InfoDict;
+use crate::peer::handshake::Handshake;
+use crate::peer::message::Message;
+use crate::peer::mse::EncryptionPolicy;
+use crate::peer::{self, PeerStream};
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use sha1::Digest;
+use std::any::Any;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time::timeout;
+
+pub const EXTENSION_NAME: &str = "ut_metadata";
+/// The metadata is transferred in pieces of 16 KiB, the last one may be smaller.
+pub const PIECE_SIZE: usize = 16 * 1024;
+/// Refuse metadata bigger than this, to protect against malicious peers.
+const MAX_METADATA_SIZE: u64 = 16 * 1024 * 1024;
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+const MSG_REQUEST: u8 = 0;
+const MSG_DATA: u8 = 1;
+const MSG_REJECT: u8 = 2;
+
+#[derive(Debug, PartialEq)]
+pub enum MetadataMessage {
+    Request {
+        piece: u32,
+    },
+    Data {
+        piece: u32,
+        total_size: u64,
+        data: Vec<u8>,
+    },
+    Reject {
+        piece: u32,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct MetadataHeader {
+    msg_type: u8,
+    piece: u32,
+    total_size: Option<u64>,
+}
+
+impl MetadataMessage {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (header, data) = match self {
+            MetadataMessage::Request { piece } => (
+                MetadataHeader {
+                    msg_type: MSG_REQUEST,
+                    piece: *piece,
+                    total_size: None,
+                },
+                &[][..],
+            ),
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => (
+                MetadataHeader {
+                    msg_type: MSG_DATA,
+                    piece: *piece,
+                    total_size: Some(*total_size),
+                },
+                &data[..],
+            ),
+            MetadataMessage::Reject { piece } => (
+                MetadataHeader {
+                    msg_type: MSG_REJECT,
+                    piece: *piece,
+                    total_size: None,
+                },
+                &[][..],
+            ),
+        };
+        let mut bytes = bencoding::to_bytes(&header).expect("header should not fail to encode");
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<MetadataMessage, Error> {
+        let (header, data): (MetadataHeader, _) = bencoding::from_bytes_partial(bytes)?;
+        match header.msg_type {
+            MSG_REQUEST => Ok(MetadataMessage::Request {
+                piece: header.piece,
+            }),
+            MSG_DATA => Ok(MetadataMessage::Data {
+                piece: header.piece,
+                total_size: header
+                    .total_size
+                    .ok_or_else(|| Error::Metadata("data message without total_size".into()))?,
+                data: data.to_vec(),
+            }),
+            MSG_REJECT => Ok(MetadataMessage::Reject {
+                piece: header.piece,
+            }),
+            t => Err(Error::Metadata(format!("unknown message type {}", t))),
+        }
+    }
+}
+
+/// Handler of the ut_metadata extension. It serves the info dict when it is known and
+/// otherwise downloads it from the remote peer, verifying it against the info hash.
+#[derive(Debug)]
+pub struct MetadataExtension {
+    info_hash: [u8; 20],
+    metadata: Option<Vec<u8>>,
+    buffer: Vec<u8>,
+    received: Vec<bool>,
+}
+
+impl MetadataExtension {
+    /// Creates a handler that downloads the metadata of `info_hash`.
+    pub fn new(info_hash: [u8; 20]) -> MetadataExtension {
+        MetadataExtension {
+            info_hash,
+            metadata: None,
+            buffer: vec![],
+            received: vec![],
+        }
+    }
+
+    /// Creates a handler that serves `metadata`, the bencoded info dict.
+    pub fn with_metadata(info_hash: [u8; 20], metadata: Vec<u8>) -> MetadataExtension {
+        MetadataExtension {
+            info_hash,
+            metadata: Some(metadata),
+            buffer: vec![],
+            received: vec![],
+        }
+    }
+
+    /// The verified bencoded info dict, once known.
+    pub fn metadata(&self) -> Option<&[u8]> {
+        self.metadata.as_deref()
+    }
+
+    fn on_data(&mut self, piece: u32, data: Vec<u8>) -> Result<(), Error> {
+        if self.metadata.is_some() {
+            return Ok(());
+        }
+
+        let piece = piece as usize;
+        if piece >= self.received.len() {
+            return Err(Error::Metadata(format!("invalid piece {}", piece)));
+        }
+        let start = piece * PIECE_SIZE;
+        let end = std::cmp::min(start + PIECE_SIZE, self.buffer.len());
+        if data.len() != end - start {
+            return Err(Error::Metadata(format!("invalid piece {}", piece)));
+        }
+        self.buffer[start..end].copy_from_slice(&data);
+        self.received[piece] = true;
+
+        if self.received.iter().all(|r| *r) {
+            let digest = sha1::Sha1::digest(&self.buffer);
+            if digest[..] != self.info_hash[..] {
+                self.received.iter_mut().for_each(|r| *r = false);
+                return Err(Error::Metadata("info hash mismatch".into()));
+            }
+            self.metadata = Some(std::mem::take(&mut self.buffer));
+        }
+        Ok(())
+    }
+}
+
+impl Extension for MetadataExtension {
+    fn name(&self) -> &'static str {
+        EXTENSION_NAME
+    }
+
+    fn fill_handshake(&self, handshake: &mut ExtendedHandshake) {
+        if let Some(metadata) = self.metadata.as_ref() {
+            handshake.metadata_size = Some(metadata.len() as u64);
+        }
+    }
+
+    fn on_handshake(&mut self, remote: &ExtendedHandshake) -> Result<Vec<Vec<u8>>, Error> {
+        let size = match (self.metadata.as_ref(), remote.metadata_size) {
+            (None, Some(size)) => size,
+            _ => return Ok(vec![]),
+        };
+        if size == 0 || size > MAX_METADATA_SIZE {
+            return Err(Error::Metadata(format!("invalid metadata size {}", size)));
+        }
+        debug!("peer has {} bytes of metadata", size);
+
+        let num_pieces = (size as usize).div_ceil(PIECE_SIZE);
+        self.buffer = vec![0u8; size as usize];
+        self.received = vec![false; num_pieces];
+        Ok((0..num_pieces)
+            .map(|piece| {
+                MetadataMessage::Request {
+                    piece: piece as u32,
+                }
+                .to_bytes()
+            })
+            .collect())
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        match MetadataMessage::from_bytes(payload)? {
+            MetadataMessage::Request { piece } => {
+                let response = match self.metadata.as_ref() {
+                    Some(metadata) if (piece as usize) * PIECE_SIZE < metadata.len() => {
+                        let start = piece as usize * PIECE_SIZE;
+                        let end = std::cmp::min(start + PIECE_SIZE, metadata.len());
+                        MetadataMessage::Data {
+                            piece,
+                            total_size: metadata.len() as u64,
+                            data: metadata[start..end].to_vec(),
+                        }
+                    }
+                    _ => MetadataMessage::Reject { piece },
+                };
+                Ok(vec![response.to_bytes()])
+            }
+            MetadataMessage::Data { piece, data, .. } => {
+                self.on_data(piece, data)?;
+                Ok(vec![])
+            }
+            MetadataMessage::Reject { piece } => {
+                Err(Error::Metadata(format!("piece {} rejected", piece)))
+            }
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Tries each peer in turn until one of them serves metadata matching `info_hash`. Returns
+/// the info dict along with its bencoded bytes as served, the ones matching `info_hash`:
+/// encoding the dict again may not give them back.
+pub async fn download_metadata<I>(
+    peers: I,
+    info_hash: [u8; 20],
+) -> Result<(InfoDict, Vec<u8>), Error>
+where
+    I: IntoIterator<Item = SocketAddr>,
+{
+    let peer_id = crate::peer::id::generate();
+
+    for addr in peers {
+        match timeout(PEER_TIMEOUT, fetch_metadata(addr, info_hash, peer_id)).await {
+            Ok(Ok(bytes)) => return Ok((bencoding::from_bytes(&bytes)?, bytes)),
+            Ok(Err(e)) => warn!("failed to fetch metadata from {}: {}", addr, e),
+            Err(_) => warn!("fetching metadata from {} timed out", addr),
+        }
+    }
+    Err(Error::Metadata("no peer provided the metadata".into()))
+}
+
+/// Fetches the raw bencoded info dict from a single peer and checks it against `info_hash`.
+pub async fn fetch_metadata(
+    addr: SocketAddr,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+) -> Result<Vec<u8>, Error> {
+    let handshake = Handshake::new(info_hash, peer_id);
+    let (mut stream, remote) =
+        peer::connect(addr, &handshake, EncryptionPolicy::Enabled, None).await?;
+    if !remote.supports_extension_protocol() {
+        return Err(Error::Unsupported("the extension protocol"));
+    }
+
+    let mut extensions = Extensions::new();
+    extensions.register(Box::new(MetadataExtension::new(info_hash)));
+    stream
+        .send(extensions.handshake_message(Some(addr.ip()))?)
+        .await?;
+
+    loop {
+        let (id, payload) = next_extended(&mut stream).await?;
+        for message in extensions.handle(id, &payload)? {
+            stream.send(message).await?;
+        }
+
+        let handler = extensions
+            .get::<MetadataExtension>()
+            .expect("handler was registered");
+        if let Some(metadata) = handler.metadata() {
+            return Ok(metadata.to_vec());
+        }
+        if let Some(remote) = extensions.remote() {
+            if remote.id_of(EXTENSION_NAME).is_none() || remote.metadata_size.is_none() {
+                return Err(Error::Unsupported(EXTENSION_NAME));
+            }
+        }
+    }
+}
+
+/// Reads messages until an extended one arrives, ignoring everything else.
+async fn next_extended(stream: &mut PeerStream) -> Result<(u8, Vec<u8>), Error> {
+    loop {
+        match stream.next().await {
+            Some(Ok(Message::Extended { id, payload })) => return Ok((id, payload)),
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(e),
+            None => return Err(Error::ConnectionClosed),
+        }
+    }
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_metadata_message_roundtrip() {
+    let request = MetadataMessage::Request { piece: 3 };
+    assert_eq!(request.to_bytes(), b"d8:msg_typei0e5:piecei3ee");
+    assert_eq!(
+        MetadataMessage::from_bytes(&request.to_bytes()).unwrap(),
+        request
+    );
+
+    let data = MetadataMessage::Data {
+        piece: 0,
+        total_size: 4,
+        data: b"d1:e".to_vec(),
+    };
+    assert_eq!(
+        data.to_bytes(),
+        b"d8:msg_typei1e5:piecei0e10:total_sizei4eed1:e".to_vec()
+    );
+    assert_eq!(MetadataMessage::from_bytes(&data.to_bytes()).unwrap(), data);
+}
+
+#[test]
+fn test_serve_and_download_metadata() {
+    let metadata: Vec<u8> = (0..PIECE_SIZE * 2 + 100).map(|i| i as u8).collect();
+    let mut info_hash = [0u8; 20];
+    info_hash.copy_from_slice(&sha1::Sha1::digest(&metadata));
+
+    let mut seeder = MetadataExtension::with_metadata(info_hash, metadata.clone());
+    let mut leecher = MetadataExtension::new(info_hash);
+
+    let mut handshake = ExtendedHandshake::default();
+    seeder.fill_handshake(&mut handshake);
+    let requests = leecher.on_handshake(&handshake).unwrap();
+    assert_eq!(requests.len(), 3);
+
+    for request in requests {
+        for response in seeder.on_message(&request).unwrap() {
+            assert!(leecher.on_message(&response).unwrap().is_empty());
+        }
+    }
+    assert_eq!(leecher.metadata(), Some(&metadata[..]));
+
+    // out of range pieces are rejected
+    let reject = seeder
+        .on_message(&MetadataMessage::Request { piece: 3 }.to_bytes())
+        .unwrap();
+    assert_eq!(
+        MetadataMessage::from_bytes(&reject[0]).unwrap(),
+        MetadataMessage::Reject { piece: 3 }
+    );
+}
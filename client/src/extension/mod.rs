@@ -0,0 +1,305 @@
+use crate::error::Error;
+use crate::peer::message::Message;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+pub mod metadata;
+pub mod pex;
+
+/// Extended message id reserved for the extension handshake (BEP 10).
+pub const HANDSHAKE_ID: u8 = 0;
+/// Number of outstanding requests we accept from a peer, advertised as `reqq`.
+pub const MAX_OUTSTANDING_REQUESTS: u32 = 250;
+/// How often connections call `Extensions::tick`, as often as PEX may send messages.
+pub const TICK_INTERVAL: Duration = pex::PEX_INTERVAL;
+
+/// The bencoded dictionary sent as the first extended message. `m` maps the names of the
+/// supported extensions to the message ids the sender wants to receive them with.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ExtendedHandshake {
+    #[serde(default)]
+    pub m: HashMap<String, u8>,
+    /// Local TCP listen port of the sender.
+    pub p: Option<u16>,
+    /// Client name and version.
+    pub v: Option<String>,
+    /// Number of outstanding request messages the sender supports.
+    pub reqq: Option<u32>,
+    /// The ip of the receiver as seen by the sender, in compact form (4 or 16 bytes).
+    #[serde(with = "serde_bytes", default)]
+    pub yourip: Option<Vec<u8>>,
+    /// Size of the info dict, advertised by peers supporting ut_metadata.
+    pub metadata_size: Option<u64>,
+}
+
+impl ExtendedHandshake {
+    /// Returns the message id the remote peer assigned to `name`, if it supports it.
+    /// An id of 0 means the extension was disabled.
+    pub fn id_of(&self, name: &str) -> Option<u8> {
+        self.m.get(name).copied().filter(|id| *id != 0)
+    }
+
+    pub fn yourip(&self) -> Option<IpAddr> {
+        let bytes = self.yourip.as_ref()?;
+        match bytes.len() {
+            4 => {
+                let mut octets = [0u8; 4];
+                octets.copy_from_slice(bytes);
+                Some(IpAddr::V4(Ipv4Addr::from(octets)))
+            }
+            16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(bytes);
+                Some(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn set_yourip(&mut self, ip: IpAddr) {
+        self.yourip = Some(match ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        });
+    }
+}
+
+/// A handler for the messages of one extension on one peer connection.
+///
+/// Handlers return the payloads they want to send to the remote peer; the registry wraps
+/// them into extended messages with the id the remote peer negotiated for the extension.
+pub trait Extension: Any + Send {
+    /// Name of the extension in the `m` dictionary, e.g. `ut_metadata`.
+    fn name(&self) -> &'static str;
+
+    /// Lets the extension add its own fields (e.g. `metadata_size`) to our handshake.
+    fn fill_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    /// Called once the remote handshake is received, only if the remote supports the extension.
+    fn on_handshake(&mut self, _remote: &ExtendedHandshake) -> Result<Vec<Vec<u8>>, Error> {
+        Ok(vec![])
+    }
+
+    /// Called with the payload of every message of this extension sent by the remote peer.
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, Error>;
+
+    /// Called periodically by the connection, for extensions that send unsolicited messages.
+    fn on_tick(&mut self) -> Vec<Vec<u8>> {
+        vec![]
+    }
+
+    fn as_any(&self) -> &dyn Any;
+
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+/// Registry of the extensions enabled on a peer connection. The local message id of an
+/// extension is its registration order, starting at 1.
+#[derive(Default)]
+pub struct Extensions {
+    handlers: Vec<Box<dyn Extension>>,
+    remote: Option<ExtendedHandshake>,
+    listen_port: Option<u16>,
+}
+
+impl Extensions {
+    pub fn new() -> Extensions {
+        Extensions::default()
+    }
+
+    /// Sets the port advertised as `p` in our handshake.
+    pub fn set_listen_port(&mut self, port: u16) {
+        self.listen_port = Some(port);
+    }
+
+    /// Registers a handler, returning the local message id assigned to it.
+    pub fn register(&mut self, extension: Box<dyn Extension>) -> u8 {
+        assert!(
+            self.handlers.len() < u8::MAX as usize,
+            "too many extensions registered"
+        );
+        self.handlers.push(extension);
+        self.handlers.len() as u8
+    }
+
+    pub fn get<T: Extension>(&self) -> Option<&T> {
+        self.handlers
+            .iter()
+            .find_map(|h| h.as_any().downcast_ref::<T>())
+    }
+
+    pub fn get_mut<T: Extension>(&mut self) -> Option<&mut T> {
+        self.handlers
+            .iter_mut()
+            .find_map(|h| h.as_any_mut().downcast_mut::<T>())
+    }
+
+    /// The handshake received from the remote peer, if any.
+    pub fn remote(&self) -> Option<&ExtendedHandshake> {
+        self.remote.as_ref()
+    }
+
+    /// The id to use when sending messages of extension `name` to the remote peer.
+    pub fn remote_id(&self, name: &str) -> Option<u8> {
+        self.remote.as_ref()?.id_of(name)
+    }
+
+    /// Builds our extension handshake, `remote_ip` is echoed back as `yourip`.
+    pub fn handshake(&self, remote_ip: Option<IpAddr>) -> ExtendedHandshake {
+        let mut handshake = ExtendedHandshake {
+            p: self.listen_port,
+            v: Some(format!("thor {}", env!("CARGO_PKG_VERSION"))),
+            reqq: Some(MAX_OUTSTANDING_REQUESTS),
+            ..Default::default()
+        };
+        if let Some(ip) = remote_ip {
+            handshake.set_yourip(ip);
+        }
+        for (i, handler) in self.handlers.iter().enumerate() {
+            handshake.m.insert(handler.name().to_owned(), i as u8 + 1);
+            handler.fill_handshake(&mut handshake);
+        }
+        handshake
+    }
+
+    pub fn handshake_message(&self, remote_ip: Option<IpAddr>) -> Result<Message, Error> {
+        Ok(Message::Extended {
+            id: HANDSHAKE_ID,
+            payload: bencoding::to_bytes(&self.handshake(remote_ip))?,
+        })
+    }
+
+    /// Dispatches an extended message to its handler, returning the messages to send back.
+    pub fn handle(&mut self, id: u8, payload: &[u8]) -> Result<Vec<Message>, Error> {
+        if id == HANDSHAKE_ID {
+            let remote: ExtendedHandshake = bencoding::from_bytes(payload)?;
+            debug!("extension handshake from {:?}: {:?}", remote.v, remote.m);
+
+            let mut messages = vec![];
+            for handler in self.handlers.iter_mut() {
+                if let Some(remote_id) = remote.id_of(handler.name()) {
+                    let payloads = handler.on_handshake(&remote)?;
+                    messages.extend(into_messages(remote_id, payloads));
+                }
+            }
+            self.remote = Some(remote);
+            return Ok(messages);
+        }
+
+        let handler = match self.handlers.get_mut(id as usize - 1) {
+            Some(h) => h,
+            None => {
+                warn!("received message for unknown extension id {}", id);
+                return Ok(vec![]);
+            }
+        };
+        let remote_id = match self.remote.as_ref().and_then(|r| r.id_of(handler.name())) {
+            Some(remote_id) => remote_id,
+            None => {
+                warn!("received {} message before the handshake", handler.name());
+                return Ok(vec![]);
+            }
+        };
+        let payloads = handler.on_message(payload)?;
+        Ok(into_messages(remote_id, payloads).collect())
+    }
+
+    /// Collects the periodic messages of the extensions supported by the remote peer.
+    pub fn tick(&mut self) -> Vec<Message> {
+        let remote = match self.remote.as_ref() {
+            Some(remote) => remote,
+            None => return vec![],
+        };
+
+        let mut messages = vec![];
+        for handler in self.handlers.iter_mut() {
+            if let Some(remote_id) = remote.id_of(handler.name()) {
+                messages.extend(into_messages(remote_id, handler.on_tick()));
+            }
+        }
+        messages
+    }
+}
+
+fn into_messages(id: u8, payloads: Vec<Vec<u8>>) -> impl Iterator<Item = Message> {
+    payloads
+        .into_iter()
+        .map(move |payload| Message::Extended { id, payload })
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_handshake_encoding() {
+    let mut handshake = ExtendedHandshake {
+        p: Some(6881),
+        v: Some("thor".to_owned()),
+        reqq: Some(250),
+        metadata_size: Some(31235),
+        ..Default::default()
+    };
+    handshake.m.insert("ut_metadata".to_owned(), 3);
+    handshake.set_yourip(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+
+    let bytes = bencoding::to_bytes(&handshake).unwrap();
+    assert_eq!(
+        bytes,
+        b"d1:md11:ut_metadatai3ee13:metadata_sizei31235e1:pi6881e4:reqqi250e1:v4:thor6:yourip4:\x0a\x00\x00\x01e".to_vec()
+    );
+
+    let decoded: ExtendedHandshake = bencoding::from_bytes(&bytes).unwrap();
+    assert_eq!(decoded, handshake);
+    assert_eq!(
+        decoded.yourip(),
+        Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)))
+    );
+}
+
+#[test]
+fn test_dispatch_with_negotiated_ids() {
+    struct Echo;
+
+    impl Extension for Echo {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+
+        fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+            Ok(vec![payload.to_vec()])
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    let mut extensions = Extensions::new();
+    let local_id = extensions.register(Box::new(Echo));
+    assert_eq!(extensions.handshake(None).m.get("echo"), Some(&local_id));
+
+    // messages before the remote handshake are dropped
+    assert!(extensions.handle(local_id, b"hi").unwrap().is_empty());
+
+    extensions
+        .handle(HANDSHAKE_ID, b"d1:md4:echoi7eee")
+        .unwrap();
+    assert_eq!(extensions.remote_id("echo"), Some(7));
+    assert_eq!(
+        extensions.handle(local_id, b"hi").unwrap(),
+        vec![Message::Extended {
+            id: 7,
+            payload: b"hi".to_vec()
+        }]
+    );
+    assert!(extensions.get::<Echo>().is_some());
+}
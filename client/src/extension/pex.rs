@@ -0,0 +1,288 @@
+use super::Extension;
+use crate::error::Error;
+use crate::model::InfoDict;
+use crate::peer;
+use crate::peer::pool::{PeerPool, PeerSource};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub const EXTENSION_NAME: &str = "ut_pex";
+/// PEX messages should not be sent more often than once a minute.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+/// Maximum number of added and dropped peers sent in one message.
+const MAX_PEERS_PER_MESSAGE: usize = 50;
+
+/// The peer prefers encrypted connections.
+pub const FLAG_PREFERS_ENCRYPTION: u8 = 0x01;
+/// The peer is a seed or only uploads.
+pub const FLAG_SEED: u8 = 0x02;
+/// The peer supports uTP.
+pub const FLAG_SUPPORTS_UTP: u8 = 0x04;
+/// The peer supports the ut_holepunch extension.
+pub const FLAG_SUPPORTS_HOLEPUNCH: u8 = 0x08;
+/// The connection to the peer was outgoing, so the peer is reachable.
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+/// A decoded ut_pex message (BEP 11).
+#[derive(Debug, Default, PartialEq)]
+pub struct PexMessage {
+    /// Peers connected since the last message, with their flags.
+    pub added: Vec<(SocketAddr, u8)>,
+    /// Peers disconnected since the last message.
+    pub dropped: Vec<SocketAddr>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct RawPexMessage {
+    #[serde(with = "serde_bytes", default)]
+    added: Option<Vec<u8>>,
+    #[serde(rename = "added.f", with = "serde_bytes", default)]
+    added_flags: Option<Vec<u8>>,
+    #[serde(with = "serde_bytes", default)]
+    added6: Option<Vec<u8>>,
+    #[serde(rename = "added6.f", with = "serde_bytes", default)]
+    added6_flags: Option<Vec<u8>>,
+    #[serde(with = "serde_bytes", default)]
+    dropped: Option<Vec<u8>>,
+    #[serde(with = "serde_bytes", default)]
+    dropped6: Option<Vec<u8>>,
+}
+
+impl PexMessage {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut raw = RawPexMessage::default();
+        for (addr, flags) in self.added.iter() {
+            let (peers, peer_flags) = if addr.is_ipv4() {
+                (&mut raw.added, &mut raw.added_flags)
+            } else {
+                (&mut raw.added6, &mut raw.added6_flags)
+            };
+            peer::write_compact(addr, peers.get_or_insert_with(Vec::new));
+            peer_flags.get_or_insert_with(Vec::new).push(*flags);
+        }
+        for addr in self.dropped.iter() {
+            let peers = if addr.is_ipv4() {
+                &mut raw.dropped
+            } else {
+                &mut raw.dropped6
+            };
+            peer::write_compact(addr, peers.get_or_insert_with(Vec::new));
+        }
+        bencoding::to_bytes(&raw).expect("pex message should not fail to encode")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<PexMessage, Error> {
+        let raw: RawPexMessage = bencoding::from_bytes(bytes)?;
+
+        let mut added = with_flags(
+            peer::parse_compact_v4(raw.added.as_deref().unwrap_or_default()),
+            raw.added_flags.as_deref().unwrap_or_default(),
+        );
+        added.extend(with_flags(
+            peer::parse_compact_v6(raw.added6.as_deref().unwrap_or_default()),
+            raw.added6_flags.as_deref().unwrap_or_default(),
+        ));
+
+        let mut dropped = peer::parse_compact_v4(raw.dropped.as_deref().unwrap_or_default());
+        dropped.extend(peer::parse_compact_v6(
+            raw.dropped6.as_deref().unwrap_or_default(),
+        ));
+
+        Ok(PexMessage { added, dropped })
+    }
+}
+
+/// Pairs each address with its flag, peers without a flag get no flags set.
+fn with_flags(addrs: Vec<SocketAddr>, flags: &[u8]) -> Vec<(SocketAddr, u8)> {
+    addrs
+        .into_iter()
+        .enumerate()
+        .map(|(i, addr)| (addr, flags.get(i).copied().unwrap_or(0)))
+        .collect()
+}
+
+/// Peer exchange must not be used for private torrents (BEP 27).
+pub fn is_enabled(info: &InfoDict) -> bool {
+    !info.is_private()
+}
+
+/// Handler of the ut_pex extension on one connection. Peers received from the remote are
+/// added as candidates to the torrent's pool, and every tick the changes in our connected
+/// peers since the previous tick are sent to the remote.
+#[derive(Debug)]
+pub struct PexExtension {
+    pool: Arc<Mutex<PeerPool>>,
+    /// Address of the peer on the other side of this connection.
+    remote_addr: SocketAddr,
+    /// Peers we told the remote about and did not drop yet.
+    advertised: HashSet<SocketAddr>,
+}
+
+impl PexExtension {
+    pub fn new(pool: Arc<Mutex<PeerPool>>, remote_addr: SocketAddr) -> PexExtension {
+        PexExtension {
+            pool,
+            remote_addr,
+            advertised: HashSet::new(),
+        }
+    }
+}
+
+impl Extension for PexExtension {
+    fn name(&self) -> &'static str {
+        EXTENSION_NAME
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        let message = PexMessage::from_bytes(payload)?;
+        debug!(
+            "pex from {}: {} added, {} dropped",
+            self.remote_addr,
+            message.added.len(),
+            message.dropped.len()
+        );
+
+        // peers found elsewhere, e.g. returned by the tracker, are worth a try anyway
+        let mut pool = self.pool.lock().unwrap();
+        for addr in message.dropped.iter() {
+            pool.remove_candidate(addr, PeerSource::Pex);
+        }
+        for (addr, _flags) in message.added.into_iter().take(2 * MAX_PEERS_PER_MESSAGE) {
+            pool.add_candidate(addr, PeerSource::Pex);
+        }
+        Ok(vec![])
+    }
+
+    fn on_tick(&mut self) -> Vec<Vec<u8>> {
+        // incoming peers are left out until they tell the port they listen on
+        let connected: Vec<(SocketAddr, u8)> = {
+            let pool = self.pool.lock().unwrap();
+            pool.listening()
+                .filter(|(addr, _, _)| *addr != self.remote_addr)
+                .map(|(_, listen_addr, flags)| (listen_addr, flags))
+                .collect()
+        };
+
+        let added: Vec<(SocketAddr, u8)> = connected
+            .iter()
+            .filter(|(addr, _)| !self.advertised.contains(addr))
+            .take(MAX_PEERS_PER_MESSAGE)
+            .copied()
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .advertised
+            .iter()
+            .filter(|addr| !connected.iter().any(|(a, _)| a == *addr))
+            .take(MAX_PEERS_PER_MESSAGE)
+            .copied()
+            .collect();
+
+        if added.is_empty() && dropped.is_empty() {
+            return vec![];
+        }
+
+        for addr in dropped.iter() {
+            self.advertised.remove(addr);
+        }
+        self.advertised.extend(added.iter().map(|(addr, _)| *addr));
+        vec![PexMessage { added, dropped }.to_bytes()]
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_pex_message_roundtrip() {
+    let message = PexMessage {
+        added: vec![
+            ("10.0.0.1:6881".parse().unwrap(), FLAG_SEED),
+            ("[2001:db8::1]:51413".parse().unwrap(), FLAG_SUPPORTS_UTP),
+        ],
+        dropped: vec!["10.0.0.2:1".parse().unwrap()],
+    };
+    let bytes = message.to_bytes();
+    assert!(bytes.starts_with(b"d5:added6:\x0a\x00\x00\x01\x1a\xe17:added.f1:\x02"));
+    assert_eq!(PexMessage::from_bytes(&bytes).unwrap(), message);
+}
+
+#[test]
+fn test_pex_added_and_dropped() {
+    let remote: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+    let other: SocketAddr = "10.0.0.2:2000".parse().unwrap();
+    let pool = Arc::new(Mutex::new(PeerPool::new()));
+    let incoming: SocketAddr = "10.0.0.5:50123".parse().unwrap();
+    pool.lock().unwrap().set_connected(remote, true, 0);
+    pool.lock()
+        .unwrap()
+        .set_connected(other, true, FLAG_REACHABLE);
+    pool.lock().unwrap().set_connected(incoming, false, 0);
+
+    let mut pex = PexExtension::new(pool.clone(), remote);
+    let sent = pex.on_tick();
+    assert_eq!(
+        PexMessage::from_bytes(&sent[0]).unwrap().added,
+        vec![(other, FLAG_REACHABLE)]
+    );
+    // nothing changed since the last tick
+    assert!(pex.on_tick().is_empty());
+
+    // the incoming peer is advertised on its listen port once known
+    pool.lock().unwrap().set_listen_port(&incoming, 5000);
+    let sent = pex.on_tick();
+    assert_eq!(
+        PexMessage::from_bytes(&sent[0]).unwrap().added,
+        vec![("10.0.0.5:5000".parse().unwrap(), 0)]
+    );
+
+    pool.lock().unwrap().set_disconnected(&other);
+    let sent = pex.on_tick();
+    assert_eq!(
+        PexMessage::from_bytes(&sent[0]).unwrap().dropped,
+        vec![other]
+    );
+
+    // received peers become candidates
+    let candidate: SocketAddr = "10.0.0.3:3000".parse().unwrap();
+    let message = PexMessage {
+        added: vec![(candidate, 0)],
+        dropped: vec![],
+    };
+    pex.on_message(&message.to_bytes()).unwrap();
+    assert_eq!(
+        pool.lock().unwrap().next_candidate(),
+        Some((candidate, PeerSource::Pex))
+    );
+
+    // only the candidates added by PEX are dropped
+    let tracker_peer: SocketAddr = "10.0.0.4:4000".parse().unwrap();
+    pool.lock()
+        .unwrap()
+        .add_candidate(tracker_peer, PeerSource::Tracker);
+    pex.on_message(&message.to_bytes()).unwrap();
+    let message = PexMessage {
+        added: vec![],
+        dropped: vec![candidate, tracker_peer],
+    };
+    pex.on_message(&message.to_bytes()).unwrap();
+    let mut pool = pool.lock().unwrap();
+    assert_eq!(
+        pool.next_candidate(),
+        Some((tracker_peer, PeerSource::Tracker))
+    );
+    assert_eq!(pool.next_candidate(), None);
+}
@@ -0,0 +1,353 @@
+use crate::error::Error;
+use std::str::FromStr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Largest response header accepted.
+const MAX_HEADER_LEN: usize = 16 * 1024;
+const MAX_REDIRECTS: usize = 5;
+
+/// A plain `http://` url, https is not supported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    /// Starts with a slash, includes the query if any.
+    pub path: String,
+}
+
+impl FromStr for Url {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Url, Error> {
+        let rest = s
+            .strip_prefix("http://")
+            .ok_or_else(|| Error::Http(format!("unsupported url {}", s)))?;
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(i) if rest[i..].starts_with('?') => (&rest[..i], format!("/{}", &rest[i..])),
+            Some(i) => (&rest[..i], rest[i..].to_owned()),
+            None => (rest, "/".to_owned()),
+        };
+        // an IPv6 address is in brackets
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(rest) => {
+                let (host, after) = rest
+                    .split_once(']')
+                    .ok_or_else(|| Error::Http(format!("invalid host in {}", s)))?;
+                (host, after.strip_prefix(':'))
+            }
+            None => match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .map_err(|_| Error::Http(format!("invalid port in {}", s)))?,
+            None => 80,
+        };
+        if host.is_empty() {
+            return Err(Error::Http(format!("no host in {}", s)));
+        }
+        Ok(Url {
+            host: host.to_owned(),
+            port,
+            path,
+        })
+    }
+}
+
+impl Url {
+    /// Resolves the `Location` of a redirect against this url.
+    fn join(&self, location: &str) -> Result<Url, Error> {
+        if location.contains("://") {
+            return location.parse();
+        }
+        let path = if location.starts_with('/') {
+            location.to_owned()
+        } else {
+            let dir = &self.path[..self.path.rfind('/').map_or(0, |i| i + 1)];
+            format!("{}{}", dir, location)
+        };
+        Ok(Url {
+            host: self.host.clone(),
+            port: self.port,
+            path,
+        })
+    }
+
+    fn host_header(&self) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        if self.port == 80 {
+            host
+        } else {
+            format!("{}:{}", host, self.port)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// The first header of that name, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Gets `url`, or only the bytes `start..=end` of it with a `range`, following redirects.
+/// Responses with a body longer than `max_len` are refused.
+pub async fn get(url: &Url, range: Option<(u64, u64)>, max_len: usize) -> Result<Response, Error> {
+    let mut url = url.clone();
+    for _ in 0..MAX_REDIRECTS {
+        let response = get_once(&url, range, max_len).await?;
+        match (response.status, response.header("location")) {
+            (301 | 302 | 303 | 307 | 308, Some(location)) => url = url.join(location)?,
+            _ => return Ok(response),
+        }
+    }
+    Err(Error::Http(format!("too many redirects from {}", url.host)))
+}
+
+async fn get_once(url: &Url, range: Option<(u64, u64)>, max_len: usize) -> Result<Response, Error> {
+    let mut stream = TcpStream::connect((url.host.as_str(), url.port)).await?;
+    let mut request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: thor/{}\r\nConnection: close\r\n",
+        url.path,
+        url.host_header(),
+        env!("CARGO_PKG_VERSION")
+    );
+    if let Some((start, end)) = range {
+        request.push_str(&format!("Range: bytes={}-{}\r\n", start, end));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // the server closes the connection after the response
+    let limit = (MAX_HEADER_LEN + max_len) as u64;
+    let mut raw = vec![];
+    (&mut stream).take(limit + 1).read_to_end(&mut raw).await?;
+    if raw.len() as u64 > limit {
+        return Err(Error::Http("response too long".to_owned()));
+    }
+    parse_response(&raw)
+}
+
+fn parse_response(raw: &[u8]) -> Result<Response, Error> {
+    let invalid = || Error::Http("invalid response".to_owned());
+    let header_end = raw
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(invalid)?;
+    let head = std::str::from_utf8(&raw[..header_end]).map_err(|_| invalid())?;
+    let mut lines = head.split("\r\n");
+    // HTTP/1.1 206 Partial Content
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(invalid)?;
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_owned(), value.trim().to_owned()))
+        .collect();
+    let mut response = Response {
+        status,
+        headers,
+        body: vec![],
+    };
+
+    let body = &raw[header_end + 4..];
+    response.body = if response
+        .header("transfer-encoding")
+        .is_some_and(|te| te.eq_ignore_ascii_case("chunked"))
+    {
+        decode_chunked(body).ok_or_else(invalid)?
+    } else if let Some(length) = response.header("content-length") {
+        let length: usize = length.parse().map_err(|_| invalid())?;
+        body.get(..length)
+            .ok_or_else(|| Error::Http("truncated response".to_owned()))?
+            .to_vec()
+    } else {
+        body.to_vec()
+    };
+    Ok(response)
+}
+
+/// Joins the chunks of a `Transfer-Encoding: chunked` body.
+fn decode_chunked(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = vec![];
+    loop {
+        let line_end = body.windows(2).position(|w| w == b"\r\n")?;
+        let size = std::str::from_utf8(&body[..line_end]).ok()?;
+        // chunk extensions follow a semicolon
+        let size = size.split(';').next()?.trim();
+        let size = usize::from_str_radix(size, 16).ok()?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Some(decoded);
+        }
+        decoded.extend_from_slice(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
+
+/// Escapes everything but the unreserved characters of RFC 3986, e.g. for a path segment.
+pub fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// Reverses `percent_encode`, also decoding `+` as a space as in query strings. Returns
+/// bytes as escapes may encode anything, e.g. an info hash.
+pub fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let byte = s
+                    .get(i + 1..i + 3)
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())?;
+                decoded.push(byte);
+                i += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    Some(decoded)
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_parse() {
+    let url: Url = "http://example.com:8080/files/a%20b?x=1".parse().unwrap();
+    assert_eq!(url.host, "example.com");
+    assert_eq!(url.port, 8080);
+    assert_eq!(url.path, "/files/a%20b?x=1");
+    assert_eq!("http://example.com".parse::<Url>().unwrap().path, "/");
+    assert!("https://example.com/".parse::<Url>().is_err());
+    assert_eq!(url.join("c").unwrap().path, "/files/c");
+    assert_eq!(url.join("/d").unwrap().path, "/d");
+    assert_eq!(url.join("http://other/e").unwrap().host, "other");
+    let url: Url = "http://[::1]:6969/announce".parse().unwrap();
+    assert_eq!((url.host.as_str(), url.port), ("::1", 6969));
+    assert_eq!(url.host_header(), "[::1]:6969");
+
+    let response = parse_response(
+        b"HTTP/1.1 206 Partial Content\r\nContent-Length: 3\r\nContent-Range: bytes 0-2/10\r\n\r\nabcdef",
+    )
+    .unwrap();
+    assert_eq!(response.status, 206);
+    assert_eq!(response.header("content-range"), Some("bytes 0-2/10"));
+    assert_eq!(response.body, b"abc");
+
+    let response = parse_response(
+        b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3;x=y\r\nabc\r\n2\r\nde\r\n0\r\n\r\n",
+    )
+    .unwrap();
+    assert_eq!(response.body, b"abcde");
+    assert!(parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\nabc").is_err());
+
+    assert_eq!(percent_encode("a b/ü.txt"), "a%20b%2F%C3%BC.txt");
+    assert_eq!(percent_decode("%00%FFa+b").unwrap(), b"\x00\xffa b");
+    assert!(percent_decode("%4").is_none());
+}
+
+/// Serves the files of `dir` with range requests, answering 404 for missing files.
+#[cfg(test)]
+pub(crate) async fn serve_files(dir: std::path::PathBuf) -> std::net::SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let dir = dir.clone();
+            tokio::spawn(async move {
+                let mut request = vec![];
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request).into_owned();
+                let path = request.split(' ').nth(1).unwrap_or("/");
+                let mut file = dir.clone();
+                for segment in path.split('/').filter(|s| !s.is_empty()) {
+                    file.push(crate::magnet::percent_decode(segment).unwrap());
+                }
+                let range = request
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Range: bytes="))
+                    .and_then(|range| range.trim().split_once('-'))
+                    .map(|(start, end)| {
+                        let start: usize = start.parse().unwrap();
+                        let end: usize = end.parse().unwrap();
+                        (start, end)
+                    });
+                let response = match std::fs::read(&file) {
+                    Ok(contents) => match range {
+                        Some((start, end)) => {
+                            let end = std::cmp::min(end + 1, contents.len());
+                            let body = &contents[start..end];
+                            let mut response = format!(
+                                "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\n\r\n",
+                                body.len()
+                            )
+                            .into_bytes();
+                            response.extend_from_slice(body);
+                            response
+                        }
+                        None => {
+                            let mut response = format!(
+                                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                                contents.len()
+                            )
+                            .into_bytes();
+                            response.extend_from_slice(&contents);
+                            response
+                        }
+                    },
+                    Err(_) => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec(),
+                };
+                let _ = stream.write_all(&response).await;
+            });
+        }
+    });
+    addr
+}
@@ -0,0 +1,341 @@
+use crate::magnet::encode_base32;
+use crate::model::MetaInfo;
+use std::fmt::{self, Write};
+
+/// What `thor info` shows about a torrent, as text or as JSON.
+#[derive(Debug)]
+pub struct TorrentSummary<'a> {
+    meta_info: &'a MetaInfo,
+    info_hash: [u8; 20],
+}
+
+/// A directory of the file tree, files have no children.
+#[derive(Debug, Default)]
+struct Node {
+    children: Vec<(String, Node)>,
+    length: Option<u64>,
+}
+
+impl Node {
+    fn insert(&mut self, path: &[String], length: u64) {
+        let (first, rest) = match path.split_first() {
+            Some(split) => split,
+            None => return,
+        };
+        let index = match self.children.iter().position(|(name, _)| name == first) {
+            Some(index) => index,
+            None => {
+                self.children.push((first.clone(), Node::default()));
+                self.children.len() - 1
+            }
+        };
+        let child = &mut self.children[index].1;
+        if rest.is_empty() {
+            child.length = Some(length);
+        } else {
+            child.insert(rest, length);
+        }
+    }
+
+    fn write(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        for (name, child) in self.children.iter() {
+            match child.length {
+                Some(length) => writeln!(
+                    f,
+                    "{:indent$}{} ({})",
+                    "",
+                    name,
+                    size(length),
+                    indent = depth * 2
+                )?,
+                None => {
+                    writeln!(f, "{:indent$}{}/", "", name, indent = depth * 2)?;
+                    child.write(f, depth + 1)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> TorrentSummary<'a> {
+    /// `info_hash` is given since it must be computed over the info dict as it appears in
+    /// the .torrent file, see `MetaInfo::from_torrent_bytes`.
+    pub fn new(meta_info: &'a MetaInfo, info_hash: [u8; 20]) -> TorrentSummary<'a> {
+        TorrentSummary {
+            meta_info,
+            info_hash,
+        }
+    }
+
+    pub fn info_hash_hex(&self) -> String {
+        self.info_hash
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    pub fn info_hash_base32(&self) -> String {
+        encode_base32(&self.info_hash)
+    }
+
+    /// The files with their path relative to the torrent's directory, the name of a
+    /// single file torrent for its only file.
+    fn files(&self) -> Vec<(Vec<String>, u64)> {
+        let info = &self.meta_info.info;
+        info.file_list()
+            .into_iter()
+            .map(|f| (f.path, f.length))
+            .collect()
+    }
+
+    /// A single line of JSON, for scripts.
+    pub fn to_json(&self) -> String {
+        let meta_info = self.meta_info;
+        let info = &meta_info.info;
+        let tiers: Vec<String> = meta_info
+            .tiers()
+            .iter()
+            .map(|tier| json_array(tier.iter().map(|t| json_string(t))))
+            .collect();
+        let files = self.files().into_iter().map(|(path, length)| {
+            format!(
+                "{{\"path\":{},\"length\":{}}}",
+                json_array(path.iter().map(|p| json_string(p))),
+                length
+            )
+        });
+
+        let fields = vec![
+            ("name", json_string(&info.name)),
+            ("info_hash", json_string(&self.info_hash_hex())),
+            ("info_hash_base32", json_string(&self.info_hash_base32())),
+            ("piece_length", info.piece_length.to_string()),
+            ("num_pieces", info.num_pieces().to_string()),
+            ("total_length", info.total_length().to_string()),
+            ("private", info.is_private().to_string()),
+            ("created_by", json_option(meta_info.created_by.as_deref())),
+            (
+                "creation_date",
+                meta_info
+                    .creation_date
+                    .map_or_else(|| "null".to_owned(), |d| d.to_string()),
+            ),
+            ("comment", json_option(meta_info.comment.as_deref())),
+            ("source", json_option(info.source.as_deref())),
+            ("trackers", json_array(tiers.into_iter())),
+            ("files", json_array(files)),
+        ];
+        let fields = fields
+            .into_iter()
+            .map(|(key, value)| format!("{}:{}", json_string(key), value));
+        format!("{{{}}}", fields.collect::<Vec<_>>().join(","))
+    }
+}
+
+impl fmt::Display for TorrentSummary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let meta_info = self.meta_info;
+        let info = &meta_info.info;
+        writeln!(f, "Name:           {}", info.name)?;
+        writeln!(f, "Info hash:      {}", self.info_hash_hex())?;
+        writeln!(f, "Info hash (32): {}", self.info_hash_base32())?;
+        writeln!(
+            f,
+            "Pieces:         {} x {}",
+            info.num_pieces(),
+            size(info.piece_length)
+        )?;
+        writeln!(
+            f,
+            "Total size:     {} ({} bytes)",
+            size(info.total_length()),
+            info.total_length()
+        )?;
+        writeln!(
+            f,
+            "Private:        {}",
+            if info.is_private() { "yes" } else { "no" }
+        )?;
+        if let Some(created_by) = meta_info.created_by.as_ref() {
+            writeln!(f, "Created by:     {}", created_by)?;
+        }
+        if let Some(date) = meta_info.creation_date {
+            writeln!(f, "Creation date:  {}", format_date(date))?;
+        }
+        if let Some(comment) = meta_info.comment.as_ref() {
+            writeln!(f, "Comment:        {}", comment)?;
+        }
+        if let Some(source) = info.source.as_ref() {
+            writeln!(f, "Source:         {}", source)?;
+        }
+
+        let tiers = meta_info.tiers();
+        if tiers.is_empty() {
+            writeln!(f, "Trackers:       none")?;
+        } else {
+            writeln!(f, "Trackers:")?;
+            for (i, tier) in tiers.iter().enumerate() {
+                writeln!(f, "  tier {}: {}", i + 1, tier.join(", "))?;
+            }
+        }
+
+        writeln!(f, "Files:")?;
+        let mut root = Node::default();
+        if info.is_single_file() {
+            root.insert(std::slice::from_ref(&info.name), info.total_length());
+        } else {
+            let mut dir = Node::default();
+            for (path, length) in self.files() {
+                dir.insert(&path, length);
+            }
+            root.children.push((info.name.clone(), dir));
+        }
+        root.write(f, 1)
+    }
+}
+
+/// A size in the largest unit keeping it at least 1.
+fn size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.2} {}", value, UNITS[unit])
+    }
+}
+
+/// Formats a unix timestamp as a UTC date.
+fn format_date(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let seconds = timestamp % 86400;
+
+    // civil from days, for the proleptic Gregorian calendar
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn json_option(s: Option<&str>) -> String {
+    s.map_or_else(|| "null".to_owned(), json_string)
+}
+
+fn json_array(values: impl Iterator<Item = String>) -> String {
+    format!("[{}]", values.collect::<Vec<_>>().join(","))
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_summary() {
+    use crate::model::{FileInfo, InfoDict};
+
+    let meta_info = MetaInfo {
+        announce: Some("udp://a:1".to_owned()),
+        announce_list: Some(vec![
+            vec!["udp://a:1".to_owned(), "udp://b:1".to_owned()],
+            vec!["udp://c:1".to_owned()],
+        ]),
+        comment: Some("a \"quoted\" comment".to_owned()),
+        created_by: None,
+        creation_date: Some(1_600_000_000),
+        encoding: None,
+        info: InfoDict {
+            file_tree: None,
+            files: Some(vec![
+                FileInfo {
+                    attr: None,
+                    length: 2048,
+                    md5sum: None,
+                    path: vec!["sub".to_owned(), "b".to_owned()],
+                    sha1: None,
+                    symlink_path: None,
+                },
+                FileInfo {
+                    attr: None,
+                    length: 100,
+                    md5sum: None,
+                    path: vec!["a".to_owned()],
+                    sha1: None,
+                    symlink_path: None,
+                },
+            ]),
+            length: None,
+            md5sum: None,
+            meta_version: None,
+            name: "dir".to_owned(),
+            piece_length: 16 * 1024,
+            pieces: vec![0; 20],
+            private: Some(1),
+            source: None,
+        },
+        piece_layers: None,
+        url_list: None,
+        raw_info: None,
+    };
+    let summary = TorrentSummary::new(&meta_info, [0xab; 20]);
+
+    let text = summary.to_string();
+    assert!(text.contains(&format!("Info hash:      {}", "ab".repeat(20))));
+    assert!(text.contains("Total size:     2.10 KiB (2148 bytes)"));
+    assert!(text.contains("Creation date:  2020-09-13 12:26:40 UTC"));
+    assert!(text.contains("  tier 1: udp://a:1, udp://b:1\n  tier 2: udp://c:1\n"));
+    assert!(text.ends_with("Files:\n  dir/\n    sub/\n      b (2.00 KiB)\n    a (100 B)\n"));
+
+    let json = summary.to_json();
+    assert!(json.starts_with(&format!(
+        "{{\"name\":\"dir\",\"info_hash\":\"{}\",\"info_hash_base32\":\"{}\"",
+        "ab".repeat(20),
+        summary.info_hash_base32()
+    )));
+    assert!(json.contains("\"private\":true,\"created_by\":null,\"creation_date\":1600000000"));
+    assert!(json.contains("\"comment\":\"a \\\"quoted\\\" comment\""));
+    assert!(json.contains("\"trackers\":[[\"udp://a:1\",\"udp://b:1\"],[\"udp://c:1\"]]"));
+    assert!(json.ends_with(
+        "\"files\":[{\"path\":[\"sub\",\"b\"],\"length\":2048},{\"path\":[\"a\"],\"length\":100}]}"
+    ));
+}
@@ -5,10 +5,27 @@ extern crate rand;
 extern crate sha1;
 extern crate tokio;
 
+pub mod bitfield;
+pub mod config;
+pub mod create;
+pub mod dht;
 pub mod error;
+pub mod event;
+pub mod extension;
+pub mod http;
+pub mod inspect;
+pub mod lsd;
+pub mod magnet;
+pub mod merkle;
 pub mod model;
-mod peer;
+pub mod peer;
+pub mod rate_limit;
+pub mod session;
+pub mod storage;
+pub mod torrent;
 pub mod tracker;
+pub mod tracker_server;
+pub mod utp;
 
 pub use error::Error;
 pub use model::{FileInfo, InfoDict, MetaInfo};
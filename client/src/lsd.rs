@@ -0,0 +1,497 @@
+use crate::error::Error;
+use crate::magnet::decode_hex;
+use crate::peer::pool::{PeerPool, PeerSource};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::{oneshot, Notify};
+
+pub const LSD_PORT: u16 = 6771;
+pub const IPV4_GROUP: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+pub const IPV6_GROUP: Ipv6Addr = Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f);
+/// How often each torrent is announced.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Announces of a torrent are at least this far apart, and so are the announces of another
+/// peer we take into account, to avoid storms on the LAN.
+const MIN_INTERVAL: Duration = Duration::from_secs(60);
+const RECV_BUF_SIZE: usize = 1500;
+
+/// The multicast groups of BEP 14.
+pub fn default_groups() -> Vec<SocketAddr> {
+    vec![
+        SocketAddr::from((IPV4_GROUP, LSD_PORT)),
+        SocketAddr::from((IPV6_GROUP, LSD_PORT)),
+    ]
+}
+
+/// A `BT-SEARCH` announce: a peer on `port` of the sender has the torrents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announce {
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    pub cookie: Option<String>,
+}
+
+impl Announce {
+    pub fn to_bytes(&self, group: &SocketAddr) -> Vec<u8> {
+        let mut message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+            group, self.port
+        );
+        for info_hash in self.info_hashes.iter() {
+            let hex: String = info_hash.iter().map(|b| format!("{:02x}", b)).collect();
+            message.push_str(&format!("Infohash: {}\r\n", hex));
+        }
+        if let Some(cookie) = self.cookie.as_ref() {
+            message.push_str(&format!("cookie: {}\r\n", cookie));
+        }
+        message.push_str("\r\n\r\n");
+        message.into_bytes()
+    }
+
+    /// Parses an announce, header names are case insensitive and info hashes that are not
+    /// 40 hex digits are skipped.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Announce, Error> {
+        let invalid = |reason: &str| Error::Server(format!("invalid lsd announce: {}", reason));
+        let message = std::str::from_utf8(bytes).map_err(|_| invalid("not utf-8"))?;
+        let mut lines = message.split("\r\n");
+        if lines.next() != Some("BT-SEARCH * HTTP/1.1") {
+            return Err(invalid("not a BT-SEARCH"));
+        }
+
+        let mut port = None;
+        let mut announce = Announce {
+            port: 0,
+            info_hashes: vec![],
+            cookie: None,
+        };
+        for line in lines.take_while(|l| !l.is_empty()) {
+            let (name, value) = match line.split_once(':') {
+                Some((name, value)) => (name.trim().to_lowercase(), value.trim()),
+                None => continue,
+            };
+            match name.as_str() {
+                "port" => port = value.parse().ok(),
+                "infohash" => {
+                    if let Some(bytes) = decode_hex(value).filter(|b| b.len() == 20) {
+                        let mut info_hash = [0u8; 20];
+                        info_hash.copy_from_slice(&bytes);
+                        announce.info_hashes.push(info_hash);
+                    }
+                }
+                "cookie" => announce.cookie = Some(value.to_owned()),
+                _ => {}
+            }
+        }
+        announce.port = port.ok_or_else(|| invalid("missing port"))?;
+        Ok(announce)
+    }
+}
+
+struct LsdTorrent {
+    /// Sent along our announces of the torrent, to recognize them when they loop back.
+    cookie: String,
+    pool: Arc<Mutex<PeerPool>>,
+    /// Announced since it was added.
+    announced: bool,
+}
+
+struct State {
+    torrents: HashMap<[u8; 20], LsdTorrent>,
+    /// When each torrent was last announced, kept after it is removed so that a torrent
+    /// paused and resumed is not announced more often.
+    announced: HashMap<[u8; 20], Instant>,
+    /// When the announces of other peers were last taken into account.
+    heard: HashMap<(IpAddr, [u8; 20]), Instant>,
+}
+
+impl State {
+    fn on_announce(&mut self, from: SocketAddr, announce: Announce) {
+        if self
+            .torrents
+            .values()
+            .any(|t| announce.cookie.as_ref() == Some(&t.cookie))
+        {
+            return;
+        }
+        let now = Instant::now();
+        self.heard
+            .retain(|_, at| now.duration_since(*at) < MIN_INTERVAL);
+        let addr = SocketAddr::new(from.ip(), announce.port);
+        for info_hash in announce.info_hashes {
+            let torrent = match self.torrents.get(&info_hash) {
+                Some(torrent) => torrent,
+                None => continue,
+            };
+            if self.heard.contains_key(&(from.ip(), info_hash)) {
+                continue;
+            }
+            self.heard.insert((from.ip(), info_hash), now);
+            debug!("lsd found {} for {:02x?}", addr, info_hash);
+            torrent
+                .pool
+                .lock()
+                .unwrap()
+                .add_candidate(addr, PeerSource::Lsd);
+        }
+    }
+
+    /// The torrents due for an announce, and how long until the next one is.
+    fn due(&mut self) -> (Vec<([u8; 20], String)>, Duration) {
+        let now = Instant::now();
+        let mut due = vec![];
+        let mut next = ANNOUNCE_INTERVAL;
+        for (info_hash, torrent) in self.torrents.iter_mut() {
+            let interval = if torrent.announced {
+                ANNOUNCE_INTERVAL
+            } else {
+                MIN_INTERVAL
+            };
+            let wait = match self.announced.get(info_hash) {
+                Some(at) => interval.saturating_sub(now.duration_since(*at)),
+                None => Duration::from_secs(0),
+            };
+            if wait == Duration::from_secs(0) {
+                torrent.announced = true;
+                self.announced.insert(*info_hash, now);
+                due.push((*info_hash, torrent.cookie.clone()));
+            } else {
+                next = std::cmp::min(next, wait);
+            }
+        }
+        (due, next)
+    }
+}
+
+/// Local Service Discovery (BEP 14): announces the torrents to the peers of the LAN over
+/// multicast, and adds the peers they announce to the pools of the torrents.
+///
+/// The announces are sent and received by background tasks for as long as the `Lsd`
+/// lives. Private torrents must not be added.
+pub struct Lsd {
+    state: Arc<Mutex<State>>,
+    wake: Arc<Notify>,
+    /// Stop the announce loop and the receive loop of each group.
+    _shutdown: Vec<oneshot::Sender<()>>,
+}
+
+impl Lsd {
+    /// Joins the `groups`, usually `default_groups()`, announcing peers on `port`. Fails
+    /// only if no group could be joined, e.g. when another client holds the LSD port.
+    pub async fn bind(port: u16, groups: &[SocketAddr]) -> Result<Lsd, Error> {
+        let mut sockets = vec![];
+        for group in groups {
+            match join(group).await {
+                Ok(socket) => sockets.push((Arc::new(socket), *group)),
+                Err(e) => warn!("failed to join lsd group {}: {}", group, e),
+            }
+        }
+        if sockets.is_empty() {
+            return Err(Error::Server("no lsd group joined".to_owned()));
+        }
+        info!("lsd announcing port {} to {} groups", port, sockets.len());
+
+        let state = Arc::new(Mutex::new(State {
+            torrents: HashMap::new(),
+            announced: HashMap::new(),
+            heard: HashMap::new(),
+        }));
+        let wake = Arc::new(Notify::new());
+        let mut shutdown = vec![];
+        for (socket, _) in sockets.iter() {
+            let (shutdown_tx, shutdown_rx) = oneshot::channel();
+            tokio::spawn(receive_loop(socket.clone(), state.clone(), shutdown_rx));
+            shutdown.push(shutdown_tx);
+        }
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        tokio::spawn(announce_loop(
+            sockets,
+            port,
+            state.clone(),
+            wake.clone(),
+            shutdown_rx,
+        ));
+        shutdown.push(shutdown_tx);
+
+        Ok(Lsd {
+            state,
+            wake,
+            _shutdown: shutdown,
+        })
+    }
+
+    /// Announces a torrent right away, or a minute after it was last announced, then
+    /// every few minutes until it is removed. Peers found are added to `pool`.
+    pub fn add_torrent(&self, info_hash: [u8; 20], pool: Arc<Mutex<PeerPool>>) {
+        let cookie: String = (0..8)
+            .map(|_| format!("{:x}", rand::random::<u8>() % 16))
+            .collect();
+        let torrent = LsdTorrent {
+            cookie,
+            pool,
+            announced: false,
+        };
+        self.state
+            .lock()
+            .unwrap()
+            .torrents
+            .insert(info_hash, torrent);
+        self.wake.notify_one();
+    }
+
+    pub fn remove_torrent(&self, info_hash: &[u8; 20]) {
+        self.state.lock().unwrap().torrents.remove(info_hash);
+    }
+}
+
+/// Binds the port of `group` and joins it when it is a multicast address. The port is
+/// shared with the other clients of the host, which listen on it as well.
+async fn join(group: &SocketAddr) -> Result<UdpSocket, Error> {
+    let any = match group {
+        SocketAddr::V4(_) => IpAddr::from(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::from(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = bind_reuse_addr(SocketAddr::new(any, group.port()))?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket)?;
+    match group.ip() {
+        IpAddr::V4(ip) if ip.is_multicast() => {
+            socket.join_multicast_v4(ip, Ipv4Addr::UNSPECIFIED)?
+        }
+        IpAddr::V6(ip) if ip.is_multicast() => socket.join_multicast_v6(&ip, 0)?,
+        _ => {}
+    }
+    Ok(socket)
+}
+
+/// Binds a UDP socket with `SO_REUSEADDR` set, which must be done before binding.
+#[cfg(unix)]
+fn bind_reuse_addr(addr: SocketAddr) -> std::io::Result<std::net::UdpSocket> {
+    use std::io;
+    use std::mem;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    let family = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    // SAFETY: the descriptor is owned by `socket` right after it is created, and the
+    // addresses passed to the kernel are valid for the lengths given
+    unsafe {
+        let fd = libc::socket(family, libc::SOCK_DGRAM, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = std::net::UdpSocket::from_raw_fd(fd);
+
+        let enable: libc::c_int = 1;
+        let result = libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_REUSEADDR,
+            &enable as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        );
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut storage: libc::sockaddr_storage = mem::zeroed();
+        let len = match addr {
+            SocketAddr::V4(addr) => {
+                let sin = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in);
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                let sin6 = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6);
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_addr.s6_addr = addr.ip().octets();
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        let result = libc::bind(
+            socket.as_raw_fd(),
+            &storage as *const _ as *const libc::sockaddr,
+            len as libc::socklen_t,
+        );
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(socket)
+    }
+}
+
+#[cfg(not(unix))]
+fn bind_reuse_addr(addr: SocketAddr) -> std::io::Result<std::net::UdpSocket> {
+    std::net::UdpSocket::bind(addr)
+}
+
+async fn receive_loop(
+    socket: Arc<UdpSocket>,
+    state: Arc<Mutex<State>>,
+    mut shutdown: oneshot::Receiver<()>,
+) {
+    let mut buf = vec![0u8; RECV_BUF_SIZE];
+    loop {
+        let (len, from) = tokio::select! {
+            _ = &mut shutdown => break,
+            received = socket.recv_from(&mut buf) => match received {
+                Ok(received) => received,
+                Err(e) => {
+                    debug!("lsd socket error: {}", e);
+                    continue;
+                }
+            },
+        };
+        match Announce::from_bytes(&buf[..len]) {
+            Ok(announce) => state.lock().unwrap().on_announce(from, announce),
+            Err(e) => debug!("{} from {}", e, from),
+        }
+    }
+}
+
+/// Sends the announces when they are due, one message per torrent since each has its
+/// own cookie.
+async fn announce_loop(
+    sockets: Vec<(Arc<UdpSocket>, SocketAddr)>,
+    port: u16,
+    state: Arc<Mutex<State>>,
+    wake: Arc<Notify>,
+    mut shutdown: oneshot::Receiver<()>,
+) {
+    loop {
+        let (due, next) = state.lock().unwrap().due();
+        for (info_hash, cookie) in due {
+            let announce = Announce {
+                port,
+                info_hashes: vec![info_hash],
+                cookie: Some(cookie),
+            };
+            for (socket, group) in sockets.iter() {
+                if let Err(e) = socket.send_to(&announce.to_bytes(group), group).await {
+                    debug!("failed to announce to lsd group {}: {}", group, e);
+                }
+            }
+        }
+
+        tokio::select! {
+            _ = &mut shutdown => return,
+            _ = tokio::time::sleep(next) => {}
+            _ = wake.notified() => {}
+        }
+    }
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_encode_decode() {
+    let announce = Announce {
+        port: 6881,
+        info_hashes: vec![[0xab; 20], [1; 20]],
+        cookie: Some("c00k1e".to_owned()),
+    };
+    let group = default_groups()[1];
+    let bytes = announce.to_bytes(&group);
+    let message = std::str::from_utf8(&bytes).unwrap();
+    assert!(message.starts_with(
+        "BT-SEARCH * HTTP/1.1\r\nHost: [ff15::efc0:988f]:6771\r\nPort: 6881\r\n\
+         Infohash: abababababababababababababababababababab\r\n"
+    ));
+    assert!(message.ends_with("cookie: c00k1e\r\n\r\n\r\n"));
+    assert_eq!(Announce::from_bytes(&bytes).unwrap(), announce);
+
+    let other = b"BT-SEARCH * HTTP/1.1\r\nhost: 239.192.152.143:6771\r\nPORT: 51413\r\n\
+        infohash: ABABABABABABABABABABABABABABABABABABABAB\r\nInfohash: 1234\r\n\r\n\r\n";
+    let parsed = Announce::from_bytes(other).unwrap();
+    assert_eq!(parsed.port, 51413);
+    assert_eq!(parsed.info_hashes, vec![[0xab; 20]]);
+    assert_eq!(parsed.cookie, None);
+
+    assert!(Announce::from_bytes(b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n").is_err());
+    assert!(Announce::from_bytes(b"BT-SEARCH * HTTP/1.1\r\n\r\n").is_err());
+}
+
+#[test]
+fn test_decode_invalid_info_hashes() {
+    // 40 bytes but not 40 hex digits, or not hex at all
+    let message = "BT-SEARCH * HTTP/1.1\r\nPort: 6881\r\n\
+        Infohash: \u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\
+        \u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\r\n\
+        Infohash: a\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\
+        \u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}b\r\n\
+        Infohash: zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz\r\n\
+        Infohash: 0101010101010101010101010101010101010101\r\n\r\n\r\n";
+    let parsed = Announce::from_bytes(message.as_bytes()).unwrap();
+    assert_eq!(parsed.info_hashes, vec![[1u8; 20]]);
+}
+
+#[tokio::test]
+async fn test_shared_port() {
+    // another client of the host already listens on the port
+    let port = std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let group = SocketAddr::from(([127, 0, 0, 1], port));
+    let _other = join(&group).await.unwrap();
+    assert!(join(&group).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_discover_peers() {
+    // a unicast group on loopback: our own announces come back to us
+    let port = std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let group = SocketAddr::from(([127, 0, 0, 1], port));
+    let lsd = Lsd::bind(7000, &[group]).await.unwrap();
+    let pool = Arc::new(Mutex::new(PeerPool::new()));
+    let info_hash = [5u8; 20];
+    lsd.add_torrent(info_hash, pool.clone());
+
+    let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let announce = |port| Announce {
+        port,
+        info_hashes: vec![[6u8; 20], info_hash],
+        cookie: Some("other".to_owned()),
+    };
+    peer.send_to(&announce(8000).to_bytes(&group), group)
+        .await
+        .unwrap();
+    // announced again too soon, ignored
+    peer.send_to(&announce(8001).to_bytes(&group), group)
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mut pool = pool.lock().unwrap();
+    assert_eq!(
+        pool.next_candidate(),
+        Some((SocketAddr::from(([127, 0, 0, 1], 8000)), PeerSource::Lsd))
+    );
+    // not our own announce
+    assert_eq!(pool.next_candidate(), None);
+    drop(pool);
+
+    let announced = lsd.state.lock().unwrap().announced[&info_hash];
+    // added again right away, the announce waits for a minute since the last one
+    lsd.remove_torrent(&info_hash);
+    lsd.add_torrent(info_hash, Arc::new(Mutex::new(PeerPool::new())));
+    let (due, next) = lsd.state.lock().unwrap().due();
+    assert!(due.is_empty());
+    assert!(next <= MIN_INTERVAL && next > MIN_INTERVAL - Duration::from_secs(5));
+    assert!(announced.elapsed() < Duration::from_secs(5));
+}
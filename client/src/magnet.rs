@@ -0,0 +1,214 @@
+use crate::error::Error;
+use crate::model::{InfoDict, MetaInfo, UrlList};
+use std::str::FromStr;
+
+const SCHEME: &str = "magnet:?";
+const BTIH_PREFIX: &str = "urn:btih:";
+
+/// A parsed `magnet:?xt=urn:btih:...` link (BEP 9).
+#[derive(Debug, Clone, PartialEq)]
+pub struct MagnetLink {
+    pub info_hash: [u8; 20],
+    /// `dn`: name to display while the metadata is not known yet.
+    pub display_name: Option<String>,
+    /// `tr`: tracker urls.
+    pub trackers: Vec<String>,
+    /// `x.pe`: peer addresses as `host:port`, where host may also be a hostname.
+    pub peers: Vec<String>,
+    /// `ws`: web seed urls.
+    pub web_seeds: Vec<String>,
+}
+
+impl FromStr for MagnetLink {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<MagnetLink, Error> {
+        if !s.starts_with(SCHEME) {
+            return Err(Error::InvalidMagnet("missing magnet:? prefix".into()));
+        }
+
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers = vec![];
+        let mut peers = vec![];
+        let mut web_seeds = vec![];
+
+        for param in s[SCHEME.len()..].split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = match param.find('=') {
+                Some(i) => (&param[..i], percent_decode(&param[i + 1..])?),
+                None => return Err(Error::InvalidMagnet(format!("invalid parameter {}", param))),
+            };
+            match key {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix(BTIH_PREFIX) {
+                        info_hash = Some(decode_info_hash(hash)?);
+                    }
+                }
+                "dn" => display_name = Some(value),
+                "tr" => trackers.push(value),
+                "x.pe" => peers.push(value),
+                "ws" => web_seeds.push(value),
+                _ => {}
+            }
+        }
+
+        Ok(MagnetLink {
+            info_hash: info_hash
+                .ok_or_else(|| Error::InvalidMagnet("missing urn:btih exact topic".into()))?,
+            display_name,
+            trackers,
+            peers,
+            web_seeds,
+        })
+    }
+}
+
+impl MagnetLink {
+    /// Builds the `MetaInfo` of the torrent once its info dict was downloaded from peers,
+    /// `raw_info` being the bytes downloaded. Each tracker of the link is placed in its own
+    /// tier, the web seeds in `url-list`.
+    pub fn into_meta_info(self, info: InfoDict, raw_info: Vec<u8>) -> MetaInfo {
+        MetaInfo {
+            announce: self.trackers.first().cloned(),
+            announce_list: if self.trackers.len() > 1 {
+                Some(self.trackers.into_iter().map(|t| vec![t]).collect())
+            } else {
+                None
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            encoding: None,
+            info,
+            piece_layers: None,
+            url_list: if self.web_seeds.is_empty() {
+                None
+            } else {
+                Some(UrlList::Many(self.web_seeds))
+            },
+            raw_info: Some(raw_info),
+        }
+    }
+}
+
+/// The info hash is either 40 hex characters or 32 base32 characters.
+fn decode_info_hash(s: &str) -> Result<[u8; 20], Error> {
+    let bytes = match s.len() {
+        40 => decode_hex(s),
+        32 => decode_base32(s),
+        _ => None,
+    }
+    .ok_or_else(|| Error::InvalidMagnet(format!("invalid info hash {}", s)))?;
+
+    let mut info_hash = [0u8; 20];
+    info_hash.copy_from_slice(&bytes);
+    Ok(info_hash)
+}
+
+/// Decodes a hex string, e.g. an info hash, `None` if it is not one.
+pub fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Decodes unpadded RFC 4648 base32.
+fn decode_base32(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for c in s.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | u32::from(value);
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+/// Encodes in unpadded RFC 4648 base32, the other form of info hashes in magnet links.
+pub fn encode_base32(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut encoded = String::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for byte in bytes {
+        buffer = (buffer << 8) | u32::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(ALPHABET[(buffer >> bits) as usize & 31] as char);
+        }
+        buffer &= (1 << bits) - 1;
+    }
+    if bits > 0 {
+        encoded.push(ALPHABET[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+    encoded
+}
+
+pub(crate) fn percent_decode(s: &str) -> Result<String, Error> {
+    let decoded = crate::http::percent_decode(s)
+        .ok_or_else(|| Error::InvalidMagnet(format!("invalid escape in {}", s)))?;
+    String::from_utf8(decoded).map_err(|_| Error::InvalidMagnet(format!("invalid utf-8 in {}", s)))
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_parse_hex_magnet() {
+    let link: MagnetLink = "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a\
+        &dn=Some+File%20Name&tr=udp%3A%2F%2Ftracker.example.com%3A6969\
+        &tr=http%3A%2F%2Fother.example.com%2Fannounce&x.pe=10.0.0.1%3A6881\
+        &ws=http%3A%2F%2Fmirror.example.com%2Ffile"
+        .parse()
+        .unwrap();
+
+    assert_eq!(link.info_hash[0], 0xc1);
+    assert_eq!(link.info_hash[19], 0x8a);
+    assert_eq!(link.display_name.as_deref(), Some("Some File Name"));
+    assert_eq!(
+        link.trackers,
+        vec![
+            "udp://tracker.example.com:6969",
+            "http://other.example.com/announce"
+        ]
+    );
+    assert_eq!(link.peers, vec!["10.0.0.1:6881"]);
+    assert_eq!(link.web_seeds, vec!["http://mirror.example.com/file"]);
+}
+
+#[test]
+fn test_parse_base32_magnet() {
+    let hex: MagnetLink = "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a"
+        .parse()
+        .unwrap();
+    let base32: MagnetLink = "magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK"
+        .parse()
+        .unwrap();
+    assert_eq!(hex.info_hash, base32.info_hash);
+    assert_eq!(
+        encode_base32(&base32.info_hash),
+        "YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK"
+    );
+}
+
+#[test]
+fn test_parse_invalid_magnet() {
+    assert!("http://example.com".parse::<MagnetLink>().is_err());
+    assert!("magnet:?dn=name".parse::<MagnetLink>().is_err());
+    assert!("magnet:?xt=urn:btih:1234".parse::<MagnetLink>().is_err());
+}
@@ -1,6 +1,11 @@
-use std::io::Read;
-use std::net::ToSocketAddrs;
-use thor::tracker::TrackerClient;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+use thor::config::Config;
+use thor::event::Event;
+use thor::magnet::MagnetLink;
+use thor::session::Session;
+use thor::torrent::picker::Priority;
+use tokio::sync::broadcast;
 // use tokio::net::TcpStream;
 
 // async fn peer_connection(addr: String) {
@@ -8,104 +13,416 @@ use thor::tracker::TrackerClient;
 //     let socket = TcpStream::connect(&socket_addr).await.unwrap();
 // }
 
-async fn make_tracker_request(meta_info: &thor::MetaInfo) -> Result<(), String> {
-    println!("announce: {}", meta_info.announce);
-    println!("announce_list: {:?}", meta_info.announce_list);
+#[derive(Debug, StructOpt)]
+#[structopt(name = "thor", about = "A BitTorrent client")]
+enum Command {
+    /// Downloads the contents of a .torrent file or a magnet link, then seeds it
+    Download {
+        torrent: String,
+        #[structopt(flatten)]
+        options: SessionArgs,
+        #[structopt(flatten)]
+        files: FileArgs,
+    },
+    /// Creates a .torrent file from a file or a directory
+    MakeTorrent(MakeTorrentArgs),
+    /// Prints the contents of a .torrent file
+    Info {
+        #[structopt(parse(from_os_str))]
+        torrent: PathBuf,
+        /// Prints a single line of JSON
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Uploads the contents of a .torrent file to other peers
+    Seed {
+        torrent: String,
+        #[structopt(flatten)]
+        options: SessionArgs,
+    },
+}
 
-    // TODO: add support for the multi tracker extension
+/// Settings of the session, overriding the configuration file and the environment.
+#[derive(Debug, StructOpt)]
+struct SessionArgs {
+    /// Directory of the torrent's files [default: .]
+    #[structopt(short, long, parse(from_os_str))]
+    dir: Option<PathBuf>,
+    /// Configuration file, defaults to $THOR_CONFIG, then to ~/.config/thor/config.toml
+    /// when it exists
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Port or range of ports to listen on, e.g. 6881-6889
+    #[structopt(long)]
+    port: Option<String>,
+    /// Connections of all torrents together
+    #[structopt(long)]
+    max_peers: Option<usize>,
+    /// Bytes per second, 0 means unlimited
+    #[structopt(long)]
+    download_rate: Option<u64>,
+    /// Bytes per second, 0 means unlimited
+    #[structopt(long)]
+    upload_rate: Option<u64>,
+    /// Does not use the DHT to find peers
+    #[structopt(long)]
+    no_dht: bool,
+    /// Does not look for peers on the local network
+    #[structopt(long)]
+    no_lsd: bool,
+    /// Only connects to peers over TCP
+    #[structopt(long)]
+    no_utp: bool,
+    /// Overrides any setting of the configuration file, e.g. tracker.timeout=5
+    #[structopt(long = "set", number_of_values = 1)]
+    settings: Vec<String>,
+}
 
-    if meta_info.announce.starts_with("udp://") {
-        let (_, mut url) = meta_info.announce.split_at("udp://".len());
+/// Which files of a multi-file torrent to download, by their index in the torrent from 0.
+#[derive(Debug, Default, StructOpt)]
+struct FileArgs {
+    /// Comma separated files to download, the others are skipped
+    #[structopt(long, use_delimiter = true)]
+    only: Vec<usize>,
+    /// Priority of a file, e.g. 2=high, one of skip, low, normal and high, can be repeated
+    #[structopt(long = "priority", number_of_values = 1)]
+    priorities: Vec<String>,
+}
 
-        if let Some(index) = url.rfind('/') {
-            let (u, _) = url.split_at(index);
-            url = u;
+impl FileArgs {
+    /// The priority of each of the `num_files` files, `None` when nothing was chosen.
+    fn priorities(&self, num_files: usize) -> Result<Option<Vec<Priority>>, String> {
+        if self.only.is_empty() && self.priorities.is_empty() {
+            return Ok(None);
         }
-
-        println!("url is: {}", url);
-
-        let mut addrs_iter = url.to_socket_addrs().unwrap();
-        if let Some(addr) = addrs_iter.next() {
-            println!("resolved to ip {}", addr);
-            let mut connection = thor::tracker::Connection::new(addr).await.unwrap();
-            let res = connection.announce(&meta_info.info).await.unwrap();
-
-            for peer in res.peers {
-                tokio::spawn(async move {
-                    match peer.start_connection().await {
-                        Ok(_) => {}
-                        Err(e) => {
-                            println!("connection with peer failed: {}", e);
-                        }
-                    };
-                });
+        let check = |index: usize| {
+            if index < num_files {
+                Ok(index)
+            } else {
+                Err(format!("no file {}, the torrent has {}", index, num_files))
             }
+        };
+        let mut priorities = if self.only.is_empty() {
+            vec![Priority::Normal; num_files]
+        } else {
+            let mut priorities = vec![Priority::Skip; num_files];
+            for &index in self.only.iter() {
+                priorities[check(index)?] = Priority::Normal;
+            }
+            priorities
+        };
+        for setting in self.priorities.iter() {
+            let (index, priority) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("expected index=priority, got {}", setting))?;
+            let index = index
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid file index {}", index))?;
+            priorities[check(index)?] = priority.parse().map_err(|e: thor::Error| e.to_string())?;
+        }
+        Ok(Some(priorities))
+    }
+}
 
-            // TODO: pass channel to peer connections in order to manage and wait for them
-            loop {}
+/// Reads the configuration file, then applies the environment and the command line.
+fn load_config(args: SessionArgs) -> Result<Config, String> {
+    let path = args
+        .config
+        .or_else(|| std::env::var_os("THOR_CONFIG").map(PathBuf::from))
+        .or_else(|| Config::default_path().filter(|path| path.exists()));
+    let mut config = match path {
+        Some(path) => Config::load(&path).map_err(|e| e.to_string())?,
+        None => Config::default(),
+    };
 
-        // Ok(())
-        } else {
-            Err(format!("failed to resolve address {}", url))
+    let vars = std::env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)));
+    config.apply_env(vars).map_err(|e| e.to_string())?;
+
+    let mut settings = vec![];
+    if let Some(dir) = args.dir {
+        config.download_dir = dir;
+    }
+    if let Some(port) = args.port {
+        settings.push(("listen_ports".to_owned(), port));
+    }
+    if let Some(max_peers) = args.max_peers {
+        config.max_peers = max_peers;
+    }
+    if let Some(rate) = args.download_rate {
+        settings.push(("download_rate".to_owned(), rate.to_string()));
+    }
+    if let Some(rate) = args.upload_rate {
+        settings.push(("upload_rate".to_owned(), rate.to_string()));
+    }
+    if args.no_dht {
+        config.dht = false;
+    }
+    if args.no_lsd {
+        config.lsd = false;
+    }
+    if args.no_utp {
+        config.utp = false;
+    }
+    for setting in args.settings {
+        match setting.split_once('=') {
+            Some((key, value)) => settings.push((key.trim().to_owned(), value.to_owned())),
+            None => return Err(format!("expected key=value, got {}", setting)),
         }
-    } else {
-        Err("Currently only UDP is supported for trackers".to_owned())
     }
+    for (key, value) in settings {
+        config.set(&key, &value).map_err(|e| e.to_string())?;
+    }
+    Ok(config)
 }
 
-#[tokio::main]
-async fn main() -> Result<(), String> {
-    env_logger::init();
+#[derive(Debug, StructOpt)]
+struct MakeTorrentArgs {
+    /// File or directory to create the torrent from
+    #[structopt(parse(from_os_str))]
+    path: PathBuf,
+    /// Where to write the .torrent file, defaults to <name>.torrent
+    #[structopt(short, long, parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// A tier of comma separated tracker urls, can be repeated
+    #[structopt(short, long = "tracker")]
+    trackers: Vec<String>,
+    /// Piece length in bytes, chosen from the total size when omitted
+    #[structopt(short, long)]
+    piece_length: Option<u64>,
+    #[structopt(short, long)]
+    comment: Option<String>,
+    /// Marks the torrent as private (BEP 27)
+    #[structopt(long)]
+    private: bool,
+    /// Source tag added to the info dict
+    #[structopt(short, long)]
+    source: Option<String>,
+    /// Omits the creation date
+    #[structopt(long)]
+    no_date: bool,
+}
 
-    let torrent_file = std::env::args().nth(1).unwrap();
+fn make_torrent(args: MakeTorrentArgs) -> Result<(), String> {
+    let mut builder = thor::create::TorrentBuilder::new(&args.path).private(args.private);
+    for tier in args.trackers {
+        builder = builder.tier(tier.split(',').map(|t| t.trim().to_owned()).collect());
+    }
+    if let Some(l) = args.piece_length {
+        builder = builder.piece_length(l);
+    }
+    if let Some(c) = args.comment {
+        builder = builder.comment(c);
+    }
+    if let Some(s) = args.source {
+        builder = builder.source(s);
+    }
+    if args.no_date {
+        builder = builder.creation_date(None);
+    }
 
-    {
-        use sha1::Digest;
-        let mut file = std::fs::File::open(&torrent_file).unwrap();
-        let mut buf = vec![];
-        file.read_to_end(&mut buf).unwrap();
+    let meta_info = builder.build().map_err(|e| e.to_string())?;
+    let bytes = bencoding::to_bytes(&meta_info).map_err(|e| e.to_string())?;
+    let output = args
+        .output
+        .unwrap_or_else(|| PathBuf::from(format!("{}.torrent", meta_info.info.name)));
+    std::fs::write(&output, bytes).map_err(|e| e.to_string())?;
 
-        let mut hasher = sha1::Sha1::new();
-        hasher.update(&buf);
+    println!(
+        "Created {} ({} pieces of {} KiB)",
+        output.display(),
+        meta_info.info.pieces.len() / 20,
+        meta_info.info.piece_length / 1024
+    );
+    Ok(())
+}
 
-        let bytes = &hasher.finalize();
-        assert!(bytes.len() == 20);
+/// What to download.
+enum Source {
+    Torrent(Box<thor::MetaInfo>, [u8; 20]),
+    Magnet(MagnetLink),
+}
 
-        println!(":D info_hash: {:02x}", &bytes);
+/// Runs a session with a single torrent, printing its progress until Ctrl-C or SIGTERM.
+async fn run_torrent(source: Source, config: Config, files: FileArgs) -> Result<(), String> {
+    let mut session = Session::new(config).await.map_err(|e| e.to_string())?;
+    tokio::spawn(print_events(session.subscribe()));
+
+    let added = tokio::select! {
+        added = add_torrent(&mut session, source) => Some(added),
+        _ = shutdown_signal() => None,
+    };
+    let result = match added {
+        Some(Ok(info_hash)) => match select_files(&session, &info_hash, &files).await {
+            Ok(()) => tokio::select! {
+                result = print_progress(&session, &info_hash) => result,
+                _ = shutdown_signal() => Ok(()),
+            },
+            Err(e) => Err(e),
+        },
+        Some(Err(e)) => Err(e.to_string()),
+        None => Ok(()),
+    };
+    println!("Shutting down");
+    session.shutdown().await;
+    result
+}
+
+async fn add_torrent(session: &mut Session, source: Source) -> Result<[u8; 20], thor::Error> {
+    match source {
+        Source::Torrent(meta_info, info_hash) => {
+            session.add_torrent(*meta_info, info_hash)?;
+            Ok(info_hash)
+        }
+        Source::Magnet(magnet) => {
+            println!(
+                "Fetching metadata for {}",
+                magnet.display_name.as_deref().unwrap_or("magnet link")
+            );
+            session.add_magnet(magnet).await
+        }
     }
+}
 
-    println!("Will parse torrent file {}", torrent_file);
+/// Applies the file priorities chosen on the command line.
+async fn select_files(
+    session: &Session,
+    info_hash: &[u8; 20],
+    files: &FileArgs,
+) -> Result<(), String> {
+    let status = session.status(info_hash).await.map_err(|e| e.to_string())?;
+    if let Some(priorities) = files.priorities(status.files.len())? {
+        session
+            .set_file_priorities(info_hash, priorities)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
 
-    let mut torrent_file_bytes = vec![];
-    let mut file = std::fs::File::open(torrent_file).unwrap();
-    let _ = file.read_to_end(&mut torrent_file_bytes).unwrap();
+/// Prints the events worth telling the user about.
+async fn print_events(mut events: broadcast::Receiver<Event>) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        match event {
+            Event::MetadataReceived { name, .. } => println!("Received metadata for {}", name),
+            Event::Announced { tracker, peers, .. } => {
+                println!("{} returned {} peers", tracker, peers)
+            }
+            Event::AnnounceFailed { tracker, error, .. } => {
+                println!("Announce to {} failed: {}", tracker, error)
+            }
+            Event::PieceFailed { index, .. } => println!("Piece {} failed the hash check", index),
+            Event::TorrentCompleted { .. } => println!("Download complete, seeding"),
+            Event::Error { message, .. } => println!("Error: {}", message),
+            _ => {}
+        }
+    }
+}
 
-    let meta_info: thor::MetaInfo = bencoding::from_bytes(&torrent_file_bytes).unwrap();
-    println!("Tracker URL = {}", meta_info.announce);
-    println!(
-        "Piece length = {:.2} KiB",
-        meta_info.info.piece_length as f32 / 1024.0
-    );
-    if let Some(l) = meta_info.info.length.as_ref() {
-        println!("File length = {:.2} MiB", *l as f32 / (1024.0 * 1024.0));
-    }
-    println!("Num pieces = {}", meta_info.info.pieces.len() / 20);
-    if let Some(files) = meta_info.info.files.as_ref() {
-        println!("Directory to download = {}", meta_info.info.name);
-        for f in files {
-            assert!(f.path.len() > 0);
-            println!("  Path: {}", f.path.join("/"));
-            println!("  Size: {} MiB", f.length as f32 / (1024.0 * 1024.0));
+/// Prints a line of progress every second, and the progress of each file of a multi-file
+/// torrent every 10 seconds.
+async fn print_progress(session: &Session, info_hash: &[u8; 20]) -> Result<(), String> {
+    let mut ticks = tokio::time::interval(std::time::Duration::from_secs(1));
+    for tick in 0u64.. {
+        ticks.tick().await;
+        let status = session.status(info_hash).await.map_err(|e| e.to_string())?;
+        println!(
+            "{}: {:?}, {}/{} pieces, {} peers, {} KiB down, {} KiB up",
+            status.name,
+            status.state,
+            status.pieces,
+            status.num_pieces,
+            status.peers,
+            status.downloaded / 1024,
+            status.uploaded / 1024
+        );
+        if status.files.len() > 1 && tick % 10 == 0 {
+            for (index, file) in status.files.iter().enumerate() {
+                let percent = match file.length {
+                    0 => 100,
+                    length => file.downloaded * 100 / length,
+                };
+                println!(
+                    "  {:>3} {:>3}% {:<6} {}",
+                    index,
+                    percent,
+                    file.priority,
+                    file.path.display()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM on unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            return;
         }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Reads a .torrent file and computes its info hash.
+fn read_meta_info(path: &Path) -> Result<(thor::MetaInfo, [u8; 20]), String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    thor::MetaInfo::from_torrent_bytes(&bytes).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn info(torrent: &Path, json: bool) -> Result<(), String> {
+    let (meta_info, info_hash) = read_meta_info(torrent)?;
+    let summary = thor::inspect::TorrentSummary::new(&meta_info, info_hash);
+    if json {
+        println!("{}", summary.to_json());
     } else {
-        println!("File to download = {}", meta_info.info.name);
+        print!("{}", summary);
     }
+    Ok(())
+}
 
-    println!("info dict: {:?}", meta_info.info);
-    // println!(
-    //     "info dict bencoded: {}",
-    //     String::from_utf8_lossy(&bencoding::to_bytes(&meta_info.info).unwrap())
-    // );
+#[tokio::main]
+async fn main() -> Result<(), String> {
+    env_logger::init();
 
-    make_tracker_request(&meta_info).await
+    match Command::from_args() {
+        Command::Download {
+            torrent,
+            options,
+            files,
+        } => {
+            let source = if torrent.starts_with("magnet:") {
+                Source::Magnet(torrent.parse().map_err(|e: thor::Error| e.to_string())?)
+            } else {
+                let (meta_info, info_hash) = read_meta_info(Path::new(&torrent))?;
+                Source::Torrent(Box::new(meta_info), info_hash)
+            };
+            run_torrent(source, load_config(options)?, files).await
+        }
+        Command::Seed { torrent, options } => {
+            let (meta_info, info_hash) = read_meta_info(Path::new(&torrent))?;
+            let config = load_config(options)?;
+            run_torrent(
+                Source::Torrent(Box::new(meta_info), info_hash),
+                config,
+                FileArgs::default(),
+            )
+            .await
+        }
+        Command::Info { torrent, json } => info(&torrent, json),
+        Command::MakeTorrent(args) => make_torrent(args),
+    }
 }
@@ -0,0 +1,179 @@
+use crate::model::MetaInfo;
+use sha2::{Digest, Sha256};
+
+/// The leaves of the merkle tree of a v2 file are the hashes of its 16 KiB blocks, the
+/// last one possibly shorter.
+pub const BLOCK_LEN: usize = 16 * 1024;
+
+pub type Hash = [u8; 32];
+
+pub fn sha256(bytes: &[u8]) -> Hash {
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&Sha256::digest(bytes));
+    hash
+}
+
+fn parent(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&hasher.finalize());
+    hash
+}
+
+/// Root of the tree of `num_leaves` leaves, a power of two: `hashes` followed by `pad`.
+pub fn root(hashes: &[Hash], num_leaves: usize, pad: Hash) -> Hash {
+    let mut layer = hashes.to_vec();
+    layer.resize(num_leaves.max(1), pad);
+    while layer.len() > 1 {
+        layer = layer.chunks(2).map(|p| parent(&p[0], &p[1])).collect();
+    }
+    layer[0]
+}
+
+/// Root of a tree of `num_leaves` zero leaves, padding the layers above the leaves.
+pub fn pad_hash(num_leaves: usize) -> Hash {
+    let mut hash = [0u8; 32];
+    let mut n = 1;
+    while n < num_leaves {
+        hash = parent(&hash, &hash);
+        n *= 2;
+    }
+    hash
+}
+
+pub fn block_hashes(data: &[u8]) -> Vec<Hash> {
+    data.chunks(BLOCK_LEN).map(sha256).collect()
+}
+
+/// Hash of a piece in its file's piece layer: the root of its blocks, the last piece of a
+/// file being padded with zero leaves.
+pub fn piece_hash(data: &[u8], piece_length: u64) -> Hash {
+    let num_leaves = piece_length as usize / BLOCK_LEN;
+    root(&block_hashes(data), num_leaves, [0; 32])
+}
+
+/// Root of a file no larger than a piece, from its data.
+pub fn file_root(data: &[u8]) -> Hash {
+    let hashes = block_hashes(data);
+    root(&hashes, hashes.len().next_power_of_two(), [0; 32])
+}
+
+/// Root of a file larger than a piece, from its piece layer.
+pub fn root_from_layer(layer: &[Hash], piece_length: u64) -> Hash {
+    let pad = pad_hash(piece_length as usize / BLOCK_LEN);
+    root(layer, layer.len().next_power_of_two(), pad)
+}
+
+/// Verifies the pieces of a v2 torrent against the merkle trees of its files.
+#[derive(Debug)]
+pub struct PieceVerifier {
+    piece_length: u64,
+    files: Vec<FileHashes>,
+}
+
+#[derive(Debug)]
+struct FileHashes {
+    offset: u64,
+    length: u64,
+    root: Hash,
+    /// Empty for the files no larger than a piece, checked against their root, and for
+    /// the files whose piece layer is unknown.
+    layer: Vec<Hash>,
+}
+
+impl PieceVerifier {
+    /// The piece layers come from the .torrent file, without them the pieces of the files
+    /// larger than a piece never verify.
+    pub fn new(meta_info: &MetaInfo) -> PieceVerifier {
+        let piece_length = meta_info.info.piece_length;
+        let files = meta_info
+            .info
+            .v2_files()
+            .into_iter()
+            .filter_map(|f| {
+                let root = f.pieces_root?;
+                let layer = if f.length > piece_length {
+                    meta_info.piece_layer(&root).unwrap_or_default()
+                } else {
+                    vec![]
+                };
+                Some(FileHashes {
+                    offset: f.offset,
+                    length: f.length,
+                    root,
+                    layer,
+                })
+            })
+            .collect();
+        PieceVerifier {
+            piece_length,
+            files,
+        }
+    }
+
+    /// Checks the data of piece `index`, which may run past the end of its file into the
+    /// padding before the next file.
+    pub fn verify(&self, index: u32, piece: &[u8]) -> bool {
+        let start = index as u64 * self.piece_length;
+        let i = self.files.partition_point(|f| f.offset + f.length <= start);
+        let f = match self.files.get(i) {
+            Some(f) if f.offset <= start => f,
+            _ => return false,
+        };
+        let length = std::cmp::min(self.piece_length, f.offset + f.length - start) as usize;
+        if piece.len() < length {
+            return false;
+        }
+        let data = &piece[..length];
+        if f.length <= self.piece_length {
+            return file_root(data) == f.root;
+        }
+        let index_in_file = ((start - f.offset) / self.piece_length) as usize;
+        f.layer.get(index_in_file) == Some(&piece_hash(data, self.piece_length))
+    }
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_roots() {
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i % 241) as u8).collect();
+
+    // a single block is its own root
+    assert_eq!(file_root(&data[..1000]), sha256(&data[..1000]));
+    let two_blocks = parent(
+        &sha256(&data[..BLOCK_LEN]),
+        &sha256(&data[BLOCK_LEN..20_000]),
+    );
+    assert_eq!(file_root(&data[..20_000]), two_blocks);
+    // 3 blocks are padded with a zero leaf
+    let zero = [0u8; 32];
+    let hashes = block_hashes(&data[..40_000]);
+    assert_eq!(
+        file_root(&data[..40_000]),
+        parent(&parent(&hashes[0], &hashes[1]), &parent(&hashes[2], &zero))
+    );
+
+    // the root from the piece layer is the root of the whole tree of blocks, the missing
+    // leaves being zero
+    let piece_length = 2 * BLOCK_LEN as u64;
+    let layer: Vec<Hash> = data
+        .chunks(piece_length as usize)
+        .map(|piece| piece_hash(piece, piece_length))
+        .collect();
+    assert_eq!(layer.len(), 4);
+    let hashes = block_hashes(&data);
+    assert_eq!(hashes.len(), 7);
+    assert_eq!(
+        root_from_layer(&layer, piece_length),
+        root(&hashes, 8, zero)
+    );
+    assert_eq!(
+        root_from_layer(&layer[..3], piece_length),
+        root(&hashes[..6], 8, zero)
+    );
+}
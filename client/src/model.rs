@@ -1,28 +1,113 @@
+use crate::error::Error;
+use crate::merkle;
+use log::debug;
 use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha1::Digest;
+use std::collections::BTreeMap;
+use std::path::{Component, Path};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileInfo {
+    /// Flags of BEP 47: `p` padding, `x` executable, `h` hidden and `l` symlink.
+    pub attr: Option<String>,
     pub length: u64,
     pub md5sum: Option<String>,
     pub path: Vec<String>,
+    /// SHA-1 of the whole file.
+    pub sha1: Option<ByteBuf>,
+    /// Target of a symlink, relative to the torrent's directory.
+    #[serde(rename = "symlink path")]
+    pub symlink_path: Option<Vec<String>>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl FileInfo {
+    pub fn has_attr(&self, flag: char) -> bool {
+        self.attr.as_deref().is_some_and(|attr| attr.contains(flag))
+    }
+
+    /// Padding files align the next file to a piece, they are all zeros and not stored.
+    /// Older clients name them instead of setting the `p` flag.
+    pub fn is_padding(&self) -> bool {
+        self.has_attr('p')
+            || self
+                .path
+                .last()
+                .is_some_and(|name| name.starts_with("_____padding_file_"))
+    }
+}
+
+/// A node of the `file tree` of v2 torrents (BEP 52): a file maps the empty name to its
+/// length and merkle root, a directory maps the names of its children to their node.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum FileTreeNode {
+    File {
+        #[serde(rename = "")]
+        file: FileTreeEntry,
+    },
+    Directory(BTreeMap<String, FileTreeNode>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileTreeEntry {
+    pub length: u64,
+    /// Root of the merkle tree of the file's 16 KiB blocks, absent for empty files.
+    #[serde(rename = "pieces root")]
+    pub pieces_root: Option<ByteBuf>,
+}
+
+/// A v1 torrent lists its files in `files` or `length` and hashes its pieces with SHA-1
+/// in `pieces`, a v2 torrent (BEP 52) lists them in `file tree` with a merkle root each.
+/// Hybrid torrents have both, with padding files aligning the v1 files to pieces.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct InfoDict {
+    #[serde(rename = "file tree")]
+    pub file_tree: Option<BTreeMap<String, FileTreeNode>>,
     pub files: Option<Vec<FileInfo>>,
     pub length: Option<usize>,
     pub md5sum: Option<String>,
+    #[serde(rename = "meta version")]
+    pub meta_version: Option<u64>,
     pub name: String,
     #[serde(rename = "piece length")]
     pub piece_length: u64,
-    #[serde(with = "serde_bytes")]
+    /// Empty for v2 only torrents.
+    #[serde(with = "serde_bytes", default, skip_serializing_if = "Vec::is_empty")]
     pub pieces: Vec<u8>,
-    pub private: Option<bool>,
+    /// Kept as the integer it was encoded with, re-encoding it differently would change
+    /// the info hash.
+    pub private: Option<i64>,
+    pub source: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// A file of a torrent, from the v1 file list or the v2 file tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorrentFile {
+    /// Relative to the torrent's directory, the name of a single file torrent for its only
+    /// file.
+    pub path: Vec<String>,
+    pub length: u64,
+    /// Position in the concatenation of all files, files of v2 torrents start on a piece.
+    pub offset: u64,
+    /// Root of the merkle tree of a v2 file, `None` for v1 and empty files.
+    pub pieces_root: Option<merkle::Hash>,
+    pub executable: bool,
+    /// Target of a symlink, relative to the torrent's directory.
+    pub symlink_path: Option<Vec<String>>,
+}
+
+/// The info hashes of a torrent: SHA-1 of the info dict for v1, SHA-256 for v2, both for
+/// hybrid torrents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InfoHashes {
+    pub v1: Option<[u8; 20]>,
+    pub v2: Option<merkle::Hash>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MetaInfo {
-    pub announce: String,
+    pub announce: Option<String>,
     #[serde(rename = "announce-list")]
     pub announce_list: Option<Vec<Vec<String>>>,
     pub comment: Option<String>,
@@ -32,4 +117,449 @@ pub struct MetaInfo {
     pub creation_date: Option<u64>,
     pub encoding: Option<String>,
     pub info: InfoDict,
+    /// The hashes of the pieces of each v2 file larger than a piece, concatenated, by the
+    /// file's pieces root.
+    #[serde(rename = "piece layers")]
+    pub piece_layers: Option<BTreeMap<ByteBuf, ByteBuf>>,
+    /// Web seeds (BEP 19).
+    #[serde(rename = "url-list")]
+    pub url_list: Option<UrlList>,
+    /// The info dict as parsed or downloaded, keeping the keys `InfoDict` does not model.
+    #[serde(skip)]
+    pub raw_info: Option<Vec<u8>>,
+}
+
+/// `url-list` is either a single url or a list of them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum UrlList {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl InfoDict {
+    /// Private torrents (BEP 27) only get peers from their trackers, never from the DHT,
+    /// PEX or local service discovery.
+    pub fn is_private(&self) -> bool {
+        self.private.is_some_and(|private| private != 0)
+    }
+
+    /// SHA-1 of the bencoded info dict, identifying the torrent. Only matches the info
+    /// hash of a parsed torrent when its info dict has no key unknown to `InfoDict`, see
+    /// `MetaInfo::from_torrent_bytes`.
+    pub fn info_hash(&self) -> [u8; 20] {
+        let info_dict_bytes =
+            bencoding::to_bytes(self).expect("info dict should not fail to encode");
+        sha1(&info_dict_bytes)
+    }
+
+    /// SHA-256 of the bencoded info dict, see `info_hash`.
+    pub fn info_hash_v2(&self) -> merkle::Hash {
+        let info_dict_bytes =
+            bencoding::to_bytes(self).expect("info dict should not fail to encode");
+        merkle::sha256(&info_dict_bytes)
+    }
+
+    /// Whether the pieces are hashed with SHA-1 (BEP 3).
+    pub fn is_v1(&self) -> bool {
+        !self.pieces.is_empty()
+    }
+
+    /// Whether the files have merkle roots (BEP 52).
+    pub fn is_v2(&self) -> bool {
+        self.meta_version == Some(2) && self.file_tree.is_some()
+    }
+
+    /// Whether the torrent's only file is stored as `name` rather than in a directory.
+    pub fn is_single_file(&self) -> bool {
+        if self.is_v1() {
+            return self.files.is_none();
+        }
+        match self.file_tree.as_ref() {
+            Some(tree) => {
+                tree.len() == 1 && matches!(tree.get(&self.name), Some(FileTreeNode::File { .. }))
+            }
+            None => true,
+        }
+    }
+
+    /// Length of all files together.
+    pub fn total_length(&self) -> u64 {
+        self.file_list().iter().map(|f| f.length).sum()
+    }
+
+    pub fn num_files(&self) -> usize {
+        self.file_list().len()
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        if self.is_v1() {
+            return self.pieces.len() / 20;
+        }
+        self.pieces_length().div_ceil(self.piece_length) as usize
+    }
+
+    /// Length of the pieces together: the v1 files including the padding files, or up to
+    /// the end of the last v2 file.
+    pub fn pieces_length(&self) -> u64 {
+        if self.is_v1() {
+            return match self.files.as_ref() {
+                Some(files) => files.iter().map(|f| f.length).sum(),
+                None => self.length.unwrap_or(0) as u64,
+            };
+        }
+        self.v2_files()
+            .iter()
+            .filter(|f| f.length > 0)
+            .map(|f| f.offset + f.length)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Makes sure the files of the torrent stay within the download directory: the name
+    /// and every component of the file paths, v1 and v2, must be plain names. So must the
+    /// components of the symlink targets, which then stay within the torrent's directory.
+    pub fn check_paths(&self) -> Result<(), Error> {
+        let invalid = |path: &[String]| Error::InvalidTorrent(format!("invalid path {:?}", path));
+        if !is_plain_name(&self.name) {
+            return Err(invalid(std::slice::from_ref(&self.name)));
+        }
+        let files = self.files.iter().flatten();
+        let v1_paths = files.clone().map(|f| &f.path);
+        let symlinks = files.filter_map(|f| f.symlink_path.as_ref());
+        let v2_files = self.v2_files();
+        let v2_paths = v2_files.iter().map(|f| &f.path);
+        for path in v1_paths.chain(v2_paths).chain(symlinks) {
+            if path.is_empty() || !path.iter().all(|name| is_plain_name(name)) {
+                return Err(invalid(path));
+            }
+        }
+        Ok(())
+    }
+
+    /// The files as stored on disk: the v1 files when there are some, leaving out the
+    /// padding files, the v2 files otherwise.
+    pub fn file_list(&self) -> Vec<TorrentFile> {
+        if !self.is_v1() && self.is_v2() {
+            return self.v2_files();
+        }
+        let files = match self.files.as_ref() {
+            Some(files) => files,
+            None => {
+                return vec![TorrentFile {
+                    path: vec![self.name.clone()],
+                    length: self.length.unwrap_or(0) as u64,
+                    offset: 0,
+                    pieces_root: None,
+                    executable: false,
+                    symlink_path: None,
+                }]
+            }
+        };
+        let mut list = vec![];
+        let mut offset = 0;
+        for f in files {
+            if !f.is_padding() {
+                list.push(TorrentFile {
+                    path: f.path.clone(),
+                    length: f.length,
+                    offset,
+                    pieces_root: None,
+                    executable: f.has_attr('x'),
+                    symlink_path: f.symlink_path.clone().filter(|_| f.has_attr('l')),
+                });
+            }
+            offset += f.length;
+        }
+        list
+    }
+
+    /// The files of the v2 file tree in order, each starting on a piece boundary. Empty
+    /// for v1 torrents.
+    pub fn v2_files(&self) -> Vec<TorrentFile> {
+        let mut files = vec![];
+        if let Some(tree) = self.file_tree.as_ref() {
+            collect_files(tree, &mut vec![], &mut files);
+        }
+        let mut offset = 0;
+        for f in files.iter_mut() {
+            f.offset = offset;
+            offset += f.length.div_ceil(self.piece_length) * self.piece_length;
+        }
+        files
+    }
+}
+
+/// Whether `name` is a single plain file name, which cannot lead out of the directory it
+/// is joined to: not empty, `.`, `..`, absolute or made of several components.
+fn is_plain_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    !name.contains(['/', '\\', '\0'])
+        && matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        )
+}
+
+fn collect_files(
+    tree: &BTreeMap<String, FileTreeNode>,
+    path: &mut Vec<String>,
+    files: &mut Vec<TorrentFile>,
+) {
+    for (name, node) in tree {
+        path.push(name.clone());
+        match node {
+            FileTreeNode::File { file } => files.push(TorrentFile {
+                path: path.clone(),
+                length: file.length,
+                offset: 0,
+                pieces_root: file.pieces_root.as_ref().and_then(|root| to_hash(root)),
+                executable: false,
+                symlink_path: None,
+            }),
+            FileTreeNode::Directory(children) => collect_files(children, path, files),
+        }
+        path.pop();
+    }
+}
+
+impl InfoHashes {
+    /// The 20 bytes identifying the torrent to trackers, the DHT and peers: the v1 info
+    /// hash, or the v2 one truncated.
+    pub fn id(&self) -> [u8; 20] {
+        match (self.v1, self.v2) {
+            (Some(v1), _) => v1,
+            (None, Some(v2)) => truncate(&v2),
+            (None, None) => [0; 20],
+        }
+    }
+
+    /// The v2 info hash truncated to 20 bytes, as announced to trackers for v2 swarms.
+    pub fn v2_truncated(&self) -> Option<[u8; 20]> {
+        self.v2.as_ref().map(truncate)
+    }
+}
+
+fn truncate(hash: &merkle::Hash) -> [u8; 20] {
+    let mut truncated = [0u8; 20];
+    truncated.copy_from_slice(&hash[..20]);
+    truncated
+}
+
+fn to_hash(bytes: &[u8]) -> Option<merkle::Hash> {
+    if bytes.len() != 32 {
+        return None;
+    }
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(bytes);
+    Some(hash)
+}
+
+impl MetaInfo {
+    /// Parses a .torrent file, along with its info hash computed over the info dict as
+    /// it appears in the file, truncated for v2 only torrents.
+    pub fn from_torrent_bytes(bytes: &[u8]) -> Result<(MetaInfo, [u8; 20]), Error> {
+        let (meta_info, info_hashes) = MetaInfo::with_info_hashes(bytes)?;
+        Ok((meta_info, info_hashes.id()))
+    }
+
+    /// Parses a .torrent file along with all its info hashes, making sure the piece layers
+    /// of a v2 torrent match the roots of its files.
+    pub fn with_info_hashes(bytes: &[u8]) -> Result<(MetaInfo, InfoHashes), Error> {
+        let mut meta_info: MetaInfo = bencoding::from_bytes(bytes)?;
+        let info =
+            bencoding::raw_dict_value(bytes, b"info")?.ok_or(bencoding::Error::ExpectedMap)?;
+        meta_info.raw_info = Some(info.to_vec());
+        meta_info.info.check_paths()?;
+        let info_hashes = InfoHashes {
+            v1: Some(sha1(info)).filter(|_| meta_info.info.is_v1()),
+            v2: Some(merkle::sha256(info)).filter(|_| meta_info.info.is_v2()),
+        };
+        if info_hashes.v1.is_none() && info_hashes.v2.is_none() {
+            return Err(Error::InvalidTorrent("no pieces nor file tree".to_owned()));
+        }
+        if meta_info.info.is_v2() {
+            meta_info.check_piece_layers()?;
+        }
+        Ok((meta_info, info_hashes))
+    }
+
+    /// The bencoded info dict, hashing to the info hash of the torrent: the bytes it was
+    /// parsed from when known, encoded from `info` otherwise.
+    pub fn info_bytes(&self) -> Vec<u8> {
+        match self.raw_info.as_ref() {
+            Some(raw) => raw.clone(),
+            None => bencoding::to_bytes(&self.info).expect("info dict should not fail to encode"),
+        }
+    }
+
+    /// The piece hashes of the v2 file of root `pieces_root`, only known for the files
+    /// larger than a piece.
+    pub fn piece_layer(&self, pieces_root: &merkle::Hash) -> Option<Vec<merkle::Hash>> {
+        let layer = self
+            .piece_layers
+            .as_ref()?
+            .get(serde_bytes::Bytes::new(pieces_root))?;
+        if layer.len() % 32 != 0 {
+            return None;
+        }
+        Some(layer.chunks_exact(32).filter_map(to_hash).collect())
+    }
+
+    fn check_piece_layers(&self) -> Result<(), Error> {
+        let piece_length = self.info.piece_length;
+        if piece_length < merkle::BLOCK_LEN as u64 || !piece_length.is_power_of_two() {
+            return Err(Error::InvalidPieceLength(piece_length));
+        }
+        for f in self.info.v2_files() {
+            let root = match f.pieces_root {
+                Some(root) if f.length > piece_length => root,
+                _ => continue,
+            };
+            let invalid = || Error::InvalidTorrent(format!("invalid piece layer of {:?}", f.path));
+            let layer = self.piece_layer(&root).ok_or_else(invalid)?;
+            if layer.len() as u64 != f.length.div_ceil(piece_length)
+                || merkle::root_from_layer(&layer, piece_length) != root
+            {
+                return Err(invalid());
+            }
+        }
+        Ok(())
+    }
+
+    /// The urls of the web seeds, `http://` or not.
+    pub fn web_seeds(&self) -> Vec<String> {
+        let urls = match self.url_list.as_ref() {
+            Some(UrlList::One(url)) => vec![url.clone()],
+            Some(UrlList::Many(urls)) => urls.clone(),
+            None => vec![],
+        };
+        urls.into_iter().filter(|url| !url.is_empty()).collect()
+    }
+
+    /// The trackers by tier (BEP 12), falling back to `announce` as the only tier.
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        match self.announce_list.as_ref() {
+            Some(list) if !list.is_empty() => list.clone(),
+            _ => self.announce.iter().map(|a| vec![a.clone()]).collect(),
+        }
+    }
+}
+
+fn sha1(bytes: &[u8]) -> [u8; 20] {
+    let mut hasher = sha1::Sha1::new();
+    hasher.update(bytes);
+    let hash = hasher.finalize();
+    debug!("info_hash: {:02x}", &hash);
+
+    let mut info_hash = [0u8; 20];
+    info_hash.copy_from_slice(&hash);
+    info_hash
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_private_flag_keeps_info_hash() {
+    let torrent = |private: &[u8]| {
+        let mut bytes = b"d4:infod6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:".to_vec();
+        bytes.extend_from_slice(&[7u8; 20]);
+        bytes.extend_from_slice(private);
+        bytes.extend_from_slice(b"ee");
+        MetaInfo::from_torrent_bytes(&bytes).unwrap()
+    };
+
+    for (flag, private) in [
+        (&b""[..], false),
+        (b"7:privatei0e", false),
+        (b"7:privatei1e", true),
+        // not 1 as BEP 27 says, but still private and encoded back as is
+        (b"7:privatei2e", true),
+    ]
+    .iter()
+    {
+        let (meta_info, info_hash) = torrent(flag);
+        assert_eq!(meta_info.info.is_private(), *private);
+        assert_eq!(meta_info.info.info_hash(), info_hash);
+    }
+}
+
+#[test]
+fn test_paths_outside_the_download_dir() {
+    let torrent = |name: &str, path: Option<&[&str]>| {
+        let mut bytes = b"d4:infod".to_vec();
+        if let Some(path) = path {
+            bytes.extend_from_slice(b"5:filesld6:lengthi5e4:pathl");
+            for name in path {
+                bytes.extend_from_slice(format!("{}:{}", name.len(), name).as_bytes());
+            }
+            bytes.extend_from_slice(b"eee");
+        } else {
+            bytes.extend_from_slice(b"6:lengthi5e");
+        }
+        bytes.extend_from_slice(format!("4:name{}:{}", name.len(), name).as_bytes());
+        bytes.extend_from_slice(b"12:piece lengthi16384e6:pieces20:");
+        bytes.extend_from_slice(&[7u8; 20]);
+        bytes.extend_from_slice(b"ee");
+        MetaInfo::from_torrent_bytes(&bytes)
+    };
+
+    assert!(torrent("a", None).is_ok());
+    assert!(torrent("a", Some(&["b", "c"])).is_ok());
+    for (name, path) in [
+        ("..", None),
+        (".", None),
+        ("", None),
+        ("/etc/passwd", None),
+        ("a/b", None),
+        ("a", Some(&["..", "x"][..])),
+        ("a", Some(&["/etc", "passwd"][..])),
+        ("a", Some(&["b", ""][..])),
+        ("a", Some(&["b\\..\\..", "x"][..])),
+        ("a", Some(&[][..])),
+    ]
+    .iter()
+    {
+        assert!(
+            matches!(torrent(name, *path), Err(Error::InvalidTorrent(_))),
+            "{:?} {:?}",
+            name,
+            path
+        );
+    }
+}
+
+#[test]
+fn test_symlinks_outside_the_torrent() {
+    let torrent = |target: &[u8]| {
+        let mut bytes =
+            b"d4:infod5:filesld4:attr1:l6:lengthi0e4:pathl4:linke12:symlink path".to_vec();
+        bytes.extend_from_slice(target);
+        bytes.extend_from_slice(b"ee4:name1:a12:piece lengthi16384e6:pieces20:");
+        bytes.extend_from_slice(&[7u8; 20]);
+        bytes.extend_from_slice(b"ee");
+        MetaInfo::from_torrent_bytes(&bytes)
+    };
+
+    assert!(torrent(b"l3:dir4:filee").is_ok());
+    for target in [&b"l2:..2:..3:etce"[..], b"l4:/etce", b"l3:dir2:..e", b"le"].iter() {
+        assert!(matches!(torrent(target), Err(Error::InvalidTorrent(_))));
+    }
+}
+
+#[test]
+fn test_info_bytes_keep_unknown_keys() {
+    let mut info = b"d6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:".to_vec();
+    info.extend_from_slice(&[7u8; 20]);
+    info.extend_from_slice(b"8:x-customi1ee");
+    let mut bytes = b"d4:info".to_vec();
+    bytes.extend_from_slice(&info);
+    bytes.extend_from_slice(b"e");
+
+    let (meta_info, info_hash) = MetaInfo::from_torrent_bytes(&bytes).unwrap();
+    assert_ne!(meta_info.info.info_hash(), info_hash);
+    assert_eq!(meta_info.info_bytes(), info);
+    assert_eq!(sha1(&meta_info.info_bytes()), info_hash);
 }
@@ -0,0 +1,267 @@
+use rand::seq::SliceRandom;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How often the choker runs.
+pub const UNCHOKE_INTERVAL: Duration = Duration::from_secs(10);
+/// How often the optimistic unchoke moves to another peer.
+pub const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+/// A peer that sent us nothing we asked for in this long snubs us.
+pub const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+
+/// What the choker needs to know about a connected peer.
+#[derive(Debug, Clone)]
+pub struct PeerStats {
+    pub addr: SocketAddr,
+    /// The peer is interested in our pieces.
+    pub interested: bool,
+    /// We are interested in the pieces of the peer.
+    pub am_interested: bool,
+    /// Bytes per second received from the peer.
+    pub download_rate: u64,
+    /// Bytes per second sent to the peer.
+    pub upload_rate: u64,
+    pub connected_at: Instant,
+    /// When the peer last sent us a block.
+    pub last_block: Option<Instant>,
+}
+
+impl PeerStats {
+    /// A peer snubs us when we want its pieces but it has not sent any block for a while.
+    pub fn is_snubbed(&self, now: Instant) -> bool {
+        let since = self.last_block.unwrap_or(self.connected_at);
+        self.am_interested && now.saturating_duration_since(since) > SNUB_TIMEOUT
+    }
+}
+
+/// The changes decided by a round of the choker.
+#[derive(Debug, Default, PartialEq)]
+pub struct Rechoke {
+    pub unchoke: Vec<SocketAddr>,
+    pub choke: Vec<SocketAddr>,
+}
+
+/// The choking algorithm of the reference client: every round the interested peers we get
+/// the best rates from are unchoked (tit-for-tat), plus one optimistic unchoke giving a
+/// chance to other peers. Snubbing peers lose their regular slot.
+#[derive(Debug)]
+pub struct Choker {
+    slots: usize,
+    unchoked: HashSet<SocketAddr>,
+    optimistic: Option<SocketAddr>,
+    optimistic_since: Option<Instant>,
+}
+
+impl Choker {
+    /// `slots` is the number of peers uploaded to at once, including the optimistic unchoke.
+    pub fn new(slots: usize) -> Choker {
+        Choker {
+            slots,
+            unchoked: HashSet::new(),
+            optimistic: None,
+            optimistic_since: None,
+        }
+    }
+
+    pub fn slots(&self) -> usize {
+        self.slots
+    }
+
+    /// Changes the number of upload slots, applied on the next round.
+    pub fn set_slots(&mut self, slots: usize) {
+        self.slots = slots;
+    }
+
+    pub fn is_unchoked(&self, addr: &SocketAddr) -> bool {
+        self.unchoked.contains(addr)
+    }
+
+    /// Runs a round over the connected peers. When `seeding` peers are ranked by how fast
+    /// we upload to them, since they have nothing to give back.
+    pub fn rechoke(&mut self, peers: &[PeerStats], seeding: bool, now: Instant) -> Rechoke {
+        let mut ranked: Vec<&PeerStats> = peers
+            .iter()
+            .filter(|p| p.interested && (seeding || !p.is_snubbed(now)))
+            .collect();
+        if seeding {
+            ranked.sort_by_key(|p| std::cmp::Reverse(p.upload_rate));
+        } else {
+            ranked.sort_by_key(|p| std::cmp::Reverse(p.download_rate));
+        }
+
+        let regular_slots = self.slots.saturating_sub(1);
+        let mut unchoked: HashSet<SocketAddr> =
+            ranked.iter().take(regular_slots).map(|p| p.addr).collect();
+
+        if self.slots > 0 {
+            self.rotate_optimistic(peers, &unchoked, now);
+        } else {
+            self.optimistic = None;
+        }
+        unchoked.extend(self.optimistic);
+
+        let mut rechoke = Rechoke {
+            unchoke: unchoked.difference(&self.unchoked).copied().collect(),
+            choke: self
+                .unchoked
+                .iter()
+                .filter(|a| !unchoked.contains(a) && peers.iter().any(|p| p.addr == **a))
+                .copied()
+                .collect(),
+        };
+        rechoke.unchoke.sort();
+        rechoke.choke.sort();
+        self.unchoked = unchoked;
+        rechoke
+    }
+
+    /// Keeps the optimistic unchoke for `OPTIMISTIC_INTERVAL`, unless it left, lost
+    /// interest or earned a regular slot.
+    fn rotate_optimistic(
+        &mut self,
+        peers: &[PeerStats],
+        regular: &HashSet<SocketAddr>,
+        now: Instant,
+    ) {
+        let current_valid = self.optimistic.is_some_and(|addr| {
+            !regular.contains(&addr) && peers.iter().any(|p| p.addr == addr && p.interested)
+        });
+        let expired = self
+            .optimistic_since
+            .is_none_or(|since| now.saturating_duration_since(since) >= OPTIMISTIC_INTERVAL);
+        if current_valid && !expired {
+            return;
+        }
+
+        let candidates: Vec<SocketAddr> = peers
+            .iter()
+            .filter(|p| p.interested && !regular.contains(&p.addr))
+            .filter(|p| Some(p.addr) != self.optimistic)
+            .map(|p| p.addr)
+            .collect();
+        self.optimistic = candidates
+            .choose(&mut rand::thread_rng())
+            .copied()
+            .or(if current_valid { self.optimistic } else { None });
+        self.optimistic_since = Some(now);
+    }
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_tit_for_tat() {
+    let now = Instant::now();
+    let mut peers = simulated_peers(&[100, 500, 300, 0, 400, 50], now);
+    let mut choker = Choker::new(4);
+
+    let round = choker.rechoke(&peers, false, now);
+    assert_eq!(round.unchoke.len(), 4);
+    assert!(round.choke.is_empty());
+    // the three fastest get the regular slots
+    for i in [1, 4, 2].iter() {
+        assert!(choker.is_unchoked(&peers[*i].addr));
+    }
+
+    // a peer speeding up takes over the slot of the slowest regular peer
+    peers[5].download_rate = 1000;
+    let now = now + UNCHOKE_INTERVAL;
+    let round = choker.rechoke(&peers, false, now);
+    assert!(choker.is_unchoked(&peers[5].addr));
+    assert!(choker.is_unchoked(&peers[1].addr));
+    assert!(choker.is_unchoked(&peers[4].addr));
+    assert_eq!(choker.unchoked.len(), 4);
+    assert!(round.choke.len() <= 1);
+
+    // peers that are not interested are never unchoked
+    for p in peers.iter_mut() {
+        p.interested = false;
+    }
+    let round = choker.rechoke(&peers, false, now + UNCHOKE_INTERVAL);
+    assert_eq!(round.choke.len(), 4);
+    assert!(choker.unchoked.is_empty());
+}
+
+#[test]
+fn test_optimistic_unchoke_rotates() {
+    let now = Instant::now();
+    let peers = simulated_peers(&[500, 400, 0, 0, 0, 0], now);
+    let mut choker = Choker::new(2);
+
+    choker.rechoke(&peers, false, now);
+    let first = choker.optimistic.unwrap();
+    assert_ne!(first, peers[0].addr);
+    assert!(choker.is_unchoked(&first));
+
+    // kept between rotations
+    choker.rechoke(&peers, false, now + UNCHOKE_INTERVAL);
+    assert_eq!(choker.optimistic, Some(first));
+
+    let round = choker.rechoke(&peers, false, now + OPTIMISTIC_INTERVAL);
+    let second = choker.optimistic.unwrap();
+    assert_ne!(second, first);
+    assert_eq!(round.unchoke, vec![second]);
+    assert_eq!(round.choke, vec![first]);
+}
+
+#[test]
+fn test_anti_snubbing() {
+    let now = Instant::now();
+    let mut peers = simulated_peers(&[500, 400, 300], now);
+    let mut choker = Choker::new(3);
+    choker.rechoke(&peers, false, now);
+    assert!(choker.is_unchoked(&peers[0].addr));
+
+    // the fastest peer stops sending us blocks: it loses its regular slot
+    let later = now + SNUB_TIMEOUT + UNCHOKE_INTERVAL;
+    for p in peers.iter_mut().skip(1) {
+        p.last_block = Some(later);
+    }
+    assert!(peers[0].is_snubbed(later));
+    choker.rechoke(&peers, false, later);
+    assert!(choker.is_unchoked(&peers[1].addr));
+    assert!(choker.is_unchoked(&peers[2].addr));
+    assert!(!choker.is_unchoked(&peers[0].addr) || choker.optimistic == Some(peers[0].addr));
+}
+
+#[test]
+fn test_seeding_ranks_by_upload_rate() {
+    let now = Instant::now();
+    let mut peers = simulated_peers(&[0, 0, 0], now);
+    for (p, rate) in peers.iter_mut().zip([10, 30, 20].iter()) {
+        p.upload_rate = *rate;
+        p.am_interested = false;
+        p.last_block = None;
+    }
+    let mut choker = Choker::new(2);
+    choker.rechoke(&peers, true, now + SNUB_TIMEOUT * 2);
+    assert!(choker.is_unchoked(&peers[1].addr));
+    assert_eq!(choker.unchoked.len(), 2);
+
+    // fewer slots at runtime
+    choker.set_slots(1);
+    let round = choker.rechoke(&peers, true, now + SNUB_TIMEOUT * 3);
+    assert_eq!(choker.unchoked.len(), 1);
+    assert!(!round.choke.is_empty());
+}
+
+#[cfg(test)]
+fn simulated_peers(download_rates: &[u64], now: Instant) -> Vec<PeerStats> {
+    download_rates
+        .iter()
+        .enumerate()
+        .map(|(i, rate)| PeerStats {
+            addr: SocketAddr::from(([10, 0, 0, i as u8 + 1], 6881)),
+            interested: true,
+            am_interested: true,
+            download_rate: *rate,
+            upload_rate: 0,
+            connected_at: now,
+            last_block: Some(now),
+        })
+        .collect()
+}
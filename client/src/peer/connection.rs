@@ -0,0 +1,969 @@
+use super::fast::{allowed_fast_set, ALLOWED_FAST_LEN};
+use super::handshake::Handshake;
+use super::id::Client;
+use super::message::Message;
+use super::PeerStream;
+use crate::bitfield::Bitfield;
+use crate::error::Error;
+use crate::extension::{Extensions, HANDSHAKE_ID, TICK_INTERVAL};
+use crate::rate_limit;
+use crate::storage::{Storage, MAX_BLOCK_LEN};
+use crate::torrent::picker::Block;
+use crate::torrent::{PeerEvent, TorrentContext};
+use futures_util::{SinkExt, StreamExt};
+use log::debug;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::time::{interval_at, sleep_until, Instant};
+
+/// Pieces suggested by a peer that are remembered, the oldest are forgotten first.
+const MAX_SUGGESTED: usize = 16;
+
+/// What the torrent tells a connection to do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PeerCommand {
+    Choke,
+    Unchoke,
+    /// We verified a new piece.
+    Have(u32),
+    /// The pieces we want changed.
+    PrioritiesChanged,
+    Shutdown,
+}
+
+/// The state of a connection read by its torrent, e.g. for the choker.
+#[derive(Debug, Clone, Default)]
+pub struct PeerStatus {
+    /// The peer is interested in our pieces.
+    pub interested: bool,
+    /// We are interested in the pieces of the peer.
+    pub am_interested: bool,
+    /// Bytes of blocks received from the peer.
+    pub downloaded: u64,
+    /// Bytes of blocks sent to the peer.
+    pub uploaded: u64,
+    pub last_block: Option<std::time::Instant>,
+    /// Told by the peer id, then by the extension handshake when the peer sends one.
+    pub client: Option<Client>,
+}
+
+/// A connection with a peer after the handshakes: downloads the blocks chosen by the piece
+/// picker while the peer unchokes us, and uploads while the torrent's choker unchokes it.
+pub struct Connection {
+    addr: SocketAddr,
+    stream: PeerStream,
+    context: Arc<TorrentContext>,
+    status: Arc<Mutex<PeerStatus>>,
+    commands: mpsc::UnboundedReceiver<PeerCommand>,
+    events: mpsc::Sender<PeerEvent>,
+    /// The pieces the peer has, counted in the availability of the picker.
+    bitfield: Bitfield,
+    am_choking: bool,
+    peer_choking: bool,
+    requested: Vec<Block>,
+    /// Both peers support the fast extension.
+    fast: bool,
+    /// Pieces the peer lets us download while it chokes us.
+    allowed_fast: Vec<u32>,
+    /// Pieces the peer would rather we download, tried first.
+    suggested: Vec<u32>,
+    /// Pieces we let the peer download while we choke it.
+    allowed_fast_out: Vec<u32>,
+    /// Both peers support the extension protocol.
+    extended: bool,
+    extensions: Extensions,
+}
+
+impl Connection {
+    /// `remote` is the handshake of the peer, telling whether it supports the fast
+    /// extension and the extension protocol like we always do.
+    pub fn new(
+        addr: SocketAddr,
+        stream: PeerStream,
+        remote: &Handshake,
+        context: Arc<TorrentContext>,
+        status: Arc<Mutex<PeerStatus>>,
+        commands: mpsc::UnboundedReceiver<PeerCommand>,
+        events: mpsc::Sender<PeerEvent>,
+    ) -> Connection {
+        let num_pieces = context.storage.num_pieces();
+        let fast = remote.supports_fast();
+        let allowed_fast_out = if fast {
+            allowed_fast_set(ALLOWED_FAST_LEN, num_pieces, &context.info_hash, addr.ip())
+        } else {
+            vec![]
+        };
+        Connection {
+            addr,
+            stream,
+            context,
+            status,
+            commands,
+            events,
+            bitfield: Bitfield::new(num_pieces),
+            am_choking: true,
+            peer_choking: true,
+            requested: vec![],
+            fast,
+            allowed_fast: vec![],
+            suggested: vec![],
+            allowed_fast_out,
+            extended: remote.supports_extension_protocol(),
+            extensions: Extensions::new(),
+        }
+    }
+
+    /// Sets the extensions offered in our extension handshake, none by default.
+    pub fn with_extensions(mut self, extensions: Extensions) -> Connection {
+        self.extensions = extensions;
+        self
+    }
+
+    /// Exchanges messages until either side closes the connection, then gives the
+    /// outstanding requests back to the picker.
+    pub async fn run(mut self) -> Result<(), Error> {
+        let result = self.exchange().await;
+
+        let mut picker = self.context.picker.lock().unwrap();
+        for block in self.requested.drain(..) {
+            picker.cancel(&block);
+        }
+        picker.peer_lost(&self.bitfield);
+        result
+    }
+
+    async fn exchange(&mut self) -> Result<(), Error> {
+        let have = self.context.picker.lock().unwrap().have().clone();
+        if self.fast && have.is_complete() {
+            self.stream.send(Message::HaveAll).await?;
+        } else if self.fast && have.count() == 0 {
+            self.stream.send(Message::HaveNone).await?;
+        } else if have.count() > 0 {
+            self.stream
+                .send(Message::Bitfield(have.as_bytes().to_vec()))
+                .await?;
+        }
+        // the pieces the peer may not have yet are allowed too, they are only requested
+        // once we announce them
+        for index in self.allowed_fast_out.clone() {
+            self.stream.send(Message::AllowedFast(index)).await?;
+        }
+        // the bitfield or its fast replacements must come first
+        if self.extended {
+            let handshake = self.extensions.handshake_message(Some(self.addr.ip()))?;
+            self.stream.send(handshake).await?;
+        }
+
+        let config = self.context.config.clone();
+        let mut keep_alive = interval_at(
+            Instant::now() + config.keep_alive_interval,
+            config.keep_alive_interval,
+        );
+        let mut extension_tick = interval_at(Instant::now() + TICK_INTERVAL, TICK_INTERVAL);
+        let mut last_received = Instant::now();
+        loop {
+            tokio::select! {
+                message = self.stream.next() => match message {
+                    Some(message) => {
+                        last_received = Instant::now();
+                        self.handle_message(message?).await?;
+                    }
+                    None => return Ok(()),
+                },
+                command = self.commands.recv() => match command {
+                    Some(PeerCommand::Shutdown) | None => return Ok(()),
+                    Some(command) => self.handle_command(command).await?,
+                },
+                _ = keep_alive.tick() => self.stream.send(Message::KeepAlive).await?,
+                _ = extension_tick.tick() => {
+                    for message in self.extensions.tick() {
+                        self.stream.send(message).await?;
+                    }
+                }
+                _ = sleep_until(last_received + config.idle_timeout) => return Err(Error::Timeout),
+            }
+        }
+    }
+
+    async fn handle_message(&mut self, message: Message) -> Result<(), Error> {
+        match message {
+            Message::Choke => {
+                self.peer_choking = true;
+                // the peer drops the requests it did not answer yet, unless it supports the
+                // fast extension and rejects them one by one
+                if !self.fast {
+                    let mut picker = self.context.picker.lock().unwrap();
+                    for block in self.requested.drain(..) {
+                        picker.cancel(&block);
+                    }
+                }
+            }
+            Message::Unchoke => {
+                self.peer_choking = false;
+                self.request_blocks().await?;
+            }
+            Message::Interested | Message::NotInterested => {
+                let interested = message == Message::Interested;
+                self.status.lock().unwrap().interested = interested;
+                let _ = self
+                    .events
+                    .send(PeerEvent::InterestChanged(self.addr))
+                    .await;
+            }
+            Message::Have(index) => {
+                if index as usize >= self.bitfield.len() {
+                    return Err(Error::InvalidMessage);
+                }
+                if !self.bitfield.get(index as usize) {
+                    self.bitfield.set(index as usize, true);
+                    self.context.picker.lock().unwrap().peer_has_piece(index);
+                }
+                self.update_interest().await?;
+                self.request_blocks().await?;
+            }
+            Message::Bitfield(bytes) => {
+                let bitfield = Bitfield::from_bytes(&bytes, self.bitfield.len())
+                    .ok_or(Error::InvalidMessage)?;
+                self.set_bitfield(bitfield).await?;
+            }
+            Message::HaveAll if self.fast => {
+                self.set_bitfield(Bitfield::full(self.bitfield.len()))
+                    .await?
+            }
+            Message::HaveNone if self.fast => {
+                self.set_bitfield(Bitfield::new(self.bitfield.len()))
+                    .await?
+            }
+            Message::SuggestPiece(index) if self.fast => {
+                if index as usize >= self.bitfield.len() {
+                    return Err(Error::InvalidMessage);
+                }
+                if !self.suggested.contains(&index) {
+                    if self.suggested.len() >= MAX_SUGGESTED {
+                        self.suggested.remove(0);
+                    }
+                    self.suggested.push(index);
+                }
+                self.request_blocks().await?;
+            }
+            Message::AllowedFast(index) if self.fast => {
+                if index as usize >= self.bitfield.len() {
+                    return Err(Error::InvalidMessage);
+                }
+                if !self.allowed_fast.contains(&index) {
+                    self.allowed_fast.push(index);
+                }
+                self.request_blocks().await?;
+            }
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } if self.fast => {
+                let position = self
+                    .requested
+                    .iter()
+                    .position(|b| b.index == index && b.begin == begin && b.length == length);
+                // the block is free again for the other peers
+                if let Some(position) = position {
+                    let block = self.requested.remove(position);
+                    self.context.picker.lock().unwrap().cancel(&block);
+                }
+                // asking again for a piece the peer does not allow would be rejected again
+                if self.peer_choking {
+                    self.allowed_fast.retain(|i| *i != index);
+                }
+                self.request_blocks().await?;
+            }
+            Message::HaveAll
+            | Message::HaveNone
+            | Message::SuggestPiece(_)
+            | Message::AllowedFast(_)
+            | Message::RejectRequest { .. } => return Err(Error::InvalidMessage),
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => self.upload(index, begin, length).await?,
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                // the next message is not read until the limits allow this one
+                let limits = [
+                    &self.context.limits.download,
+                    &self.context.session_limits.download,
+                ];
+                rate_limit::acquire(&limits, block.len() as u64).await;
+                self.download(index, begin, block).await?
+            }
+            Message::Extended { id, payload } if self.extended => {
+                let replies = self.extensions.handle(id, &payload)?;
+                if id == HANDSHAKE_ID {
+                    let p = self.extensions.remote().and_then(|r| r.p);
+                    if let Some(port) = p {
+                        let mut pool = self.context.pool.lock().unwrap();
+                        pool.set_listen_port(&self.addr, port);
+                    }
+                    let v = self.extensions.remote().and_then(|r| r.v.as_deref());
+                    if let Some(v) = v {
+                        let client = Client::from_extension_version(v);
+                        debug!("{} is running {}", self.addr, client);
+                        self.status.lock().unwrap().client = Some(client);
+                    }
+                }
+                for reply in replies {
+                    self.stream.send(reply).await?;
+                }
+            }
+            Message::Extended { .. } => return Err(Error::InvalidMessage),
+            // requests are answered as soon as they arrive, so there is nothing to cancel
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn handle_command(&mut self, command: PeerCommand) -> Result<(), Error> {
+        match command {
+            PeerCommand::Choke if !self.am_choking => {
+                self.am_choking = true;
+                self.stream.send(Message::Choke).await?;
+            }
+            PeerCommand::Unchoke if self.am_choking => {
+                self.am_choking = false;
+                self.stream.send(Message::Unchoke).await?;
+            }
+            PeerCommand::PrioritiesChanged => {
+                self.update_interest().await?;
+                self.request_blocks().await?;
+            }
+            PeerCommand::Have(index) => {
+                self.stream.send(Message::Have(index)).await?;
+                // in endgame mode the piece may still be requested from this peer
+                let (done, requested) = self
+                    .requested
+                    .drain(..)
+                    .partition(|block| block.index == index);
+                self.requested = requested;
+                for block in done {
+                    self.stream
+                        .send(Message::Cancel {
+                            index: block.index,
+                            begin: block.begin,
+                            length: block.length,
+                        })
+                        .await?;
+                }
+                self.update_interest().await?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Replaces the pieces the peer has, as told by its bitfield, `HaveAll` or `HaveNone`.
+    async fn set_bitfield(&mut self, bitfield: Bitfield) -> Result<(), Error> {
+        {
+            let mut picker = self.context.picker.lock().unwrap();
+            picker.peer_lost(&self.bitfield);
+            picker.peer_has(&bitfield);
+        }
+        self.bitfield = bitfield;
+        self.update_interest().await?;
+        self.request_blocks().await
+    }
+
+    /// Tells the peer whether it has pieces we miss, when that changed.
+    async fn update_interest(&mut self) -> Result<(), Error> {
+        let interested = self
+            .context
+            .picker
+            .lock()
+            .unwrap()
+            .is_interesting(&self.bitfield);
+        let changed = {
+            let mut status = self.status.lock().unwrap();
+            let changed = status.am_interested != interested;
+            status.am_interested = interested;
+            changed
+        };
+        if changed {
+            let message = if interested {
+                Message::Interested
+            } else {
+                Message::NotInterested
+            };
+            self.stream.send(message).await?;
+        }
+        Ok(())
+    }
+
+    /// Fills the request pipeline with the blocks the picker chooses, from the suggested
+    /// pieces first. While the peer chokes us, only the allowed fast pieces are requested.
+    async fn request_blocks(&mut self) -> Result<(), Error> {
+        if !self.status.lock().unwrap().am_interested {
+            return Ok(());
+        }
+        if self.peer_choking && self.allowed_fast.is_empty() {
+            return Ok(());
+        }
+        let count = self
+            .context
+            .config
+            .pipeline_len
+            .saturating_sub(self.requested.len());
+        if count == 0 {
+            return Ok(());
+        }
+        let blocks = {
+            let mut picker = self.context.picker.lock().unwrap();
+            if self.peer_choking {
+                let allowed = self.pieces_among(&self.allowed_fast);
+                picker.pick(&allowed, count, &self.requested)
+            } else {
+                let mut blocks = picker.pick_suggested(&self.bitfield, &self.suggested, count);
+                if blocks.len() < count {
+                    let mut requested = self.requested.clone();
+                    requested.extend_from_slice(&blocks);
+                    let more = picker.pick(&self.bitfield, count - blocks.len(), &requested);
+                    blocks.extend(more);
+                }
+                blocks
+            }
+        };
+        for block in blocks {
+            self.requested.push(block);
+            self.stream
+                .send(Message::Request {
+                    index: block.index,
+                    begin: block.begin,
+                    length: block.length,
+                })
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// The pieces of `indices` that the peer has, e.g. to pick only allowed fast pieces.
+    fn pieces_among(&self, indices: &[u32]) -> Bitfield {
+        let mut pieces = Bitfield::new(self.bitfield.len());
+        for index in indices.iter().map(|i| *i as usize) {
+            if self.bitfield.get(index) {
+                pieces.set(index, true);
+            }
+        }
+        pieces
+    }
+
+    async fn upload(&mut self, index: u32, begin: u32, length: u32) -> Result<(), Error> {
+        if self.am_choking && !self.allowed_fast_out.contains(&index) {
+            debug!("{} requested a block while choked", self.addr);
+            return self.reject(index, begin, length).await;
+        }
+        let have = self
+            .context
+            .picker
+            .lock()
+            .unwrap()
+            .have()
+            .get(index as usize);
+        if !have && self.fast {
+            debug!("{} requested piece {} we don't have", self.addr, index);
+            return self.reject(index, begin, length).await;
+        }
+        if !have || length > MAX_BLOCK_LEN {
+            return Err(Error::InvalidBlock);
+        }
+
+        let block = on_storage(&self.context.storage, move |storage| {
+            storage.read_block(index, begin, length)
+        })
+        .await?;
+        let limits = [
+            &self.context.limits.upload,
+            &self.context.session_limits.upload,
+        ];
+        rate_limit::acquire(&limits, length as u64).await;
+        self.stream
+            .send(Message::Piece {
+                index,
+                begin,
+                block,
+            })
+            .await?;
+
+        self.status.lock().unwrap().uploaded += length as u64;
+        self.context.add_uploaded(length as u64);
+        Ok(())
+    }
+
+    /// Tells the peer a request will not be answered, if the fast extension lets us.
+    async fn reject(&mut self, index: u32, begin: u32, length: u32) -> Result<(), Error> {
+        if self.fast {
+            self.stream
+                .send(Message::RejectRequest {
+                    index,
+                    begin,
+                    length,
+                })
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Writes a block we requested, verifying its piece once complete. Blocks we did not
+    /// request, or cancelled, are dropped.
+    async fn download(&mut self, index: u32, begin: u32, data: Vec<u8>) -> Result<(), Error> {
+        let position = self
+            .requested
+            .iter()
+            .position(|b| b.index == index && b.begin == begin && b.length as usize == data.len());
+        let block = match position {
+            Some(position) => self.requested.remove(position),
+            None => {
+                debug!("{} sent a block we did not request", self.addr);
+                return Ok(());
+            }
+        };
+
+        {
+            let mut status = self.status.lock().unwrap();
+            status.downloaded += block.length as u64;
+            status.last_block = Some(std::time::Instant::now());
+        }
+        self.context.add_downloaded(block.length as u64);
+
+        on_storage(&self.context.storage, move |storage| {
+            storage.write_block(index, begin, &data)
+        })
+        .await?;
+        let complete = self.context.picker.lock().unwrap().received(&block);
+        if complete {
+            let valid = on_storage(&self.context.storage, move |storage| {
+                storage.verify_piece(index)
+            })
+            .await?;
+            self.context.picker.lock().unwrap().verified(index, valid);
+            let _ = self
+                .events
+                .send(PeerEvent::PieceVerified { index, valid })
+                .await;
+        }
+
+        self.request_blocks().await
+    }
+}
+
+/// Runs a blocking storage operation on the blocking thread pool.
+pub(crate) async fn on_storage<T, F>(storage: &Arc<Storage>, f: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce(&Storage) -> Result<T, Error> + Send + 'static,
+{
+    let storage = storage.clone();
+    tokio::task::spawn_blocking(move || f(&storage))
+        .await
+        .map_err(|e| Error::Tokio(std::io::Error::other(e)))?
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_upload_when_unchoked() {
+    let (dir, _, context, contents) = seed_context(40_000, "seed");
+    // a peer without the fast extension
+    let mut handshake = Handshake::new(context.info_hash, [2u8; 20]);
+    handshake.reserved = [0; 8];
+    let (mut stream, commands, mut events, status) =
+        accept(context, handshake, Extensions::new()).await;
+    let bitfield = match stream.next().await.unwrap().unwrap() {
+        Message::Bitfield(bytes) => Bitfield::from_bytes(&bytes, 3).unwrap(),
+        m => panic!("unexpected message {:?}", m),
+    };
+    assert!(bitfield.is_complete());
+
+    // the torrent decides to unchoke the interested peer
+    stream.send(Message::Interested).await.unwrap();
+    match events.recv().await.unwrap() {
+        PeerEvent::InterestChanged(_) => assert!(status.lock().unwrap().interested),
+        e => panic!("unexpected event {:?}", e),
+    }
+    commands.send(PeerCommand::Unchoke).unwrap();
+    assert_eq!(stream.next().await.unwrap().unwrap(), Message::Unchoke);
+
+    stream
+        .send(Message::Request {
+            index: 2,
+            begin: 100,
+            length: 1000,
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().unwrap(),
+        Message::Piece {
+            index: 2,
+            begin: 100,
+            block: contents[32 * 1024 + 100..32 * 1024 + 1100].to_vec(),
+        }
+    );
+    assert_eq!(status.lock().unwrap().uploaded, 1000);
+
+    commands.send(PeerCommand::Shutdown).unwrap();
+    assert!(stream.next().await.is_none());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_fast_upload_while_choked() {
+    let (dir, _, context, contents) = seed_context(200_000, "fast-seed");
+    let info_hash = context.info_hash;
+    let handshake = Handshake::new(info_hash, [2u8; 20]);
+    let (mut stream, commands, _events, _status) =
+        accept(context, handshake, Extensions::new()).await;
+    assert_eq!(stream.next().await.unwrap().unwrap(), Message::HaveAll);
+    let ip = std::net::IpAddr::from([127, 0, 0, 1]);
+    let allowed = allowed_fast_set(ALLOWED_FAST_LEN, 13, &info_hash, ip);
+    for index in allowed.iter() {
+        let message = stream.next().await.unwrap().unwrap();
+        assert_eq!(message, Message::AllowedFast(*index));
+    }
+    let message = stream.next().await.unwrap().unwrap();
+    assert!(matches!(
+        message,
+        Message::Extended {
+            id: HANDSHAKE_ID,
+            ..
+        }
+    ));
+
+    // choked, only the allowed fast pieces are sent
+    let request = |index| Message::Request {
+        index,
+        begin: 0,
+        length: 10,
+    };
+    stream.send(request(allowed[0])).await.unwrap();
+    let begin = allowed[0] as usize * 16 * 1024;
+    assert_eq!(
+        stream.next().await.unwrap().unwrap(),
+        Message::Piece {
+            index: allowed[0],
+            begin: 0,
+            block: contents[begin..begin + 10].to_vec(),
+        }
+    );
+    let other = (0..13).find(|i| !allowed.contains(i)).unwrap();
+    stream.send(request(other)).await.unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().unwrap(),
+        Message::RejectRequest {
+            index: other,
+            begin: 0,
+            length: 10,
+        }
+    );
+
+    // requests for pieces we don't have are rejected too, keeping the connection
+    commands.send(PeerCommand::Unchoke).unwrap();
+    assert_eq!(stream.next().await.unwrap().unwrap(), Message::Unchoke);
+    stream.send(request(13)).await.unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().unwrap(),
+        Message::RejectRequest {
+            index: 13,
+            begin: 0,
+            length: 10,
+        }
+    );
+    stream.send(request(other)).await.unwrap();
+    assert!(matches!(
+        stream.next().await.unwrap().unwrap(),
+        Message::Piece { index, .. } if index == other
+    ));
+
+    commands.send(PeerCommand::Shutdown).unwrap();
+    assert!(stream.next().await.is_none());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_fast_download_retries_rejected_blocks() {
+    let (dir, meta_info, seed, _) = seed_context(40_000, "fast-leech");
+    let storage = Arc::new(Storage::new(&meta_info, &dir.join("empty")));
+    let have = Bitfield::new(3);
+    let context = Arc::new(TorrentContext::new(
+        seed.info_hash,
+        [9u8; 20],
+        storage,
+        have,
+    ));
+    let handshake = Handshake::new(seed.info_hash, [2u8; 20]);
+    let (mut stream, commands, _events, _status) =
+        accept(context.clone(), handshake, Extensions::new()).await;
+    assert_eq!(stream.next().await.unwrap().unwrap(), Message::HaveNone);
+    for _ in 0..3 {
+        let message = stream.next().await.unwrap().unwrap();
+        assert!(matches!(message, Message::AllowedFast(_)));
+    }
+    let message = stream.next().await.unwrap().unwrap();
+    assert!(matches!(
+        message,
+        Message::Extended {
+            id: HANDSHAKE_ID,
+            ..
+        }
+    ));
+
+    // the allowed fast piece is requested while choked
+    stream.send(Message::HaveAll).await.unwrap();
+    stream.send(Message::AllowedFast(1)).await.unwrap();
+    assert_eq!(stream.next().await.unwrap().unwrap(), Message::Interested);
+    let block = Message::Request {
+        index: 1,
+        begin: 0,
+        length: 16 * 1024,
+    };
+    assert_eq!(stream.next().await.unwrap().unwrap(), block);
+
+    // a rejected block is free again, and asked for first once unchoked
+    stream
+        .send(Message::RejectRequest {
+            index: 1,
+            begin: 0,
+            length: 16 * 1024,
+        })
+        .await
+        .unwrap();
+    stream.send(Message::Unchoke).await.unwrap();
+    assert_eq!(stream.next().await.unwrap().unwrap(), block);
+
+    commands.send(PeerCommand::Shutdown).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_extension_messages() {
+    use crate::extension::metadata::{MetadataExtension, MetadataMessage};
+    use crate::extension::ExtendedHandshake;
+
+    let (dir, meta_info, context, _) = seed_context(40_000, "extended");
+    let metadata = bencoding::to_bytes(&meta_info.info).unwrap();
+    let mut extensions = Extensions::new();
+    extensions.set_listen_port(6881);
+    extensions.register(Box::new(MetadataExtension::with_metadata(
+        context.info_hash,
+        metadata.clone(),
+    )));
+    let handshake = Handshake::new(context.info_hash, [2u8; 20]);
+    let (mut stream, commands, _events, status) = accept(context, handshake, extensions).await;
+    assert_eq!(stream.next().await.unwrap().unwrap(), Message::HaveAll);
+    for _ in 0..3 {
+        let message = stream.next().await.unwrap().unwrap();
+        assert!(matches!(message, Message::AllowedFast(_)));
+    }
+
+    // our handshake comes after the pieces we have
+    let local: ExtendedHandshake = match stream.next().await.unwrap().unwrap() {
+        Message::Extended { id, payload } if id == HANDSHAKE_ID => {
+            bencoding::from_bytes(&payload).unwrap()
+        }
+        m => panic!("unexpected message {:?}", m),
+    };
+    assert_eq!(local.p, Some(6881));
+    assert_eq!(local.metadata_size, Some(metadata.len() as u64));
+    let local_id = local.id_of("ut_metadata").unwrap();
+
+    let mut remote = ExtendedHandshake {
+        v: Some("Transmission 3.00".to_owned()),
+        ..Default::default()
+    };
+    remote.m.insert("ut_metadata".to_owned(), 7);
+    let payload = bencoding::to_bytes(&remote).unwrap();
+    stream
+        .send(Message::Extended {
+            id: HANDSHAKE_ID,
+            payload,
+        })
+        .await
+        .unwrap();
+
+    // the extension answers with the id we asked for
+    let request = MetadataMessage::Request { piece: 0 }.to_bytes();
+    stream
+        .send(Message::Extended {
+            id: local_id,
+            payload: request,
+        })
+        .await
+        .unwrap();
+    let expected = MetadataMessage::Data {
+        piece: 0,
+        total_size: metadata.len() as u64,
+        data: metadata,
+    };
+    assert_eq!(
+        stream.next().await.unwrap().unwrap(),
+        Message::Extended {
+            id: 7,
+            payload: expected.to_bytes()
+        }
+    );
+    let client = status.lock().unwrap().client.clone().unwrap();
+    assert_eq!(client.to_string(), "Transmission 3.00");
+
+    commands.send(PeerCommand::Shutdown).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_pex_messages() {
+    use crate::extension::pex::{PexExtension, PexMessage};
+    use crate::extension::ExtendedHandshake;
+    use crate::peer::pool::PeerSource;
+
+    let (dir, _, context, _) = seed_context(40_000, "pex");
+    let pool = context.pool.clone();
+    let mut extensions = Extensions::new();
+    let remote_addr = SocketAddr::from(([127, 0, 0, 1], 1));
+    extensions.register(Box::new(PexExtension::new(pool.clone(), remote_addr)));
+    let handshake = Handshake::new(context.info_hash, [2u8; 20]);
+    let (mut stream, commands, _events, _status) = accept(context, handshake, extensions).await;
+    let local_id = loop {
+        match stream.next().await.unwrap().unwrap() {
+            Message::Extended { id, payload } if id == HANDSHAKE_ID => {
+                let local: ExtendedHandshake = bencoding::from_bytes(&payload).unwrap();
+                break local.id_of("ut_pex").unwrap();
+            }
+            _ => continue,
+        }
+    };
+
+    let mut remote = ExtendedHandshake::default();
+    remote.m.insert("ut_pex".to_owned(), 3);
+    let payload = bencoding::to_bytes(&remote).unwrap();
+    stream
+        .send(Message::Extended {
+            id: HANDSHAKE_ID,
+            payload,
+        })
+        .await
+        .unwrap();
+    let peer: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+    let message = PexMessage {
+        added: vec![(peer, 0)],
+        dropped: vec![],
+    };
+    stream
+        .send(Message::Extended {
+            id: local_id,
+            payload: message.to_bytes(),
+        })
+        .await
+        .unwrap();
+
+    // the request is answered once the messages before it were handled
+    let request = Message::Request {
+        index: 0,
+        begin: 0,
+        length: 10,
+    };
+    stream.send(request).await.unwrap();
+    loop {
+        match stream.next().await.unwrap().unwrap() {
+            Message::Piece { .. } | Message::RejectRequest { .. } => break,
+            _ => continue,
+        }
+    }
+    assert_eq!(
+        pool.lock().unwrap().next_candidate(),
+        Some((peer, PeerSource::Pex))
+    );
+
+    commands.send(PeerCommand::Shutdown).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// A context seeding a single file of `len` bytes in pieces of 16 KiB, with its torrent and
+/// contents.
+#[cfg(test)]
+fn seed_context(
+    len: u32,
+    name: &str,
+) -> (
+    std::path::PathBuf,
+    crate::model::MetaInfo,
+    Arc<TorrentContext>,
+    Vec<u8>,
+) {
+    use crate::create::TorrentBuilder;
+
+    let dir = std::env::temp_dir().join(format!("thor-{}-{}", name, rand::random::<u32>()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let contents: Vec<u8> = (0..len).map(|i| (i % 253) as u8).collect();
+    std::fs::write(dir.join("file"), &contents).unwrap();
+    let meta_info = TorrentBuilder::new(dir.join("file"))
+        .piece_length(16 * 1024)
+        .build()
+        .unwrap();
+    let info_hash = meta_info.info.info_hash();
+    let storage = Arc::new(Storage::new(&meta_info, &dir));
+    let have = storage.check().unwrap();
+    let context = Arc::new(TorrentContext::new(info_hash, [9u8; 20], storage, have));
+    (dir, meta_info, context, contents)
+}
+
+/// Runs a connection for `context` with a peer connecting with `handshake`, returns the
+/// stream of the peer and the channels of the connection.
+#[cfg(test)]
+async fn accept(
+    context: Arc<TorrentContext>,
+    handshake: Handshake,
+    extensions: Extensions,
+) -> (
+    PeerStream,
+    mpsc::UnboundedSender<PeerCommand>,
+    mpsc::Receiver<PeerEvent>,
+    Arc<Mutex<PeerStatus>>,
+) {
+    use super::listener::{Listener, TorrentRegistry};
+
+    let registry = TorrentRegistry::new();
+    let mut incoming = registry.register(context.info_hash);
+    let timeout = crate::config::PeerConfig::default().handshake_timeout;
+    let encryption = crate::peer::mse::EncryptionPolicy::Enabled;
+    let listener = Listener::bind(0..=0, context.peer_id, registry, timeout, encryption)
+        .await
+        .unwrap();
+    let addr = SocketAddr::from(([127, 0, 0, 1], listener.port()));
+    tokio::spawn(listener.run());
+
+    let (commands, commands_rx) = mpsc::unbounded_channel();
+    let (events_tx, events) = mpsc::channel(8);
+    let status = Arc::new(Mutex::new(PeerStatus::default()));
+    let peer_status = status.clone();
+    tokio::spawn(async move {
+        let peer = incoming.recv().await.unwrap();
+        Connection::new(
+            peer.addr,
+            peer.stream,
+            &peer.handshake,
+            context,
+            peer_status,
+            commands_rx,
+            events_tx,
+        )
+        .with_extensions(extensions)
+        .run()
+        .await
+    });
+
+    let (stream, _) = super::connect(addr, &handshake, encryption, None)
+        .await
+        .unwrap();
+    (stream, commands, events, status)
+}
@@ -0,0 +1,66 @@
+use sha1::{Digest, Sha1};
+use std::net::IpAddr;
+
+/// Pieces a peer may download from us while choked, when it supports the fast extension.
+pub const ALLOWED_FAST_LEN: usize = 10;
+
+/// The canonical allowed fast set of BEP 6: the first `k` distinct pieces drawn from
+/// hashing the /24 network of the peer with the info hash, so that peers cannot get more
+/// pieces by reconnecting from a nearby address. Only defined for IPv4.
+pub fn allowed_fast_set(k: usize, num_pieces: usize, info_hash: &[u8; 20], ip: IpAddr) -> Vec<u32> {
+    let ip = match ip {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip,
+            None => return vec![],
+        },
+    };
+    let k = std::cmp::min(k, num_pieces);
+    let mut set = Vec::with_capacity(k);
+    let mut x = (u32::from(ip) & 0xffff_ff00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash);
+    while set.len() < k {
+        x = Sha1::digest(&x).to_vec();
+        for word in x.chunks_exact(4) {
+            if set.len() >= k {
+                break;
+            }
+            let y = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+            let index = y % num_pieces as u32;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+    set
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_allowed_fast_set() {
+    // the example of BEP 6
+    let ip = IpAddr::from([80, 4, 4, 200]);
+    let info_hash = [0xaa; 20];
+    assert_eq!(
+        allowed_fast_set(7, 1313, &info_hash, ip),
+        vec![1059, 431, 808, 1217, 287, 376, 1188]
+    );
+    assert_eq!(
+        allowed_fast_set(9, 1313, &info_hash, ip),
+        vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+    );
+    // the same network gets the same set
+    let neighbour = IpAddr::from([80, 4, 4, 1]);
+    assert_eq!(
+        allowed_fast_set(7, 1313, &info_hash, neighbour),
+        allowed_fast_set(7, 1313, &info_hash, ip)
+    );
+
+    let mut few = allowed_fast_set(10, 3, &info_hash, ip);
+    few.sort_unstable();
+    assert_eq!(few, vec![0, 1, 2]);
+    assert!(allowed_fast_set(10, 3, &info_hash, "::1".parse().unwrap()).is_empty());
+}
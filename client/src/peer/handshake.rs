@@ -0,0 +1,106 @@
+use crate::error::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+pub const HANDSHAKE_LEN: usize = 1 + 19 + 8 + 20 + 20;
+
+/// Bit 20 from the right of the reserved bytes, set by peers that support BEP 10.
+const EXTENSION_PROTOCOL_BYTE: usize = 5;
+const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+/// Bit 3 from the right, set by peers that support the fast extension (BEP 6).
+const FAST_BYTE: usize = 7;
+const FAST_BIT: u8 = 0x04;
+
+/// The first message exchanged on a peer connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Handshake {
+    pub reserved: [u8; 8],
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    /// Creates a handshake advertising the extensions supported by this client.
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Handshake {
+        let mut reserved = [0u8; 8];
+        reserved[EXTENSION_PROTOCOL_BYTE] |= EXTENSION_PROTOCOL_BIT;
+        reserved[FAST_BYTE] |= FAST_BIT;
+        Handshake {
+            reserved,
+            info_hash,
+            peer_id,
+        }
+    }
+
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.reserved[EXTENSION_PROTOCOL_BYTE] & EXTENSION_PROTOCOL_BIT != 0
+    }
+
+    pub fn supports_fast(&self) -> bool {
+        self.reserved[FAST_BYTE] & FAST_BIT != 0
+    }
+
+    /// Whether a connection starting with `bytes` sends a plaintext handshake, rather than
+    /// the public key of an encrypted one.
+    pub fn is_plaintext_start(bytes: &[u8; 20]) -> bool {
+        bytes[0] as usize == PROTOCOL.len() && &bytes[1..] == PROTOCOL
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HANDSHAKE_LEN);
+        buf.push(PROTOCOL.len() as u8);
+        buf.extend_from_slice(PROTOCOL);
+        buf.extend_from_slice(&self.reserved);
+        buf.extend_from_slice(&self.info_hash);
+        buf.extend_from_slice(&self.peer_id);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8; HANDSHAKE_LEN]) -> Result<Handshake, Error> {
+        if buf[0] as usize != PROTOCOL.len() || &buf[1..20] != PROTOCOL {
+            return Err(Error::InvalidHandshake);
+        }
+        let mut handshake = Handshake {
+            reserved: [0u8; 8],
+            info_hash: [0u8; 20],
+            peer_id: [0u8; 20],
+        };
+        handshake.reserved.copy_from_slice(&buf[20..28]);
+        handshake.info_hash.copy_from_slice(&buf[28..48]);
+        handshake.peer_id.copy_from_slice(&buf[48..68]);
+        Ok(handshake)
+    }
+
+    pub async fn write<S: AsyncWrite + Unpin>(&self, stream: &mut S) -> Result<(), Error> {
+        stream.write_all(&self.to_bytes()).await?;
+        Ok(())
+    }
+
+    pub async fn read<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Handshake, Error> {
+        let mut buf = [0u8; HANDSHAKE_LEN];
+        stream.read_exact(&mut buf).await?;
+        Handshake::from_bytes(&buf)
+    }
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_handshake_roundtrip() {
+    let handshake = Handshake::new([1u8; 20], [2u8; 20]);
+    assert!(handshake.supports_extension_protocol());
+    assert!(handshake.supports_fast());
+
+    let mut buf = [0u8; HANDSHAKE_LEN];
+    buf.copy_from_slice(&handshake.to_bytes());
+    assert_eq!(Handshake::from_bytes(&buf).unwrap(), handshake);
+
+    let mut start = [0u8; 20];
+    start.copy_from_slice(&buf[..20]);
+    assert!(Handshake::is_plaintext_start(&start));
+
+    buf[1] = b'b';
+    assert!(Handshake::from_bytes(&buf).is_err());
+}
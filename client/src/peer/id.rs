@@ -0,0 +1,223 @@
+use rand::Rng;
+use std::fmt;
+
+pub const PEER_ID_LEN: usize = 20;
+/// Characters of the random part of our peer ids, safe to put in a url unescaped.
+const URL_SAFE: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz-._~";
+
+/// The clients identified by the two letter code of their Azureus-style peer ids.
+const AZUREUS_CLIENTS: &[(&str, &str)] = &[
+    ("AZ", "Vuze"),
+    ("BC", "BitComet"),
+    ("BI", "BiglyBT"),
+    ("BT", "BitTorrent"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("lt", "rTorrent"),
+    ("qB", "qBittorrent"),
+    ("TH", "thor"),
+    ("TR", "Transmission"),
+    ("UM", "µTorrent Mac"),
+    ("UT", "µTorrent"),
+    ("UW", "µTorrent Web"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei"),
+];
+
+/// A remote client, as told by its peer id or by the `v` field of its extension handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Client {
+    pub name: String,
+    /// Empty when unknown.
+    pub version: String,
+}
+
+impl Client {
+    /// Identifies the Azureus-style (`-qB4520-...`) and Mainline-style (`M7-2-0--...`) peer
+    /// ids, returns `None` for other conventions.
+    pub fn from_peer_id(peer_id: &[u8; PEER_ID_LEN]) -> Option<Client> {
+        if peer_id[0] == b'-' && peer_id[7] == b'-' {
+            let code = std::str::from_utf8(&peer_id[1..3]).ok()?;
+            let digits = &peer_id[3..7];
+            let name = match AZUREUS_CLIENTS.iter().find(|(c, _)| *c == code) {
+                Some((_, name)) => (*name).to_owned(),
+                None if code.bytes().all(|b| b.is_ascii_alphanumeric()) => code.to_owned(),
+                None => return None,
+            };
+            let version = if code == "TR" {
+                transmission_version(digits)?
+            } else {
+                azureus_version(digits)?
+            };
+            return Some(Client { name, version });
+        }
+
+        if peer_id[0] == b'M' {
+            // M<major>-<minor>-<patch>-- with components of one or two digits
+            let text = std::str::from_utf8(&peer_id[1..8]).ok()?;
+            let parts: Vec<&str> = text.trim_end_matches('-').split('-').collect();
+            if parts.len() == 3 && parts.iter().all(|p| is_number(p)) {
+                return Some(Client {
+                    name: "BitTorrent".to_owned(),
+                    version: parts.join("."),
+                });
+            }
+        }
+        None
+    }
+
+    /// Splits the `v` field of an extension handshake, e.g. `qBittorrent/4.5.2` or
+    /// `Transmission 3.00`, into a name and a version.
+    pub fn from_extension_version(v: &str) -> Client {
+        let v = v.trim();
+        match v.rfind([' ', '/']) {
+            Some(i) if v[i + 1..].starts_with(|c: char| c.is_ascii_digit()) => Client {
+                name: v[..i].trim_end().to_owned(),
+                version: v[i + 1..].to_owned(),
+            },
+            _ => Client {
+                name: v.to_owned(),
+                version: String::new(),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.version.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{} {}", self.name, self.version)
+        }
+    }
+}
+
+/// A peer id like `-TH0100-` followed by random url safe characters, the digits being the
+/// version of the crate.
+pub fn generate() -> [u8; PEER_ID_LEN] {
+    let mut peer_id = [0u8; PEER_ID_LEN];
+    peer_id[..8].copy_from_slice(&prefix());
+    let mut rng = rand::thread_rng();
+    for byte in peer_id[8..].iter_mut() {
+        *byte = URL_SAFE[rng.gen_range(0, URL_SAFE.len())];
+    }
+    peer_id
+}
+
+/// `-TH` followed by the major, minor and patch versions of the crate and a 0, a version
+/// component above 9 is written as a letter.
+fn prefix() -> [u8; 8] {
+    let component = |v: &str| {
+        let v: u8 = v.parse().unwrap_or(0);
+        match v {
+            0..=9 => b'0' + v,
+            10..=35 => b'A' + v - 10,
+            _ => b'Z',
+        }
+    };
+    [
+        b'-',
+        b'T',
+        b'H',
+        component(env!("CARGO_PKG_VERSION_MAJOR")),
+        component(env!("CARGO_PKG_VERSION_MINOR")),
+        component(env!("CARGO_PKG_VERSION_PATCH")),
+        b'0',
+        b'-',
+    ]
+}
+
+/// The 4 characters of most Azureus-style clients: major, minor and patch versions, where
+/// letters stand for 10 and above, then a build number shown when not 0.
+fn azureus_version(digits: &[u8]) -> Option<String> {
+    let mut components = vec![];
+    for &c in digits[..3].iter() {
+        components.push(match c {
+            b'0'..=b'9' => c - b'0',
+            b'A'..=b'Z' => c - b'A' + 10,
+            b'a'..=b'z' => c - b'a' + 10,
+            _ => return None,
+        });
+    }
+    let mut version: Vec<String> = components.iter().map(|c| c.to_string()).collect();
+    if digits[3].is_ascii_digit() && digits[3] != b'0' {
+        version.push((digits[3] as char).to_string());
+    }
+    Some(version.join("."))
+}
+
+/// Transmission writes its major version then a two digit minor version, and a `Z` or `X`
+/// last for betas, e.g. `-TR2940-` for 2.94 and `-TR300Z-` for a 3.00 beta.
+fn transmission_version(digits: &[u8]) -> Option<String> {
+    let text = std::str::from_utf8(digits).ok()?;
+    let (number, beta) = match text.strip_suffix(['Z', 'X']) {
+        Some(number) => (number, true),
+        None => (text, false),
+    };
+    if !is_number(number) || number.len() < 3 {
+        return None;
+    }
+    let version = format!("{}.{}", &number[..1], &number[1..3]);
+    Some(if beta {
+        format!("{} beta", version)
+    } else {
+        version
+    })
+}
+
+fn is_number(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_generate() {
+    let peer_id = generate();
+    assert_eq!(&peer_id[..8], b"-TH0100-");
+    assert!(peer_id[8..].iter().all(|b| URL_SAFE.contains(b)));
+    assert_ne!(generate(), peer_id);
+
+    let client = Client::from_peer_id(&peer_id).unwrap();
+    assert_eq!(client.to_string(), "thor 0.1.0");
+}
+
+#[test]
+fn test_identify_clients() {
+    let identify = |prefix: &[u8]| {
+        let mut peer_id = [b'x'; PEER_ID_LEN];
+        peer_id[..prefix.len()].copy_from_slice(prefix);
+        Client::from_peer_id(&peer_id).map(|c| c.to_string())
+    };
+    assert_eq!(identify(b"-qB4520-").as_deref(), Some("qBittorrent 4.5.2"));
+    assert_eq!(identify(b"-TR2940-").as_deref(), Some("Transmission 2.94"));
+    assert_eq!(
+        identify(b"-TR300Z-").as_deref(),
+        Some("Transmission 3.00 beta")
+    );
+    assert_eq!(identify(b"-LT1270-").as_deref(), Some("libtorrent 1.2.7"));
+    assert_eq!(identify(b"-DE13F0-").as_deref(), Some("Deluge 1.3.15"));
+    assert_eq!(identify(b"-UT355W-").as_deref(), Some("µTorrent 3.5.5"));
+    assert_eq!(identify(b"-ZZ1234-").as_deref(), Some("ZZ 1.2.3.4"));
+    assert_eq!(identify(b"M7-10-2-").as_deref(), Some("BitTorrent 7.10.2"));
+    assert_eq!(identify(b"TH-0.1.0---"), None);
+    assert_eq!(identify(&[0xff; 8]), None);
+
+    assert_eq!(
+        Client::from_extension_version("qBittorrent/4.5.2"),
+        Client {
+            name: "qBittorrent".to_owned(),
+            version: "4.5.2".to_owned()
+        }
+    );
+    assert_eq!(
+        Client::from_extension_version("µTorrent Mac 1.8.7").to_string(),
+        "µTorrent Mac 1.8.7"
+    );
+    assert_eq!(Client::from_extension_version("thor").version, "");
+}
@@ -0,0 +1,318 @@
+use super::handshake::Handshake;
+use super::message::PeerCodec;
+use super::mse::{self, CryptoStream, EncryptionPolicy};
+use super::{PeerStream, Transport};
+use crate::error::Error;
+use crate::utp::UtpSocket;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tokio_util::codec::Framed;
+
+/// Ports tried in order for the listener, the first free one is announced to trackers.
+pub const DEFAULT_PORTS: RangeInclusive<u16> = 6881..=6889;
+/// Accepted peers waiting to be picked up by their torrent.
+const INCOMING_QUEUE_LEN: usize = 16;
+
+/// A connection accepted by the listener, after the handshakes were exchanged.
+#[derive(Debug)]
+pub struct IncomingPeer {
+    pub addr: SocketAddr,
+    pub stream: PeerStream,
+    pub handshake: Handshake,
+}
+
+/// The torrents accepting incoming connections, by info hash.
+#[derive(Debug, Clone, Default)]
+pub struct TorrentRegistry {
+    torrents: Arc<Mutex<HashMap<[u8; 20], mpsc::Sender<IncomingPeer>>>>,
+}
+
+impl TorrentRegistry {
+    pub fn new() -> TorrentRegistry {
+        TorrentRegistry::default()
+    }
+
+    /// Starts routing the connections for `info_hash` to the returned receiver.
+    pub fn register(&self, info_hash: [u8; 20]) -> mpsc::Receiver<IncomingPeer> {
+        let (sender, receiver) = mpsc::channel(INCOMING_QUEUE_LEN);
+        self.torrents.lock().unwrap().insert(info_hash, sender);
+        receiver
+    }
+
+    pub fn unregister(&self, info_hash: &[u8; 20]) {
+        self.torrents.lock().unwrap().remove(info_hash);
+    }
+
+    fn get(&self, info_hash: &[u8; 20]) -> Option<mpsc::Sender<IncomingPeer>> {
+        self.torrents.lock().unwrap().get(info_hash).cloned()
+    }
+
+    fn info_hashes(&self) -> Vec<[u8; 20]> {
+        self.torrents.lock().unwrap().keys().copied().collect()
+    }
+}
+
+/// Accepts incoming peer connections and hands them to the torrent they ask for.
+pub struct Listener {
+    listener: TcpListener,
+    /// Also accepts uTP connections on this socket.
+    utp: Option<Arc<UtpSocket>>,
+    port: u16,
+    peer_id: [u8; 20],
+    registry: TorrentRegistry,
+    /// Incoming connections that do not send their handshake in time are dropped.
+    handshake_timeout: Duration,
+    encryption: EncryptionPolicy,
+}
+
+impl Listener {
+    /// Listens on the first free port of `ports`, answering handshakes with `peer_id`.
+    /// Encrypted and plaintext connections are accepted as `encryption` allows.
+    pub async fn bind(
+        ports: RangeInclusive<u16>,
+        peer_id: [u8; 20],
+        registry: TorrentRegistry,
+        handshake_timeout: Duration,
+        encryption: EncryptionPolicy,
+    ) -> Result<Listener, Error> {
+        for port in ports {
+            match TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port))).await {
+                Ok(listener) => {
+                    let port = listener.local_addr()?.port();
+                    info!("listening for peers on port {}", port);
+                    return Ok(Listener {
+                        listener,
+                        utp: None,
+                        port,
+                        peer_id,
+                        registry,
+                        handshake_timeout,
+                        encryption,
+                    });
+                }
+                Err(e) => warn!("failed to listen on port {}: {}", port, e),
+            }
+        }
+        Err(Error::PortsExhausted)
+    }
+
+    /// Accepts the uTP connections of `utp` as well, usually bound to the same port.
+    pub fn with_utp(mut self, utp: Arc<UtpSocket>) -> Listener {
+        self.utp = Some(utp);
+        self
+    }
+
+    /// The port to announce to trackers and peers.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Accepts connections until the task is dropped. Failed accepts, e.g. when out of
+    /// file descriptors, are logged and do not stop the listener.
+    pub async fn run(self) {
+        loop {
+            let accepted = tokio::select! {
+                accepted = self.listener.accept() => accepted
+                    .map(|(socket, addr)| (Box::new(socket) as Box<dyn Transport>, addr))
+                    .map_err(Error::from),
+                accepted = accept_utp(self.utp.as_deref()) => accepted.map(|socket| {
+                    let addr = socket.peer_addr();
+                    (Box::new(socket) as Box<dyn Transport>, addr)
+                }),
+            };
+            let (socket, addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("failed to accept a peer connection: {}", e);
+                    continue;
+                }
+            };
+            let peer_id = self.peer_id;
+            let registry = self.registry.clone();
+            let handshake_timeout = self.handshake_timeout;
+            let encryption = self.encryption;
+            tokio::spawn(async move {
+                let accepted = timeout(
+                    handshake_timeout,
+                    accept(socket, addr, peer_id, registry, encryption),
+                );
+                if let Err(e) = accepted.await.unwrap_or(Err(Error::Timeout)) {
+                    debug!("rejected incoming connection from {}: {}", addr, e);
+                }
+            });
+        }
+    }
+}
+
+/// Waits for a uTP connection, forever without a uTP socket.
+async fn accept_utp(utp: Option<&UtpSocket>) -> Result<crate::utp::UtpStream, Error> {
+    match utp {
+        Some(utp) => utp.accept().await,
+        None => futures_util::future::pending().await,
+    }
+}
+
+/// Exchanges the handshakes, first the encryption handshake unless the peer starts with
+/// a plaintext BitTorrent handshake.
+async fn accept(
+    mut socket: Box<dyn Transport>,
+    addr: SocketAddr,
+    peer_id: [u8; 20],
+    registry: TorrentRegistry,
+    encryption: EncryptionPolicy,
+) -> Result<(), Error> {
+    let mut start = [0u8; 20];
+    socket.read_exact(&mut start).await?;
+    let (mut socket, encrypted_for) = if Handshake::is_plaintext_start(&start) {
+        if encryption == EncryptionPolicy::Forced {
+            return Err(Error::Encryption("plaintext connections are refused"));
+        }
+        (CryptoStream::plain(socket, start.to_vec()), None)
+    } else {
+        if encryption == EncryptionPolicy::Disabled {
+            return Err(Error::InvalidHandshake);
+        }
+        let info_hashes = registry.info_hashes();
+        let (socket, info_hash) =
+            mse::accept(socket, start.to_vec(), &info_hashes, encryption).await?;
+        (socket, Some(info_hash))
+    };
+
+    let handshake = Handshake::read(&mut socket).await?;
+    if encrypted_for.is_some_and(|info_hash| info_hash != handshake.info_hash) {
+        return Err(Error::InvalidHandshake);
+    }
+    let torrent = registry
+        .get(&handshake.info_hash)
+        .ok_or(Error::InvalidHandshake)?;
+
+    Handshake::new(handshake.info_hash, peer_id)
+        .write(&mut socket)
+        .await?;
+    debug!("accepted peer {}", addr);
+
+    let incoming = IncomingPeer {
+        addr,
+        stream: Framed::new(socket, PeerCodec),
+        handshake,
+    };
+    torrent
+        .send(incoming)
+        .await
+        .map_err(|_| Error::ConnectionClosed)
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_routes_by_info_hash() {
+    let registry = TorrentRegistry::new();
+    let mut torrent = registry.register([1u8; 20]);
+    let timeout = Duration::from_secs(10);
+    let encryption = EncryptionPolicy::Enabled;
+    let listener = Listener::bind(0..=0, [9u8; 20], registry.clone(), timeout, encryption)
+        .await
+        .unwrap();
+    let addr = SocketAddr::from(([127, 0, 0, 1], listener.port()));
+    tokio::spawn(listener.run());
+
+    // both encrypted and plaintext peers are accepted
+    for policy in [EncryptionPolicy::Forced, EncryptionPolicy::Disabled] {
+        let handshake = Handshake::new([1u8; 20], [2u8; 20]);
+        let (stream, remote) = super::connect(addr, &handshake, policy, None)
+            .await
+            .unwrap();
+        assert_eq!(remote.peer_id, [9u8; 20]);
+        assert_eq!(
+            stream.get_ref().is_encrypted(),
+            policy == EncryptionPolicy::Forced
+        );
+        let incoming = torrent.recv().await.unwrap();
+        assert_eq!(incoming.handshake.peer_id, [2u8; 20]);
+        assert_eq!(
+            incoming.stream.get_ref().is_encrypted(),
+            policy == EncryptionPolicy::Forced
+        );
+    }
+
+    // unknown torrents are rejected before we send our handshake
+    for policy in [EncryptionPolicy::Forced, EncryptionPolicy::Disabled] {
+        let handshake = Handshake::new([3u8; 20], [2u8; 20]);
+        assert!(super::connect(addr, &handshake, policy, None)
+            .await
+            .is_err());
+    }
+
+    // a listener without encryption makes the peers fall back to plaintext
+    let listener = Listener::bind(
+        0..=0,
+        [9u8; 20],
+        registry,
+        timeout,
+        EncryptionPolicy::Disabled,
+    )
+    .await
+    .unwrap();
+    let addr = SocketAddr::from(([127, 0, 0, 1], listener.port()));
+    tokio::spawn(listener.run());
+    let handshake = Handshake::new([1u8; 20], [2u8; 20]);
+    let (stream, _) = super::connect(addr, &handshake, encryption, None)
+        .await
+        .unwrap();
+    assert!(!stream.get_ref().is_encrypted());
+    assert!(
+        super::connect(addr, &handshake, EncryptionPolicy::Forced, None)
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_utp_connections() {
+    let registry = TorrentRegistry::new();
+    let mut torrent = registry.register([1u8; 20]);
+    let timeout = Duration::from_secs(10);
+    let encryption = EncryptionPolicy::Enabled;
+    let utp = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let utp_addr = utp.local_addr();
+    let listener = Listener::bind(0..=0, [9u8; 20], registry, timeout, encryption)
+        .await
+        .unwrap()
+        .with_utp(Arc::new(utp));
+    let tcp_addr = SocketAddr::from(([127, 0, 0, 1], listener.port()));
+    tokio::spawn(listener.run());
+
+    let client = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let handshake = Handshake::new([1u8; 20], [2u8; 20]);
+
+    // nothing listens for tcp on the port of the utp socket
+    let (stream, remote) = super::connect(utp_addr, &handshake, encryption, Some(&client))
+        .await
+        .unwrap();
+    assert_eq!(remote.peer_id, [9u8; 20]);
+    assert!(stream.get_ref().is_encrypted());
+    let incoming = torrent.recv().await.unwrap();
+    assert_eq!(incoming.handshake.peer_id, [2u8; 20]);
+    assert_eq!(incoming.addr, client.local_addr());
+
+    // without uTP on its port, the peer is connected over tcp
+    super::connect(tcp_addr, &handshake, encryption, Some(&client))
+        .await
+        .unwrap();
+    let incoming = torrent.recv().await.unwrap();
+    assert_ne!(incoming.addr, client.local_addr());
+}
@@ -0,0 +1,288 @@
+use crate::error::Error;
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Messages larger than this are rejected, a 16 KiB block plus its header is the
+/// largest message a well behaved peer sends besides big bitfields.
+const MAX_MESSAGE_LEN: usize = 2 * 1024 * 1024;
+
+const ID_CHOKE: u8 = 0;
+const ID_UNCHOKE: u8 = 1;
+const ID_INTERESTED: u8 = 2;
+const ID_NOT_INTERESTED: u8 = 3;
+const ID_HAVE: u8 = 4;
+const ID_BITFIELD: u8 = 5;
+const ID_REQUEST: u8 = 6;
+const ID_PIECE: u8 = 7;
+const ID_CANCEL: u8 = 8;
+const ID_PORT: u8 = 9;
+const ID_SUGGEST_PIECE: u8 = 13;
+const ID_HAVE_ALL: u8 = 14;
+const ID_HAVE_NONE: u8 = 15;
+const ID_REJECT_REQUEST: u8 = 16;
+const ID_ALLOWED_FAST: u8 = 17;
+const ID_EXTENDED: u8 = 20;
+
+/// A message of the peer wire protocol, sent after the handshake.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Port(u16),
+    /// The messages of the fast extension (BEP 6), only exchanged when both peers set its
+    /// bit in the handshake. The peer would rather we download this piece.
+    SuggestPiece(u32),
+    /// Replaces the bitfield, for a seed.
+    HaveAll,
+    /// Replaces the bitfield, for a peer without pieces.
+    HaveNone,
+    /// The peer will not answer a request.
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// The peer answers requests for this piece even while it chokes us.
+    AllowedFast(u32),
+    /// A message of the extension protocol (BEP 10), `id` 0 is the extended handshake.
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+/// Frames `Message`s as `<length prefix><message id><payload>`.
+#[derive(Debug, Default)]
+pub struct PeerCodec;
+
+impl Decoder for PeerCodec {
+    type Item = Message;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, Error> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+
+        let mut len_bytes = [0u8; 4];
+        len_bytes.copy_from_slice(&src[..4]);
+        let len = u32::from_be_bytes(len_bytes) as usize;
+
+        if len > MAX_MESSAGE_LEN {
+            return Err(Error::InvalidMessage);
+        }
+
+        if src.len() < 4 + len {
+            src.reserve(4 + len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(4);
+        if len == 0 {
+            return Ok(Some(Message::KeepAlive));
+        }
+
+        let mut payload = src.split_to(len);
+        let id = payload.get_u8();
+        let message = match id {
+            ID_CHOKE => Message::Choke,
+            ID_UNCHOKE => Message::Unchoke,
+            ID_INTERESTED => Message::Interested,
+            ID_NOT_INTERESTED => Message::NotInterested,
+            ID_HAVE if payload.len() == 4 => Message::Have(payload.get_u32()),
+            ID_BITFIELD => Message::Bitfield(payload.to_vec()),
+            ID_REQUEST if payload.len() == 12 => Message::Request {
+                index: payload.get_u32(),
+                begin: payload.get_u32(),
+                length: payload.get_u32(),
+            },
+            ID_PIECE if payload.len() >= 8 => Message::Piece {
+                index: payload.get_u32(),
+                begin: payload.get_u32(),
+                block: payload.to_vec(),
+            },
+            ID_CANCEL if payload.len() == 12 => Message::Cancel {
+                index: payload.get_u32(),
+                begin: payload.get_u32(),
+                length: payload.get_u32(),
+            },
+            ID_PORT if payload.len() == 2 => Message::Port(payload.get_u16()),
+            ID_SUGGEST_PIECE if payload.len() == 4 => Message::SuggestPiece(payload.get_u32()),
+            ID_HAVE_ALL => Message::HaveAll,
+            ID_HAVE_NONE => Message::HaveNone,
+            ID_REJECT_REQUEST if payload.len() == 12 => Message::RejectRequest {
+                index: payload.get_u32(),
+                begin: payload.get_u32(),
+                length: payload.get_u32(),
+            },
+            ID_ALLOWED_FAST if payload.len() == 4 => Message::AllowedFast(payload.get_u32()),
+            ID_EXTENDED if !payload.is_empty() => Message::Extended {
+                id: payload.get_u8(),
+                payload: payload.to_vec(),
+            },
+            _ => return Err(Error::InvalidMessage),
+        };
+        Ok(Some(message))
+    }
+}
+
+impl Encoder<Message> for PeerCodec {
+    type Error = Error;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<(), Error> {
+        match message {
+            Message::KeepAlive => dst.put_u32(0),
+            Message::Choke => put_header(dst, ID_CHOKE, 0),
+            Message::Unchoke => put_header(dst, ID_UNCHOKE, 0),
+            Message::Interested => put_header(dst, ID_INTERESTED, 0),
+            Message::NotInterested => put_header(dst, ID_NOT_INTERESTED, 0),
+            Message::Have(index) => {
+                put_header(dst, ID_HAVE, 4);
+                dst.put_u32(index);
+            }
+            Message::Bitfield(bitfield) => {
+                put_header(dst, ID_BITFIELD, bitfield.len());
+                dst.put_slice(&bitfield);
+            }
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => {
+                put_header(dst, ID_REQUEST, 12);
+                dst.put_u32(index);
+                dst.put_u32(begin);
+                dst.put_u32(length);
+            }
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                put_header(dst, ID_PIECE, 8 + block.len());
+                dst.put_u32(index);
+                dst.put_u32(begin);
+                dst.put_slice(&block);
+            }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                put_header(dst, ID_CANCEL, 12);
+                dst.put_u32(index);
+                dst.put_u32(begin);
+                dst.put_u32(length);
+            }
+            Message::Port(port) => {
+                put_header(dst, ID_PORT, 2);
+                dst.put_u16(port);
+            }
+            Message::SuggestPiece(index) => {
+                put_header(dst, ID_SUGGEST_PIECE, 4);
+                dst.put_u32(index);
+            }
+            Message::HaveAll => put_header(dst, ID_HAVE_ALL, 0),
+            Message::HaveNone => put_header(dst, ID_HAVE_NONE, 0),
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                put_header(dst, ID_REJECT_REQUEST, 12);
+                dst.put_u32(index);
+                dst.put_u32(begin);
+                dst.put_u32(length);
+            }
+            Message::AllowedFast(index) => {
+                put_header(dst, ID_ALLOWED_FAST, 4);
+                dst.put_u32(index);
+            }
+            Message::Extended { id, payload } => {
+                put_header(dst, ID_EXTENDED, 1 + payload.len());
+                dst.put_u8(id);
+                dst.put_slice(&payload);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn put_header(dst: &mut BytesMut, id: u8, payload_len: usize) {
+    dst.reserve(5 + payload_len);
+    dst.put_u32(1 + payload_len as u32);
+    dst.put_u8(id);
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_message_roundtrip() {
+    let messages = vec![
+        Message::KeepAlive,
+        Message::Unchoke,
+        Message::Have(42),
+        Message::Bitfield(vec![0xff, 0x80]),
+        Message::Request {
+            index: 1,
+            begin: 16384,
+            length: 16384,
+        },
+        Message::Piece {
+            index: 3,
+            begin: 0,
+            block: vec![1, 2, 3],
+        },
+        Message::Extended {
+            id: 0,
+            payload: b"de".to_vec(),
+        },
+        Message::HaveAll,
+        Message::RejectRequest {
+            index: 2,
+            begin: 0,
+            length: 100,
+        },
+        Message::AllowedFast(7),
+    ];
+
+    let mut buf = BytesMut::new();
+    for m in messages.iter() {
+        PeerCodec.encode(m.clone(), &mut buf).unwrap();
+    }
+
+    // a partial frame must not be decoded
+    let mut partial = BytesMut::from(&buf[..6]);
+    assert_eq!(
+        PeerCodec.decode(&mut partial).unwrap(),
+        Some(Message::KeepAlive)
+    );
+    assert_eq!(PeerCodec.decode(&mut partial).unwrap(), None);
+
+    for m in messages.into_iter() {
+        assert_eq!(PeerCodec.decode(&mut buf).unwrap(), Some(m));
+    }
+    assert!(buf.is_empty());
+}
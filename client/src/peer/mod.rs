@@ -0,0 +1,159 @@
+use crate::error::Error;
+use crate::utp::UtpSocket;
+use handshake::Handshake;
+use log::debug;
+use message::PeerCodec;
+use mse::{CryptoStream, EncryptionPolicy};
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+pub mod choker;
+pub mod connection;
+pub mod fast;
+pub mod handshake;
+pub mod id;
+pub mod listener;
+pub mod message;
+pub mod mse;
+pub mod pool;
+
+/// Size of an IPv4 address and port in compact form.
+pub const COMPACT_V4_LEN: usize = 6;
+/// Size of an IPv6 address and port in compact form.
+pub const COMPACT_V6_LEN: usize = 18;
+
+/// Time for the encryption handshake, before falling back to plaintext. Peers without
+/// encryption may wait for more bytes of their handshake rather than close the connection.
+const ENCRYPTION_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time for a peer to accept a uTP connection before connecting over TCP. Peers without
+/// uTP do not answer at all.
+const UTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A connection carrying the peer protocol: a `TcpStream` or a `UtpStream`.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + fmt::Debug {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + fmt::Debug> Transport for T {}
+
+/// A peer connection after the handshake, exchanging framed messages.
+pub type PeerStream = Framed<CryptoStream<Box<dyn Transport>>, PeerCodec>;
+
+#[derive(Debug)]
+pub struct Peer {
+    pub ip: u32,
+    pub port: u16,
+}
+
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let first = self.ip >> 24;
+        let second = (self.ip & 0b00000000_11111111_00000000_00000000) >> 16;
+        let third = (self.ip & 0b00000000_00000000_11111111_00000000) >> 8;
+        let fourth = self.ip & 0b00000000_00000000_00000000_11111111;
+        write!(f, "{}.{}.{}.{}:{}", first, second, third, fourth, self.port)
+    }
+}
+
+impl Peer {
+    pub fn size() -> usize {
+        use std::mem::size_of;
+        size_of::<u32>() + size_of::<u16>()
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::from(self.ip), self.port))
+    }
+}
+
+/// Connects to `addr` and exchanges handshakes, making sure the remote peer serves
+/// the same info hash. Returns the framed connection and the remote handshake.
+///
+/// With a `utp` socket the connection is made over uTP, or over TCP if the peer does not
+/// accept it.
+pub async fn connect(
+    addr: SocketAddr,
+    handshake: &Handshake,
+    encryption: EncryptionPolicy,
+    utp: Option<&UtpSocket>,
+) -> Result<(PeerStream, Handshake), Error> {
+    let (socket, utp) = match utp {
+        Some(utp) => match open(addr, Some(utp)).await {
+            Ok(socket) => (socket, Some(utp)),
+            Err(e) => {
+                debug!("utp connection to {} failed, using tcp: {}", addr, e);
+                (open(addr, None).await?, None)
+            }
+        },
+        None => (open(addr, None).await?, None),
+    };
+    let mut socket = if encryption == EncryptionPolicy::Disabled {
+        CryptoStream::plain(socket, vec![])
+    } else {
+        let initiate = mse::initiate(socket, &handshake.info_hash, encryption);
+        match tokio::time::timeout(ENCRYPTION_TIMEOUT, initiate).await {
+            Ok(Ok(socket)) => socket,
+            Ok(Err(e)) if encryption == EncryptionPolicy::Forced => return Err(e),
+            Err(_) if encryption == EncryptionPolicy::Forced => return Err(Error::Timeout),
+            Ok(Err(_)) | Err(_) => {
+                debug!("{} refused encryption, connecting in plaintext", addr);
+                CryptoStream::plain(open(addr, utp).await?, vec![])
+            }
+        }
+    };
+    handshake.write(&mut socket).await?;
+
+    let remote = Handshake::read(&mut socket).await?;
+    if remote.info_hash != handshake.info_hash {
+        return Err(Error::InvalidHandshake);
+    }
+
+    Ok((Framed::new(socket, PeerCodec), remote))
+}
+
+/// Opens a connection to `addr`, over uTP when `utp` is given.
+async fn open(addr: SocketAddr, utp: Option<&UtpSocket>) -> Result<Box<dyn Transport>, Error> {
+    match utp {
+        Some(utp) => {
+            let stream = tokio::time::timeout(UTP_CONNECT_TIMEOUT, utp.connect(addr))
+                .await
+                .map_err(|_| Error::Timeout)??;
+            Ok(Box::new(stream))
+        }
+        None => Ok(Box::new(TcpStream::connect(addr).await?)),
+    }
+}
+
+/// Appends `addr` in compact form: the ip followed by the port, in network byte order.
+pub fn write_compact(addr: &SocketAddr, buf: &mut Vec<u8>) {
+    match addr {
+        SocketAddr::V4(a) => buf.extend_from_slice(&a.ip().octets()),
+        SocketAddr::V6(a) => buf.extend_from_slice(&a.ip().octets()),
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Parses a list of compact IPv4 addresses, ignoring trailing bytes.
+pub fn parse_compact_v4(bytes: &[u8]) -> Vec<SocketAddr> {
+    bytes
+        .chunks_exact(COMPACT_V4_LEN)
+        .map(|c| {
+            let ip = Ipv4Addr::new(c[0], c[1], c[2], c[3]);
+            SocketAddr::from((ip, u16::from_be_bytes([c[4], c[5]])))
+        })
+        .collect()
+}
+
+/// Parses a list of compact IPv6 addresses, ignoring trailing bytes.
+pub fn parse_compact_v6(bytes: &[u8]) -> Vec<SocketAddr> {
+    bytes
+        .chunks_exact(COMPACT_V6_LEN)
+        .map(|c| {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&c[..16]);
+            SocketAddr::from((Ipv6Addr::from(octets), u16::from_be_bytes([c[16], c[17]])))
+        })
+        .collect()
+}
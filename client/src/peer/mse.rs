@@ -0,0 +1,588 @@
+use crate::error::Error;
+use num_bigint::BigUint;
+use rand::Rng;
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use std::fmt;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// The Diffie-Hellman prime of message stream encryption, the generator is 2.
+const PRIME: &[u8] = b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74\
+020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+/// Size of the public keys, sent in big endian.
+const KEY_LEN: usize = 96;
+/// Bytes of private key, 160 bits are enough for a 768 bits prime.
+const PRIVATE_KEY_LEN: usize = 20;
+/// Random padding after each public key, and longest padding accepted in the other messages.
+const MAX_PAD_LEN: usize = 512;
+/// The verification constant, found encrypted to synchronize on the encrypted stream.
+const VC: [u8; 8] = [0; 8];
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+/// Bytes of RC4 key stream dropped before encrypting, the first ones leak the key.
+const RC4_DISCARD: usize = 1024;
+
+/// Whether peer connections are encrypted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EncryptionPolicy {
+    /// Plaintext only.
+    Disabled,
+    /// Outgoing connections try encryption first and fall back to plaintext, incoming
+    /// connections may use either.
+    #[default]
+    Enabled,
+    /// Encrypted connections only.
+    Forced,
+}
+
+impl FromStr for EncryptionPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<EncryptionPolicy, Error> {
+        match s {
+            "disabled" => Ok(EncryptionPolicy::Disabled),
+            "enabled" => Ok(EncryptionPolicy::Enabled),
+            "forced" => Ok(EncryptionPolicy::Forced),
+            _ => Err(Error::Config(format!(
+                "invalid encryption {}, expected disabled, enabled or forced",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for EncryptionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            EncryptionPolicy::Disabled => "disabled",
+            EncryptionPolicy::Enabled => "enabled",
+            EncryptionPolicy::Forced => "forced",
+        };
+        f.pad(name)
+    }
+}
+
+/// The RC4 stream cipher, encrypting and decrypting alike.
+#[derive(Clone)]
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Rc4 {
+        let mut state = [0u8; 256];
+        for (i, s) in state.iter_mut().enumerate() {
+            *s = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        Rc4 { state, i: 0, j: 0 }
+    }
+
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[k as usize];
+        }
+    }
+}
+
+struct Ciphers {
+    encrypt: Rc4,
+    decrypt: Rc4,
+}
+
+/// A peer connection after the encryption handshake, RC4 encrypted or plaintext as
+/// negotiated. Plaintext connections of peers without encryption are wrapped as well.
+pub struct CryptoStream<S> {
+    inner: S,
+    /// Received during the handshakes and already decrypted, read before `inner`.
+    read_buf: Vec<u8>,
+    cipher: Option<Ciphers>,
+    /// Encrypted bytes not written to `inner` yet.
+    write_buf: Vec<u8>,
+}
+
+impl<S> CryptoStream<S> {
+    /// A plaintext connection, `received` being its first bytes already read.
+    pub fn plain(inner: S, received: Vec<u8>) -> CryptoStream<S> {
+        CryptoStream {
+            inner,
+            read_buf: received,
+            cipher: None,
+            write_buf: vec![],
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Appends bytes read past the handshake, decrypting them.
+    fn push_received(&mut self, mut received: Vec<u8>) {
+        if let Some(cipher) = self.cipher.as_mut() {
+            cipher.decrypt.apply(&mut received);
+        }
+        self.read_buf.extend_from_slice(&received);
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for CryptoStream<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CryptoStream")
+            .field("inner", &self.inner)
+            .field("encrypted", &self.is_encrypted())
+            .finish()
+    }
+}
+
+impl<S: AsyncWrite + Unpin> CryptoStream<S> {
+    /// Writes the pending encrypted bytes.
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = match Pin::new(&mut self.inner).poll_write(cx, &self.write_buf) {
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            if n == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CryptoStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if !this.read_buf.is_empty() {
+            let n = std::cmp::min(buf.remaining(), this.read_buf.len());
+            buf.put_slice(&this.read_buf[..n]);
+            this.read_buf.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        let filled = buf.filled().len();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                if let Some(cipher) = this.cipher.as_mut() {
+                    cipher.decrypt.apply(&mut buf.filled_mut()[filled..]);
+                }
+                Poll::Ready(Ok(()))
+            }
+            poll => poll,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CryptoStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let cipher = match this.cipher.as_mut() {
+            Some(cipher) => cipher,
+            None => return Pin::new(&mut this.inner).poll_write(cx, buf),
+        };
+        // the key stream moves on with every byte encrypted, so encrypted bytes are kept
+        // until written
+        if this.write_buf.is_empty() {
+            let mut data = buf.to_vec();
+            cipher.encrypt.apply(&mut data);
+            this.write_buf = data;
+            if let Poll::Ready(Err(e)) = this.poll_write_buf(cx) {
+                return Poll::Ready(Err(e));
+            }
+            return Poll::Ready(Ok(buf.len()));
+        }
+        match this.poll_write_buf(cx) {
+            Poll::Ready(Ok(())) => Pin::new(this).poll_write(cx, buf),
+            poll => poll.map_ok(|()| 0),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_write_buf(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+            poll => poll,
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_write_buf(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_shutdown(cx),
+            poll => poll,
+        }
+    }
+}
+
+/// Reads the handshake messages, which are found by searching the stream past the random
+/// padding of the other side.
+struct Handshaker<S> {
+    stream: S,
+    /// Read but not handled yet.
+    buf: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Handshaker<S> {
+    async fn read_more(&mut self) -> Result<(), Error> {
+        let mut chunk = [0u8; 1024];
+        let n = self.stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(Error::ConnectionClosed);
+        }
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(())
+    }
+
+    async fn take(&mut self, n: usize) -> Result<Vec<u8>, Error> {
+        while self.buf.len() < n {
+            self.read_more().await?;
+        }
+        Ok(self.buf.drain(..n).collect())
+    }
+
+    /// Skips the padding before `pattern`, and the pattern.
+    async fn skip_to(&mut self, pattern: &[u8]) -> Result<(), Error> {
+        loop {
+            let found = self.buf.windows(pattern.len()).position(|w| w == pattern);
+            match found {
+                Some(i) if i <= MAX_PAD_LEN => {
+                    self.buf.drain(..i + pattern.len());
+                    return Ok(());
+                }
+                _ if self.buf.len() >= MAX_PAD_LEN + pattern.len() => {
+                    return Err(Error::Encryption("no synchronization"))
+                }
+                _ => self.read_more().await?,
+            }
+        }
+    }
+
+    /// Sends our public key followed by random padding.
+    async fn send_public_key(&mut self, public: &[u8]) -> Result<(), Error> {
+        let mut message = public.to_vec();
+        let pad_len = rand::thread_rng().gen_range(0, MAX_PAD_LEN + 1);
+        message.extend((0..pad_len).map(|_| rand::random::<u8>()));
+        self.stream.write_all(&message).await?;
+        Ok(())
+    }
+}
+
+/// Runs the encryption handshake as the side opening the connection, for the torrent
+/// `info_hash`. Only RC4 is offered when `policy` forces encryption.
+pub async fn initiate<S>(
+    stream: S,
+    info_hash: &[u8; 20],
+    policy: EncryptionPolicy,
+) -> Result<CryptoStream<S>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (private, public) = generate_keys();
+    let mut handshaker = Handshaker {
+        stream,
+        buf: vec![],
+    };
+    handshaker.send_public_key(&public).await?;
+    let remote_public = handshaker.take(KEY_LEN).await?;
+    let secret = shared_secret(&private, &remote_public)?;
+    let Ciphers {
+        mut encrypt,
+        mut decrypt,
+    } = ciphers(&secret, info_hash, true);
+
+    let mut provide = CRYPTO_RC4;
+    if policy != EncryptionPolicy::Forced {
+        provide |= CRYPTO_PLAINTEXT;
+    }
+    // no padding, and the BitTorrent handshake is sent afterwards rather than as initial
+    // payload
+    let mut encrypted = VC.to_vec();
+    encrypted.extend_from_slice(&provide.to_be_bytes());
+    encrypted.extend_from_slice(&0u16.to_be_bytes());
+    encrypted.extend_from_slice(&0u16.to_be_bytes());
+    encrypt.apply(&mut encrypted);
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    message.extend(xor(
+        &hash(&[b"req2", info_hash]),
+        &hash(&[b"req3", &secret]),
+    ));
+    message.extend_from_slice(&encrypted);
+    handshaker.stream.write_all(&message).await?;
+
+    let mut vc = VC;
+    decrypt.apply(&mut vc);
+    handshaker.skip_to(&vc).await?;
+    let mut reply = handshaker.take(6).await?;
+    decrypt.apply(&mut reply);
+    let select = u32::from_be_bytes([reply[0], reply[1], reply[2], reply[3]]);
+    let pad_len = u16::from_be_bytes([reply[4], reply[5]]) as usize;
+    if pad_len > MAX_PAD_LEN {
+        return Err(Error::Encryption("padding too long"));
+    }
+    let mut pad = handshaker.take(pad_len).await?;
+    decrypt.apply(&mut pad);
+
+    let cipher = match select {
+        CRYPTO_RC4 => Some(Ciphers { encrypt, decrypt }),
+        CRYPTO_PLAINTEXT if policy != EncryptionPolicy::Forced => None,
+        _ => return Err(Error::Encryption("invalid method selected")),
+    };
+    let mut stream = CryptoStream {
+        inner: handshaker.stream,
+        read_buf: vec![],
+        cipher,
+        write_buf: vec![],
+    };
+    stream.push_received(handshaker.buf);
+    Ok(stream)
+}
+
+/// Runs the encryption handshake as the side accepting the connection, `received` being
+/// the bytes already read from it. The torrent is found among `info_hashes`, and returned.
+pub async fn accept<S>(
+    stream: S,
+    received: Vec<u8>,
+    info_hashes: &[[u8; 20]],
+    policy: EncryptionPolicy,
+) -> Result<(CryptoStream<S>, [u8; 20]), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut handshaker = Handshaker {
+        stream,
+        buf: received,
+    };
+    let remote_public = handshaker.take(KEY_LEN).await?;
+    let (private, public) = generate_keys();
+    handshaker.send_public_key(&public).await?;
+    let secret = shared_secret(&private, &remote_public)?;
+
+    handshaker.skip_to(&hash(&[b"req1", &secret])).await?;
+    let obfuscated = handshaker.take(20).await?;
+    let req2 = xor(&obfuscated, &hash(&[b"req3", &secret]));
+    let info_hash = *info_hashes
+        .iter()
+        .find(|info_hash| hash(&[b"req2", &info_hash[..]])[..] == req2[..])
+        .ok_or(Error::UnknownTorrent)?;
+    let Ciphers {
+        mut encrypt,
+        mut decrypt,
+    } = ciphers(&secret, &info_hash, false);
+
+    let mut request = handshaker.take(14).await?;
+    decrypt.apply(&mut request);
+    if request[..8] != VC {
+        return Err(Error::Encryption("invalid verification constant"));
+    }
+    let provide = u32::from_be_bytes([request[8], request[9], request[10], request[11]]);
+    let pad_len = u16::from_be_bytes([request[12], request[13]]) as usize;
+    if pad_len > MAX_PAD_LEN {
+        return Err(Error::Encryption("padding too long"));
+    }
+    let mut pad_and_len = handshaker.take(pad_len + 2).await?;
+    decrypt.apply(&mut pad_and_len);
+    let ia_len = u16::from_be_bytes([pad_and_len[pad_len], pad_and_len[pad_len + 1]]) as usize;
+    // the initial payload is encrypted whatever the method selected
+    let mut initial_payload = handshaker.take(ia_len).await?;
+    decrypt.apply(&mut initial_payload);
+
+    let select = if provide & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if provide & CRYPTO_PLAINTEXT != 0 && policy != EncryptionPolicy::Forced {
+        CRYPTO_PLAINTEXT
+    } else {
+        return Err(Error::Encryption("no method in common"));
+    };
+    let mut reply = VC.to_vec();
+    reply.extend_from_slice(&select.to_be_bytes());
+    reply.extend_from_slice(&0u16.to_be_bytes());
+    encrypt.apply(&mut reply);
+    handshaker.stream.write_all(&reply).await?;
+
+    let cipher = if select == CRYPTO_RC4 {
+        Some(Ciphers { encrypt, decrypt })
+    } else {
+        None
+    };
+    let mut stream = CryptoStream {
+        inner: handshaker.stream,
+        read_buf: initial_payload,
+        cipher,
+        write_buf: vec![],
+    };
+    stream.push_received(handshaker.buf);
+    Ok((stream, info_hash))
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(PRIME, 16).expect("valid prime")
+}
+
+/// A private key and its public key.
+fn generate_keys() -> (BigUint, Vec<u8>) {
+    let private: [u8; PRIVATE_KEY_LEN] = rand::random();
+    let private = BigUint::from_bytes_be(&private);
+    let public = BigUint::from(2u32).modpow(&private, &prime());
+    (private, to_key_bytes(&public))
+}
+
+fn shared_secret(private: &BigUint, remote_public: &[u8]) -> Result<Vec<u8>, Error> {
+    let remote_public = BigUint::from_bytes_be(remote_public);
+    // keys of 0, 1 and p - 1 give away the secret
+    if remote_public <= BigUint::from(1u32) || remote_public >= prime() - 1u32 {
+        return Err(Error::Encryption("invalid public key"));
+    }
+    Ok(to_key_bytes(&remote_public.modpow(private, &prime())))
+}
+
+/// A number in big endian, padded to `KEY_LEN` bytes.
+fn to_key_bytes(n: &BigUint) -> Vec<u8> {
+    let bytes = n.to_bytes_be();
+    let mut key = vec![0u8; KEY_LEN - bytes.len()];
+    key.extend_from_slice(&bytes);
+    key
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    let mut hash = [0u8; 20];
+    hash.copy_from_slice(&hasher.finalize());
+    hash
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b).map(|(a, b)| a ^ b).collect()
+}
+
+/// The ciphers of one side, the side opening the connection encrypts with key A.
+fn ciphers(secret: &[u8], info_hash: &[u8; 20], initiator: bool) -> Ciphers {
+    let cipher = |name: &[u8]| {
+        let mut rc4 = Rc4::new(&hash(&[name, secret, info_hash]));
+        rc4.apply(&mut [0u8; RC4_DISCARD]);
+        rc4
+    };
+    let (a, b) = (cipher(b"keyA"), cipher(b"keyB"));
+    if initiator {
+        Ciphers {
+            encrypt: a,
+            decrypt: b,
+        }
+    } else {
+        Ciphers {
+            encrypt: b,
+            decrypt: a,
+        }
+    }
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_rc4() {
+    // the test vectors of the original RC4 description
+    let mut data = b"Plaintext".to_vec();
+    Rc4::new(b"Key").apply(&mut data);
+    assert_eq!(data, [0xbb, 0xf3, 0x16, 0xe8, 0xd9, 0x40, 0xaf, 0x0a, 0xd3]);
+    let mut data = b"Attack at dawn".to_vec();
+    Rc4::new(b"Secret").apply(&mut data);
+    assert_eq!(
+        data,
+        [0x45, 0xa0, 0x1f, 0x64, 0x5f, 0xc3, 0x5b, 0x38, 0x35, 0x52, 0x54, 0x4b, 0x9b, 0xf5]
+    );
+
+    assert_eq!(
+        "forced".parse::<EncryptionPolicy>().unwrap(),
+        EncryptionPolicy::Forced
+    );
+    assert!("always".parse::<EncryptionPolicy>().is_err());
+}
+
+#[tokio::test]
+async fn test_handshakes() {
+    use tokio::net::{TcpListener, TcpStream};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let accepting = async {
+        let mut results = vec![];
+        for policy in [EncryptionPolicy::Enabled, EncryptionPolicy::Forced] {
+            let (socket, _) = listener.accept().await.unwrap();
+            let accepted = accept(socket, vec![], &[[1; 20], [2; 20]], policy).await;
+            let (mut stream, info_hash) = accepted.unwrap();
+            let mut message = [0u8; 5];
+            stream.read_exact(&mut message).await.unwrap();
+            stream.write_all(b"world").await.unwrap();
+            stream.flush().await.unwrap();
+            results.push((stream.is_encrypted(), info_hash, message));
+        }
+        results
+    };
+    let initiating = async {
+        let mut encrypted = vec![];
+        for _ in 0..2 {
+            let socket = TcpStream::connect(addr).await.unwrap();
+            let policy = EncryptionPolicy::Enabled;
+            let mut stream = initiate(socket, &[2; 20], policy).await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            stream.flush().await.unwrap();
+            let mut message = [0u8; 5];
+            stream.read_exact(&mut message).await.unwrap();
+            assert_eq!(&message, b"world");
+            encrypted.push(stream.is_encrypted());
+        }
+        encrypted
+    };
+    let (accepted, initiated) = tokio::join!(accepting, initiating);
+    // RC4 is preferred whenever offered
+    assert_eq!(initiated, vec![true, true]);
+    for (encrypted, info_hash, message) in accepted {
+        assert!(encrypted);
+        assert_eq!(info_hash, [2; 20]);
+        assert_eq!(&message, b"hello");
+    }
+
+    // an unknown torrent is rejected
+    let accepting = async {
+        let (socket, _) = listener.accept().await.unwrap();
+        accept(socket, vec![], &[[1; 20]], EncryptionPolicy::Enabled).await
+    };
+    let initiating = async {
+        let socket = TcpStream::connect(addr).await.unwrap();
+        initiate(socket, &[2; 20], EncryptionPolicy::Enabled).await
+    };
+    let (accepted, initiated) = tokio::join!(accepting, initiating);
+    assert!(matches!(accepted, Err(Error::UnknownTorrent)));
+    assert!(initiated.is_err());
+}
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// Keep at most this many addresses waiting for a connection.
+const MAX_CANDIDATES: usize = 1000;
+
+/// Where the address of a peer was learned from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerSource {
+    Tracker,
+    Magnet,
+    Pex,
+    Dht,
+    Lsd,
+    Incoming,
+    /// Added by the user of the session.
+    Manual,
+}
+
+impl PeerSource {
+    /// Whether private torrents (BEP 27) may get peers from this source: their trackers,
+    /// the peers connecting to us and the ones added by the user.
+    pub fn is_allowed_for_private(self) -> bool {
+        matches!(
+            self,
+            PeerSource::Tracker | PeerSource::Incoming | PeerSource::Manual
+        )
+    }
+}
+
+/// A peer we are connected to.
+#[derive(Debug, Clone, Copy)]
+struct ConnectedPeer {
+    /// Where the peer accepts connections, unknown for incoming peers until they tell
+    /// their port: the port they connected from is not the one they listen on.
+    listen_addr: Option<SocketAddr>,
+    /// PEX flags of the peer.
+    flags: u8,
+}
+
+/// The peers of a single torrent: addresses we may connect to and the peers we
+/// are currently connected to.
+#[derive(Debug, Default)]
+pub struct PeerPool {
+    candidates: HashMap<SocketAddr, PeerSource>,
+    /// Connected peers, by the address of the connection.
+    connected: HashMap<SocketAddr, ConnectedPeer>,
+    /// Rejects the candidates from sources private torrents must not use.
+    private: bool,
+}
+
+impl PeerPool {
+    pub fn new() -> PeerPool {
+        PeerPool::default()
+    }
+
+    /// The pool of a torrent, only accepting the sources allowed for it if it is private.
+    pub fn for_torrent(private: bool) -> PeerPool {
+        PeerPool {
+            private,
+            ..PeerPool::default()
+        }
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private
+    }
+
+    /// Adds an address we may connect to, returns false if it is already known, the pool
+    /// is full or the source is not allowed for a private torrent.
+    pub fn add_candidate(&mut self, addr: SocketAddr, source: PeerSource) -> bool {
+        if (self.private && !source.is_allowed_for_private())
+            || self.connected.contains_key(&addr)
+            || self.candidates.contains_key(&addr)
+            || self.candidates.len() >= MAX_CANDIDATES
+            || addr.port() == 0
+        {
+            return false;
+        }
+        self.candidates.insert(addr, source);
+        true
+    }
+
+    /// Removes a candidate learned from `source`, e.g. a peer dropped by PEX. The ones
+    /// learned from other sources are kept.
+    pub fn remove_candidate(&mut self, addr: &SocketAddr, source: PeerSource) {
+        if self.candidates.get(addr) == Some(&source) {
+            self.candidates.remove(addr);
+        }
+    }
+
+    /// Takes an address out of the candidates so it can be connected to.
+    pub fn next_candidate(&mut self) -> Option<(SocketAddr, PeerSource)> {
+        let addr = *self.candidates.keys().next()?;
+        self.candidates.remove(&addr).map(|source| (addr, source))
+    }
+
+    pub fn num_candidates(&self) -> usize {
+        self.candidates.len()
+    }
+
+    /// Adds a connected peer, `outgoing` if we connected to `addr`, which is then where the
+    /// peer listens.
+    pub fn set_connected(&mut self, addr: SocketAddr, outgoing: bool, flags: u8) {
+        self.candidates.remove(&addr);
+        let listen_addr = Some(addr).filter(|_| outgoing);
+        self.connected
+            .insert(addr, ConnectedPeer { listen_addr, flags });
+    }
+
+    /// Sets the port a connected peer listens on, as told by its extension handshake.
+    pub fn set_listen_port(&mut self, addr: &SocketAddr, port: u16) {
+        if let Some(peer) = self.connected.get_mut(addr).filter(|_| port != 0) {
+            peer.listen_addr = Some(SocketAddr::new(addr.ip(), port));
+        }
+    }
+
+    pub fn set_disconnected(&mut self, addr: &SocketAddr) {
+        self.connected.remove(addr);
+    }
+
+    pub fn is_connected(&self, addr: &SocketAddr) -> bool {
+        self.connected.contains_key(addr)
+    }
+
+    pub fn num_connected(&self) -> usize {
+        self.connected.len()
+    }
+
+    /// The connected peers whose listen address is known: the address of the connection,
+    /// the listen address and the PEX flags.
+    pub fn listening(&self) -> impl Iterator<Item = (SocketAddr, SocketAddr, u8)> + '_ {
+        self.connected
+            .iter()
+            .filter_map(|(addr, peer)| Some((*addr, peer.listen_addr?, peer.flags)))
+    }
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_candidates_and_connected_peers() {
+    let a: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+    let b: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+
+    let mut pool = PeerPool::new();
+    assert!(pool.add_candidate(a, PeerSource::Tracker));
+    assert!(!pool.add_candidate(a, PeerSource::Pex));
+    assert!(!pool.add_candidate("10.0.0.3:0".parse().unwrap(), PeerSource::Pex));
+
+    pool.set_connected(b, true, 0);
+    assert!(!pool.add_candidate(b, PeerSource::Pex));
+
+    assert_eq!(pool.next_candidate(), Some((a, PeerSource::Tracker)));
+    assert_eq!(pool.next_candidate(), None);
+
+    pool.set_disconnected(&b);
+    assert_eq!(pool.num_connected(), 0);
+}
+
+#[test]
+fn test_listen_addresses() {
+    let outgoing: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+    let incoming: SocketAddr = "10.0.0.2:50123".parse().unwrap();
+
+    let mut pool = PeerPool::new();
+    pool.set_connected(outgoing, true, 0);
+    pool.set_connected(incoming, false, 0);
+    let listening: Vec<_> = pool.listening().collect();
+    assert_eq!(listening, vec![(outgoing, outgoing, 0)]);
+
+    // incoming peers are only reachable on the port they tell
+    pool.set_listen_port(&incoming, 0);
+    assert_eq!(pool.listening().count(), 1);
+    pool.set_listen_port(&incoming, 6881);
+    let mut listening: Vec<_> = pool.listening().collect();
+    listening.sort();
+    assert_eq!(
+        listening,
+        vec![
+            (outgoing, outgoing, 0),
+            (incoming, "10.0.0.2:6881".parse().unwrap(), 0)
+        ]
+    );
+}
+
+#[test]
+fn test_private_pool_sources() {
+    let mut pool = PeerPool::for_torrent(true);
+    for (i, source) in [
+        PeerSource::Dht,
+        PeerSource::Pex,
+        PeerSource::Lsd,
+        PeerSource::Magnet,
+    ]
+    .iter()
+    .enumerate()
+    {
+        let addr = SocketAddr::from(([10, 0, 0, i as u8 + 1], 6881));
+        assert!(!pool.add_candidate(addr, *source));
+    }
+    assert_eq!(pool.num_candidates(), 0);
+
+    assert!(pool.add_candidate("10.0.1.1:6881".parse().unwrap(), PeerSource::Tracker));
+    assert!(pool.add_candidate("10.0.1.2:6881".parse().unwrap(), PeerSource::Manual));
+    assert!(pool.add_candidate("10.0.1.3:6881".parse().unwrap(), PeerSource::Incoming));
+    assert_eq!(pool.num_candidates(), 3);
+
+    let mut public = PeerPool::for_torrent(false);
+    assert!(public.add_candidate("10.0.0.1:6881".parse().unwrap(), PeerSource::Dht));
+}
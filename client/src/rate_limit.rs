@@ -0,0 +1,223 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+/// A token bucket limiting the bytes per second going through the connections sharing it.
+///
+/// Bytes are reserved as soon as they are asked for, letting the bucket go into debt, and
+/// callers then wait for the debt to be paid back. Connections are thus served in the order
+/// they asked, so a fast peer cannot starve the others.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes per second, `None` when unlimited.
+    rate: Option<u64>,
+    /// Bytes that may pass right now, negative when in debt.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant, rate: u64) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        // a second worth of bytes may pass in a burst
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        self.last_refill = now;
+    }
+}
+
+impl RateLimiter {
+    /// Limits to `rate` bytes per second, `None` or 0 means unlimited.
+    pub fn new(rate: Option<u64>) -> RateLimiter {
+        let rate = rate.filter(|r| *r > 0);
+        RateLimiter {
+            bucket: Arc::new(Mutex::new(Bucket {
+                rate,
+                tokens: rate.unwrap_or(0) as f64,
+                last_refill: Instant::now(),
+            })),
+        }
+    }
+
+    pub fn unlimited() -> RateLimiter {
+        RateLimiter::new(None)
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket.lock().unwrap().rate
+    }
+
+    /// Changes the rate for the bytes asked for from now on, `None` or 0 means unlimited.
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let rate = rate.filter(|r| *r > 0);
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        match (bucket.rate, rate) {
+            (Some(old), Some(_)) => bucket.refill(now, old),
+            // the debt made under the old limit is forgiven
+            _ => {
+                bucket.tokens = rate.unwrap_or(0) as f64;
+                bucket.last_refill = now;
+            }
+        }
+        bucket.rate = rate;
+    }
+
+    /// Waits until `amount` bytes may pass.
+    pub async fn acquire(&self, amount: u64) {
+        acquire(&[self], amount).await
+    }
+
+    /// Takes `amount` bytes from the bucket, returns how long to wait for them.
+    fn reserve(&self, amount: u64) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let rate = match bucket.rate {
+            Some(rate) => rate,
+            None => return Duration::from_secs(0),
+        };
+        bucket.refill(Instant::now(), rate);
+        bucket.tokens -= amount as f64;
+        if bucket.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate as f64)
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> RateLimiter {
+        RateLimiter::unlimited()
+    }
+}
+
+/// Waits until `amount` bytes may pass every limiter of `limiters`, e.g. the limiter of a
+/// torrent and the one of the session.
+pub async fn acquire(limiters: &[&RateLimiter], amount: u64) {
+    let wait = limiters
+        .iter()
+        .map(|l| l.reserve(amount))
+        .max()
+        .unwrap_or_else(|| Duration::from_secs(0));
+    if wait > Duration::from_secs(0) {
+        sleep(wait).await;
+    }
+}
+
+/// The download and upload limits of a torrent or of a whole session.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    pub download: RateLimiter,
+    pub upload: RateLimiter,
+}
+
+impl RateLimits {
+    /// Limits in bytes per second, `None` or 0 means unlimited.
+    pub fn new(download: Option<u64>, upload: Option<u64>) -> RateLimits {
+        RateLimits {
+            download: RateLimiter::new(download),
+            upload: RateLimiter::new(upload),
+        }
+    }
+
+    pub fn set(&self, download: Option<u64>, upload: Option<u64>) {
+        self.download.set_rate(download);
+        self.upload.set_rate(upload);
+    }
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_token_bucket() {
+    tokio::time::pause();
+    let limiter = RateLimiter::new(Some(16 * 1024));
+
+    // a full bucket lets a second worth of bytes through at once
+    let start = Instant::now();
+    limiter.acquire(16 * 1024).await;
+    assert_eq!(start.elapsed(), Duration::from_secs(0));
+    for _ in 0..3 {
+        limiter.acquire(16 * 1024).await;
+    }
+    assert_eq!(start.elapsed().as_secs(), 3);
+
+    // idle time refills the bucket, up to a second worth of bytes
+    tokio::time::advance(Duration::from_secs(10)).await;
+    let start = Instant::now();
+    limiter.acquire(16 * 1024).await;
+    assert_eq!(start.elapsed(), Duration::from_secs(0));
+    limiter.acquire(8 * 1024).await;
+    assert_eq!((start.elapsed().as_secs_f64() * 10.0).round(), 5.0);
+}
+
+#[tokio::test]
+async fn test_rate_changes_at_runtime() {
+    tokio::time::pause();
+    let limiter = RateLimiter::new(Some(1000));
+    limiter.acquire(1000).await;
+
+    limiter.set_rate(Some(4000));
+    let start = Instant::now();
+    limiter.acquire(2000).await;
+    assert_eq!((start.elapsed().as_secs_f64() * 10.0).round(), 5.0);
+
+    limiter.set_rate(None);
+    assert_eq!(limiter.rate(), None);
+    let start = Instant::now();
+    limiter.acquire(1 << 30).await;
+    assert_eq!(start.elapsed(), Duration::from_secs(0));
+
+    // the session limit applies on top of the torrent limit
+    let session = RateLimiter::new(Some(1000));
+    let torrent = RateLimiter::new(Some(2000));
+    session.acquire(1000).await;
+    torrent.acquire(2000).await;
+    let start = Instant::now();
+    acquire(&[&session, &torrent], 1000).await;
+    assert_eq!(start.elapsed().as_secs(), 1);
+}
+
+#[tokio::test]
+async fn test_peers_share_the_rate_fairly() {
+    tokio::time::pause();
+    let limiter = RateLimiter::new(Some(16 * 1024));
+    limiter.acquire(16 * 1024).await;
+
+    // peers asking for blocks as fast as they can get the same share
+    let received = Arc::new(Mutex::new(vec![0u64; 3]));
+    let mut tasks = vec![];
+    for peer in 0..3 {
+        let limiter = limiter.clone();
+        let received = received.clone();
+        tasks.push(tokio::spawn(async move {
+            loop {
+                limiter.acquire(4 * 1024).await;
+                received.lock().unwrap()[peer] += 4 * 1024;
+            }
+        }));
+    }
+
+    tokio::time::sleep(Duration::from_secs(30)).await;
+    for task in tasks.iter() {
+        task.abort();
+    }
+    let received = received.lock().unwrap();
+    let total: u64 = received.iter().sum();
+    assert!(
+        total.abs_diff(30 * 16 * 1024) <= 3 * 4 * 1024,
+        "{:?}",
+        received
+    );
+    for bytes in received.iter() {
+        assert!(bytes.abs_diff(total / 3) <= 4 * 1024, "{:?}", received);
+    }
+}
@@ -0,0 +1,594 @@
+use crate::config::Config;
+use crate::dht::{Dht, DhtConfig};
+use crate::error::Error;
+use crate::event::{Event, EventBus, EventHandler};
+use crate::lsd::{self, Lsd};
+use crate::magnet::MagnetLink;
+use crate::model::MetaInfo;
+use crate::peer::listener::{Listener, TorrentRegistry};
+use crate::rate_limit::RateLimits;
+use crate::torrent::announcer::announce_udp;
+use crate::torrent::picker::Priority;
+use crate::torrent::{
+    self, TorrentCommand, TorrentEnv, TorrentHandle, TorrentStatus, SHUTDOWN_TIMEOUT,
+};
+use crate::tracker::{AnnounceEvent, AnnounceParams};
+use crate::utp::UtpSocket;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Weak};
+use tokio::sync::{broadcast, oneshot, Semaphore};
+use tokio::task::JoinHandle;
+
+/// Sent to trackers as the bytes left of a magnet link until its metadata is known, not
+/// zero so that the trackers do not take us for a seed.
+const UNKNOWN_LEFT: u64 = i64::MAX as u64;
+
+/// Runs several torrents sharing a peer id, a listening port, the DHT, local service
+/// discovery and a limit on the number of connections. Each torrent runs in its own task,
+/// controlled through a channel.
+pub struct Session {
+    config: Config,
+    peer_id: [u8; 20],
+    port: u16,
+    registry: TorrentRegistry,
+    dht: Option<Arc<Dht>>,
+    lsd: Option<Arc<Lsd>>,
+    utp: Option<Arc<UtpSocket>>,
+    peer_permits: Arc<Semaphore>,
+    limits: RateLimits,
+    bus: EventBus,
+    torrents: HashMap<[u8; 20], TorrentHandle>,
+    listener: JoinHandle<()>,
+    dht_refresh: Option<JoinHandle<()>>,
+}
+
+impl Session {
+    /// Starts listening for peers, over uTP as well when enabled, and joins the DHT and the
+    /// LSD groups when enabled. The DHT shares the UDP port of uTP. A DHT failing to
+    /// bootstrap, or a UDP or LSD port taken by another client, is not fatal.
+    pub async fn new(config: Config) -> Result<Session, Error> {
+        let peer_id = crate::peer::id::generate();
+
+        let registry = TorrentRegistry::new();
+        let listener = Listener::bind(
+            config.listen_ports.clone(),
+            peer_id,
+            registry.clone(),
+            config.peer.handshake_timeout,
+            config.peer.encryption,
+        )
+        .await?;
+        let port = listener.port();
+        let udp_addr = SocketAddr::from(([0, 0, 0, 0], port));
+
+        let (utp, dht_packets) = if config.utp {
+            let bound = if config.dht {
+                UtpSocket::bind_shared(udp_addr)
+                    .await
+                    .map(|(utp, packets)| (utp, Some(packets)))
+            } else {
+                UtpSocket::bind(udp_addr).await.map(|utp| (utp, None))
+            };
+            match bound {
+                Ok((utp, packets)) => (Some(Arc::new(utp)), packets),
+                Err(e) => {
+                    warn!("utp disabled: {}", e);
+                    (None, None)
+                }
+            }
+        } else {
+            (None, None)
+        };
+        let listener = match utp.as_ref() {
+            Some(utp) => listener.with_utp(utp.clone()),
+            None => listener,
+        };
+
+        let dht = if config.dht {
+            let dht_config = DhtConfig {
+                bind_addr: udp_addr,
+                bootstrap_nodes: config.dht_bootstrap_nodes.clone(),
+                state_path: config.dht_state.clone(),
+            };
+            let dht = match (utp.as_ref(), dht_packets) {
+                (Some(utp), Some(packets)) => {
+                    Dht::with_socket(dht_config, utp.udp_socket(), packets)?
+                }
+                _ => Dht::bind(dht_config).await?,
+            };
+            if let Err(e) = dht.bootstrap().await {
+                warn!("failed to bootstrap the dht: {}", e);
+            }
+            Some(Arc::new(dht))
+        } else {
+            None
+        };
+
+        let lsd = if config.lsd {
+            match Lsd::bind(port, &lsd::default_groups()).await {
+                Ok(lsd) => Some(Arc::new(lsd)),
+                Err(e) => {
+                    warn!("local service discovery disabled: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let dht_refresh = dht
+            .as_ref()
+            .map(|dht| tokio::spawn(refresh_dht(Arc::downgrade(dht))));
+
+        Ok(Session {
+            peer_permits: Arc::new(Semaphore::new(config.max_peers)),
+            limits: RateLimits::new(config.download_rate, config.upload_rate),
+            bus: EventBus::new(),
+            config,
+            peer_id,
+            port,
+            registry,
+            dht,
+            lsd,
+            utp,
+            torrents: HashMap::new(),
+            listener: tokio::spawn(listener.run()),
+            dht_refresh,
+        })
+    }
+
+    pub fn peer_id(&self) -> &[u8; 20] {
+        &self.peer_id
+    }
+
+    /// The port peers can reach us on.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Adds a torrent and starts it once its files are checked. `info_hash` is given since
+    /// the info dict of a magnet link must match the link, not its own encoding.
+    pub fn add_torrent(&mut self, meta_info: MetaInfo, info_hash: [u8; 20]) -> Result<(), Error> {
+        if self.torrents.contains_key(&info_hash) {
+            return Err(Error::DuplicateTorrent);
+        }
+        // the files must not be written outside the download directory
+        meta_info.info.check_paths()?;
+        info!("adding torrent {}", meta_info.info.name);
+
+        let incoming = self.registry.register(info_hash);
+        let env = TorrentEnv {
+            peer_id: self.peer_id,
+            port: self.port,
+            download_dir: self.config.download_dir.clone(),
+            max_peers: self.config.max_peers_per_torrent,
+            upload_slots: self.config.upload_slots,
+            peer: self.config.peer.clone(),
+            tracker: self.config.tracker.clone(),
+            peer_permits: self.peer_permits.clone(),
+            limits: self.limits.clone(),
+            bus: self.bus.clone(),
+            dht: self.dht.clone(),
+            lsd: self.lsd.clone(),
+            utp: self.utp.clone(),
+        };
+        let name = meta_info.info.name.clone();
+        let handle = torrent::spawn(meta_info, info_hash, incoming, env);
+        self.torrents.insert(info_hash, handle);
+        self.bus.emit(Event::TorrentAdded { info_hash, name });
+        Ok(())
+    }
+
+    /// Downloads the info dict of a magnet link from the peers of its trackers, of the link
+    /// itself and of the DHT, then adds the torrent, with only the peers of its trackers if
+    /// it is private. Returns its info hash.
+    pub async fn add_magnet(&mut self, magnet: MagnetLink) -> Result<[u8; 20], Error> {
+        let info_hash = magnet.info_hash;
+        if self.torrents.contains_key(&info_hash) {
+            return Err(Error::DuplicateTorrent);
+        }
+
+        // peers of the link and of the DHT must not be kept if the torrent is private
+        let mut other_peers = vec![];
+        for peer in magnet.peers.iter() {
+            match tokio::net::lookup_host(peer.as_str()).await {
+                Ok(addrs) => other_peers.extend(addrs),
+                Err(e) => warn!("failed to resolve peer {}: {}", peer, e),
+            }
+        }
+        let params = AnnounceParams {
+            peer_id: self.peer_id,
+            downloaded: 0,
+            left: UNKNOWN_LEFT,
+            uploaded: 0,
+            event: AnnounceEvent::Started,
+        };
+        let mut peers = vec![];
+        for tracker in magnet.trackers.iter() {
+            match announce_udp(
+                tracker,
+                &info_hash,
+                self.port,
+                &params,
+                &self.config.tracker,
+            )
+            .await
+            {
+                Ok((addrs, _)) => {
+                    self.bus.emit(Event::Announced {
+                        info_hash,
+                        tracker: tracker.clone(),
+                        peers: addrs.len(),
+                    });
+                    peers.extend(addrs);
+                }
+                Err(e) => self.bus.emit(Event::AnnounceFailed {
+                    info_hash,
+                    tracker: tracker.clone(),
+                    error: e.to_string(),
+                }),
+            }
+        }
+        if let Some(dht) = self.dht.as_ref() {
+            other_peers.extend(dht.get_peers(info_hash).await);
+        }
+
+        let all_peers = peers.iter().chain(other_peers.iter()).copied();
+        let (info, raw_info) =
+            crate::extension::metadata::download_metadata(all_peers, info_hash).await?;
+        self.bus.emit(Event::MetadataReceived {
+            info_hash,
+            name: info.name.clone(),
+        });
+        if !info.is_private() {
+            peers.extend(other_peers);
+        }
+        self.add_torrent(magnet.into_meta_info(info, raw_info), info_hash)?;
+        self.add_peers(&info_hash, peers)?;
+        Ok(info_hash)
+    }
+
+    /// Stops a torrent, announcing it stopped, and keeps its files.
+    pub async fn remove_torrent(&mut self, info_hash: &[u8; 20]) -> Result<(), Error> {
+        let handle = self
+            .torrents
+            .remove(info_hash)
+            .ok_or(Error::UnknownTorrent)?;
+        self.registry.unregister(info_hash);
+        stop_torrent(handle).await;
+        self.bus.emit(Event::TorrentRemoved {
+            info_hash: *info_hash,
+        });
+        Ok(())
+    }
+
+    /// Disconnects the peers of a torrent until it is resumed.
+    pub fn pause(&self, info_hash: &[u8; 20]) -> Result<(), Error> {
+        self.send(info_hash, TorrentCommand::Pause)
+    }
+
+    pub fn resume(&self, info_hash: &[u8; 20]) -> Result<(), Error> {
+        self.send(info_hash, TorrentCommand::Resume)
+    }
+
+    /// A receiver of the events of the session from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.bus.subscribe()
+    }
+
+    /// Calls `handler` with every event of the session from now on.
+    pub fn add_handler(&self, handler: Arc<dyn EventHandler>) {
+        self.bus.add_handler(handler);
+    }
+
+    /// Changes the limits of all torrents together, in bytes per second, `None` means
+    /// unlimited.
+    pub fn set_rate_limits(&self, download: Option<u64>, upload: Option<u64>) {
+        self.limits.set(download, upload);
+    }
+
+    /// Changes the limits of a single torrent, applied on top of the session limits.
+    pub fn set_torrent_rate_limits(
+        &self,
+        info_hash: &[u8; 20],
+        download: Option<u64>,
+        upload: Option<u64>,
+    ) -> Result<(), Error> {
+        let torrent = self.torrents.get(info_hash).ok_or(Error::UnknownTorrent)?;
+        torrent.limits.set(download, upload);
+        Ok(())
+    }
+
+    /// Adds peers a torrent may connect to, e.g. known from elsewhere than its trackers.
+    pub fn add_peers(&self, info_hash: &[u8; 20], peers: Vec<SocketAddr>) -> Result<(), Error> {
+        self.send(info_hash, TorrentCommand::AddPeers(peers))
+    }
+
+    pub async fn status(&self, info_hash: &[u8; 20]) -> Result<TorrentStatus, Error> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(info_hash, TorrentCommand::Status(sender))?;
+        receiver.await.map_err(|_| Error::UnknownTorrent)
+    }
+
+    /// Sets the priority of each file of a torrent, in the order of its info dict. Pieces
+    /// shared with wanted files are still downloaded, the parts of skipped files they hold
+    /// are kept aside until the files are wanted.
+    pub async fn set_file_priorities(
+        &self,
+        info_hash: &[u8; 20],
+        priorities: Vec<Priority>,
+    ) -> Result<(), Error> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(
+            info_hash,
+            TorrentCommand::SetFilePriorities(priorities, sender),
+        )?;
+        receiver.await.map_err(|_| Error::UnknownTorrent)?
+    }
+
+    /// The info hashes of the torrents of the session.
+    pub fn torrents(&self) -> impl Iterator<Item = &[u8; 20]> + '_ {
+        self.torrents.keys()
+    }
+
+    /// Stops accepting peers, then stops every torrent at once and saves the DHT routing
+    /// table. Returns once all the tasks of the session ended.
+    pub async fn shutdown(mut self) {
+        info!("shutting down");
+        self.listener.abort();
+        let _ = (&mut self.listener).await;
+
+        let torrents: Vec<([u8; 20], TorrentHandle)> = self.torrents.drain().collect();
+        for (info_hash, _) in torrents.iter() {
+            self.registry.unregister(info_hash);
+        }
+        futures_util::future::join_all(torrents.into_iter().map(|(_, t)| stop_torrent(t))).await;
+
+        // the announcers released the dht, dropping it saves its routing table
+        if let Some(refresh) = self.dht_refresh.take() {
+            refresh.abort();
+            let _ = refresh.await;
+        }
+        drop(self.dht.take());
+    }
+
+    fn send(&self, info_hash: &[u8; 20], command: TorrentCommand) -> Result<(), Error> {
+        self.torrents
+            .get(info_hash)
+            .ok_or(Error::UnknownTorrent)?
+            .commands
+            .send(command)
+            .map_err(|_| Error::UnknownTorrent)
+    }
+}
+
+/// Refreshes the routing table of the DHT every `REFRESH_INTERVAL`, for as long as the
+/// session keeps the DHT.
+async fn refresh_dht(dht: Weak<Dht>) {
+    let mut tick = tokio::time::interval(crate::dht::REFRESH_INTERVAL);
+    // the first tick completes right away, the DHT was just bootstrapped
+    tick.tick().await;
+    loop {
+        tick.tick().await;
+        match dht.upgrade() {
+            Some(dht) => dht.refresh().await,
+            None => return,
+        }
+    }
+}
+
+/// Asks a torrent to stop and waits for its task. The torrent waits up to
+/// `SHUTDOWN_TIMEOUT` for its peers and trackers, as long again is left to flush its files,
+/// then the task is aborted.
+async fn stop_torrent(mut handle: TorrentHandle) {
+    let (done, stopped) = oneshot::channel();
+    let stop = async {
+        if handle.commands.send(TorrentCommand::Shutdown(done)).is_ok() {
+            let _ = stopped.await;
+        }
+        let _ = (&mut handle.task).await;
+    };
+    if tokio::time::timeout(2 * SHUTDOWN_TIMEOUT, stop)
+        .await
+        .is_err()
+    {
+        warn!("a torrent did not stop in time");
+        handle.task.abort();
+    }
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_download_between_sessions() {
+    use crate::create::TorrentBuilder;
+    use crate::torrent::TorrentState;
+
+    let seed_dir = std::env::temp_dir().join(format!("thor-session-{}", rand::random::<u32>()));
+    let leech_dir = seed_dir.with_extension("leech");
+    std::fs::create_dir_all(seed_dir.join("data/sub")).unwrap();
+    let first: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    let second: Vec<u8> = (0..70_000u32).map(|i| (i % 13) as u8).collect();
+    std::fs::write(seed_dir.join("data/first"), &first).unwrap();
+    std::fs::write(seed_dir.join("data/sub/second"), &second).unwrap();
+    let meta_info = TorrentBuilder::new(seed_dir.join("data"))
+        .piece_length(32 * 1024)
+        .build()
+        .unwrap();
+    let info_hash = meta_info.info.info_hash();
+
+    let mut seeder = local_session(&seed_dir).await;
+    seeder.add_torrent(meta_info.clone(), info_hash).unwrap();
+    let mut leecher = local_session(&leech_dir).await;
+    let mut events = leecher.subscribe();
+    leecher.add_torrent(meta_info.clone(), info_hash).unwrap();
+    assert!(leecher.add_torrent(meta_info, info_hash).is_err());
+    let seeder_addr = SocketAddr::from(([127, 0, 0, 1], seeder.port()));
+    leecher.add_peers(&info_hash, vec![seeder_addr]).unwrap();
+
+    let status = wait_for(&leecher, &info_hash, TorrentState::Seeding).await;
+    assert_eq!(status.pieces, status.num_pieces);
+    assert_eq!(status.downloaded, 170_000);
+    assert_eq!(status.clients.get("thor"), Some(&1));
+    let mut received = vec![];
+    while let Ok(event) = events.try_recv() {
+        received.push(event);
+    }
+    assert_eq!(
+        received[0],
+        Event::TorrentAdded {
+            info_hash,
+            name: "data".to_owned()
+        }
+    );
+    assert!(received.contains(&Event::PeerConnected {
+        info_hash,
+        addr: seeder_addr
+    }));
+    let verified = received
+        .iter()
+        .filter(|e| matches!(e, Event::PieceVerified { .. }))
+        .count();
+    assert_eq!(verified, status.num_pieces);
+    assert!(received.contains(&Event::TorrentCompleted { info_hash }));
+    assert_eq!(std::fs::read(leech_dir.join("data/first")).unwrap(), first);
+    assert_eq!(
+        std::fs::read(leech_dir.join("data/sub/second")).unwrap(),
+        second
+    );
+    assert_eq!(seeder.status(&info_hash).await.unwrap().uploaded, 170_000);
+
+    leecher.pause(&info_hash).unwrap();
+    wait_for(&leecher, &info_hash, TorrentState::Paused).await;
+    leecher.resume(&info_hash).unwrap();
+    wait_for(&leecher, &info_hash, TorrentState::Seeding).await;
+
+    leecher.remove_torrent(&info_hash).await.unwrap();
+    assert!(leecher.status(&info_hash).await.is_err());
+    assert_eq!(leecher.torrents().count(), 0);
+
+    seeder.shutdown().await;
+    leecher.shutdown().await;
+    std::fs::remove_dir_all(&seed_dir).unwrap();
+    std::fs::remove_dir_all(&leech_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_download_selected_files() {
+    use crate::create::TorrentBuilder;
+    use crate::torrent::TorrentState;
+
+    let seed_dir = std::env::temp_dir().join(format!("thor-select-{}", rand::random::<u32>()));
+    let leech_dir = seed_dir.with_extension("leech");
+    std::fs::create_dir_all(seed_dir.join("data")).unwrap();
+    let first: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    let second: Vec<u8> = (0..70_000u32).map(|i| (i % 13) as u8).collect();
+    std::fs::write(seed_dir.join("data/first"), &first).unwrap();
+    std::fs::write(seed_dir.join("data/second"), &second).unwrap();
+    let meta_info = TorrentBuilder::new(seed_dir.join("data"))
+        .piece_length(32 * 1024)
+        .build()
+        .unwrap();
+    let info_hash = meta_info.info.info_hash();
+
+    let mut seeder = local_session(&seed_dir).await;
+    seeder.add_torrent(meta_info.clone(), info_hash).unwrap();
+    let mut leecher = local_session(&leech_dir).await;
+    leecher.add_torrent(meta_info, info_hash).unwrap();
+    let priorities = vec![Priority::High, Priority::Skip];
+    leecher
+        .set_file_priorities(&info_hash, priorities)
+        .await
+        .unwrap();
+    let seeder_addr = SocketAddr::from(([127, 0, 0, 1], seeder.port()));
+    leecher.add_peers(&info_hash, vec![seeder_addr]).unwrap();
+
+    // the 4th piece is shared by both files
+    let status = wait_for(&leecher, &info_hash, TorrentState::Seeding).await;
+    assert_eq!(status.pieces, 4);
+    assert_eq!(status.files[0].downloaded, 100_000);
+    assert_eq!(status.files[1].downloaded, 4 * 32 * 1024 - 100_000);
+    assert_eq!(status.files[1].priority, Priority::Skip);
+    assert_eq!(std::fs::read(leech_dir.join("data/first")).unwrap(), first);
+    assert!(!leech_dir.join("data/second").exists());
+
+    leecher
+        .set_file_priorities(&info_hash, vec![Priority::Normal; 2])
+        .await
+        .unwrap();
+    let status = wait_for(&leecher, &info_hash, TorrentState::Seeding).await;
+    assert_eq!(status.pieces, status.num_pieces);
+    assert_eq!(status.files[1].downloaded, 70_000);
+    assert_eq!(
+        std::fs::read(leech_dir.join("data/second")).unwrap(),
+        second
+    );
+
+    seeder.shutdown().await;
+    leecher.shutdown().await;
+    std::fs::remove_dir_all(&seed_dir).unwrap();
+    std::fs::remove_dir_all(&leech_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_shutdown_with_unresponsive_tracker() {
+    use crate::create::TorrentBuilder;
+
+    let dir = std::env::temp_dir().join(format!("thor-shutdown-{}", rand::random::<u32>()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("file"), vec![7u8; 50_000]).unwrap();
+    // a tracker that never answers
+    let tracker = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let meta_info = TorrentBuilder::new(dir.join("file"))
+        .tier(vec![format!("udp://{}", tracker.local_addr().unwrap())])
+        .build()
+        .unwrap();
+    let info_hash = meta_info.info.info_hash();
+
+    let mut session = local_session(&dir).await;
+    session.add_torrent(meta_info, info_hash).unwrap();
+    wait_for(&session, &info_hash, crate::torrent::TorrentState::Seeding).await;
+
+    let started = std::time::Instant::now();
+    session.shutdown().await;
+    assert!(started.elapsed() < SHUTDOWN_TIMEOUT + std::time::Duration::from_secs(1));
+
+    let mut buf = [0u8; 1024];
+    assert!(tracker.recv(&mut buf).await.is_ok());
+    // the pieces are not checked again on the next start
+    assert!(dir.join(".file.resume").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(test)]
+async fn local_session(dir: &std::path::Path) -> Session {
+    Session::new(Config {
+        download_dir: dir.to_owned(),
+        listen_ports: 0..=0,
+        dht: false,
+        lsd: false,
+        ..Default::default()
+    })
+    .await
+    .unwrap()
+}
+
+#[cfg(test)]
+async fn wait_for(
+    session: &Session,
+    info_hash: &[u8; 20],
+    state: crate::torrent::TorrentState,
+) -> TorrentStatus {
+    for _ in 0..200 {
+        let status = session.status(info_hash).await.unwrap();
+        if status.state == state {
+            return status;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    panic!("torrent did not reach {:?}", state);
+}
@@ -0,0 +1,847 @@
+use crate::bitfield::Bitfield;
+use crate::error::Error;
+use crate::merkle::PieceVerifier;
+use crate::model::MetaInfo;
+use crate::torrent::picker::Priority;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha1::Digest;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::time::UNIX_EPOCH;
+
+/// Largest block a peer may request, larger requests are refused.
+pub const MAX_BLOCK_LEN: u32 = 128 * 1024;
+
+#[derive(Debug, Clone)]
+struct StorageFile {
+    path: PathBuf,
+    /// Position of the file in the concatenation of all files of the torrent, padding
+    /// files included.
+    offset: u64,
+    length: u64,
+    executable: bool,
+    /// Target of a symlink, relative to the symlink.
+    symlink: Option<PathBuf>,
+}
+
+/// The size and modification time of a file, to tell whether it changed.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct FileState {
+    length: u64,
+    /// Nanoseconds since the epoch, 0 for a missing file.
+    modified: u64,
+}
+
+/// The pieces we had when the torrent stopped, trusted on the next start instead of
+/// checking the files again as long as none of them changed since.
+#[derive(Debug, Serialize, Deserialize)]
+struct ResumeData {
+    #[serde(with = "serde_bytes")]
+    pieces: Vec<u8>,
+    files: Vec<FileState>,
+}
+
+/// Maps the pieces of a torrent to its files on disk.
+///
+/// The files of v2 torrents start on a piece boundary, and padding files (BEP 47) align
+/// the files of hybrid torrents: these gaps between files read as zeros and are not
+/// written.
+///
+/// Skipped files are not created: the parts of their pieces shared with wanted files are
+/// kept in a parts file next to the torrent's files instead, holding one slot per piece
+/// spanning several files. The parts move to the file once it is wanted again.
+#[derive(Debug)]
+pub struct Storage {
+    files: Vec<StorageFile>,
+    piece_length: u64,
+    total_length: u64,
+    num_pieces: usize,
+    /// SHA-1 of each piece, empty for v2 only torrents.
+    piece_hashes: Vec<[u8; 20]>,
+    /// Hybrid torrents are checked against both hashes, unless their piece layers are
+    /// unknown.
+    v2: Option<PieceVerifier>,
+    priorities: RwLock<Vec<Priority>>,
+    /// The pieces spanning several files, sorted, by slot in the parts file.
+    shared_pieces: Vec<u32>,
+    parts_path: PathBuf,
+    resume_path: PathBuf,
+    /// Makes a running check stop early.
+    cancelled: AtomicBool,
+}
+
+impl Storage {
+    /// Lays out the files of the torrent under `dir`: a single file torrent is stored as
+    /// `dir/name`, a multi file torrent in the directory `dir/name`.
+    pub fn new(meta_info: &MetaInfo, dir: &Path) -> Storage {
+        let info = &meta_info.info;
+        let single_file = info.is_single_file();
+        let files: Vec<StorageFile> = info
+            .file_list()
+            .into_iter()
+            .map(|f| {
+                let mut path = dir.to_path_buf();
+                if !single_file {
+                    path.push(&info.name);
+                }
+                path.extend(f.path.iter());
+                // the symlink is in the directory of its path, its target relative to the
+                // torrent's directory
+                let symlink = f.symlink_path.as_ref().map(|target| {
+                    let mut relative = PathBuf::new();
+                    for _ in 1..f.path.len() {
+                        relative.push("..");
+                    }
+                    relative.extend(target.iter());
+                    relative
+                });
+                StorageFile {
+                    path,
+                    offset: f.offset,
+                    length: f.length,
+                    executable: f.executable,
+                    symlink,
+                }
+            })
+            .collect();
+        let offset = info.pieces_length();
+        let v2 = if info.is_v2() && (!info.is_v1() || meta_info.piece_layers.is_some()) {
+            Some(PieceVerifier::new(meta_info))
+        } else {
+            None
+        };
+
+        let mut shared_pieces: Vec<u32> = files
+            .iter()
+            .skip(1)
+            .filter(|f| f.offset % info.piece_length != 0 && f.offset < offset)
+            .map(|f| (f.offset / info.piece_length) as u32)
+            .collect();
+        shared_pieces.dedup();
+
+        Storage {
+            priorities: RwLock::new(vec![Priority::Normal; files.len()]),
+            files,
+            shared_pieces,
+            parts_path: dir.join(format!(".{}.parts", info.name)),
+            resume_path: dir.join(format!(".{}.resume", info.name)),
+            cancelled: AtomicBool::new(false),
+            piece_length: info.piece_length,
+            total_length: offset,
+            num_pieces: info.num_pieces(),
+            v2,
+            piece_hashes: info
+                .pieces
+                .chunks_exact(20)
+                .map(|c| {
+                    let mut hash = [0u8; 20];
+                    hash.copy_from_slice(c);
+                    hash
+                })
+                .collect(),
+        }
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.num_pieces
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.total_length
+    }
+
+    pub fn piece_length(&self, index: u32) -> u64 {
+        let start = index as u64 * self.piece_length;
+        std::cmp::min(self.piece_length, self.total_length.saturating_sub(start))
+    }
+
+    pub fn num_files(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn file_priorities(&self) -> Vec<Priority> {
+        self.priorities.read().unwrap().clone()
+    }
+
+    /// Sets the priority of each file, moving the parts of the files no longer skipped
+    /// from the parts file to the files.
+    pub fn set_file_priorities(&self, priorities: &[Priority]) -> Result<(), Error> {
+        if priorities.len() != self.files.len() {
+            return Err(Error::FileCount {
+                expected: self.files.len(),
+                got: priorities.len(),
+            });
+        }
+        let mut current = self.priorities.write().unwrap();
+        for (index, f) in self.files.iter().enumerate() {
+            if current[index] == Priority::Skip
+                && priorities[index] != Priority::Skip
+                && !f.path.exists()
+            {
+                self.restore_parts(f)?;
+            }
+        }
+        current.copy_from_slice(priorities);
+        Ok(())
+    }
+
+    /// The priority of each piece: the highest priority of the files it spans.
+    pub fn piece_priorities(&self) -> Vec<Priority> {
+        let priorities = self.priorities.read().unwrap();
+        let mut pieces = vec![Priority::Skip; self.num_pieces()];
+        for (f, priority) in self.files.iter().zip(priorities.iter()) {
+            for index in self.pieces_of(f) {
+                pieces[index] = std::cmp::max(pieces[index], *priority);
+            }
+        }
+        pieces
+    }
+
+    /// Bytes of each file within the pieces of `have`.
+    pub fn file_progress(&self, have: &Bitfield) -> Vec<u64> {
+        self.files
+            .iter()
+            .map(|f| {
+                self.pieces_of(f)
+                    .filter(|index| have.get(*index))
+                    .map(|index| {
+                        let start = index as u64 * self.piece_length;
+                        let end = start + self.piece_length(index as u32);
+                        std::cmp::min(end, f.offset + f.length) - std::cmp::max(start, f.offset)
+                    })
+                    .sum()
+            })
+            .collect()
+    }
+
+    /// Reads a block, as requested by a peer.
+    pub fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>, Error> {
+        let start = self.block_start(index, begin, length)?;
+        let mut buf = vec![0u8; length as usize];
+        self.read_range(start, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Writes a block received from a peer, creating the files it spans if needed.
+    pub fn write_block(&self, index: u32, begin: u32, block: &[u8]) -> Result<(), Error> {
+        let start = self.block_start(index, begin, block.len() as u32)?;
+        let end = start + block.len() as u64;
+
+        let priorities = self.priorities.read().unwrap();
+        for (index, f) in self.files_in(start, end) {
+            let from = std::cmp::max(start, f.offset);
+            let to = std::cmp::min(end, f.offset + f.length);
+
+            let (path, position) = self.location(f, priorities[index], from);
+            write_at(
+                path,
+                position,
+                &block[(from - start) as usize..(to - start) as usize],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Makes sure the blocks written so far reach the disk.
+    pub fn flush(&self) -> Result<(), Error> {
+        let paths = self.files.iter().map(|f| &f.path);
+        for path in paths.chain(std::iter::once(&self.parts_path)) {
+            match OpenOptions::new().write(true).open(path) {
+                Ok(file) => file.sync_all()?,
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the data of a piece against its hash, missing data fails the check.
+    pub fn verify_piece(&self, index: u32) -> Result<bool, Error> {
+        if index as usize >= self.num_pieces {
+            return Err(Error::InvalidBlock);
+        }
+        let mut buf = vec![0u8; self.piece_length(index) as usize];
+        match self.read_range(index as u64 * self.piece_length, &mut buf) {
+            Ok(()) => {}
+            Err(Error::Tokio(e))
+                if e.kind() == ErrorKind::NotFound || e.kind() == ErrorKind::UnexpectedEof =>
+            {
+                return Ok(false)
+            }
+            Err(e) => return Err(e),
+        }
+
+        if let Some(expected) = self.piece_hashes.get(index as usize) {
+            if sha1::Sha1::digest(&buf)[..] != expected[..] {
+                return Ok(false);
+            }
+        }
+        Ok(self.v2.as_ref().is_none_or(|v2| v2.verify(index, &buf)))
+    }
+
+    /// Applies the attributes of the files whose pieces are all in `have`: makes the
+    /// executable files executable and creates the symlinks. `piece` limits it to the
+    /// files of a piece just verified. Skipped files are left alone.
+    pub fn finish_files(&self, have: &Bitfield, piece: Option<u32>) -> Result<(), Error> {
+        let priorities = self.priorities.read().unwrap();
+        for (index, f) in self.files.iter().enumerate() {
+            let in_piece = match piece {
+                Some(piece) => self.pieces_of(f).any(|i| i == piece as usize),
+                None => true,
+            };
+            if !in_piece
+                || priorities[index] == Priority::Skip
+                || !self.pieces_of(f).all(|i| have.get(i))
+            {
+                continue;
+            }
+            if let Some(target) = f.symlink.as_ref() {
+                create_symlink(target, &f.path)?;
+            } else if f.executable && f.path.exists() {
+                set_executable(&f.path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Verifies every piece already on disk, returning the pieces we have. Fails with
+    /// `Error::Cancelled` once `cancel_check` is called.
+    pub fn check(&self) -> Result<Bitfield, Error> {
+        let verified = (0..self.num_pieces() as u32)
+            .into_par_iter()
+            .map(|index| {
+                if self.cancelled.load(Ordering::Relaxed) {
+                    return Err(Error::Cancelled);
+                }
+                self.verify_piece(index)
+            })
+            .collect::<Result<Vec<bool>, Error>>()?;
+
+        let mut have = Bitfield::new(self.num_pieces());
+        for (index, ok) in verified.into_iter().enumerate() {
+            have.set(index, ok);
+        }
+        Ok(have)
+    }
+
+    /// Stops the running check and the ones started later.
+    pub fn cancel_check(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Saves the pieces we have, call it once the torrent is stopped and flushed.
+    pub fn save_resume(&self, have: &Bitfield) -> Result<(), Error> {
+        let data = ResumeData {
+            pieces: have.as_bytes().to_vec(),
+            files: self.file_states(),
+        };
+        // a crash while writing must not leave half the data behind
+        let tmp_path = self.resume_path.with_extension("resume.tmp");
+        std::fs::write(&tmp_path, bencoding::to_bytes(&data)?)?;
+        std::fs::rename(&tmp_path, &self.resume_path)?;
+        Ok(())
+    }
+
+    /// The pieces saved by `save_resume`, `None` if there are none or if a file changed
+    /// since, then the files must be checked.
+    pub fn load_resume(&self) -> Option<Bitfield> {
+        let bytes = std::fs::read(&self.resume_path).ok()?;
+        let data: ResumeData = bencoding::from_bytes(&bytes).ok()?;
+        if data.files != self.file_states() {
+            return None;
+        }
+        Bitfield::from_bytes(&data.pieces, self.num_pieces)
+    }
+
+    fn file_states(&self) -> Vec<FileState> {
+        let paths = self.files.iter().map(|f| &f.path);
+        paths
+            .chain(std::iter::once(&self.parts_path))
+            .map(|path| match std::fs::symlink_metadata(path) {
+                Ok(metadata) => FileState {
+                    length: metadata.len(),
+                    modified: metadata
+                        .modified()
+                        .ok()
+                        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                        .map_or(0, |d| d.as_nanos() as u64),
+                },
+                Err(_) => FileState {
+                    length: 0,
+                    modified: 0,
+                },
+            })
+            .collect()
+    }
+
+    /// Position of a block in the torrent, making sure it lies within its piece.
+    fn block_start(&self, index: u32, begin: u32, length: u32) -> Result<u64, Error> {
+        if index as usize >= self.num_pieces()
+            || length == 0
+            || length > MAX_BLOCK_LEN
+            || begin as u64 + length as u64 > self.piece_length(index)
+        {
+            return Err(Error::InvalidBlock);
+        }
+        Ok(index as u64 * self.piece_length + begin as u64)
+    }
+
+    /// The files overlapping the range, with their index.
+    fn files_in(&self, start: u64, end: u64) -> impl Iterator<Item = (usize, &StorageFile)> {
+        self.files
+            .iter()
+            .enumerate()
+            .filter(move |(_, f)| f.offset < end && f.offset + f.length > start)
+    }
+
+    fn pieces_of(&self, f: &StorageFile) -> impl Iterator<Item = usize> {
+        let first = f.offset / self.piece_length;
+        let end = (f.offset + f.length).div_ceil(self.piece_length);
+        first as usize..end as usize
+    }
+
+    /// Where the byte of `f` at `offset` in the torrent is kept, and its position there.
+    /// `offset` and the bytes after it up to the end of the file or of the piece go to the
+    /// same place.
+    fn location<'a>(
+        &'a self,
+        f: &'a StorageFile,
+        priority: Priority,
+        offset: u64,
+    ) -> (&'a Path, u64) {
+        if priority == Priority::Skip && !f.path.exists() {
+            let piece = (offset / self.piece_length) as u32;
+            if let Ok(slot) = self.shared_pieces.binary_search(&piece) {
+                let position = slot as u64 * self.piece_length + offset % self.piece_length;
+                return (&self.parts_path, position);
+            }
+        }
+        (&f.path, offset - f.offset)
+    }
+
+    /// Copies the parts of `f` kept in the parts file to the file, the parts that were not
+    /// downloaded yet are left out.
+    fn restore_parts(&self, f: &StorageFile) -> Result<(), Error> {
+        for index in self.pieces_of(f) {
+            let slot = match self.shared_pieces.binary_search(&(index as u32)) {
+                Ok(slot) => slot as u64,
+                Err(_) => continue,
+            };
+            let start = index as u64 * self.piece_length;
+            let from = std::cmp::max(start, f.offset);
+            let to = std::cmp::min(start + self.piece_length, f.offset + f.length);
+
+            let mut buf = vec![0u8; (to - from) as usize];
+            let position = slot * self.piece_length + from - start;
+            match read_at(&self.parts_path, position, &mut buf) {
+                Ok(()) => write_at(&f.path, from - f.offset, &buf)?,
+                Err(Error::Tokio(e))
+                    if e.kind() == ErrorKind::NotFound || e.kind() == ErrorKind::UnexpectedEof => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn read_range(&self, start: u64, buf: &mut [u8]) -> Result<(), Error> {
+        let end = start + buf.len() as u64;
+        let priorities = self.priorities.read().unwrap();
+        for (index, f) in self.files_in(start, end) {
+            let from = std::cmp::max(start, f.offset);
+            let to = std::cmp::min(end, f.offset + f.length);
+
+            let (path, position) = self.location(f, priorities[index], from);
+            read_at(
+                path,
+                position,
+                &mut buf[(from - start) as usize..(to - start) as usize],
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn read_at(path: &Path, position: u64, buf: &mut [u8]) -> Result<(), Error> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(position))?;
+    file.read_exact(buf)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &Path, path: &Path) -> Result<(), Error> {
+    if path.symlink_metadata().is_ok() {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::os::unix::fs::symlink(target, path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_symlink(_target: &Path, _path: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Adds the executable bits where the file is readable.
+#[cfg(unix)]
+fn set_executable(path: &Path) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut permissions = std::fs::metadata(path)?.permissions();
+    let mode = permissions.mode();
+    permissions.set_mode(mode | (mode & 0o444) >> 2);
+    std::fs::set_permissions(path, permissions)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Writes `buf` at `position`, creating the file and its directory if needed.
+fn write_at(path: &Path, position: u64, buf: &[u8]) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    file.seek(SeekFrom::Start(position))?;
+    file.write_all(buf)?;
+    Ok(())
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_blocks_across_files() {
+    use crate::create::TorrentBuilder;
+
+    let dir = std::env::temp_dir().join(format!("thor-storage-{}", rand::random::<u32>()));
+    let source = dir.join("source").join("data");
+    std::fs::create_dir_all(&source).unwrap();
+    let contents: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(source.join("a"), &contents[..20_000]).unwrap();
+    std::fs::write(source.join("b"), &contents[20_000..]).unwrap();
+
+    let meta_info = TorrentBuilder::new(&source)
+        .piece_length(16 * 1024)
+        .build()
+        .unwrap();
+    let seed = Storage::new(&meta_info, &dir.join("source"));
+    assert_eq!(seed.num_pieces(), 4);
+    assert!(seed.check().unwrap().is_complete());
+
+    // the second piece spans both files
+    assert_eq!(
+        seed.read_block(1, 0, 16 * 1024).unwrap(),
+        &contents[16 * 1024..32 * 1024]
+    );
+    assert!(seed.read_block(3, 0, 16 * 1024).is_err());
+    assert!(seed.read_block(4, 0, 1).is_err());
+
+    let leech = Storage::new(&meta_info, &dir.join("download"));
+    assert_eq!(leech.check().unwrap().count(), 0);
+    for index in 0..4 {
+        let length = leech.piece_length(index) as u32;
+        let block = seed.read_block(index, 0, length).unwrap();
+        leech.write_block(index, 0, &block).unwrap();
+    }
+    assert!(leech.check().unwrap().is_complete());
+    assert_eq!(
+        std::fs::read(dir.join("download").join("data").join("b")).unwrap(),
+        &contents[20_000..]
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_resume_data() {
+    use crate::create::TorrentBuilder;
+
+    let dir = std::env::temp_dir().join(format!("thor-resume-{}", rand::random::<u32>()));
+    let source = dir.join("data");
+    std::fs::create_dir_all(&source).unwrap();
+    let contents: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(source.join("a"), &contents[..20_000]).unwrap();
+    std::fs::write(source.join("b"), &contents[20_000..]).unwrap();
+    let meta_info = TorrentBuilder::new(&source)
+        .piece_length(16 * 1024)
+        .build()
+        .unwrap();
+
+    let storage = Storage::new(&meta_info, &dir);
+    assert_eq!(storage.load_resume(), None);
+    let mut have = storage.check().unwrap();
+    have.set(2, false);
+    storage.save_resume(&have).unwrap();
+    assert_eq!(Storage::new(&meta_info, &dir).load_resume(), Some(have));
+
+    // a file changed while the torrent was stopped
+    std::fs::write(source.join("b"), &contents[..30_000]).unwrap();
+    let storage = Storage::new(&meta_info, &dir);
+    assert_eq!(storage.load_resume(), None);
+
+    storage.cancel_check();
+    assert!(matches!(storage.check(), Err(Error::Cancelled)));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_skipped_files() {
+    use crate::create::TorrentBuilder;
+
+    let dir = std::env::temp_dir().join(format!("thor-skip-{}", rand::random::<u32>()));
+    let source = dir.join("source").join("data");
+    std::fs::create_dir_all(&source).unwrap();
+    let contents: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(source.join("a"), &contents[..20_000]).unwrap();
+    std::fs::write(source.join("b"), &contents[20_000..40_000]).unwrap();
+    std::fs::write(source.join("c"), &contents[40_000..]).unwrap();
+
+    let meta_info = TorrentBuilder::new(&source)
+        .piece_length(16 * 1024)
+        .build()
+        .unwrap();
+    let seed = Storage::new(&meta_info, &dir.join("source"));
+    let leech = Storage::new(&meta_info, &dir.join("download"));
+    assert!(leech.set_file_priorities(&[Priority::Skip]).is_err());
+    leech
+        .set_file_priorities(&[Priority::High, Priority::Skip, Priority::Low])
+        .unwrap();
+    // pieces 1 and 2 are shared with the skipped file
+    assert_eq!(
+        leech.piece_priorities(),
+        vec![Priority::High, Priority::High, Priority::Low, Priority::Low]
+    );
+
+    let mut have = Bitfield::new(4);
+    for index in [0, 1, 2, 3] {
+        if index == 2 {
+            continue;
+        }
+        let length = leech.piece_length(index) as u32;
+        let block = seed.read_block(index, 0, length).unwrap();
+        leech.write_block(index, 0, &block).unwrap();
+        have.set(index as usize, true);
+    }
+    let data = dir.join("download").join("data");
+    assert!(!data.join("b").exists());
+    assert_eq!(leech.check().unwrap(), have);
+    assert_eq!(
+        leech.file_progress(&have),
+        vec![20_000, 32 * 1024 - 20_000, 848]
+    );
+
+    leech.set_file_priorities(&[Priority::Normal; 3]).unwrap();
+    assert_eq!(leech.check().unwrap(), have);
+    let b = std::fs::read(data.join("b")).unwrap();
+    assert_eq!(&b[..32 * 1024 - 20_000], &contents[20_000..32 * 1024]);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_v2_pieces() {
+    use crate::merkle::{self, BLOCK_LEN};
+    use crate::model::{FileTreeEntry, FileTreeNode, InfoDict};
+    use serde_bytes::ByteBuf;
+    use std::collections::BTreeMap;
+
+    let dir = std::env::temp_dir().join(format!("thor-v2-{}", rand::random::<u32>()));
+    let source = dir.join("source").join("data");
+    std::fs::create_dir_all(&source).unwrap();
+    let piece_length = 2 * BLOCK_LEN as u64;
+    let a: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+    let b: Vec<u8> = (0..10_000u32).map(|i| (i % 13) as u8).collect();
+    std::fs::write(source.join("a"), &a).unwrap();
+    std::fs::write(source.join("b"), &b).unwrap();
+
+    let mut file_tree = BTreeMap::new();
+    let mut piece_layers = BTreeMap::new();
+    for (name, data) in [("a", &a), ("b", &b)] {
+        let root = if data.len() as u64 > piece_length {
+            let layer: Vec<merkle::Hash> = data
+                .chunks(piece_length as usize)
+                .map(|piece| merkle::piece_hash(piece, piece_length))
+                .collect();
+            let root = merkle::root_from_layer(&layer, piece_length);
+            piece_layers.insert(ByteBuf::from(root.to_vec()), ByteBuf::from(layer.concat()));
+            root
+        } else {
+            merkle::file_root(data)
+        };
+        let file = FileTreeEntry {
+            length: data.len() as u64,
+            pieces_root: Some(ByteBuf::from(root.to_vec())),
+        };
+        file_tree.insert(name.to_owned(), FileTreeNode::File { file });
+    }
+    let mut meta_info = MetaInfo {
+        announce: None,
+        announce_list: None,
+        comment: None,
+        created_by: None,
+        creation_date: None,
+        encoding: None,
+        info: InfoDict {
+            file_tree: Some(file_tree),
+            files: None,
+            length: None,
+            md5sum: None,
+            meta_version: Some(2),
+            name: "data".to_owned(),
+            piece_length,
+            pieces: vec![],
+            private: None,
+            source: None,
+        },
+        piece_layers: Some(piece_layers),
+        url_list: None,
+        raw_info: None,
+    };
+
+    let bytes = bencoding::to_bytes(&meta_info).unwrap();
+    let (parsed, info_hashes) = MetaInfo::with_info_hashes(&bytes).unwrap();
+    assert_eq!(info_hashes.v1, None);
+    assert_eq!(info_hashes.v2, Some(meta_info.info.info_hash_v2()));
+    assert_eq!(info_hashes.id()[..], meta_info.info.info_hash_v2()[..20]);
+    assert_eq!(parsed.info.file_tree, meta_info.info.file_tree);
+    // b starts on the piece after the end of a
+    let offsets: Vec<u64> = parsed.info.file_list().iter().map(|f| f.offset).collect();
+    assert_eq!(offsets, vec![0, 2 * piece_length]);
+    assert_eq!(parsed.info.num_pieces(), 3);
+
+    let seed = Storage::new(&parsed, &dir.join("source"));
+    assert!(seed.check().unwrap().is_complete());
+    let leech = Storage::new(&parsed, &dir.join("download"));
+    for index in 0..3 {
+        let length = leech.piece_length(index) as u32;
+        let block = seed.read_block(index, 0, length).unwrap();
+        leech.write_block(index, 0, &block).unwrap();
+    }
+    assert!(leech.check().unwrap().is_complete());
+    assert_eq!(std::fs::read(dir.join("download/data/a")).unwrap(), a);
+    leech.write_block(1, 0, &[0xff; 100]).unwrap();
+    assert!(!leech.verify_piece(1).unwrap());
+
+    // a piece layer not matching its root is refused
+    let layers = meta_info.piece_layers.as_mut().unwrap();
+    layers.values_mut().for_each(|layer| layer[0] ^= 1);
+    let bytes = bencoding::to_bytes(&meta_info).unwrap();
+    assert!(MetaInfo::with_info_hashes(&bytes).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_padding_and_attributes() {
+    use crate::model::{FileInfo, InfoDict};
+
+    let dir = std::env::temp_dir().join(format!("thor-attr-{}", rand::random::<u32>()));
+    let source = dir.join("source").join("data");
+    std::fs::create_dir_all(&source).unwrap();
+    let piece_length = 16 * 1024;
+    let a: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+    let b: Vec<u8> = (0..10_000u32).map(|i| (i % 13) as u8).collect();
+    std::fs::write(source.join("a"), &a).unwrap();
+    std::fs::write(source.join("b"), &b).unwrap();
+
+    let pad_length = 2 * piece_length - a.len() as u64;
+    let mut concatenated = a.clone();
+    concatenated.resize(2 * piece_length as usize, 0);
+    concatenated.extend_from_slice(&b);
+    let pieces = concatenated
+        .chunks(piece_length as usize)
+        .flat_map(|piece| sha1::Sha1::digest(piece).to_vec())
+        .collect();
+    let file = |path: &[&str], length: u64, attr: Option<&str>| FileInfo {
+        attr: attr.map(str::to_owned),
+        length,
+        md5sum: None,
+        path: path.iter().map(|p| p.to_string()).collect(),
+        sha1: None,
+        symlink_path: None,
+    };
+    let mut link = file(&["sub", "link"], 0, Some("l"));
+    link.symlink_path = Some(vec!["a".to_owned()]);
+    let meta_info = MetaInfo {
+        announce: None,
+        announce_list: None,
+        comment: None,
+        created_by: None,
+        creation_date: None,
+        encoding: None,
+        info: InfoDict {
+            file_tree: None,
+            files: Some(vec![
+                file(&["a"], a.len() as u64, Some("x")),
+                file(&[".pad", &pad_length.to_string()], pad_length, Some("p")),
+                file(&["b"], b.len() as u64, None),
+                link,
+            ]),
+            length: None,
+            md5sum: None,
+            meta_version: None,
+            name: "data".to_owned(),
+            piece_length,
+            pieces,
+            private: None,
+            source: None,
+        },
+        piece_layers: None,
+        url_list: None,
+        raw_info: None,
+    };
+    assert_eq!(meta_info.info.num_files(), 3);
+    assert_eq!(meta_info.info.total_length(), 30_000);
+
+    let seed = Storage::new(&meta_info, &dir.join("source"));
+    assert!(seed.check().unwrap().is_complete());
+    let leech = Storage::new(&meta_info, &dir.join("download"));
+    let mut have = Bitfield::new(3);
+    for index in 0..3 {
+        let length = leech.piece_length(index) as u32;
+        let block = seed.read_block(index, 0, length).unwrap();
+        leech.write_block(index, 0, &block).unwrap();
+        have.set(index as usize, true);
+    }
+    assert!(leech.check().unwrap().is_complete());
+    let data = dir.join("download").join("data");
+    assert!(!data.join(".pad").exists());
+    assert_eq!(std::fs::read(data.join("b")).unwrap(), b);
+
+    leech.finish_files(&have, None).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = |name: &str| {
+            std::fs::metadata(data.join(name))
+                .unwrap()
+                .permissions()
+                .mode()
+        };
+        assert_eq!(mode("a") & 0o111, 0o111);
+        assert_eq!(mode("b") & 0o111, 0);
+        let target = std::fs::read_link(data.join("sub").join("link")).unwrap();
+        assert_eq!(target, Path::new("../a"));
+        assert_eq!(std::fs::read(data.join("sub").join("link")).unwrap(), a);
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
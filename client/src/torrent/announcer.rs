@@ -0,0 +1,163 @@
+use super::TorrentContext;
+use crate::config::TrackerConfig;
+use crate::dht::Dht;
+use crate::error::Error;
+use crate::event::Event;
+use crate::model::MetaInfo;
+use crate::peer::pool::PeerSource;
+use crate::tracker::{AnnounceEvent, AnnounceParams, Connection, TrackerClient};
+use log::{debug, info, warn};
+use rand::seq::SliceRandom;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Instant};
+
+/// Used until a tracker tells us its interval, and when every tracker failed.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Announces a torrent to its trackers and to the DHT, adding the peers they return
+/// to the torrent's pool. The DHT must be `None` for private torrents.
+pub struct Announcer {
+    /// Trackers of the torrent by tier (BEP 12), each tier is shuffled once and the
+    /// tracker that answered last moves to the front of its tier.
+    tiers: Vec<Vec<String>>,
+    context: Arc<TorrentContext>,
+    dht: Option<Arc<Dht>>,
+    port: u16,
+    config: TrackerConfig,
+}
+
+impl Announcer {
+    pub fn new(
+        meta_info: &MetaInfo,
+        context: Arc<TorrentContext>,
+        dht: Option<Arc<Dht>>,
+        port: u16,
+        config: TrackerConfig,
+    ) -> Announcer {
+        let mut tiers = meta_info.tiers();
+        for tier in tiers.iter_mut() {
+            tier.shuffle(&mut rand::thread_rng());
+        }
+
+        Announcer {
+            tiers,
+            context,
+            dht,
+            port,
+            config,
+        }
+    }
+
+    /// Announces `started`, then regularly, and once more with the events sent to `events`
+    /// until `stopped` is sent or the sender dropped.
+    pub async fn run(mut self, mut events: mpsc::Receiver<AnnounceEvent>) {
+        let mut event = AnnounceEvent::Started;
+        loop {
+            let interval = self.announce(event).await;
+            if event == AnnounceEvent::Stopped {
+                return;
+            }
+
+            event = tokio::select! {
+                _ = sleep_until(Instant::now() + interval) => AnnounceEvent::None,
+                e = events.recv() => e.unwrap_or(AnnounceEvent::Stopped),
+            };
+        }
+    }
+
+    /// Announces to the first tracker of each tier that answers and to the DHT, returns
+    /// when to announce next.
+    async fn announce(&mut self, event: AnnounceEvent) -> Duration {
+        let params = AnnounceParams {
+            peer_id: self.context.peer_id,
+            downloaded: self.context.downloaded(),
+            left: self.context.picker.lock().unwrap().left(),
+            uploaded: self.context.uploaded(),
+            event,
+        };
+
+        let mut interval = None;
+        for tier in self.tiers.iter_mut() {
+            for i in 0..tier.len() {
+                let tracker = &tier[i];
+                let info_hash = &self.context.info_hash;
+                match announce_udp(tracker, info_hash, self.port, &params, &self.config).await {
+                    Ok((peers, tracker_interval)) => {
+                        info!("{} returned {} peers", tier[i], peers.len());
+                        self.context.bus.emit(Event::Announced {
+                            info_hash: self.context.info_hash,
+                            tracker: tier[i].clone(),
+                            peers: peers.len(),
+                        });
+                        add_peers(&self.context, peers, PeerSource::Tracker);
+                        interval = Some(interval.unwrap_or(tracker_interval));
+                        let tracker = tier.remove(i);
+                        tier.insert(0, tracker);
+                        break;
+                    }
+                    Err(e) => {
+                        warn!("announce to {} failed: {}", tier[i], e);
+                        self.context.bus.emit(Event::AnnounceFailed {
+                            info_hash: self.context.info_hash,
+                            tracker: tier[i].clone(),
+                            error: e.to_string(),
+                        });
+                    }
+                }
+            }
+        }
+
+        if let Some(dht) = self.dht.as_ref() {
+            if event == AnnounceEvent::Stopped {
+                return Duration::from_secs(0);
+            }
+            let peers = dht.announce(self.context.info_hash, Some(self.port)).await;
+            info!("dht returned {} peers", peers.len());
+            add_peers(&self.context, peers, PeerSource::Dht);
+        }
+
+        match interval {
+            Some(interval) => std::cmp::max(interval, RETRY_INTERVAL),
+            None if self.tiers.is_empty() => DEFAULT_INTERVAL,
+            None => RETRY_INTERVAL,
+        }
+    }
+}
+
+fn add_peers(context: &TorrentContext, peers: Vec<SocketAddr>, source: PeerSource) {
+    let mut pool = context.pool.lock().unwrap();
+    for addr in peers {
+        pool.add_candidate(addr, source);
+    }
+}
+
+/// Announces to a single UDP tracker, returning its peers and announce interval.
+pub async fn announce_udp(
+    url: &str,
+    info_hash: &[u8; 20],
+    port: u16,
+    params: &AnnounceParams,
+    config: &TrackerConfig,
+) -> Result<(Vec<SocketAddr>, Duration), Error> {
+    let host = url
+        .strip_prefix("udp://")
+        .ok_or_else(|| Error::UnsupportedTracker(url.to_owned()))?;
+    let host = host.split('/').next().unwrap_or(host);
+
+    let addr = tokio::net::lookup_host(host)
+        .await?
+        .find(SocketAddr::is_ipv4)
+        .ok_or_else(|| Error::Server(format!("failed to resolve {}", host)))?;
+    debug!("announcing to {} at {}", url, addr);
+
+    let mut connection = Connection::new(addr, port, config.clone()).await?;
+    let response = connection.announce(info_hash, params).await?;
+    Ok((
+        response.peers.iter().map(|p| p.addr()).collect(),
+        response.interval,
+    ))
+}
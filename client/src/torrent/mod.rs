@@ -0,0 +1,938 @@
+use crate::bitfield::Bitfield;
+use crate::config::{PeerConfig, TrackerConfig};
+use crate::dht::Dht;
+use crate::error::Error;
+use crate::event::{Event, EventBus};
+use crate::extension::metadata::MetadataExtension;
+use crate::extension::pex::{self, PexExtension};
+use crate::extension::Extensions;
+use crate::lsd::Lsd;
+use crate::model::MetaInfo;
+use crate::peer::choker::{Choker, PeerStats, UNCHOKE_INTERVAL};
+use crate::peer::connection::{Connection, PeerCommand, PeerStatus};
+use crate::peer::handshake::Handshake;
+use crate::peer::id::Client;
+use crate::peer::listener::IncomingPeer;
+use crate::peer::pool::{PeerPool, PeerSource};
+use crate::peer::PeerStream;
+use crate::rate_limit::RateLimits;
+use crate::storage::Storage;
+use crate::torrent::picker::Priority;
+use crate::tracker::AnnounceEvent;
+use crate::utp::UtpSocket;
+use announcer::Announcer;
+use log::{debug, info, warn};
+use picker::PiecePicker;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use webseed::WebSeed;
+
+pub mod announcer;
+pub mod picker;
+pub mod webseed;
+
+/// How often new connections are opened to candidate peers.
+const CONNECT_INTERVAL: Duration = Duration::from_secs(1);
+/// How long stopping a torrent waits for its connections to close and for its trackers to
+/// answer the `stopped` announce.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// Events of the connections waiting to be handled by their torrent.
+const EVENT_QUEUE_LEN: usize = 64;
+
+/// The state of a torrent shared with its peer connections and announcer.
+#[derive(Debug)]
+pub struct TorrentContext {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub storage: Arc<Storage>,
+    pub picker: Mutex<PiecePicker>,
+    pub pool: Arc<Mutex<PeerPool>>,
+    /// Limits of the torrent, applied with the limits of the session.
+    pub limits: RateLimits,
+    pub session_limits: RateLimits,
+    pub bus: EventBus,
+    pub config: PeerConfig,
+    downloaded: AtomicU64,
+    uploaded: AtomicU64,
+}
+
+impl TorrentContext {
+    /// A context for the pieces of `have` already on disk.
+    pub fn new(
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+        storage: Arc<Storage>,
+        have: Bitfield,
+    ) -> TorrentContext {
+        let picker = PiecePicker::new(have, storage.piece_length(0), storage.total_length());
+        TorrentContext {
+            info_hash,
+            peer_id,
+            storage,
+            picker: Mutex::new(picker),
+            pool: Arc::new(Mutex::new(PeerPool::new())),
+            limits: RateLimits::default(),
+            session_limits: RateLimits::default(),
+            bus: EventBus::new(),
+            config: PeerConfig::default(),
+            downloaded: AtomicU64::new(0),
+            uploaded: AtomicU64::new(0),
+        }
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// What peer connections report to their torrent.
+#[derive(Debug)]
+pub enum PeerEvent {
+    /// The peer started or stopped being interested in our pieces.
+    InterestChanged(SocketAddr),
+    /// All blocks of a piece were received and the piece was checked.
+    PieceVerified {
+        index: u32,
+        valid: bool,
+    },
+    Closed(SocketAddr),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TorrentState {
+    /// Checking the data already on disk.
+    Checking,
+    Downloading,
+    Seeding,
+    Paused,
+}
+
+#[derive(Debug, Clone)]
+pub struct TorrentStatus {
+    pub info_hash: [u8; 20],
+    pub name: String,
+    pub state: TorrentState,
+    /// Number of pieces we have.
+    pub pieces: usize,
+    pub num_pieces: usize,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub peers: usize,
+    /// Connected peers by the name of their client, for the clients that could be told.
+    pub clients: HashMap<String, usize>,
+    pub files: Vec<FileStatus>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileStatus {
+    /// Relative to the download directory.
+    pub path: PathBuf,
+    pub length: u64,
+    /// Bytes within verified pieces.
+    pub downloaded: u64,
+    pub priority: Priority,
+}
+
+#[derive(Debug)]
+pub(crate) enum TorrentCommand {
+    AddPeers(Vec<SocketAddr>),
+    Pause,
+    Resume,
+    Status(oneshot::Sender<TorrentStatus>),
+    SetFilePriorities(Vec<Priority>, oneshot::Sender<Result<(), Error>>),
+    Shutdown(oneshot::Sender<()>),
+}
+
+/// What a torrent gets from its session.
+#[derive(Clone)]
+pub(crate) struct TorrentEnv {
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub download_dir: PathBuf,
+    pub max_peers: usize,
+    pub upload_slots: usize,
+    pub peer: PeerConfig,
+    pub tracker: TrackerConfig,
+    /// Limits the number of connections of all torrents together.
+    pub peer_permits: Arc<Semaphore>,
+    pub limits: RateLimits,
+    pub bus: EventBus,
+    pub dht: Option<Arc<Dht>>,
+    pub lsd: Option<Arc<Lsd>>,
+    /// Outgoing connections are tried over uTP first when set.
+    pub utp: Option<Arc<UtpSocket>>,
+}
+
+/// Controls a torrent running in its own task.
+#[derive(Debug)]
+pub(crate) struct TorrentHandle {
+    pub commands: mpsc::UnboundedSender<TorrentCommand>,
+    pub task: JoinHandle<()>,
+    /// Shared with the connections, so changes apply right away.
+    pub limits: RateLimits,
+}
+
+struct PeerHandle {
+    commands: mpsc::UnboundedSender<PeerCommand>,
+    task: JoinHandle<()>,
+    status: Arc<Mutex<PeerStatus>>,
+    connected_at: Instant,
+    /// Byte counters at the last choker round, to compute rates.
+    last_downloaded: u64,
+    last_uploaded: u64,
+    download_rate: u64,
+    upload_rate: u64,
+}
+
+struct Torrent {
+    meta_info: MetaInfo,
+    info_hash: [u8; 20],
+    /// The bencoded info dict, served to the peers with ut_metadata.
+    metadata: Vec<u8>,
+    env: TorrentEnv,
+    state: TorrentState,
+    /// Set once the data on disk was checked.
+    context: Option<Arc<TorrentContext>>,
+    file_priorities: Vec<Priority>,
+    pool: Arc<Mutex<PeerPool>>,
+    limits: RateLimits,
+    peers: HashMap<SocketAddr, PeerHandle>,
+    choker: Choker,
+    last_rechoke: Instant,
+    announcer: Option<(mpsc::Sender<AnnounceEvent>, JoinHandle<()>)>,
+    /// Announcers sending `stopped` after the torrent was paused.
+    stopping: Vec<JoinHandle<()>>,
+    /// Run while downloading, stopped by sending or dropping their sender.
+    web_seeds: Vec<(oneshot::Sender<()>, JoinHandle<()>)>,
+    events: mpsc::Sender<PeerEvent>,
+}
+
+/// Starts a torrent in a new task, `incoming` receives the peers connecting to us for it.
+pub(crate) fn spawn(
+    meta_info: MetaInfo,
+    info_hash: [u8; 20],
+    incoming: mpsc::Receiver<IncomingPeer>,
+    env: TorrentEnv,
+) -> TorrentHandle {
+    let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+    let (events_tx, events_rx) = mpsc::channel(EVENT_QUEUE_LEN);
+    let limits = RateLimits::default();
+    let num_files = meta_info.info.num_files();
+    let pool = PeerPool::for_torrent(meta_info.info.is_private());
+    let metadata = meta_info.info_bytes();
+    let torrent = Torrent {
+        file_priorities: vec![Priority::Normal; num_files],
+        meta_info,
+        info_hash,
+        metadata,
+        choker: Choker::new(env.upload_slots),
+        env,
+        state: TorrentState::Checking,
+        context: None,
+        pool: Arc::new(Mutex::new(pool)),
+        limits: limits.clone(),
+        peers: HashMap::new(),
+        last_rechoke: Instant::now(),
+        announcer: None,
+        stopping: vec![],
+        web_seeds: vec![],
+        events: events_tx,
+    };
+    TorrentHandle {
+        commands: commands_tx,
+        task: tokio::spawn(torrent.run(commands_rx, events_rx, incoming)),
+        limits,
+    }
+}
+
+impl Torrent {
+    async fn run(
+        mut self,
+        mut commands: mpsc::UnboundedReceiver<TorrentCommand>,
+        mut events: mpsc::Receiver<PeerEvent>,
+        mut incoming: mpsc::Receiver<IncomingPeer>,
+    ) {
+        let (context, deferred) = match self.check(&mut commands).await {
+            Some(checked) => checked,
+            None => return,
+        };
+        let context = Arc::new(context);
+        self.state = if context.picker.lock().unwrap().is_done() {
+            TorrentState::Seeding
+        } else {
+            TorrentState::Downloading
+        };
+        self.context = Some(context);
+        self.start_announcer();
+        self.start_web_seeds();
+        for command in deferred {
+            if !self.handle_command(Some(command), &mut events).await {
+                return;
+            }
+        }
+
+        let mut connect_tick = tokio::time::interval(CONNECT_INTERVAL);
+        let mut choke_tick = tokio::time::interval(UNCHOKE_INTERVAL);
+        loop {
+            tokio::select! {
+                command = commands.recv() => {
+                    if !self.handle_command(command, &mut events).await {
+                        return;
+                    }
+                }
+                Some(event) = events.recv() => self.handle_event(event),
+                Some(peer) = incoming.recv() => self.accept(peer),
+                _ = connect_tick.tick() => self.connect_peers(),
+                _ = choke_tick.tick() => {
+                    self.update_rates();
+                    self.rechoke();
+                }
+            }
+        }
+    }
+
+    /// Handles a command, returns false once the torrent is stopped.
+    async fn handle_command(
+        &mut self,
+        command: Option<TorrentCommand>,
+        events: &mut mpsc::Receiver<PeerEvent>,
+    ) -> bool {
+        match command {
+            Some(TorrentCommand::AddPeers(addrs)) => {
+                let mut pool = self.pool.lock().unwrap();
+                for addr in addrs {
+                    pool.add_candidate(addr, PeerSource::Manual);
+                }
+            }
+            Some(TorrentCommand::Pause) => self.pause(),
+            Some(TorrentCommand::Resume) => self.resume(),
+            Some(TorrentCommand::Status(sender)) => {
+                let _ = sender.send(self.status());
+            }
+            Some(TorrentCommand::SetFilePriorities(priorities, done)) => {
+                let _ = done.send(self.set_file_priorities(priorities).await);
+            }
+            Some(TorrentCommand::Shutdown(done)) => {
+                self.stop(events).await;
+                let _ = done.send(());
+                return false;
+            }
+            None => {
+                self.stop(events).await;
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Checks the pieces already downloaded, unless the resume data saved when the torrent
+    /// last stopped still holds. A failed check starts from scratch. Stopping the torrent
+    /// cancels the check and returns `None`, the other commands are returned to be handled
+    /// once the torrent is checked.
+    async fn check(
+        &self,
+        commands: &mut mpsc::UnboundedReceiver<TorrentCommand>,
+    ) -> Option<(TorrentContext, Vec<TorrentCommand>)> {
+        let info = &self.meta_info.info;
+        let storage = Arc::new(Storage::new(&self.meta_info, &self.env.download_dir));
+        // skipped files are read from the parts file
+        storage
+            .set_file_priorities(&self.file_priorities)
+            .expect("one priority per file");
+        let mut checking = {
+            let storage = storage.clone();
+            tokio::task::spawn_blocking(move || match storage.load_resume() {
+                Some(have) => Ok((have, true)),
+                None => storage.check().map(|have| (have, false)),
+            })
+        };
+        let mut deferred = vec![];
+        let checked = loop {
+            tokio::select! {
+                checked = &mut checking => break checked,
+                command = commands.recv() => match command {
+                    Some(TorrentCommand::Status(sender)) => {
+                        let _ = sender.send(self.status());
+                    }
+                    Some(TorrentCommand::Shutdown(done)) => {
+                        storage.cancel_check();
+                        let _ = checking.await;
+                        let _ = done.send(());
+                        return None;
+                    }
+                    None => {
+                        storage.cancel_check();
+                        let _ = checking.await;
+                        return None;
+                    }
+                    Some(command) => deferred.push(command),
+                },
+            }
+        };
+        let have = match checked {
+            Ok(Ok((have, true))) => {
+                info!("{}: resumed without checking the files", info.name);
+                have
+            }
+            Ok(Ok((have, false))) => have,
+            Ok(Err(e)) => {
+                self.error(format!("failed to check {}: {}", info.name, e));
+                Bitfield::new(storage.num_pieces())
+            }
+            Err(e) => {
+                self.error(format!("failed to check {}: {}", info.name, e));
+                Bitfield::new(storage.num_pieces())
+            }
+        };
+        info!(
+            "{}: {}/{} pieces on disk",
+            info.name,
+            have.count(),
+            have.len()
+        );
+        if let Err(e) = storage.finish_files(&have, None) {
+            self.error(format!(
+                "failed to finish the files of {}: {}",
+                info.name, e
+            ));
+        }
+
+        let piece_priorities = storage.piece_priorities();
+        let mut context = TorrentContext::new(self.info_hash, self.env.peer_id, storage, have);
+        context
+            .picker
+            .get_mut()
+            .unwrap()
+            .set_priorities(piece_priorities);
+        context.pool = self.pool.clone();
+        context.limits = self.limits.clone();
+        context.session_limits = self.env.limits.clone();
+        context.bus = self.env.bus.clone();
+        context.config = self.env.peer.clone();
+        Some((context, deferred))
+    }
+
+    /// Reports an error that does not stop the torrent.
+    fn error(&self, message: String) {
+        warn!("{}", message);
+        self.env.bus.emit(Event::Error {
+            info_hash: Some(self.info_hash),
+            message,
+        });
+    }
+
+    fn start_announcer(&mut self) {
+        let context = self.context.clone().expect("torrent should be checked");
+        let announcer = Announcer::new(
+            &self.meta_info,
+            context,
+            self.dht().cloned(),
+            self.env.port,
+            self.env.tracker.clone(),
+        );
+        let (sender, receiver) = mpsc::channel(4);
+        let task = tokio::spawn(announcer.run(receiver));
+        self.announcer = Some((sender, task));
+        if let Some(lsd) = self.lsd() {
+            lsd.add_torrent(self.info_hash, self.pool.clone());
+        }
+    }
+
+    /// Announces `stopped` in the background, the announcer is joined when the torrent stops.
+    fn stop_announcer(&mut self) {
+        if let Some((sender, task)) = self.announcer.take() {
+            let _ = sender.try_send(AnnounceEvent::Stopped);
+            self.stopping.push(task);
+        }
+        if let Some(lsd) = self.lsd() {
+            lsd.remove_torrent(&self.info_hash);
+        }
+    }
+
+    /// The DHT, which must not leak private torrents (BEP 27).
+    fn dht(&self) -> Option<&Arc<Dht>> {
+        self.env.dht.as_ref().filter(|_| !self.is_private())
+    }
+
+    /// Local service discovery, which must not leak private torrents either.
+    fn lsd(&self) -> Option<&Arc<Lsd>> {
+        self.env.lsd.as_ref().filter(|_| !self.is_private())
+    }
+
+    /// Private torrents only use their trackers, their pool rejects peers from elsewhere.
+    fn is_private(&self) -> bool {
+        self.meta_info.info.is_private()
+    }
+
+    /// Starts downloading from the web seeds of the torrent, if not already.
+    fn start_web_seeds(&mut self) {
+        if self.state != TorrentState::Downloading || !self.web_seeds.is_empty() {
+            return;
+        }
+        let context = self.context().clone();
+        for url in self.meta_info.web_seeds() {
+            let seed = WebSeed::new(&url, &self.meta_info, context.clone(), self.events.clone());
+            match seed {
+                Ok(seed) => {
+                    let (sender, receiver) = oneshot::channel();
+                    let task = tokio::spawn(seed.run(receiver));
+                    self.web_seeds.push((sender, task));
+                }
+                Err(e) => debug!("skipping web seed {}: {}", url, e),
+            }
+        }
+    }
+
+    /// Web seeds give their blocks back to the picker before they stop, they are joined
+    /// when the torrent stops.
+    fn stop_web_seeds(&mut self) {
+        for (sender, task) in self.web_seeds.drain(..) {
+            let _ = sender.send(());
+            self.stopping.push(task);
+        }
+    }
+
+    fn is_seeding(&self) -> bool {
+        self.state == TorrentState::Seeding
+    }
+
+    fn pause(&mut self) {
+        if self.state == TorrentState::Paused || self.state == TorrentState::Checking {
+            return;
+        }
+        info!("pausing {}", self.meta_info.info.name);
+        self.state = TorrentState::Paused;
+        for peer in self.peers.values() {
+            let _ = peer.commands.send(PeerCommand::Shutdown);
+        }
+        self.stop_announcer();
+        self.stop_web_seeds();
+    }
+
+    fn resume(&mut self) {
+        if self.state != TorrentState::Paused {
+            return;
+        }
+        info!("resuming {}", self.meta_info.info.name);
+        let done = self.context().picker.lock().unwrap().is_done();
+        self.state = if done {
+            TorrentState::Seeding
+        } else {
+            TorrentState::Downloading
+        };
+        self.start_announcer();
+        self.start_web_seeds();
+    }
+
+    /// Closes the connections and announces `stopped`, waiting for both until
+    /// `SHUTDOWN_TIMEOUT`, then flushes the written pieces to disk and saves the resume
+    /// data.
+    async fn stop(&mut self, events: &mut mpsc::Receiver<PeerEvent>) {
+        info!("stopping {}", self.meta_info.info.name);
+        for peer in self.peers.values() {
+            let _ = peer.commands.send(PeerCommand::Shutdown);
+        }
+        // connections must not wait for room in a queue nobody reads anymore
+        events.close();
+        self.stop_announcer();
+        self.stop_web_seeds();
+
+        let deadline = tokio::time::Instant::now() + SHUTDOWN_TIMEOUT;
+        let mut connections: Vec<JoinHandle<()>> =
+            self.peers.drain().map(|(_, peer)| peer.task).collect();
+        futures_util::future::join(
+            join_until(&mut connections, deadline),
+            join_until(&mut self.stopping, deadline),
+        )
+        .await;
+
+        if let Some(context) = self.context.as_ref() {
+            let storage = context.storage.clone();
+            let have = context.picker.lock().unwrap().have().clone();
+            let saved = tokio::task::spawn_blocking(move || {
+                storage.flush()?;
+                storage.save_resume(&have)
+            });
+            match saved.await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => self.error(format!(
+                    "failed to save {}: {}",
+                    self.meta_info.info.name, e
+                )),
+                Err(e) => self.error(format!(
+                    "failed to save {}: {}",
+                    self.meta_info.info.name, e
+                )),
+            }
+        }
+    }
+
+    /// Seeds once the wanted pieces are downloaded, downloads again when more are wanted.
+    fn update_done(&mut self) {
+        let (done, complete) = {
+            let picker = self.context().picker.lock().unwrap();
+            (picker.is_done(), picker.is_complete())
+        };
+        if done && self.state == TorrentState::Downloading {
+            info!("{} downloaded", self.meta_info.info.name);
+            self.state = TorrentState::Seeding;
+            self.env.bus.emit(Event::TorrentCompleted {
+                info_hash: self.info_hash,
+            });
+            // trackers count completed downloads, not selections
+            if let Some((sender, _)) = self.announcer.as_mut().filter(|_| complete) {
+                let _ = sender.try_send(AnnounceEvent::Completed);
+            }
+            self.stop_web_seeds();
+        } else if !done && self.state == TorrentState::Seeding {
+            self.state = TorrentState::Downloading;
+            self.start_web_seeds();
+        }
+    }
+
+    /// Changes which files are downloaded first, or at all.
+    async fn set_file_priorities(&mut self, priorities: Vec<Priority>) -> Result<(), Error> {
+        if priorities.len() != self.file_priorities.len() {
+            return Err(Error::FileCount {
+                expected: self.file_priorities.len(),
+                got: priorities.len(),
+            });
+        }
+        self.file_priorities = priorities.clone();
+        let context = match self.context.clone() {
+            Some(context) => context,
+            // applied once the torrent is checked
+            None => return Ok(()),
+        };
+
+        let storage = context.storage.clone();
+        let piece_priorities = tokio::task::spawn_blocking(move || {
+            storage
+                .set_file_priorities(&priorities)
+                .map(|()| storage.piece_priorities())
+        })
+        .await
+        .map_err(|e| Error::Tokio(e.into()))??;
+        context
+            .picker
+            .lock()
+            .unwrap()
+            .set_priorities(piece_priorities);
+
+        self.update_done();
+        for peer in self.peers.values() {
+            let _ = peer.commands.send(PeerCommand::PrioritiesChanged);
+        }
+        Ok(())
+    }
+
+    fn context(&self) -> &Arc<TorrentContext> {
+        self.context.as_ref().expect("torrent should be checked")
+    }
+
+    fn status(&self) -> TorrentStatus {
+        let (pieces, downloaded, uploaded, progress) = match self.context.as_ref() {
+            Some(context) => {
+                let picker = context.picker.lock().unwrap();
+                (
+                    picker.have().count(),
+                    context.downloaded(),
+                    context.uploaded(),
+                    context.storage.file_progress(picker.have()),
+                )
+            }
+            None => (0, 0, 0, vec![0; self.file_priorities.len()]),
+        };
+        let info = &self.meta_info.info;
+        let single_file = info.is_single_file();
+        let files = info
+            .file_list()
+            .into_iter()
+            .zip(progress)
+            .zip(self.file_priorities.iter())
+            .map(|((f, downloaded), priority)| {
+                let mut path = PathBuf::new();
+                if !single_file {
+                    path.push(&info.name);
+                }
+                path.extend(f.path.iter());
+                FileStatus {
+                    path,
+                    length: f.length,
+                    downloaded,
+                    priority: *priority,
+                }
+            })
+            .collect();
+        let mut clients = HashMap::new();
+        for peer in self.peers.values() {
+            if let Some(client) = peer.status.lock().unwrap().client.as_ref() {
+                *clients.entry(client.name.clone()).or_insert(0) += 1;
+            }
+        }
+        TorrentStatus {
+            info_hash: self.info_hash,
+            name: self.meta_info.info.name.clone(),
+            state: self.state,
+            pieces,
+            num_pieces: self.meta_info.info.num_pieces(),
+            downloaded,
+            uploaded,
+            peers: self.peers.len(),
+            clients,
+            files,
+        }
+    }
+
+    fn handle_event(&mut self, event: PeerEvent) {
+        match event {
+            PeerEvent::InterestChanged(_) => self.rechoke(),
+            PeerEvent::PieceVerified { index, valid } => {
+                let info_hash = self.info_hash;
+                if !valid {
+                    warn!("piece {} failed the hash check", index);
+                    self.env.bus.emit(Event::PieceFailed { info_hash, index });
+                    return;
+                }
+                self.env.bus.emit(Event::PieceVerified { info_hash, index });
+                for peer in self.peers.values() {
+                    let _ = peer.commands.send(PeerCommand::Have(index));
+                }
+                if let Some(context) = self.context.as_ref() {
+                    let picker = context.picker.lock().unwrap();
+                    let finished = context.storage.finish_files(picker.have(), Some(index));
+                    drop(picker);
+                    if let Err(e) = finished {
+                        self.error(format!(
+                            "failed to finish the files of piece {}: {}",
+                            index, e
+                        ));
+                    }
+                }
+                self.update_done();
+            }
+            PeerEvent::Closed(addr) => {
+                debug!("disconnected from {}", addr);
+                self.peers.remove(&addr);
+                self.pool.lock().unwrap().set_disconnected(&addr);
+            }
+        }
+    }
+
+    /// Opens connections to candidates, while below the peer limits. Seeds leave it to
+    /// the peers that need them to connect.
+    fn connect_peers(&mut self) {
+        if self.state != TorrentState::Downloading {
+            return;
+        }
+        while self.peers.len() < self.env.max_peers {
+            let permit = match self.env.peer_permits.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => return,
+            };
+            let (addr, _) = match self.pool.lock().unwrap().next_candidate() {
+                Some(candidate) => candidate,
+                None => return,
+            };
+            self.spawn_connection(addr, None, permit);
+        }
+    }
+
+    fn accept(&mut self, peer: IncomingPeer) {
+        if self.state == TorrentState::Paused || self.state == TorrentState::Checking {
+            return;
+        }
+        if self.peers.len() >= self.env.max_peers || self.peers.contains_key(&peer.addr) {
+            debug!("rejecting incoming peer {}", peer.addr);
+            return;
+        }
+        match self.env.peer_permits.clone().try_acquire_owned() {
+            Ok(permit) => {
+                let stream = (peer.stream, peer.handshake);
+                self.spawn_connection(peer.addr, Some(stream), permit)
+            }
+            Err(_) => debug!("rejecting incoming peer {}, too many peers", peer.addr),
+        }
+    }
+
+    /// Runs a connection in a new task, connecting first if there is no `stream` and remote
+    /// handshake. The permit is held for as long as the connection lives.
+    fn spawn_connection(
+        &mut self,
+        addr: SocketAddr,
+        stream: Option<(PeerStream, Handshake)>,
+        permit: OwnedSemaphorePermit,
+    ) {
+        let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+        let status = Arc::new(Mutex::new(PeerStatus::default()));
+        let outgoing = stream.is_none();
+        let flags = if outgoing { pex::FLAG_REACHABLE } else { 0 };
+        self.pool
+            .lock()
+            .unwrap()
+            .set_connected(addr, outgoing, flags);
+
+        let context = self.context().clone();
+        let extensions = self.extensions(addr);
+        let events = self.events.clone();
+        let utp = self.env.utp.clone();
+        let connection_status = status.clone();
+        let task = tokio::spawn(async move {
+            let _permit = permit;
+            let result = async {
+                let (stream, remote) = match stream {
+                    Some(stream) => stream,
+                    None => {
+                        let handshake = Handshake::new(context.info_hash, context.peer_id);
+                        let encryption = context.config.encryption;
+                        let connect =
+                            crate::peer::connect(addr, &handshake, encryption, utp.as_deref());
+                        let (stream, remote) =
+                            tokio::time::timeout(context.config.connect_timeout, connect)
+                                .await
+                                .map_err(|_| Error::Timeout)??;
+                        // trackers may return our own address
+                        if remote.peer_id == context.peer_id {
+                            return Err(Error::InvalidHandshake);
+                        }
+                        (stream, remote)
+                    }
+                };
+                let peer_id = remote.peer_id;
+                let client = Client::from_peer_id(&peer_id);
+                match client.as_ref() {
+                    Some(client) => debug!("connected to {} running {}", addr, client),
+                    None => debug!("connected to {} with peer id {:?}", addr, peer_id),
+                }
+                connection_status.lock().unwrap().client = client;
+                let info_hash = context.info_hash;
+                let bus = context.bus.clone();
+                bus.emit(Event::PeerConnected { info_hash, addr });
+                let result = Connection::new(
+                    addr,
+                    stream,
+                    &remote,
+                    context,
+                    connection_status,
+                    commands_rx,
+                    events.clone(),
+                )
+                .with_extensions(extensions)
+                .run()
+                .await;
+                bus.emit(Event::PeerDisconnected { info_hash, addr });
+                result
+            }
+            .await;
+
+            if let Err(e) = result {
+                debug!("connection with {} failed: {}", addr, e);
+            }
+            let _ = events.send(PeerEvent::Closed(addr)).await;
+        });
+        self.peers.insert(
+            addr,
+            PeerHandle {
+                commands: commands_tx,
+                task,
+                status,
+                connected_at: Instant::now(),
+                last_downloaded: 0,
+                last_uploaded: 0,
+                download_rate: 0,
+                upload_rate: 0,
+            },
+        );
+    }
+
+    /// The extensions offered to a new connection with `addr`, without PEX for private
+    /// torrents.
+    fn extensions(&self, addr: SocketAddr) -> Extensions {
+        let mut extensions = Extensions::new();
+        extensions.set_listen_port(self.env.port);
+        extensions.register(Box::new(MetadataExtension::with_metadata(
+            self.info_hash,
+            self.metadata.clone(),
+        )));
+        if pex::is_enabled(&self.meta_info.info) {
+            extensions.register(Box::new(PexExtension::new(self.pool.clone(), addr)));
+        }
+        extensions
+    }
+
+    fn update_rates(&mut self) {
+        let elapsed = self.last_rechoke.elapsed().as_secs_f64().max(1.0);
+        self.last_rechoke = Instant::now();
+        for peer in self.peers.values_mut() {
+            let status = peer.status.lock().unwrap();
+            peer.download_rate =
+                ((status.downloaded - peer.last_downloaded) as f64 / elapsed) as u64;
+            peer.upload_rate = ((status.uploaded - peer.last_uploaded) as f64 / elapsed) as u64;
+            peer.last_downloaded = status.downloaded;
+            peer.last_uploaded = status.uploaded;
+        }
+    }
+
+    fn rechoke(&mut self) {
+        let stats: Vec<PeerStats> = self
+            .peers
+            .iter()
+            .map(|(addr, peer)| {
+                let status = peer.status.lock().unwrap();
+                PeerStats {
+                    addr: *addr,
+                    interested: status.interested,
+                    am_interested: status.am_interested,
+                    download_rate: peer.download_rate,
+                    upload_rate: peer.upload_rate,
+                    connected_at: peer.connected_at,
+                    last_block: status.last_block,
+                }
+            })
+            .collect();
+
+        let rechoke = self
+            .choker
+            .rechoke(&stats, self.is_seeding(), Instant::now());
+        for addr in rechoke.unchoke {
+            if let Some(peer) = self.peers.get(&addr) {
+                let _ = peer.commands.send(PeerCommand::Unchoke);
+            }
+        }
+        for addr in rechoke.choke {
+            if let Some(peer) = self.peers.get(&addr) {
+                let _ = peer.commands.send(PeerCommand::Choke);
+            }
+        }
+    }
+}
+
+/// Waits for `tasks` until `deadline`, then aborts the ones still running.
+async fn join_until(tasks: &mut Vec<JoinHandle<()>>, deadline: tokio::time::Instant) {
+    let all = futures_util::future::join_all(tasks.iter_mut());
+    if tokio::time::timeout_at(deadline, all).await.is_err() {
+        warn!("{} tasks did not stop in time", tasks.len());
+        for task in tasks.iter() {
+            task.abort();
+        }
+    }
+    tasks.clear();
+}
@@ -0,0 +1,448 @@
+use crate::bitfield::Bitfield;
+use crate::error::Error;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Size of the blocks pieces are requested in.
+pub const BLOCK_LEN: u32 = 16 * 1024;
+
+/// A part of a piece, the unit of requests and piece messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Block {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+/// How much we want a file, or a piece: a piece has the highest priority of its files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Not downloaded, unless part of a piece shared with a wanted file.
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FromStr for Priority {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Priority, Error> {
+        match s {
+            "skip" => Ok(Priority::Skip),
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(Error::InvalidPriority(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Priority::Skip => "skip",
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        };
+        f.pad(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BlockState {
+    Free,
+    /// Requested from this many peers, more than one only in endgame mode.
+    Requested(u32),
+    Received,
+}
+
+/// Chooses which blocks to request from which peer: pieces already started are finished
+/// first, then the rarest pieces among the connected peers are started, higher priorities
+/// first. Once every missing block is requested, blocks are requested again from other
+/// peers (endgame mode). Skipped pieces are never picked.
+#[derive(Debug)]
+pub struct PiecePicker {
+    have: Bitfield,
+    /// Number of connected peers having each piece.
+    availability: Vec<u32>,
+    priorities: Vec<Priority>,
+    partial: HashMap<u32, Vec<BlockState>>,
+    piece_length: u64,
+    total_length: u64,
+}
+
+impl PiecePicker {
+    pub fn new(have: Bitfield, piece_length: u64, total_length: u64) -> PiecePicker {
+        PiecePicker {
+            availability: vec![0; have.len()],
+            priorities: vec![Priority::Normal; have.len()],
+            have,
+            partial: HashMap::new(),
+            piece_length,
+            total_length,
+        }
+    }
+
+    /// The pieces we have and verified.
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.have.is_complete()
+    }
+
+    /// Whether we have every piece that is not skipped.
+    pub fn is_done(&self) -> bool {
+        (0..self.have.len()).all(|i| self.have.get(i) || self.priorities[i] == Priority::Skip)
+    }
+
+    /// Sets the priority of each piece, see `Storage::piece_priorities`.
+    pub fn set_priorities(&mut self, priorities: Vec<Priority>) {
+        assert_eq!(priorities.len(), self.have.len());
+        self.priorities = priorities;
+    }
+
+    /// Bytes still missing.
+    pub fn left(&self) -> u64 {
+        self.have.iter_set().fold(self.total_length, |left, i| {
+            left - self.piece_size(i as u32)
+        })
+    }
+
+    pub fn peer_has(&mut self, bitfield: &Bitfield) {
+        for i in bitfield.iter_set() {
+            self.availability[i] += 1;
+        }
+    }
+
+    pub fn peer_has_piece(&mut self, index: u32) {
+        self.availability[index as usize] += 1;
+    }
+
+    /// Forgets the pieces of a disconnected peer.
+    pub fn peer_lost(&mut self, bitfield: &Bitfield) {
+        for i in bitfield.iter_set() {
+            self.availability[i] = self.availability[i].saturating_sub(1);
+        }
+    }
+
+    /// Whether a peer has any piece we are missing and want.
+    pub fn is_interesting(&self, bitfield: &Bitfield) -> bool {
+        bitfield.iter_set().any(|i| self.is_wanted(i))
+    }
+
+    /// Picks up to `count` blocks to request from a peer having `bitfield`, skipping
+    /// blocks already requested from it.
+    pub fn pick(&mut self, bitfield: &Bitfield, count: usize, requested: &[Block]) -> Vec<Block> {
+        let mut blocks = vec![];
+
+        let mut partial: Vec<u32> = self
+            .partial
+            .keys()
+            .filter(|i| bitfield.get(**i as usize) && self.is_wanted(**i as usize))
+            .copied()
+            .collect();
+        partial.sort_unstable_by_key(|i| (Reverse(self.priorities[*i as usize]), *i));
+        for index in partial {
+            self.pick_free_blocks(index, count, &mut blocks);
+        }
+
+        if blocks.len() < count {
+            let mut candidates: Vec<u32> = bitfield
+                .iter_set()
+                .filter(|i| self.is_wanted(*i) && !self.partial.contains_key(&(*i as u32)))
+                .map(|i| i as u32)
+                .collect();
+            candidates.sort_by_key(|i| {
+                let i = *i as usize;
+                (Reverse(self.priorities[i]), self.availability[i], i)
+            });
+            for index in candidates {
+                if blocks.len() >= count {
+                    break;
+                }
+                let num_blocks = self.piece_size(index).div_ceil(BLOCK_LEN as u64) as usize;
+                self.partial
+                    .insert(index, vec![BlockState::Free; num_blocks]);
+                self.pick_free_blocks(index, count, &mut blocks);
+            }
+        }
+
+        if blocks.is_empty() {
+            self.pick_endgame(bitfield, count, requested, &mut blocks);
+        }
+        blocks
+    }
+
+    /// Picks up to `count` free blocks of the `suggested` pieces a peer having `bitfield`
+    /// would rather send, regardless of rarity. Never enters endgame mode.
+    pub fn pick_suggested(
+        &mut self,
+        bitfield: &Bitfield,
+        suggested: &[u32],
+        count: usize,
+    ) -> Vec<Block> {
+        let mut blocks = vec![];
+        for index in suggested.iter().copied() {
+            if blocks.len() >= count {
+                break;
+            }
+            if !bitfield.get(index as usize) || !self.is_wanted(index as usize) {
+                continue;
+            }
+            if !self.partial.contains_key(&index) {
+                let num_blocks = self.piece_size(index).div_ceil(BLOCK_LEN as u64) as usize;
+                self.partial
+                    .insert(index, vec![BlockState::Free; num_blocks]);
+            }
+            self.pick_free_blocks(index, count, &mut blocks);
+        }
+        blocks
+    }
+
+    /// A request was not answered, e.g. the peer choked us or disconnected.
+    pub fn cancel(&mut self, block: &Block) {
+        if let Some(state) = self
+            .partial
+            .get_mut(&block.index)
+            .and_then(|blocks| blocks.get_mut((block.begin / BLOCK_LEN) as usize))
+        {
+            *state = match *state {
+                BlockState::Requested(n) if n > 1 => BlockState::Requested(n - 1),
+                BlockState::Requested(_) => BlockState::Free,
+                s => s,
+            };
+        }
+    }
+
+    /// Marks a block as received, returns true when it was the last missing block of its
+    /// piece, which must then be verified.
+    pub fn received(&mut self, block: &Block) -> bool {
+        let blocks = match self.partial.get_mut(&block.index) {
+            Some(blocks) => blocks,
+            None => return false,
+        };
+        match blocks.get_mut((block.begin / BLOCK_LEN) as usize) {
+            Some(state) if *state != BlockState::Received => *state = BlockState::Received,
+            _ => return false,
+        }
+        blocks.iter().all(|s| *s == BlockState::Received)
+    }
+
+    /// Records the result of checking a complete piece, a piece failing the check is
+    /// downloaded again.
+    pub fn verified(&mut self, index: u32, valid: bool) {
+        self.partial.remove(&index);
+        if valid {
+            self.have.set(index as usize, true);
+        }
+    }
+
+    fn is_wanted(&self, index: usize) -> bool {
+        !self.have.get(index) && self.priorities[index] != Priority::Skip
+    }
+
+    fn piece_size(&self, index: u32) -> u64 {
+        let start = index as u64 * self.piece_length;
+        std::cmp::min(self.piece_length, self.total_length - start)
+    }
+
+    fn block(&self, index: u32, block: usize) -> Block {
+        let begin = block as u32 * BLOCK_LEN;
+        Block {
+            index,
+            begin,
+            length: std::cmp::min(BLOCK_LEN as u64, self.piece_size(index) - begin as u64) as u32,
+        }
+    }
+
+    fn pick_free_blocks(&mut self, index: u32, count: usize, picked: &mut Vec<Block>) {
+        let free: Vec<usize> = self.partial[&index]
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| **s == BlockState::Free)
+            .map(|(i, _)| i)
+            .take(count - picked.len())
+            .collect();
+        for i in free {
+            self.partial.get_mut(&index).unwrap()[i] = BlockState::Requested(1);
+            picked.push(self.block(index, i));
+        }
+    }
+
+    fn pick_endgame(
+        &mut self,
+        bitfield: &Bitfield,
+        count: usize,
+        requested: &[Block],
+        picked: &mut Vec<Block>,
+    ) {
+        let mut indices: Vec<u32> = self.partial.keys().copied().collect();
+        indices.sort_unstable();
+        for index in indices {
+            if !bitfield.get(index as usize) || !self.is_wanted(index as usize) {
+                continue;
+            }
+            for i in 0..self.partial[&index].len() {
+                if picked.len() >= count {
+                    return;
+                }
+                let block = self.block(index, i);
+                let state = &mut self.partial.get_mut(&index).unwrap()[i];
+                if let BlockState::Requested(n) = *state {
+                    if !requested.contains(&block) {
+                        *state = BlockState::Requested(n + 1);
+                        picked.push(block);
+                    }
+                }
+            }
+        }
+    }
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_rarest_first() {
+    // 3 pieces of 2 blocks, the last piece has a single short block
+    let mut picker = PiecePicker::new(Bitfield::new(3), 32 * 1024, 64 * 1024 + 100);
+    let mut all = Bitfield::new(3);
+    for i in 0..3 {
+        all.set(i, true);
+    }
+    let mut common = Bitfield::new(3);
+    common.set(0, true);
+    picker.peer_has(&all);
+    picker.peer_has(&common);
+    assert_eq!(picker.left(), 64 * 1024 + 100);
+
+    // pieces 1 and 2 are the rarest
+    let blocks = picker.pick(&all, 1, &[]);
+    assert_eq!(
+        blocks,
+        vec![Block {
+            index: 1,
+            begin: 0,
+            length: BLOCK_LEN
+        }]
+    );
+    // the started piece is finished first
+    let blocks = picker.pick(&all, 2, &[]);
+    assert_eq!(blocks[0].index, 1);
+    assert_eq!(blocks[0].begin, BLOCK_LEN);
+    assert_eq!(
+        blocks[1],
+        Block {
+            index: 2,
+            begin: 0,
+            length: 100
+        }
+    );
+}
+
+#[test]
+fn test_receive_verify_and_endgame() {
+    let mut picker = PiecePicker::new(Bitfield::new(1), 32 * 1024, 32 * 1024);
+    let mut all = Bitfield::new(1);
+    all.set(0, true);
+    assert!(picker.is_interesting(&all));
+
+    let first = picker.pick(&all, 10, &[]);
+    assert_eq!(first.len(), 2);
+
+    // endgame: the same blocks are requested from another peer, not twice from the same
+    assert!(picker.pick(&all, 10, &first).is_empty());
+    let second = picker.pick(&all, 10, &[]);
+    assert_eq!(second, first);
+
+    assert!(!picker.received(&first[0]));
+    assert!(!picker.received(&first[0]));
+    assert!(picker.received(&first[1]));
+
+    // a piece failing the hash check is downloaded again
+    picker.verified(0, false);
+    assert_eq!(picker.pick(&all, 10, &[]).len(), 2);
+    for block in first.iter() {
+        picker.received(block);
+    }
+    picker.verified(0, true);
+    assert!(picker.is_complete());
+    assert_eq!(picker.left(), 0);
+    assert!(!picker.is_interesting(&all));
+    assert!(picker.pick(&all, 10, &[]).is_empty());
+}
+
+#[test]
+fn test_cancelled_blocks_are_picked_again() {
+    let mut picker = PiecePicker::new(Bitfield::new(1), 16 * 1024, 16 * 1024);
+    let mut all = Bitfield::new(1);
+    all.set(0, true);
+
+    let blocks = picker.pick(&all, 1, &[]);
+    picker.cancel(&blocks[0]);
+    assert_eq!(picker.pick(&all, 1, &[]), blocks);
+}
+
+#[test]
+fn test_priorities() {
+    let mut picker = PiecePicker::new(Bitfield::new(4), 16 * 1024, 64 * 1024);
+    let mut all = Bitfield::new(4);
+    for i in 0..4 {
+        all.set(i, true);
+    }
+    let mut rare = Bitfield::new(4);
+    rare.set(3, true);
+    picker.peer_has(&all);
+    picker.peer_has(&all);
+    picker.peer_has(&rare);
+    picker.set_priorities(vec![
+        Priority::Skip,
+        Priority::High,
+        Priority::Low,
+        Priority::Normal,
+    ]);
+
+    // higher priorities come before rarity, skipped pieces never come
+    let indices: Vec<u32> = picker.pick(&all, 10, &[]).iter().map(|b| b.index).collect();
+    assert_eq!(indices, vec![1, 3, 2]);
+    let mut first = Bitfield::new(4);
+    first.set(0, true);
+    assert!(!picker.is_interesting(&first));
+
+    for index in 1..4 {
+        picker.received(&picker.block(index, 0));
+        picker.verified(index, true);
+    }
+    assert!(picker.is_done());
+    assert!(!picker.is_complete());
+
+    picker.set_priorities(vec![Priority::Normal; 4]);
+    assert!(!picker.is_done());
+    assert!(picker.is_interesting(&first));
+}
+
+#[test]
+fn test_suggested_pieces() {
+    let mut picker = PiecePicker::new(Bitfield::new(3), 32 * 1024, 96 * 1024);
+    let all = Bitfield::full(3);
+    picker.set_priorities(vec![Priority::Skip, Priority::High, Priority::Low]);
+
+    // suggested pieces come before priorities, unless skipped
+    let suggested = picker.pick_suggested(&all, &[0, 2], 1);
+    assert_eq!(suggested, vec![picker.block(2, 0)]);
+    // the started piece is finished, no endgame once its blocks are requested
+    assert_eq!(
+        picker.pick_suggested(&all, &[2], 10),
+        vec![picker.block(2, 1)]
+    );
+    assert!(picker.pick_suggested(&all, &[2], 10).is_empty());
+    assert_eq!(picker.pick(&all, 1, &[])[0].index, 1);
+}
@@ -0,0 +1,389 @@
+use crate::bitfield::Bitfield;
+use crate::error::Error;
+use crate::http::{self, Url};
+use crate::model::{MetaInfo, TorrentFile};
+use crate::peer::connection::on_storage;
+use crate::rate_limit;
+use crate::torrent::picker::Block;
+use crate::torrent::{PeerEvent, TorrentContext};
+use log::{debug, warn};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+/// Blocks asked for at once, in as few range requests as they allow.
+const REQUEST_BLOCKS: usize = 16;
+/// How long a seed waits after its first failure, doubled for each failure in a row.
+const MIN_BACKOFF: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+/// How long a seed waits for the peers to leave it blocks to download.
+const IDLE_INTERVAL: Duration = Duration::from_secs(5);
+/// Time for a server to answer a request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// A server ignoring ranges sends the whole file, accepted for the files at most this much
+/// larger than the range asked for. Larger files would be buffered whole for each request.
+const WHOLE_FILE_SLACK: u64 = 256 * 1024;
+
+/// Downloads pieces from a plain HTTP server (BEP 19), sharing the piece picker with the
+/// peer connections. The url is the file of a single file torrent, or the directory
+/// holding the torrent's directory for a multi file torrent.
+pub struct WebSeed {
+    url: String,
+    /// The url of each file, along with where it lies in the torrent.
+    files: Vec<(Url, TorrentFile)>,
+    context: Arc<TorrentContext>,
+    events: mpsc::Sender<PeerEvent>,
+    /// Has every piece, so that the picker offers any missing block.
+    bitfield: Bitfield,
+    failures: u32,
+}
+
+impl WebSeed {
+    /// Fails for urls other than `http://`.
+    pub fn new(
+        url: &str,
+        meta_info: &MetaInfo,
+        context: Arc<TorrentContext>,
+        events: mpsc::Sender<PeerEvent>,
+    ) -> Result<WebSeed, Error> {
+        let info = &meta_info.info;
+        let single_file = info.is_single_file();
+        let files = info
+            .file_list()
+            .into_iter()
+            .map(|f| {
+                let mut file_url = url.to_owned();
+                if single_file {
+                    if file_url.ends_with('/') {
+                        file_url.push_str(&http::percent_encode(&info.name));
+                    }
+                } else {
+                    if !file_url.ends_with('/') {
+                        file_url.push('/');
+                    }
+                    let path = std::iter::once(&info.name).chain(f.path.iter());
+                    let segments: Vec<String> = path.map(|s| http::percent_encode(s)).collect();
+                    file_url.push_str(&segments.join("/"));
+                }
+                Ok((file_url.parse()?, f))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let bitfield = Bitfield::full(context.storage.num_pieces());
+        Ok(WebSeed {
+            url: url.to_owned(),
+            files,
+            context,
+            events,
+            bitfield,
+            failures: 0,
+        })
+    }
+
+    /// Downloads until `shutdown` is sent or dropped, waiting longer after each failure.
+    pub async fn run(mut self, mut shutdown: oneshot::Receiver<()>) {
+        loop {
+            let blocks =
+                self.context
+                    .picker
+                    .lock()
+                    .unwrap()
+                    .pick(&self.bitfield, REQUEST_BLOCKS, &[]);
+            let wait = if blocks.is_empty() {
+                IDLE_INTERVAL
+            } else {
+                let fetched = tokio::select! {
+                    fetched = self.fetch(&blocks) => fetched,
+                    _ = &mut shutdown => {
+                        self.cancel(&blocks);
+                        return;
+                    }
+                };
+                match fetched {
+                    Ok(()) => {
+                        self.failures = 0;
+                        Duration::from_secs(0)
+                    }
+                    Err(e) => {
+                        self.cancel(&blocks);
+                        self.failures += 1;
+                        let wait = backoff(self.failures);
+                        warn!(
+                            "web seed {} failed: {}, retrying in {:?}",
+                            self.url, e, wait
+                        );
+                        wait
+                    }
+                }
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = &mut shutdown => return,
+            }
+        }
+    }
+
+    /// Downloads and writes the blocks, in one range of the torrent per run of adjacent
+    /// blocks of a piece.
+    async fn fetch(&self, blocks: &[Block]) -> Result<(), Error> {
+        let mut blocks = blocks.to_vec();
+        blocks.sort_by_key(|b| (b.index, b.begin));
+        let mut runs: Vec<Vec<Block>> = vec![];
+        for block in blocks {
+            match runs.last_mut() {
+                Some(run)
+                    if run.last().is_some_and(|last| {
+                        last.index == block.index && last.begin + last.length == block.begin
+                    }) =>
+                {
+                    run.push(block)
+                }
+                _ => runs.push(vec![block]),
+            }
+        }
+
+        for run in runs {
+            let length: u64 = run.iter().map(|b| b.length as u64).sum();
+            let limits = [
+                &self.context.limits.download,
+                &self.context.session_limits.download,
+            ];
+            rate_limit::acquire(&limits, length).await;
+
+            let piece_length = self.context.storage.piece_length(0);
+            let start = run[0].index as u64 * piece_length + run[0].begin as u64;
+            let data = self.read_range(start, length as usize).await?;
+            self.context.add_downloaded(length);
+
+            let mut offset = 0;
+            for block in run {
+                let data = data[offset..offset + block.length as usize].to_vec();
+                offset += block.length as usize;
+                self.store(block, data).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Gets the bytes of the torrent from `start`, with a range request to each file they
+    /// span. The bytes of padding between files are zeros.
+    async fn read_range(&self, start: u64, length: usize) -> Result<Vec<u8>, Error> {
+        let end = start + length as u64;
+        let mut data = vec![0u8; length];
+        let files = self
+            .files
+            .iter()
+            .filter(|(_, f)| f.offset < end && f.offset + f.length > start);
+        for (url, f) in files {
+            let from = std::cmp::max(start, f.offset);
+            let to = std::cmp::min(end, f.offset + f.length);
+            let (first, last) = (from - f.offset, to - f.offset - 1);
+
+            let len = (to - from) as usize;
+            let max_len = std::cmp::min(f.length, len as u64 + WHOLE_FILE_SLACK);
+            let response = tokio::time::timeout(
+                REQUEST_TIMEOUT,
+                http::get(url, Some((first, last)), max_len as usize),
+            )
+            .await
+            .map_err(|_| Error::Timeout)??;
+            let body = match response.status {
+                206 => &response.body[..],
+                // the whole file of a small file, when the server ignores ranges
+                200 if response.body.len() as u64 == f.length => {
+                    &response.body[first as usize..=last as usize]
+                }
+                status => {
+                    return Err(Error::Http(format!(
+                        "{}{} answered {}",
+                        url.host, url.path, status
+                    )))
+                }
+            };
+            if body.len() != len {
+                return Err(Error::Http(format!(
+                    "{}{} sent {} bytes instead of {}",
+                    url.host,
+                    url.path,
+                    body.len(),
+                    len
+                )));
+            }
+            let offset = (from - start) as usize;
+            data[offset..offset + len].copy_from_slice(body);
+        }
+        Ok(data)
+    }
+
+    /// Writes a block, verifying its piece once complete. A piece failing the check counts
+    /// as a failure of the seed.
+    async fn store(&self, block: Block, data: Vec<u8>) -> Result<(), Error> {
+        let (index, begin) = (block.index, block.begin);
+        on_storage(&self.context.storage, move |storage| {
+            storage.write_block(index, begin, &data)
+        })
+        .await?;
+        let complete = self.context.picker.lock().unwrap().received(&block);
+        if !complete {
+            return Ok(());
+        }
+        let valid = on_storage(&self.context.storage, move |storage| {
+            storage.verify_piece(index)
+        })
+        .await?;
+        self.context.picker.lock().unwrap().verified(index, valid);
+        let _ = self
+            .events
+            .send(PeerEvent::PieceVerified { index, valid })
+            .await;
+        if valid {
+            debug!("web seed {} sent piece {}", self.url, index);
+            Ok(())
+        } else {
+            Err(Error::Http(format!(
+                "piece {} failed the hash check",
+                index
+            )))
+        }
+    }
+
+    fn cancel(&self, blocks: &[Block]) {
+        let mut picker = self.context.picker.lock().unwrap();
+        for block in blocks {
+            picker.cancel(block);
+        }
+    }
+}
+
+/// How long to wait after `failures` failures in a row.
+fn backoff(failures: u32) -> Duration {
+    let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+    std::cmp::min(MIN_BACKOFF.saturating_mul(factor), MAX_BACKOFF)
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_download_from_web_seed() {
+    use crate::create::TorrentBuilder;
+    use crate::storage::Storage;
+
+    let dir = std::env::temp_dir().join(format!("thor-webseed-{}", rand::random::<u32>()));
+    let source = dir.join("www").join("my data");
+    std::fs::create_dir_all(source.join("sub")).unwrap();
+    let contents: Vec<u8> = (0..70_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(source.join("a"), &contents[..20_000]).unwrap();
+    std::fs::write(source.join("sub").join("b c"), &contents[20_000..]).unwrap();
+    let meta_info = TorrentBuilder::new(&source)
+        .piece_length(32 * 1024)
+        .build()
+        .unwrap();
+
+    let addr = http::serve_files(dir.join("www")).await;
+    let storage = Arc::new(Storage::new(&meta_info, &dir.join("download")));
+    let have = Bitfield::new(storage.num_pieces());
+    let info_hash = meta_info.info.info_hash();
+    let context = Arc::new(TorrentContext::new(info_hash, [1; 20], storage, have));
+
+    let (events_tx, mut events) = mpsc::channel(16);
+    let url = format!("http://{}", addr);
+    let seed = WebSeed::new(&url, &meta_info, context.clone(), events_tx.clone()).unwrap();
+    let (_shutdown, shutdown_rx) = oneshot::channel();
+    tokio::spawn(seed.run(shutdown_rx));
+    for _ in 0..3 {
+        match events.recv().await {
+            Some(PeerEvent::PieceVerified { valid: true, .. }) => {}
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+    assert!(context.picker.lock().unwrap().is_complete());
+    assert_eq!(context.downloaded(), 70_000);
+    let b = std::fs::read(dir.join("download/my data/sub/b c")).unwrap();
+    assert_eq!(b, &contents[20_000..]);
+
+    // a seed without the files fails, and gives its blocks back to the picker
+    let (context, meta_info) = {
+        let storage = Arc::new(Storage::new(&meta_info, &dir.join("other")));
+        let have = Bitfield::new(storage.num_pieces());
+        let context = TorrentContext::new(info_hash, [1; 20], storage, have);
+        (Arc::new(context), meta_info)
+    };
+    let url = format!("http://{}/missing/", addr);
+    let seed = WebSeed::new(&url, &meta_info, context.clone(), events_tx).unwrap();
+    let blocks = context.picker.lock().unwrap().pick(&seed.bitfield, 2, &[]);
+    assert!(seed.fetch(&blocks).await.is_err());
+    seed.cancel(&blocks);
+    assert_eq!(
+        context.picker.lock().unwrap().pick(&seed.bitfield, 2, &[]),
+        blocks
+    );
+
+    assert!(WebSeed::new(
+        "ftp://host/",
+        &meta_info,
+        context.clone(),
+        mpsc::channel(1).0
+    )
+    .is_err());
+    assert_eq!(backoff(1), MIN_BACKOFF);
+    assert_eq!(backoff(3), MIN_BACKOFF * 4);
+    assert_eq!(backoff(100), MAX_BACKOFF);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_server_ignoring_ranges() {
+    use crate::create::TorrentBuilder;
+    use crate::storage::Storage;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let dir = std::env::temp_dir().join(format!("thor-ranges-{}", rand::random::<u32>()));
+    let source = dir.join("data");
+    std::fs::create_dir_all(&source).unwrap();
+    let big = vec![1u8; 1024 * 1024];
+    let small: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+    std::fs::write(source.join("big"), &big).unwrap();
+    std::fs::write(source.join("small"), &small).unwrap();
+    let meta_info = TorrentBuilder::new(&source)
+        .piece_length(16 * 1024)
+        .build()
+        .unwrap();
+
+    // answers every request with the whole file
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (big_body, small_body) = (big.clone(), small.clone());
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![0u8; 1024];
+            let len = socket.read(&mut request).await.unwrap();
+            let request = String::from_utf8_lossy(&request[..len]).into_owned();
+            let body = if request.contains("/big ") {
+                &big_body
+            } else {
+                &small_body
+            };
+            let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            let _ = socket.write_all(header.as_bytes()).await;
+            let _ = socket.write_all(body).await;
+        }
+    });
+
+    let storage = Arc::new(Storage::new(&meta_info, &dir.join("download")));
+    let have = Bitfield::new(storage.num_pieces());
+    let info_hash = meta_info.info.info_hash();
+    let context = Arc::new(TorrentContext::new(info_hash, [1; 20], storage, have));
+    let url = format!("http://{}/", addr);
+    let seed = WebSeed::new(&url, &meta_info, context, mpsc::channel(1).0).unwrap();
+
+    // the big file is not buffered whole for a block
+    assert!(seed.read_range(0, 16 * 1024).await.is_err());
+    let start = big.len() as u64 + 100;
+    assert_eq!(seed.read_range(start, 200).await.unwrap(), &small[100..300]);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
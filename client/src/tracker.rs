@@ -1,35 +1,98 @@
+use crate::config::TrackerConfig;
 use crate::error::Error;
-use crate::model::InfoDict;
 use crate::peer::Peer;
 use async_trait::async_trait;
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use log::{debug, error, warn};
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use log::{debug, error};
 use rand::Rng;
-use sha1::Digest;
-use std::net::SocketAddr;
-use std::ops::RangeInclusive;
+use std::net::{IpAddr, SocketAddr};
 use std::time::Duration;
 use tokio::io;
 use tokio::net::UdpSocket;
 use tokio::time::timeout;
 
 const MAGIC_CONSTANT: i64 = 0x41727101980;
-const RECV_BUF_SIZE: usize = 1024;
 
 const ACTION_CONNECT: i32 = 0;
 const ACTION_ANNOUNCE: i32 = 1;
 const ACTION_SCRAPE: i32 = 2;
 const ACTION_ERROR: i32 = 3;
 
-// const EVENT_NONE: i32 = 0;
-// const EVENT_COMPLETED: i32 = 1;
-const EVENT_STARTED: i32 = 2;
-// const EVENT_STOPPED: i32 = 3;
+/// The event of an announce, sent when a download starts, completes or stops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    None = 0,
+    Completed = 1,
+    Started = 2,
+    Stopped = 3,
+}
+
+impl AnnounceEvent {
+    pub fn from_i32(event: i32) -> Option<AnnounceEvent> {
+        match event {
+            0 => Some(AnnounceEvent::None),
+            1 => Some(AnnounceEvent::Completed),
+            2 => Some(AnnounceEvent::Started),
+            3 => Some(AnnounceEvent::Stopped),
+            _ => None,
+        }
+    }
+}
+
+/// What we tell the tracker about our download.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnnounceParams {
+    pub peer_id: [u8; 20],
+    pub downloaded: u64,
+    pub left: u64,
+    pub uploaded: u64,
+    pub event: AnnounceEvent,
+}
+
+/// The numbers of a torrent in a scrape response.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScrapeStats {
+    pub seeders: u32,
+    pub completed: u32,
+    pub leechers: u32,
+}
+
+/// A request received by a tracker, see `read_request`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    Connect {
+        transaction_id: i32,
+    },
+    Announce(AnnounceRequest),
+    Scrape {
+        connection_id: i64,
+        transaction_id: i32,
+        info_hashes: Vec<[u8; 20]>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnnounceRequest {
+    pub connection_id: i64,
+    pub transaction_id: i32,
+    pub info_hash: [u8; 20],
+    pub params: AnnounceParams,
+    /// The address the peer asks to be announced with, 0 for the sender's address.
+    pub ip: u32,
+    pub key: u32,
+    /// Negative when the peer lets the tracker decide.
+    pub num_want: i32,
+    pub port: u16,
+}
 
 #[async_trait]
 pub trait TrackerClient {
     /// Allows the user to announce its existence to the tracker that this client represents.
-    async fn announce(&mut self, info_dict: &InfoDict) -> Result<AnnounceResponsePayload, Error>;
+    async fn announce(
+        &mut self,
+        info_hash: &[u8; 20],
+        params: &AnnounceParams,
+    ) -> Result<AnnounceResponsePayload, Error>;
 }
 
 #[derive(Debug)]
@@ -38,12 +101,12 @@ pub struct Connection {
     socket: UdpSocket,
     id: i64,
     port: u16,
+    config: TrackerConfig,
 }
 
 #[derive(Debug)]
 struct ConnectResponsePayload {
     transaction_id: i32,
-    action: i32,
     connection_id: i64,
 }
 
@@ -56,7 +119,7 @@ enum ConnectResponse {
 #[derive(Debug)]
 pub struct AnnounceResponsePayload {
     transaction_id: i32,
-    interval: std::time::Duration,
+    pub interval: std::time::Duration,
     num_leechers: i32,
     num_seeders: i32,
     pub peers: Vec<Peer>,
@@ -69,66 +132,80 @@ enum AnnounceResponse {
 }
 
 impl Connection {
-    pub async fn new(addr: SocketAddr) -> Result<Connection, Error> {
-        if let Some((mut socket, port)) = try_bind_socket().await {
-            socket.connect(&addr).await.map_err(Error::Tokio)?;
-            let connection_id = connect(&mut socket).await?;
-
-            debug!(
-                "socket connected to addr {} with id {}",
-                addr, connection_id
-            );
-
-            Ok(Connection {
-                addr: addr,
-                socket: socket,
-                id: connection_id,
-                port: port,
-            })
-        } else {
-            Err(Error::PortsExhausted)
-        }
+    /// Connects to the tracker at `addr`, `port` is the port peers can reach us on.
+    pub async fn new(
+        addr: SocketAddr,
+        port: u16,
+        config: TrackerConfig,
+    ) -> Result<Connection, Error> {
+        let mut socket = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0))).await?;
+        socket.connect(&addr).await.map_err(Error::Tokio)?;
+        let connection_id = connect(&mut socket, &config).await?;
+
+        debug!(
+            "socket connected to addr {} with id {}",
+            addr, connection_id
+        );
+
+        Ok(Connection {
+            addr,
+            socket,
+            id: connection_id,
+            port,
+            config,
+        })
+    }
+
+    /// Address of the tracker this connection talks to.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
     }
 
-    pub async fn scrape(&mut self) -> Result<(), Error> {
+    /// Asks the tracker for the numbers of up to 74 torrents, in the same order.
+    pub async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>, Error> {
         let transaction_id = get_transaction_id();
-        let scrape_req = get_scrape_request(self.id, transaction_id);
+        let scrape_req = get_scrape_request(self.id, transaction_id, info_hashes);
 
         self.socket.send(&scrape_req).await?;
-        let mut buf = [0u8; RECV_BUF_SIZE];
+        let mut buf = vec![0u8; self.config.recv_buf_size];
 
-        let len = timeout(Duration::from_secs(2), self.socket.recv(&mut buf))
+        let len = timeout(self.config.timeout, self.socket.recv(&mut buf))
             .await
             .map_err(|e| {
-                error!("attempt to receive announce response timed out: {}", e);
+                error!("attempt to receive scrape response timed out: {}", e);
                 Error::Timeout
             })??;
 
         debug!("[scrape] read {} bytes from dgram", len);
-        assert!(len >= 20);
-
-        Ok(())
+        let (recv_transaction_id, stats) = read_scrape_response(&buf[..len])?;
+        if recv_transaction_id != transaction_id {
+            return Err(Error::IncorrectTransactionId);
+        }
+        Ok(stats)
     }
 }
 
 #[async_trait]
 impl TrackerClient for Connection {
-    async fn announce(&mut self, info_dict: &InfoDict) -> Result<AnnounceResponsePayload, Error> {
-        let hashed_info_dict = get_hashed_info_dict(info_dict);
+    async fn announce(
+        &mut self,
+        info_hash: &[u8; 20],
+        params: &AnnounceParams,
+    ) -> Result<AnnounceResponsePayload, Error> {
         let transaction_id = get_transaction_id();
-        let peer_id = get_peer_id();
         let announce_req = get_announce_request(
             self.id,
             transaction_id,
             self.port,
-            &hashed_info_dict,
-            &peer_id,
+            self.config.num_want,
+            info_hash,
+            params,
         );
 
         self.socket.send(&announce_req).await?;
-        let mut buf = [0u8; RECV_BUF_SIZE];
+        let mut buf = vec![0u8; self.config.recv_buf_size];
 
-        let len = timeout(Duration::from_secs(2), self.socket.recv(&mut buf))
+        let len = timeout(self.config.timeout, self.socket.recv(&mut buf))
             .await
             .map_err(|e| {
                 error!("attempt to receive announce response timed out: {}", e);
@@ -164,43 +241,7 @@ impl TrackerClient for Connection {
     }
 }
 
-fn get_hashed_info_dict(info_dict: &InfoDict) -> Vec<u8> {
-    let info_dict_bytes =
-        bencoding::to_bytes(info_dict).expect("info dict should not fail to encode");
-    {
-        use std::io::Write;
-        let mut f = std::fs::File::create("rust_myfile").unwrap();
-        f.write_all(&info_dict_bytes).unwrap();
-    }
-
-    let mut hasher = sha1::Sha1::new();
-    hasher.update(info_dict_bytes);
-
-    let bytes = &hasher.finalize();
-    assert!(bytes.len() == 20);
-
-    debug!("info_hash: {:02x}", &bytes);
-
-    bytes.to_vec()
-}
-
-async fn try_bind_socket() -> Option<(UdpSocket, u16)> {
-    let port_range: RangeInclusive<u16> = 6881..=6889;
-    for port in port_range {
-        let local_addr: SocketAddr = format!("0.0.0.0:{}", port).parse().ok()?;
-        let socket = match UdpSocket::bind(&local_addr).await {
-            Ok(s) => s,
-            Err(e) => {
-                warn!("failed to bind to socket on port {}: {}", port, e);
-                continue;
-            }
-        };
-        return Some((socket, port));
-    }
-    None
-}
-
-async fn connect(socket: &mut UdpSocket) -> Result<i64, Error> {
+async fn connect(socket: &mut UdpSocket, config: &TrackerConfig) -> Result<i64, Error> {
     let transaction_id = get_transaction_id();
     let connect_req = get_connect_request(transaction_id);
 
@@ -210,9 +251,9 @@ async fn connect(socket: &mut UdpSocket) -> Result<i64, Error> {
     );
     socket.send(&connect_req).await.map_err(Error::Tokio)?;
 
-    let mut buf = [0u8; RECV_BUF_SIZE];
+    let mut buf = vec![0u8; config.recv_buf_size];
 
-    let len = timeout(Duration::from_secs(2), socket.recv(&mut buf))
+    let len = timeout(config.timeout, socket.recv(&mut buf))
         .await
         .map_err(|e| {
             error!("attempt to connect timed out: {}", e);
@@ -234,24 +275,6 @@ async fn connect(socket: &mut UdpSocket) -> Result<i64, Error> {
     }
 }
 
-fn get_peer_id() -> Vec<u8> {
-    const PEER_ID_SIZE: usize = 20;
-
-    let mut res = vec![];
-    std::io::Write::write(&mut res, b"TH-0.1.0---").unwrap();
-
-    let bytes_remaining = PEER_ID_SIZE - res.len();
-    for _ in 0..bytes_remaining {
-        // generate a random ascii byte
-        let byte = rand::thread_rng().gen::<u8>();
-        res.write_u8(byte).unwrap();
-    }
-
-    // the peer id should have 20 bytes in size
-    assert!(res.len() == PEER_ID_SIZE);
-    res
-}
-
 fn get_transaction_id() -> i32 {
     // A transaction id is just a random i32
     rand::thread_rng().gen::<i32>()
@@ -280,8 +303,7 @@ fn read_connect_response(buf: &[u8], nread: usize) -> Result<ConnectResponse, Er
         assert!(nread == 16);
         let connection_id = reader.read_i64::<BigEndian>()?;
         Ok(ConnectResponse::Payload(ConnectResponsePayload {
-            action: action,
-            connection_id: connection_id,
+            connection_id,
             transaction_id: recv_transaction_id,
         }))
     } else if action == ACTION_ERROR {
@@ -299,12 +321,12 @@ fn get_announce_request(
     connection_id: i64,
     transaction_id: i32,
     listening_port: u16,
+    num_want: u32,
     info_hash: &[u8],
-    peer_id: &[u8],
+    params: &AnnounceParams,
 ) -> Vec<u8> {
     use std::io::Write;
     assert!(info_hash.len() == 20);
-    assert!(peer_id.len() == 20);
 
     let mut writer = vec![];
     writer.write_i64::<BigEndian>(connection_id).unwrap(); // connection_id
@@ -312,15 +334,19 @@ fn get_announce_request(
     writer.write_i32::<BigEndian>(transaction_id).unwrap(); // transaction_id
 
     Write::write(&mut writer, info_hash).unwrap(); // info_hash: 20 bytes
-    Write::write(&mut writer, peer_id).unwrap(); // peer_id: 20 bytes
+    Write::write(&mut writer, &params.peer_id).unwrap(); // peer_id: 20 bytes
 
-    writer.write_i64::<BigEndian>(0).unwrap(); // downloaded
-    writer.write_i64::<BigEndian>(0).unwrap(); // left
-    writer.write_i64::<BigEndian>(0).unwrap(); // uploaded
-    writer.write_i32::<BigEndian>(EVENT_STARTED).unwrap(); // event
+    writer
+        .write_i64::<BigEndian>(params.downloaded as i64)
+        .unwrap(); // downloaded
+    writer.write_i64::<BigEndian>(params.left as i64).unwrap(); // left
+    writer
+        .write_i64::<BigEndian>(params.uploaded as i64)
+        .unwrap(); // uploaded
+    writer.write_i32::<BigEndian>(params.event as i32).unwrap(); // event
     writer.write_u32::<BigEndian>(0).unwrap(); // ip
     writer.write_u32::<BigEndian>(get_random_key()).unwrap(); // key
-    writer.write_i32::<BigEndian>(30).unwrap(); // num_want
+    writer.write_u32::<BigEndian>(num_want).unwrap(); // num_want
     writer.write_u16::<BigEndian>(listening_port).unwrap(); // port
     writer.write_u16::<BigEndian>(0).unwrap(); // extensions
     writer
@@ -344,16 +370,16 @@ fn read_announce_response(
         while bytes_left >= Peer::size() {
             let ip = reader.read_u32::<BigEndian>().unwrap();
             let port = reader.read_u16::<BigEndian>().unwrap();
-            peers.push(Peer { ip: ip, port: port });
+            peers.push(Peer { ip, port });
             bytes_left = bytes_read - reader.position() as usize;
         }
         assert!(bytes_left == 0);
         let res = AnnounceResponsePayload {
             transaction_id: recv_transaction_id,
             interval: std::time::Duration::from_secs(interval as u64),
-            num_leechers: num_leechers,
-            num_seeders: num_seeders,
-            peers: peers,
+            num_leechers,
+            num_seeders,
+            peers,
         };
         Ok(AnnounceResponse::Payload(res))
     } else if action == ACTION_ERROR {
@@ -363,14 +389,253 @@ fn read_announce_response(
         Ok(AnnounceResponse::Error(error_string))
     } else {
         error!("Received invalid action {}", action);
-        Err(std::io::Error::new(std::io::ErrorKind::Other, ""))
+        Err(std::io::Error::other(""))
     }
 }
 
-fn get_scrape_request(connection_id: i64, transaction_id: i32) -> Vec<u8> {
+fn get_scrape_request(
+    connection_id: i64,
+    transaction_id: i32,
+    info_hashes: &[[u8; 20]],
+) -> Vec<u8> {
     let mut writer = vec![];
     writer.write_i64::<BigEndian>(connection_id).unwrap(); // connection_id
     writer.write_i32::<BigEndian>(ACTION_SCRAPE).unwrap(); // action
     writer.write_i32::<BigEndian>(transaction_id).unwrap(); // transaction_id
+    for info_hash in info_hashes {
+        writer.extend_from_slice(info_hash); // info_hash: 20 bytes each
+    }
     writer
 }
+
+fn read_scrape_response(buf: &[u8]) -> Result<(i32, Vec<ScrapeStats>), Error> {
+    let mut reader = std::io::Cursor::new(buf);
+    let action = reader.read_i32::<BigEndian>()?;
+    let transaction_id = reader.read_i32::<BigEndian>()?;
+
+    match action {
+        ACTION_SCRAPE => {
+            let stats = buf[8..]
+                .chunks_exact(12)
+                .map(|chunk| ScrapeStats {
+                    seeders: BigEndian::read_u32(&chunk[0..4]),
+                    completed: BigEndian::read_u32(&chunk[4..8]),
+                    leechers: BigEndian::read_u32(&chunk[8..12]),
+                })
+                .collect();
+            Ok((transaction_id, stats))
+        }
+        ACTION_ERROR => Err(Error::Server(
+            String::from_utf8_lossy(&buf[8..]).to_string(),
+        )),
+        _ => Err(Error::IncorrectAction),
+    }
+}
+
+/// Parses a request sent to a tracker. Requests too short to hold a transaction id are
+/// only worth dropping, the others can be answered with an error.
+pub fn read_request(buf: &[u8]) -> Result<Request, Error> {
+    let mut reader = std::io::Cursor::new(buf);
+    let connection_id = reader
+        .read_i64::<BigEndian>()
+        .map_err(|_| Error::InvalidRequest("too short"))?;
+    let action = reader
+        .read_i32::<BigEndian>()
+        .map_err(|_| Error::InvalidRequest("too short"))?;
+    let transaction_id = reader
+        .read_i32::<BigEndian>()
+        .map_err(|_| Error::InvalidRequest("too short"))?;
+
+    match action {
+        ACTION_CONNECT if connection_id == MAGIC_CONSTANT => {
+            Ok(Request::Connect { transaction_id })
+        }
+        ACTION_CONNECT => Err(Error::InvalidRequest("invalid protocol id")),
+        ACTION_ANNOUNCE => {
+            // 98 bytes, extensions after them are ignored
+            if buf.len() < 98 {
+                return Err(Error::InvalidRequest("announce too short"));
+            }
+            let event = AnnounceEvent::from_i32(BigEndian::read_i32(&buf[80..84]))
+                .ok_or(Error::InvalidRequest("unknown event"))?;
+            Ok(Request::Announce(AnnounceRequest {
+                connection_id,
+                transaction_id,
+                info_hash: to_id(&buf[16..36]),
+                params: AnnounceParams {
+                    peer_id: to_id(&buf[36..56]),
+                    downloaded: BigEndian::read_i64(&buf[56..64]).max(0) as u64,
+                    left: BigEndian::read_i64(&buf[64..72]).max(0) as u64,
+                    uploaded: BigEndian::read_i64(&buf[72..80]).max(0) as u64,
+                    event,
+                },
+                ip: BigEndian::read_u32(&buf[84..88]),
+                key: BigEndian::read_u32(&buf[88..92]),
+                num_want: BigEndian::read_i32(&buf[92..96]),
+                port: BigEndian::read_u16(&buf[96..98]),
+            }))
+        }
+        ACTION_SCRAPE => {
+            let info_hashes = &buf[16..];
+            if info_hashes.is_empty() || !info_hashes.len().is_multiple_of(20) {
+                return Err(Error::InvalidRequest("invalid info hashes"));
+            }
+            Ok(Request::Scrape {
+                connection_id,
+                transaction_id,
+                info_hashes: info_hashes.chunks_exact(20).map(to_id).collect(),
+            })
+        }
+        _ => Err(Error::InvalidRequest("unknown action")),
+    }
+}
+
+fn to_id(bytes: &[u8]) -> [u8; 20] {
+    let mut id = [0u8; 20];
+    id.copy_from_slice(bytes);
+    id
+}
+
+/// The transaction id of a request, to answer it with an error when it cannot be parsed.
+pub fn request_transaction_id(buf: &[u8]) -> Option<i32> {
+    buf.get(12..16).map(BigEndian::read_i32)
+}
+
+pub fn get_connect_response(transaction_id: i32, connection_id: i64) -> Vec<u8> {
+    let mut writer = vec![];
+    writer.write_i32::<BigEndian>(ACTION_CONNECT).unwrap(); // action
+    writer.write_i32::<BigEndian>(transaction_id).unwrap(); // transaction_id
+    writer.write_i64::<BigEndian>(connection_id).unwrap(); // connection_id
+    writer
+}
+
+/// Peers take 6 bytes each over IPv4 and 18 over IPv6 (BEP 15), the tracker only returns
+/// peers of the family the request came over.
+pub fn get_announce_response(
+    transaction_id: i32,
+    interval: Duration,
+    leechers: u32,
+    seeders: u32,
+    peers: &[SocketAddr],
+) -> Vec<u8> {
+    let mut writer = vec![];
+    writer.write_i32::<BigEndian>(ACTION_ANNOUNCE).unwrap(); // action
+    writer.write_i32::<BigEndian>(transaction_id).unwrap(); // transaction_id
+    writer
+        .write_u32::<BigEndian>(interval.as_secs() as u32)
+        .unwrap(); // interval
+    writer.write_u32::<BigEndian>(leechers).unwrap(); // leechers
+    writer.write_u32::<BigEndian>(seeders).unwrap(); // seeders
+    for peer in peers {
+        match peer.ip() {
+            IpAddr::V4(ip) => writer.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => writer.extend_from_slice(&ip.octets()),
+        }
+        writer.write_u16::<BigEndian>(peer.port()).unwrap();
+    }
+    writer
+}
+
+pub fn get_scrape_response(transaction_id: i32, stats: &[ScrapeStats]) -> Vec<u8> {
+    let mut writer = vec![];
+    writer.write_i32::<BigEndian>(ACTION_SCRAPE).unwrap(); // action
+    writer.write_i32::<BigEndian>(transaction_id).unwrap(); // transaction_id
+    for stats in stats {
+        writer.write_u32::<BigEndian>(stats.seeders).unwrap();
+        writer.write_u32::<BigEndian>(stats.completed).unwrap();
+        writer.write_u32::<BigEndian>(stats.leechers).unwrap();
+    }
+    writer
+}
+
+pub fn get_error_response(transaction_id: i32, message: &str) -> Vec<u8> {
+    let mut writer = vec![];
+    writer.write_i32::<BigEndian>(ACTION_ERROR).unwrap(); // action
+    writer.write_i32::<BigEndian>(transaction_id).unwrap(); // transaction_id
+    writer.extend_from_slice(message.as_bytes());
+    writer
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_requests() {
+    assert_eq!(
+        read_request(&get_connect_request(7)).unwrap(),
+        Request::Connect { transaction_id: 7 }
+    );
+
+    let params = AnnounceParams {
+        peer_id: [1u8; 20],
+        downloaded: 10,
+        left: 20,
+        uploaded: 30,
+        event: AnnounceEvent::Completed,
+    };
+    let request = get_announce_request(42, 8, 6881, 50, &[2u8; 20], &params);
+    match read_request(&request).unwrap() {
+        Request::Announce(announce) => {
+            assert_eq!(announce.connection_id, 42);
+            assert_eq!(announce.transaction_id, 8);
+            assert_eq!(announce.info_hash, [2u8; 20]);
+            assert_eq!(announce.params, params);
+            assert_eq!(announce.num_want, 50);
+            assert_eq!(announce.port, 6881);
+        }
+        request => panic!("unexpected request {:?}", request),
+    }
+    assert!(read_request(&request[..97]).is_err());
+
+    let request = get_scrape_request(42, 9, &[[3u8; 20], [4u8; 20]]);
+    assert_eq!(
+        read_request(&request).unwrap(),
+        Request::Scrape {
+            connection_id: 42,
+            transaction_id: 9,
+            info_hashes: vec![[3u8; 20], [4u8; 20]],
+        }
+    );
+    assert!(read_request(&request[..30]).is_err());
+    assert_eq!(request_transaction_id(&request[..30]), Some(9));
+    assert_eq!(request_transaction_id(&request[..10]), None);
+}
+
+#[test]
+fn test_responses() {
+    match read_connect_response(&get_connect_response(7, 42), 16).unwrap() {
+        ConnectResponse::Payload(res) => {
+            assert_eq!(res.transaction_id, 7);
+            assert_eq!(res.connection_id, 42);
+        }
+        ConnectResponse::Error(e) => panic!("unexpected error {}", e),
+    }
+
+    let peers = vec!["10.0.0.1:6881".parse().unwrap()];
+    let response = get_announce_response(8, Duration::from_secs(60), 1, 2, &peers);
+    match read_announce_response(&response, response.len()).unwrap() {
+        AnnounceResponse::Payload(res) => {
+            assert_eq!(res.transaction_id, 8);
+            assert_eq!(res.interval, Duration::from_secs(60));
+            assert_eq!((res.num_leechers, res.num_seeders), (1, 2));
+            assert_eq!(res.peers[0].addr(), peers[0]);
+        }
+        AnnounceResponse::Error(e) => panic!("unexpected error {}", e),
+    }
+
+    let stats = vec![ScrapeStats {
+        seeders: 1,
+        completed: 2,
+        leechers: 3,
+    }];
+    let (transaction_id, scraped) = read_scrape_response(&get_scrape_response(9, &stats)).unwrap();
+    assert_eq!(transaction_id, 9);
+    assert_eq!(scraped, stats);
+
+    let response = get_error_response(10, "unknown torrent");
+    match read_announce_response(&response, response.len()).unwrap() {
+        AnnounceResponse::Error(e) => assert_eq!(e, "unknown torrent"),
+        AnnounceResponse::Payload(_) => panic!("expected an error"),
+    }
+}
@@ -0,0 +1,478 @@
+use super::{Announce, Swarms};
+use crate::error::Error;
+use crate::http::percent_decode;
+use crate::tracker::{AnnounceEvent, AnnounceParams};
+use log::{debug, info, warn};
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+/// Largest request accepted, announces fit in a few hundred bytes.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+/// Time for a client to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often peers that stopped announcing are forgotten.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The bencoded response to an announce. Peers are compact (BEP 23 and BEP 7) unless the
+/// client asked for `compact=0`.
+#[derive(Serialize, Debug)]
+struct AnnounceResponse {
+    complete: u32,
+    incomplete: u32,
+    interval: u64,
+    peers: Peers,
+    peers6: Option<ByteBuf>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum Peers {
+    Compact(ByteBuf),
+    List(Vec<PeerEntry>),
+}
+
+#[derive(Serialize, Debug)]
+struct PeerEntry {
+    ip: String,
+    /// Left out when the client sent `no_peer_id=1`.
+    #[serde(rename = "peer id")]
+    peer_id: Option<ByteBuf>,
+    port: u16,
+}
+
+#[derive(Serialize, Debug)]
+struct ScrapeResponse {
+    /// By info hash.
+    files: BTreeMap<ByteBuf, ScrapeFile>,
+}
+
+#[derive(Serialize, Debug)]
+struct ScrapeFile {
+    complete: u32,
+    downloaded: u32,
+    incomplete: u32,
+}
+
+#[derive(Serialize, Debug)]
+struct Failure {
+    #[serde(rename = "failure reason")]
+    failure_reason: String,
+}
+
+/// The query parameters of a request, values are bytes as info hashes are binary.
+struct Query(Vec<(String, Vec<u8>)>);
+
+impl Query {
+    fn parse(query: &str) -> Result<Query, Error> {
+        let mut params = vec![];
+        for param in query.split('&').filter(|p| !p.is_empty()) {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            let value = percent_decode(value).ok_or(Error::InvalidRequest("invalid escape"))?;
+            params.push((name.to_owned(), value));
+        }
+        Ok(Query(params))
+    }
+
+    fn get(&self, name: &str) -> Option<&[u8]> {
+        self.0
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    fn all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.0
+            .iter()
+            .filter(move |(n, _)| n == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    fn id(&self, name: &str, missing: &'static str) -> Result<[u8; 20], Error> {
+        let value = self.get(name).ok_or(Error::InvalidRequest(missing))?;
+        to_id(value).ok_or(Error::InvalidRequest(missing))
+    }
+
+    /// A number, `None` if the parameter is missing.
+    fn number<T: std::str::FromStr>(&self, name: &str) -> Result<Option<T>, Error> {
+        match self.get(name) {
+            Some(value) => std::str::from_utf8(value)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .map(Some)
+                .ok_or(Error::InvalidRequest("invalid number")),
+            None => Ok(None),
+        }
+    }
+}
+
+fn to_id(bytes: &[u8]) -> Option<[u8; 20]> {
+    if bytes.len() != 20 {
+        return None;
+    }
+    let mut id = [0u8; 20];
+    id.copy_from_slice(bytes);
+    Some(id)
+}
+
+/// Answers a `GET` of `target` from `remote`, returning the status and body. Trackers
+/// report failures in the body of a 200 response.
+fn handle(swarms: &Mutex<Swarms>, target: &str, remote: IpAddr) -> (u16, Vec<u8>) {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    // `/announce` or `/<passkey>/announce` and the same for scrapes
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let (passkey, endpoint) = match segments.as_slice() {
+        [endpoint] => (None, *endpoint),
+        [passkey, endpoint] => (Some(*passkey), *endpoint),
+        _ => return (404, b"not found".to_vec()),
+    };
+    let passkey = match passkey.map(percent_decode) {
+        Some(Some(passkey)) => Some(String::from_utf8_lossy(&passkey).into_owned()),
+        Some(None) => return (404, b"not found".to_vec()),
+        None => None,
+    };
+
+    let body = match endpoint {
+        "announce" => {
+            Query::parse(query).and_then(|query| announce(swarms, &query, passkey, remote))
+        }
+        "scrape" => Query::parse(query).and_then(|query| scrape(swarms, &query, passkey)),
+        _ => return (404, b"not found".to_vec()),
+    };
+    let body = body.unwrap_or_else(|e| {
+        debug!("refused request from {}: {}", remote, e);
+        let failure = Failure {
+            failure_reason: e.to_string(),
+        };
+        bencoding::to_bytes(&failure).expect("failure should not fail to encode")
+    });
+    (200, body)
+}
+
+fn announce(
+    swarms: &Mutex<Swarms>,
+    query: &Query,
+    passkey: Option<String>,
+    remote: IpAddr,
+) -> Result<Vec<u8>, Error> {
+    let event = match query.get("event") {
+        None | Some(b"") | Some(b"empty") => AnnounceEvent::None,
+        Some(b"started") => AnnounceEvent::Started,
+        Some(b"completed") => AnnounceEvent::Completed,
+        Some(b"stopped") => AnnounceEvent::Stopped,
+        Some(_) => return Err(Error::InvalidRequest("unknown event")),
+    };
+    let port = query
+        .number::<u16>("port")?
+        .filter(|port| *port != 0)
+        .ok_or(Error::InvalidRequest("invalid port"))?;
+    // the `ip` parameter is ignored, a peer can only announce itself
+    let request = Announce {
+        info_hash: query.id("info_hash", "invalid info_hash")?,
+        addr: SocketAddr::new(remote, port),
+        params: AnnounceParams {
+            peer_id: query.id("peer_id", "invalid peer_id")?,
+            downloaded: query.number("downloaded")?.unwrap_or(0),
+            left: query.number("left")?.unwrap_or(0),
+            uploaded: query.number("uploaded")?.unwrap_or(0),
+            event,
+        },
+        num_want: query.number("numwant")?,
+        passkey,
+    };
+    let reply = swarms.lock().unwrap().announce(&request, Instant::now())?;
+
+    let compact = query.get("compact") != Some(b"0");
+    let no_peer_id = query.get("no_peer_id") == Some(b"1");
+    let (peers, peers6) = if compact {
+        let mut peers = vec![];
+        let mut peers6 = vec![];
+        for (_, addr) in reply.peers.iter() {
+            let port = addr.port().to_be_bytes();
+            match addr.ip() {
+                IpAddr::V4(ip) => peers.extend(ip.octets().iter().chain(port.iter())),
+                IpAddr::V6(ip) => peers6.extend(ip.octets().iter().chain(port.iter())),
+            }
+        }
+        let peers6 = Some(ByteBuf::from(peers6)).filter(|p| !p.is_empty());
+        (Peers::Compact(ByteBuf::from(peers)), peers6)
+    } else {
+        let peers = reply
+            .peers
+            .iter()
+            .map(|(peer_id, addr)| PeerEntry {
+                ip: addr.ip().to_string(),
+                peer_id: Some(ByteBuf::from(peer_id.to_vec())).filter(|_| !no_peer_id),
+                port: addr.port(),
+            })
+            .collect();
+        (Peers::List(peers), None)
+    };
+
+    let response = AnnounceResponse {
+        complete: reply.stats.seeders,
+        incomplete: reply.stats.leechers,
+        interval: reply.interval.as_secs(),
+        peers,
+        peers6,
+    };
+    Ok(bencoding::to_bytes(&response)?)
+}
+
+/// Only the torrents asked for are scraped, unknown ones are left out.
+fn scrape(
+    swarms: &Mutex<Swarms>,
+    query: &Query,
+    passkey: Option<String>,
+) -> Result<Vec<u8>, Error> {
+    let swarms = swarms.lock().unwrap();
+    swarms.check_passkey(passkey.as_deref())?;
+    let mut files = BTreeMap::new();
+    for info_hash in query.all("info_hash") {
+        let info_hash = to_id(info_hash).ok_or(Error::InvalidRequest("invalid info_hash"))?;
+        if !swarms.is_allowed(&info_hash) {
+            continue;
+        }
+        let stats = swarms.scrape(&info_hash);
+        let file = ScrapeFile {
+            complete: stats.seeders,
+            downloaded: stats.completed,
+            incomplete: stats.leechers,
+        };
+        files.insert(ByteBuf::from(info_hash.to_vec()), file);
+    }
+    Ok(bencoding::to_bytes(&ScrapeResponse { files })?)
+}
+
+/// An HTTP tracker serving the peers of `Swarms` until dropped, at `/announce` and
+/// `/scrape`, or `/<passkey>/announce` and `/<passkey>/scrape` when passkeys are required.
+#[derive(Debug)]
+pub struct HttpTracker {
+    local_addr: SocketAddr,
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl HttpTracker {
+    pub async fn bind(addr: SocketAddr, swarms: Arc<Mutex<Swarms>>) -> Result<HttpTracker, Error> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        info!("http tracker listening on {}", local_addr);
+
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        tokio::spawn(run(listener, swarms, shutdown_rx));
+        Ok(HttpTracker {
+            local_addr,
+            _shutdown: shutdown_tx,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+async fn run(
+    listener: TcpListener,
+    swarms: Arc<Mutex<Swarms>>,
+    mut shutdown: oneshot::Receiver<()>,
+) {
+    let mut expire_tick = tokio::time::interval(EXPIRE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => return,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, remote)) => {
+                    let swarms = swarms.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(stream, remote, &swarms).await {
+                            debug!("http tracker request from {} failed: {}", remote, e);
+                        }
+                    });
+                }
+                Err(e) => warn!("http tracker failed to accept: {}", e),
+            },
+            _ = expire_tick.tick() => {
+                swarms.lock().unwrap().expire(Instant::now());
+            }
+        }
+    }
+}
+
+/// Answers a single request, then closes the connection.
+async fn serve(
+    mut stream: TcpStream,
+    remote: SocketAddr,
+    swarms: &Mutex<Swarms>,
+) -> Result<(), Error> {
+    let mut request = vec![];
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_LEN {
+            return Err(Error::Http("request too long".to_owned()));
+        }
+        let n = timeout(REQUEST_TIMEOUT, stream.read(&mut buf))
+            .await
+            .map_err(|_| Error::Timeout)??;
+        if n == 0 {
+            return Err(Error::ConnectionClosed);
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    // GET /announce?info_hash=... HTTP/1.1
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or("").split(' ');
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some(target)) => handle(swarms, target, remote.ip()),
+        _ => (405, b"method not allowed".to_vec()),
+    };
+
+    let reason = match status {
+        200 => "OK",
+        404 => "Not Found",
+        _ => "Method Not Allowed",
+    };
+    let mut response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason,
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(&body);
+    stream.write_all(&response).await?;
+    Ok(())
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_announce_and_scrape() {
+    use crate::http::{get, percent_encode};
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    struct Compact {
+        complete: u32,
+        incomplete: u32,
+        interval: u64,
+        peers: ByteBuf,
+    }
+    #[derive(Deserialize)]
+    struct Entry {
+        ip: String,
+        #[serde(rename = "peer id")]
+        peer_id: ByteBuf,
+        port: u16,
+    }
+    #[derive(Deserialize)]
+    struct List {
+        peers: Vec<Entry>,
+    }
+    #[derive(Deserialize)]
+    struct File {
+        complete: u32,
+        downloaded: u32,
+        incomplete: u32,
+    }
+    #[derive(Deserialize)]
+    struct Scrape {
+        files: BTreeMap<ByteBuf, File>,
+    }
+    #[derive(Deserialize)]
+    struct FailureReason {
+        #[serde(rename = "failure reason")]
+        failure_reason: String,
+    }
+
+    let config = super::ServerConfig {
+        whitelist: Some(vec![[1u8; 20]].into_iter().collect()),
+        passkeys: Some(vec!["secret".to_owned()].into_iter().collect()),
+        ..Default::default()
+    };
+    let swarms = Arc::new(Mutex::new(Swarms::new(config)));
+    let server = HttpTracker::bind("127.0.0.1:0".parse().unwrap(), swarms.clone())
+        .await
+        .unwrap();
+    let base = format!("http://{}", server.local_addr());
+    let escape = |bytes: &[u8]| {
+        bytes
+            .iter()
+            .map(|b| format!("%{:02X}", b))
+            .collect::<String>()
+    };
+    let announce = |passkey: &str, id: u8, port: u16, extra: &str| {
+        format!(
+            "/{}/announce?info_hash={}&peer_id={}&port={}&uploaded=10&downloaded=5&left=0{}",
+            percent_encode(passkey),
+            escape(&[1u8; 20]),
+            escape(&[id; 20]),
+            port,
+            extra
+        )
+    };
+
+    let body = fetch(&base, &announce("secret", 1, 7001, "&event=started")).await;
+    let response: Compact = bencoding::from_bytes(&body).unwrap();
+    assert_eq!((response.complete, response.incomplete), (1, 0));
+    assert_eq!(response.interval, 30 * 60);
+    assert!(response.peers.is_empty());
+
+    let body = fetch(&base, &announce("secret", 2, 7002, "&compact=1")).await;
+    let response: Compact = bencoding::from_bytes(&body).unwrap();
+    assert_eq!(&response.peers[..], &[127, 0, 0, 1, 0x1b, 0x59]);
+
+    let body = fetch(&base, &announce("secret", 3, 7003, "&compact=0&numwant=1")).await;
+    let response: List = bencoding::from_bytes(&body).unwrap();
+    assert_eq!(response.peers.len(), 1);
+    let peer = &response.peers[0];
+    assert_eq!(peer.ip, "127.0.0.1");
+    assert!(matches!(peer.port, 7001 | 7002));
+    assert_eq!(peer.peer_id[0], (peer.port - 7000) as u8);
+
+    let stats = swarms.lock().unwrap().user_transfer("secret");
+    assert_eq!((stats.uploaded, stats.downloaded), (30, 15));
+
+    // unknown passkeys and torrents are refused
+    let body = fetch(&base, &announce("other", 4, 7004, "")).await;
+    let failure: FailureReason = bencoding::from_bytes(&body).unwrap();
+    assert!(failure.failure_reason.contains("passkey"));
+    let path = format!(
+        "/secret/announce?info_hash={}&peer_id={}&port=7004",
+        escape(&[2u8; 20]),
+        escape(&[4u8; 20])
+    );
+    let failure: FailureReason = bencoding::from_bytes(&fetch(&base, &path).await).unwrap();
+    assert!(failure.failure_reason.contains("unregistered"));
+
+    let path = format!(
+        "/secret/scrape?info_hash={}&info_hash={}",
+        escape(&[1u8; 20]),
+        escape(&[2u8; 20])
+    );
+    let scrape: Scrape = bencoding::from_bytes(&fetch(&base, &path).await).unwrap();
+    assert_eq!(scrape.files.len(), 1);
+    let file = &scrape.files[&ByteBuf::from(vec![1u8; 20])];
+    assert_eq!((file.complete, file.downloaded, file.incomplete), (3, 0, 0));
+
+    let url = format!("{}/secret/unknown", base).parse().unwrap();
+    assert_eq!(get(&url, None, 1024).await.unwrap().status, 404);
+}
+
+/// Gets `path` from the server at `base`, expecting a 200 response.
+#[cfg(test)]
+async fn fetch(base: &str, path: &str) -> Vec<u8> {
+    let url = format!("{}{}", base, path).parse().unwrap();
+    let response = crate::http::get(&url, None, 64 * 1024).await.unwrap();
+    assert_eq!(response.status, 200);
+    response.body
+}
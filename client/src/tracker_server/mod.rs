@@ -0,0 +1,380 @@
+use crate::error::Error;
+use crate::tracker::{AnnounceEvent, AnnounceParams, ScrapeStats};
+use rand::seq::IteratorRandom;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+pub mod http;
+pub mod udp;
+
+/// Peers returned when an announce does not say how many it wants.
+pub const DEFAULT_NUM_WANT: usize = 50;
+/// Most peers returned by a single announce.
+pub const MAX_NUM_WANT: usize = 200;
+/// Peers are forgotten when they miss this many announce intervals.
+const MISSED_INTERVALS: u32 = 2;
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// How long peers should wait between announces.
+    pub interval: Duration,
+    /// The only torrents the tracker serves, any torrent when `None`.
+    pub whitelist: Option<HashSet<[u8; 20]>>,
+    /// When set, peers must announce to a url with one of these passkeys, e.g.
+    /// `/<passkey>/announce`, and their transfers are accounted to it.
+    pub passkeys: Option<HashSet<String>>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            interval: Duration::from_secs(30 * 60),
+            whitelist: None,
+            passkeys: None,
+        }
+    }
+}
+
+/// An announce as received by one of the listeners.
+#[derive(Debug, Clone)]
+pub struct Announce {
+    pub info_hash: [u8; 20],
+    /// Where the other peers can reach the announcing peer.
+    pub addr: SocketAddr,
+    pub params: AnnounceParams,
+    /// `None` lets the tracker decide.
+    pub num_want: Option<usize>,
+    pub passkey: Option<String>,
+}
+
+/// What a tracker answers to an announce.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnounceReply {
+    pub interval: Duration,
+    /// Peer ids and addresses.
+    pub peers: Vec<([u8; 20], SocketAddr)>,
+    pub stats: ScrapeStats,
+}
+
+/// Bytes transferred, as reported by peers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Transfer {
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+/// A peer as last announced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerStats {
+    pub peer_id: [u8; 20],
+    pub addr: SocketAddr,
+    pub transfer: Transfer,
+    pub left: u64,
+}
+
+#[derive(Debug, Clone)]
+struct SwarmPeer {
+    stats: PeerStats,
+    last_seen: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Swarm {
+    /// By peer id.
+    peers: HashMap<[u8; 20], SwarmPeer>,
+    completed: u32,
+}
+
+impl Swarm {
+    fn stats(&self) -> ScrapeStats {
+        let seeders = self.peers.values().filter(|p| p.stats.left == 0).count() as u32;
+        ScrapeStats {
+            seeders,
+            completed: self.completed,
+            leechers: self.peers.len() as u32 - seeders,
+        }
+    }
+}
+
+/// The peers of every torrent announced to a tracker, shared by its listeners.
+#[derive(Debug)]
+pub struct Swarms {
+    config: ServerConfig,
+    torrents: HashMap<[u8; 20], Swarm>,
+    /// Transfers of all the peers of each passkey.
+    users: HashMap<String, Transfer>,
+}
+
+impl Swarms {
+    pub fn new(config: ServerConfig) -> Swarms {
+        Swarms {
+            config,
+            torrents: HashMap::new(),
+            users: HashMap::new(),
+        }
+    }
+
+    /// Refuses the requests without a known passkey when passkeys are required.
+    pub fn check_passkey(&self, passkey: Option<&str>) -> Result<(), Error> {
+        match (self.config.passkeys.as_ref(), passkey) {
+            (None, _) => Ok(()),
+            (Some(passkeys), Some(passkey)) if passkeys.contains(passkey) => Ok(()),
+            (Some(_), _) => Err(Error::InvalidRequest("invalid passkey")),
+        }
+    }
+
+    /// Whether the tracker serves a torrent.
+    pub fn is_allowed(&self, info_hash: &[u8; 20]) -> bool {
+        self.config
+            .whitelist
+            .as_ref()
+            .is_none_or(|whitelist| whitelist.contains(info_hash))
+    }
+
+    /// Records an announce and returns up to `num_want` other peers of the torrent, picked
+    /// at random among those of the same address family. A stopped peer is removed and
+    /// gets no peers.
+    pub fn announce(&mut self, announce: &Announce, now: Instant) -> Result<AnnounceReply, Error> {
+        self.check_passkey(announce.passkey.as_deref())?;
+        if !self.is_allowed(&announce.info_hash) {
+            return Err(Error::InvalidRequest("unregistered torrent"));
+        }
+
+        let params = &announce.params;
+        let swarm = self.torrents.entry(announce.info_hash).or_default();
+        let previous = swarm.peers.remove(&params.peer_id);
+        let transfer = Transfer {
+            uploaded: params.uploaded,
+            downloaded: params.downloaded,
+        };
+        if let Some(passkey) = announce.passkey.as_ref() {
+            let before = previous
+                .as_ref()
+                .map(|peer| peer.stats.transfer)
+                .unwrap_or_default();
+            let user = self.users.entry(passkey.clone()).or_default();
+            user.uploaded += delta(before.uploaded, transfer.uploaded);
+            user.downloaded += delta(before.downloaded, transfer.downloaded);
+        }
+
+        let peers = if params.event == AnnounceEvent::Stopped {
+            vec![]
+        } else {
+            // count each download once, even when the completed event is sent again
+            let was_done = previous.is_some_and(|peer| peer.stats.left == 0);
+            if params.event == AnnounceEvent::Completed && !was_done {
+                swarm.completed += 1;
+            }
+
+            let num_want = announce
+                .num_want
+                .unwrap_or(DEFAULT_NUM_WANT)
+                .min(MAX_NUM_WANT);
+            let family = announce.addr.is_ipv4();
+            let peers = swarm
+                .peers
+                .values()
+                .filter(|peer| peer.stats.addr.is_ipv4() == family)
+                .map(|peer| (peer.stats.peer_id, peer.stats.addr))
+                .choose_multiple(&mut rand::thread_rng(), num_want);
+            let stats = PeerStats {
+                peer_id: params.peer_id,
+                addr: announce.addr,
+                transfer,
+                left: params.left,
+            };
+            swarm.peers.insert(
+                params.peer_id,
+                SwarmPeer {
+                    stats,
+                    last_seen: now,
+                },
+            );
+            peers
+        };
+        let stats = swarm.stats();
+        if swarm.peers.is_empty() && swarm.completed == 0 {
+            self.torrents.remove(&announce.info_hash);
+        }
+
+        Ok(AnnounceReply {
+            interval: self.config.interval,
+            peers,
+            stats,
+        })
+    }
+
+    /// The numbers of a torrent, all zero for unknown torrents.
+    pub fn scrape(&self, info_hash: &[u8; 20]) -> ScrapeStats {
+        self.torrents
+            .get(info_hash)
+            .map(Swarm::stats)
+            .unwrap_or_default()
+    }
+
+    /// The peers of a torrent as they last announced.
+    pub fn peers(&self, info_hash: &[u8; 20]) -> Vec<PeerStats> {
+        self.torrents
+            .get(info_hash)
+            .map(|swarm| swarm.peers.values().map(|p| p.stats.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    /// What the peers of a passkey transferred in total.
+    pub fn user_transfer(&self, passkey: &str) -> Transfer {
+        self.users.get(passkey).copied().unwrap_or_default()
+    }
+
+    /// Forgets the peers that stopped announcing.
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = self.config.interval * MISSED_INTERVALS;
+        for swarm in self.torrents.values_mut() {
+            swarm
+                .peers
+                .retain(|_, peer| now.saturating_duration_since(peer.last_seen) < timeout);
+        }
+        self.torrents
+            .retain(|_, swarm| !swarm.peers.is_empty() || swarm.completed > 0);
+    }
+
+    pub fn num_peers(&self) -> usize {
+        self.torrents.values().map(|swarm| swarm.peers.len()).sum()
+    }
+}
+
+/// Bytes transferred since the previous announce. Peers count from 0 again when they
+/// restart.
+fn delta(before: u64, now: u64) -> u64 {
+    if now >= before {
+        now - before
+    } else {
+        now
+    }
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_announce_and_scrape() {
+    use AnnounceEvent::{Completed, Started, Stopped};
+    let mut swarms = Swarms::new(ServerConfig::default());
+    let now = Instant::now();
+    let a: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+    let b: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+    let c: SocketAddr = "[::1]:6881".parse().unwrap();
+    let mut send = |addr, id, left, event, num_want| {
+        let request = announce(addr, id, left, event, num_want, None);
+        swarms.announce(&request, now).unwrap()
+    };
+
+    assert!(send(a, 1, 0, Started, None).peers.is_empty());
+    assert_eq!(send(b, 2, 10, Started, None).peers, vec![([1; 20], a)]);
+    // peers of the other family are not returned
+    let reply = send(c, 3, 10, Started, None);
+    assert!(reply.peers.is_empty());
+    assert_eq!((reply.stats.seeders, reply.stats.leechers), (1, 2));
+
+    assert!(send(b, 2, 10, Started, Some(0)).peers.is_empty());
+
+    send(b, 2, 0, Completed, None);
+    send(b, 2, 0, Completed, None);
+    send(c, 3, 10, Stopped, None);
+    let expected = ScrapeStats {
+        seeders: 2,
+        completed: 1,
+        leechers: 0,
+    };
+    assert_eq!(swarms.scrape(&[1u8; 20]), expected);
+    assert_eq!(swarms.scrape(&[2u8; 20]), ScrapeStats::default());
+}
+
+#[test]
+fn test_whitelist_and_passkeys() {
+    let config = ServerConfig {
+        whitelist: Some(vec![[1u8; 20]].into_iter().collect()),
+        passkeys: Some(vec!["alice".to_owned()].into_iter().collect()),
+        ..ServerConfig::default()
+    };
+    let mut swarms = Swarms::new(config);
+    let now = Instant::now();
+    let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+    let alice = Some("alice".to_owned());
+
+    let mut request = announce(addr, 1, 10, AnnounceEvent::Started, None, alice.clone());
+    request.params.uploaded = 100;
+    swarms.announce(&request, now).unwrap();
+    request.params.uploaded = 300;
+    request.params.downloaded = 50;
+    swarms.announce(&request, now).unwrap();
+    let transfer = Transfer {
+        uploaded: 300,
+        downloaded: 50,
+    };
+    assert_eq!(swarms.user_transfer("alice"), transfer);
+    assert_eq!(swarms.peers(&[1u8; 20])[0].transfer, transfer);
+
+    // a restarted client counts from 0 again
+    request.params.uploaded = 20;
+    swarms.announce(&request, now).unwrap();
+    assert_eq!(swarms.user_transfer("alice").uploaded, 320);
+
+    request.info_hash = [2u8; 20];
+    assert!(swarms.announce(&request, now).is_err());
+    request.info_hash = [1u8; 20];
+    request.passkey = Some("bob".to_owned());
+    assert!(swarms.announce(&request, now).is_err());
+    request.passkey = None;
+    assert!(swarms.announce(&request, now).is_err());
+    assert_eq!(swarms.num_peers(), 1);
+}
+
+#[test]
+fn test_expire_peers() {
+    let config = ServerConfig {
+        interval: Duration::from_secs(60),
+        ..ServerConfig::default()
+    };
+    let mut swarms = Swarms::new(config);
+    let now = Instant::now();
+    let later = now + Duration::from_secs(90);
+    let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+    let mut started = announce(addr, 1, 10, AnnounceEvent::Started, None, None);
+    swarms.announce(&started, now).unwrap();
+    started.info_hash = [2u8; 20];
+    swarms.announce(&started, later).unwrap();
+
+    swarms.expire(now + Duration::from_secs(119));
+    assert_eq!(swarms.num_peers(), 2);
+    swarms.expire(now + Duration::from_secs(120));
+    assert_eq!(swarms.num_peers(), 1);
+    assert_eq!(swarms.scrape(&[1u8; 20]), ScrapeStats::default());
+    assert_eq!(swarms.scrape(&[2u8; 20]).leechers, 1);
+}
+
+/// An announce for the torrent `[1; 20]` by the peer `[id; 20]`.
+#[cfg(test)]
+fn announce(
+    addr: SocketAddr,
+    id: u8,
+    left: u64,
+    event: AnnounceEvent,
+    num_want: Option<usize>,
+    passkey: Option<String>,
+) -> Announce {
+    Announce {
+        info_hash: [1u8; 20],
+        addr,
+        params: AnnounceParams {
+            peer_id: [id; 20],
+            downloaded: 0,
+            left,
+            uploaded: 0,
+            event,
+        },
+        num_want,
+        passkey,
+    }
+}
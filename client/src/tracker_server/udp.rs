@@ -0,0 +1,357 @@
+use super::{Announce, Swarms};
+use crate::error::Error;
+use crate::tracker::{self, Request};
+use log::{debug, info, warn};
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+
+/// Connection ids are built from a secret changed this often. Ids of the previous secret
+/// are still accepted, so clients can use an id for the two minutes of BEP 15.
+const ROTATE_INTERVAL: Duration = Duration::from_secs(2 * 60);
+/// How often peers that stopped announcing are forgotten.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60);
+/// Most info hashes in a scrape, the most that fit in a response.
+const MAX_SCRAPE: usize = 74;
+const RECV_BUF_SIZE: usize = 2048;
+
+/// Hands out the connection ids of the connect responses and checks them in announces and
+/// scrapes, so that only clients receiving at their source address can use the tracker.
+#[derive(Debug)]
+struct ConnectionIds {
+    secret: [u8; 20],
+    previous: [u8; 20],
+    rotated_at: Instant,
+}
+
+impl ConnectionIds {
+    fn new() -> ConnectionIds {
+        let secret = rand::thread_rng().gen();
+        ConnectionIds {
+            secret,
+            previous: secret,
+            rotated_at: Instant::now(),
+        }
+    }
+
+    fn generate(&mut self, addr: SocketAddr) -> i64 {
+        self.rotate();
+        make_connection_id(&self.secret, addr)
+    }
+
+    fn validate(&mut self, addr: SocketAddr, id: i64) -> bool {
+        self.rotate();
+        id == make_connection_id(&self.secret, addr)
+            || id == make_connection_id(&self.previous, addr)
+    }
+
+    fn rotate(&mut self) {
+        if self.rotated_at.elapsed() >= ROTATE_INTERVAL {
+            self.previous = self.secret;
+            self.secret = rand::thread_rng().gen();
+            self.rotated_at = Instant::now();
+        }
+    }
+}
+
+fn make_connection_id(secret: &[u8; 20], addr: SocketAddr) -> i64 {
+    let mut hasher = Sha1::new();
+    hasher.update(secret);
+    match addr.ip() {
+        IpAddr::V4(ip) => hasher.update(ip.octets()),
+        IpAddr::V6(ip) => hasher.update(ip.octets()),
+    }
+    hasher.update(addr.port().to_be_bytes());
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&hasher.finalize()[..8]);
+    i64::from_be_bytes(id)
+}
+
+/// Answers the requests of a UDP tracker (BEP 15).
+#[derive(Debug)]
+struct Handler {
+    swarms: Arc<Mutex<Swarms>>,
+    connection_ids: ConnectionIds,
+}
+
+impl Handler {
+    /// Returns the response to a request from `from`, if it deserves one.
+    fn handle(&mut self, packet: &[u8], from: SocketAddr) -> Option<Vec<u8>> {
+        let request = match tracker::read_request(packet) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("invalid request from {}: {}", from, e);
+                let transaction_id = tracker::request_transaction_id(packet)?;
+                return Some(tracker::get_error_response(transaction_id, &e.to_string()));
+            }
+        };
+
+        match request {
+            Request::Connect { transaction_id } => {
+                let connection_id = self.connection_ids.generate(from);
+                Some(tracker::get_connect_response(transaction_id, connection_id))
+            }
+            Request::Announce(announce) => {
+                if !self.connection_ids.validate(from, announce.connection_id) {
+                    return Some(invalid_connection_id(announce.transaction_id));
+                }
+                if announce.port == 0 {
+                    let message = "invalid port";
+                    return Some(tracker::get_error_response(
+                        announce.transaction_id,
+                        message,
+                    ));
+                }
+                // the ip of the request is ignored, a peer can only announce itself, and
+                // there is no url to take a passkey from
+                let request = Announce {
+                    info_hash: announce.info_hash,
+                    addr: SocketAddr::new(from.ip(), announce.port),
+                    params: announce.params,
+                    num_want: Some(announce.num_want)
+                        .filter(|n| *n >= 0)
+                        .map(|n| n as usize),
+                    passkey: None,
+                };
+                let transaction_id = announce.transaction_id;
+                let reply = match self
+                    .swarms
+                    .lock()
+                    .unwrap()
+                    .announce(&request, Instant::now())
+                {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        return Some(tracker::get_error_response(transaction_id, &e.to_string()))
+                    }
+                };
+                let peers: Vec<_> = reply.peers.iter().map(|(_, addr)| *addr).collect();
+                Some(tracker::get_announce_response(
+                    transaction_id,
+                    reply.interval,
+                    reply.stats.leechers,
+                    reply.stats.seeders,
+                    &peers,
+                ))
+            }
+            Request::Scrape {
+                connection_id,
+                transaction_id,
+                info_hashes,
+            } => {
+                if !self.connection_ids.validate(from, connection_id) {
+                    return Some(invalid_connection_id(transaction_id));
+                }
+                let swarms = self.swarms.lock().unwrap();
+                if let Err(e) = swarms.check_passkey(None) {
+                    return Some(tracker::get_error_response(transaction_id, &e.to_string()));
+                }
+                // responses are positional, torrents the tracker doesn't serve get no stats
+                let stats: Vec<_> = info_hashes
+                    .iter()
+                    .take(MAX_SCRAPE)
+                    .map(|info_hash| {
+                        if swarms.is_allowed(info_hash) {
+                            swarms.scrape(info_hash)
+                        } else {
+                            Default::default()
+                        }
+                    })
+                    .collect();
+                Some(tracker::get_scrape_response(transaction_id, &stats))
+            }
+        }
+    }
+}
+
+fn invalid_connection_id(transaction_id: i32) -> Vec<u8> {
+    tracker::get_error_response(transaction_id, "invalid connection id")
+}
+
+/// A UDP tracker serving the peers of `Swarms` until dropped.
+#[derive(Debug)]
+pub struct UdpTracker {
+    local_addr: SocketAddr,
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl UdpTracker {
+    pub async fn bind(addr: SocketAddr, swarms: Arc<Mutex<Swarms>>) -> Result<UdpTracker, Error> {
+        let socket = UdpSocket::bind(addr).await?;
+        let local_addr = socket.local_addr()?;
+        info!("udp tracker listening on {}", local_addr);
+
+        let handler = Handler {
+            swarms,
+            connection_ids: ConnectionIds::new(),
+        };
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        tokio::spawn(run(socket, handler, shutdown_rx));
+        Ok(UdpTracker {
+            local_addr,
+            _shutdown: shutdown_tx,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+async fn run(socket: UdpSocket, mut handler: Handler, mut shutdown: oneshot::Receiver<()>) {
+    let mut buf = vec![0u8; RECV_BUF_SIZE];
+    let mut expire_tick = tokio::time::interval(EXPIRE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => return,
+            received = socket.recv_from(&mut buf) => match received {
+                Ok((len, from)) => {
+                    if let Some(response) = handler.handle(&buf[..len], from) {
+                        if let Err(e) = socket.send_to(&response, from).await {
+                            debug!("failed to answer {}: {}", from, e);
+                        }
+                    }
+                }
+                Err(e) => warn!("udp tracker failed to receive: {}", e),
+            },
+            _ = expire_tick.tick() => {
+                handler.swarms.lock().unwrap().expire(Instant::now());
+            }
+        }
+    }
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_connection_ids() {
+    let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+    let other: SocketAddr = "10.0.0.1:6882".parse().unwrap();
+    let mut ids = ConnectionIds::new();
+
+    let id = ids.generate(addr);
+    assert!(ids.validate(addr, id));
+    assert!(!ids.validate(other, id));
+
+    // still valid right after a rotation, but not after two
+    ids.rotated_at -= ROTATE_INTERVAL;
+    assert!(ids.validate(addr, id));
+    ids.rotated_at -= ROTATE_INTERVAL;
+    assert!(!ids.validate(addr, id));
+}
+
+#[test]
+fn test_error_responses() {
+    let swarms = Arc::new(Mutex::new(Swarms::new(Default::default())));
+    let mut handler = Handler {
+        swarms,
+        connection_ids: ConnectionIds::new(),
+    };
+    let from: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+    let error = |response: Vec<u8>| String::from_utf8_lossy(&response[8..]).to_string();
+
+    // scrape with an id the tracker never handed out
+    let mut scrape = 42i64.to_be_bytes().to_vec();
+    scrape.extend_from_slice(&2i32.to_be_bytes());
+    scrape.extend_from_slice(&7i32.to_be_bytes());
+    scrape.extend_from_slice(&[1u8; 20]);
+    let response = handler.handle(&scrape, from).unwrap();
+    assert_eq!(&response[..8], &[0, 0, 0, 3, 0, 0, 0, 7]);
+    assert_eq!(error(response), "invalid connection id");
+
+    // unknown action
+    scrape[11] = 9;
+    let response = handler.handle(&scrape, from).unwrap();
+    assert_eq!(error(response), "invalid tracker request: unknown action");
+
+    // too short to answer
+    assert!(handler.handle(&scrape[..10], from).is_none());
+}
+
+#[test]
+fn test_scrape_whitelist() {
+    use crate::tracker::{AnnounceEvent, AnnounceParams};
+
+    let swarms = Arc::new(Mutex::new(Swarms::new(Default::default())));
+    let mut handler = Handler {
+        swarms: swarms.clone(),
+        connection_ids: ConnectionIds::new(),
+    };
+    let from: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+    for info_hash in [[1u8; 20], [2u8; 20]].iter() {
+        let announce = Announce {
+            info_hash: *info_hash,
+            addr: from,
+            params: AnnounceParams {
+                peer_id: [1; 20],
+                downloaded: 0,
+                left: 0,
+                uploaded: 0,
+                event: AnnounceEvent::Started,
+            },
+            num_want: None,
+            passkey: None,
+        };
+        let mut swarms = swarms.lock().unwrap();
+        swarms.announce(&announce, Instant::now()).unwrap();
+    }
+    // the second torrent is no longer served
+    swarms.lock().unwrap().config.whitelist = Some(vec![[1u8; 20]].into_iter().collect());
+
+    let connection_id = handler.connection_ids.generate(from);
+    let mut scrape = connection_id.to_be_bytes().to_vec();
+    scrape.extend_from_slice(&2i32.to_be_bytes());
+    scrape.extend_from_slice(&7i32.to_be_bytes());
+    scrape.extend_from_slice(&[1u8; 20]);
+    scrape.extend_from_slice(&[2u8; 20]);
+    let response = handler.handle(&scrape, from).unwrap();
+    // seeders, completed and leechers of each torrent
+    assert_eq!(&response[..8], &[0, 0, 0, 2, 0, 0, 0, 7]);
+    assert_eq!(&response[8..20], &[0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(&response[20..], &[0u8; 12]);
+}
+
+#[tokio::test]
+async fn test_announce_and_scrape() {
+    use crate::config::TrackerConfig;
+    use crate::tracker::{AnnounceEvent, AnnounceParams, Connection, TrackerClient};
+
+    let swarms = Arc::new(Mutex::new(Swarms::new(Default::default())));
+    let server = UdpTracker::bind("127.0.0.1:0".parse().unwrap(), swarms.clone())
+        .await
+        .unwrap();
+    let info_hash = [1u8; 20];
+    let params = |id: u8, left: u64| AnnounceParams {
+        peer_id: [id; 20],
+        downloaded: 0,
+        left,
+        uploaded: 0,
+        event: AnnounceEvent::Started,
+    };
+
+    let config = TrackerConfig::default();
+    let mut seeder = Connection::new(server.local_addr(), 7001, config.clone())
+        .await
+        .unwrap();
+    let response = seeder.announce(&info_hash, &params(1, 0)).await.unwrap();
+    assert!(response.peers.is_empty());
+
+    let mut leecher = Connection::new(server.local_addr(), 7002, config)
+        .await
+        .unwrap();
+    let response = leecher.announce(&info_hash, &params(2, 10)).await.unwrap();
+    assert_eq!(response.interval, Duration::from_secs(30 * 60));
+    let peers: Vec<_> = response.peers.iter().map(|p| p.addr()).collect();
+    assert_eq!(peers, vec!["127.0.0.1:7001".parse().unwrap()]);
+
+    let stats = leecher.scrape(&[info_hash, [2u8; 20]]).await.unwrap();
+    assert_eq!((stats[0].seeders, stats[0].leechers), (1, 1));
+    assert_eq!(stats[1], Default::default());
+    assert_eq!(swarms.lock().unwrap().num_peers(), 2);
+}
@@ -0,0 +1,831 @@
+use super::packet::{Packet, PacketType, HEADER_LEN};
+use crate::error::Error;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use tokio::io::ReadBuf;
+use tokio::sync::oneshot;
+
+/// Queuing delay LEDBAT aims for, in microseconds.
+const TARGET_DELAY: f64 = 100_000.0;
+/// Most the window grows by in a round trip, when nothing is queued on the way.
+const MAX_WINDOW_INCREASE: f64 = 3000.0;
+const INITIAL_WINDOW: f64 = 16.0 * 1024.0;
+const MIN_WINDOW: f64 = 1500.0;
+const MAX_WINDOW: f64 = 1024.0 * 1024.0;
+/// Minutes of delay minima kept to find the base delay.
+const BASE_DELAY_HISTORY: usize = 10;
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(60);
+/// Timeouts in a row before the other side is considered gone.
+const MAX_TIMEOUTS: u32 = 8;
+const MAX_SYN_TIMEOUTS: u32 = 3;
+/// Packets acknowledged past an unacknowledged one before it is deemed lost.
+const DUPLICATE_ACKS: usize = 3;
+/// Bytes written and not yet sent, before writes wait.
+const SEND_BUF_LEN: usize = 256 * 1024;
+/// Bytes received and not yet read, advertised as the receive window.
+const RECV_BUF_LEN: usize = 1024 * 1024;
+/// How far past the last packet received in order others are kept.
+const MAX_OUT_OF_ORDER: u16 = 2048;
+/// Longest selective ack sent, in bytes.
+const MAX_SELECTIVE_ACK: usize = 32;
+const ETHERNET_MTU: usize = 1500;
+/// Sizes of the IP and UDP headers.
+const IPV4_OVERHEAD: usize = 28;
+const IPV6_OVERHEAD: usize = 48;
+/// The MTU search stops once the packet size is known within this many bytes.
+const MTU_SEARCH_PRECISION: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    SynSent,
+    Connected,
+    /// Reset by the other side, or timed out.
+    Closed,
+}
+
+/// Whether `a` comes before `b`, with sequence numbers wrapping around.
+fn seq_before(a: u16, b: u16) -> bool {
+    a != b && b.wrapping_sub(a) < 0x8000
+}
+
+/// A packet waiting for its acknowledgement.
+struct Sent {
+    seq_nr: u16,
+    ty: PacketType,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    transmissions: u32,
+    /// Lost, to be sent again once the window allows.
+    resend: bool,
+}
+
+impl Sent {
+    fn size(&self) -> usize {
+        HEADER_LEN + self.payload.len()
+    }
+}
+
+/// The lowest one way delay seen in the last minutes: the delay without queuing, which
+/// also includes the offset between the clocks of both sides.
+struct BaseDelay {
+    /// The lowest delay of each minute, the last one being the current minute.
+    minima: VecDeque<u32>,
+    since: Instant,
+}
+
+impl BaseDelay {
+    fn add(&mut self, delay: u32, now: Instant) {
+        match self.minima.back_mut() {
+            Some(min) if now.duration_since(self.since) < Duration::from_secs(60) => {
+                *min = std::cmp::min(*min, delay)
+            }
+            _ => {
+                if self.minima.len() == BASE_DELAY_HISTORY {
+                    self.minima.pop_front();
+                }
+                self.minima.push_back(delay);
+                self.since = now;
+            }
+        }
+    }
+
+    /// The queuing delay of a packet delayed by `delay`.
+    fn queuing_delay(&self, delay: u32) -> u32 {
+        let base = self.minima.iter().min().copied().unwrap_or(delay);
+        std::cmp::min(delay.wrapping_sub(base), i32::MAX as u32)
+    }
+}
+
+/// Searches the largest packet that makes it to the other side, by sending probes
+/// halfway between the largest size acknowledged and the smallest size lost. A lost
+/// probe is sent again as is, the IP layer fragments it once it learnt the path MTU.
+struct MtuSearch {
+    /// Sizes of whole uTP packets.
+    floor: usize,
+    ceiling: usize,
+    probe: Option<(u16, usize)>,
+}
+
+impl MtuSearch {
+    fn new(addr: &SocketAddr) -> MtuSearch {
+        let (min_mtu, overhead) = match addr {
+            SocketAddr::V4(_) => (576, IPV4_OVERHEAD),
+            SocketAddr::V6(_) => (1280, IPV6_OVERHEAD),
+        };
+        MtuSearch {
+            floor: min_mtu - overhead,
+            ceiling: ETHERNET_MTU - overhead,
+            probe: None,
+        }
+    }
+
+    /// The payload length of the next data packet, with `available` bytes to send, and
+    /// whether it is a probe.
+    fn next_payload(&self, available: usize) -> (usize, bool) {
+        if self.probe.is_none() && self.ceiling - self.floor >= MTU_SEARCH_PRECISION {
+            let probe = (self.floor + self.ceiling).div_ceil(2) - HEADER_LEN;
+            if available >= probe {
+                return (probe, true);
+            }
+        }
+        (std::cmp::min(available, self.floor - HEADER_LEN), false)
+    }
+
+    fn acked(&mut self, seq_nr: u16) {
+        if let Some((probe, size)) = self.probe {
+            if probe == seq_nr {
+                self.floor = size;
+                self.probe = None;
+            }
+        }
+    }
+
+    /// Returns whether the lost packet was the probe.
+    fn lost(&mut self, seq_nr: u16) -> bool {
+        match self.probe {
+            Some((probe, size)) if probe == seq_nr => {
+                self.ceiling = size - 1;
+                self.probe = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// One side of a uTP connection, independent of the socket: incoming packets and timer
+/// ticks are fed in, and the packets to send come out.
+pub struct Connection {
+    pub addr: SocketAddr,
+    pub state: State,
+    recv_id: u16,
+    send_id: u16,
+    epoch: Instant,
+    /// The next sequence number sent.
+    seq_nr: u16,
+    /// The last sequence number received in order.
+    ack_nr: u16,
+    /// The last `ack_nr` received, and how many times in a row.
+    last_ack: u16,
+    duplicate_acks: usize,
+
+    send_buf: VecDeque<u8>,
+    in_flight: VecDeque<Sent>,
+    /// The stream was shut down, a FIN follows the buffered bytes.
+    closing: bool,
+    fin_sent: bool,
+    /// The congestion window, in bytes.
+    window: f64,
+    slow_start: bool,
+    last_decrease: Option<Instant>,
+    peer_window: u32,
+    base_delay: BaseDelay,
+    mtu: MtuSearch,
+    /// The smoothed round trip time and its variation.
+    rtt: Option<(Duration, Duration)>,
+    rto: Duration,
+    timeouts: u32,
+    timeout_at: Option<Instant>,
+
+    recv_buf: VecDeque<u8>,
+    out_of_order: HashMap<u16, Vec<u8>>,
+    remote_fin: Option<u16>,
+    eof: bool,
+    /// The delay of the last packet received, sent back in ours.
+    reply_micro: u32,
+
+    error: Option<io::ErrorKind>,
+    /// The stream is gone, the connection only lives on to close properly.
+    pub dropped: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    connected: Option<oneshot::Sender<Result<(), Error>>>,
+}
+
+impl Connection {
+    fn new(addr: SocketAddr, recv_id: u16, send_id: u16, epoch: Instant, now: Instant) -> Self {
+        Connection {
+            addr,
+            state: State::SynSent,
+            recv_id,
+            send_id,
+            epoch,
+            seq_nr: 1,
+            ack_nr: 0,
+            last_ack: 0,
+            duplicate_acks: 0,
+            send_buf: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            closing: false,
+            fin_sent: false,
+            window: INITIAL_WINDOW,
+            slow_start: true,
+            last_decrease: None,
+            peer_window: RECV_BUF_LEN as u32,
+            base_delay: BaseDelay {
+                minima: VecDeque::new(),
+                since: now,
+            },
+            mtu: MtuSearch::new(&addr),
+            rtt: None,
+            rto: INITIAL_RTO,
+            timeouts: 0,
+            timeout_at: None,
+            recv_buf: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            remote_fin: None,
+            eof: false,
+            reply_micro: 0,
+            error: None,
+            dropped: false,
+            read_waker: None,
+            write_waker: None,
+            connected: None,
+        }
+    }
+
+    /// Starts a connection receiving on `recv_id`, `connected` is sent the outcome of
+    /// the handshake. Returns the SYN to send.
+    pub fn connect(
+        addr: SocketAddr,
+        recv_id: u16,
+        epoch: Instant,
+        now: Instant,
+        connected: oneshot::Sender<Result<(), Error>>,
+    ) -> (Connection, Packet) {
+        let mut connection = Connection::new(addr, recv_id, recv_id.wrapping_add(1), epoch, now);
+        connection.connected = Some(connected);
+        connection.in_flight.push_back(Sent {
+            seq_nr: connection.seq_nr,
+            ty: PacketType::Syn,
+            payload: vec![],
+            sent_at: now,
+            transmissions: 0,
+            resend: false,
+        });
+        connection.seq_nr = connection.seq_nr.wrapping_add(1);
+        let syn = connection.transmit(0, now);
+        connection.timeout_at = Some(now + connection.rto);
+        (connection, syn)
+    }
+
+    /// Accepts the connection started by `syn`. Returns the acknowledgement to send.
+    pub fn accept(addr: SocketAddr, syn: &Packet, epoch: Instant, now: Instant) -> (Self, Packet) {
+        let (recv_id, send_id) = (syn.connection_id.wrapping_add(1), syn.connection_id);
+        let mut connection = Connection::new(addr, recv_id, send_id, epoch, now);
+        connection.state = State::Connected;
+        connection.seq_nr = rand::random();
+        connection.last_ack = connection.seq_nr.wrapping_sub(1);
+        connection.ack_nr = syn.seq_nr;
+        connection.peer_window = syn.wnd_size;
+        connection.reply_micro = connection.micros(now).wrapping_sub(syn.timestamp);
+        let ack = connection.ack(now);
+        (connection, ack)
+    }
+
+    /// Whether the connection can be forgotten: the stream is gone, and so is the other
+    /// side or it acknowledged everything up to our FIN.
+    pub fn is_finished(&self) -> bool {
+        let fin_acked = self.fin_sent && self.in_flight.is_empty();
+        self.dropped && (self.state == State::Closed || fin_acked)
+    }
+
+    pub fn on_packet(&mut self, packet: Packet, now: Instant) -> Vec<Packet> {
+        if self.state == State::Closed {
+            return vec![];
+        }
+        self.reply_micro = self.micros(now).wrapping_sub(packet.timestamp);
+        self.peer_window = packet.wnd_size;
+        match (packet.ty, self.state) {
+            (PacketType::Reset, _) => {
+                self.fail(io::ErrorKind::ConnectionReset);
+                return vec![];
+            }
+            // our acknowledgement of the SYN was lost
+            (PacketType::Syn, _) => return vec![self.ack(now)],
+            (PacketType::State, State::SynSent) => {
+                self.state = State::Connected;
+                self.ack_nr = packet.seq_nr.wrapping_sub(1);
+                if let Some(connected) = self.connected.take() {
+                    let _ = connected.send(Ok(()));
+                }
+            }
+            (_, State::SynSent) => return vec![],
+            _ => {}
+        }
+
+        self.on_ack(&packet, now);
+        let mut packets = vec![];
+        if packet.ty == PacketType::Data || packet.ty == PacketType::Fin {
+            if packet.ty == PacketType::Fin {
+                self.remote_fin.get_or_insert(packet.seq_nr);
+            }
+            self.on_data(packet.seq_nr, packet.payload);
+            packets.push(self.ack(now));
+        }
+        packets.extend(self.flush(now));
+        packets
+    }
+
+    /// Sends the lost packets again when their timeout expires.
+    pub fn on_tick(&mut self, now: Instant) -> Vec<Packet> {
+        match self.timeout_at {
+            Some(at) if now >= at && self.state != State::Closed => {}
+            _ => return vec![],
+        }
+        self.timeouts += 1;
+        let max_timeouts = match self.state {
+            State::SynSent => MAX_SYN_TIMEOUTS,
+            _ => MAX_TIMEOUTS,
+        };
+        if self.timeouts > max_timeouts {
+            self.fail(io::ErrorKind::TimedOut);
+            return vec![];
+        }
+
+        self.window = MIN_WINDOW;
+        self.slow_start = false;
+        for i in 0..self.in_flight.len() {
+            self.mark_lost(i);
+        }
+        self.rto = std::cmp::min(self.rto * 2, MAX_RTO);
+        self.timeout_at = Some(now + self.rto);
+        self.flush(now)
+    }
+
+    pub fn poll_read(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.recv_buf.is_empty() {
+            let len = std::cmp::min(buf.remaining(), self.recv_buf.len());
+            let data: Vec<u8> = self.recv_buf.drain(..len).collect();
+            buf.put_slice(&data);
+            return Poll::Ready(Ok(()));
+        }
+        if self.eof {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(kind) = self.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        self.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Buffers as much of `data` as fits. Returns how much and the packets to send.
+    pub fn poll_write(
+        &mut self,
+        cx: &mut Context<'_>,
+        data: &[u8],
+        now: Instant,
+    ) -> Poll<io::Result<(usize, Vec<Packet>)>> {
+        if let Some(kind) = self.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if self.closing {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let len = std::cmp::min(data.len(), SEND_BUF_LEN - self.send_buf.len());
+        if len == 0 && !data.is_empty() {
+            self.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        self.send_buf.extend(&data[..len]);
+        Poll::Ready(Ok((len, self.flush(now))))
+    }
+
+    /// Sends a FIN after the buffered bytes.
+    pub fn close(&mut self, now: Instant) -> Vec<Packet> {
+        if self.state != State::Connected || self.closing {
+            return vec![];
+        }
+        self.closing = true;
+        self.flush(now)
+    }
+
+    fn micros(&self, now: Instant) -> u32 {
+        now.duration_since(self.epoch).as_micros() as u32
+    }
+
+    fn packet(&self, ty: PacketType, seq_nr: u16, payload: Vec<u8>, now: Instant) -> Packet {
+        Packet {
+            ty,
+            connection_id: self.send_id,
+            timestamp: self.micros(now),
+            timestamp_diff: self.reply_micro,
+            wnd_size: RECV_BUF_LEN.saturating_sub(self.buffered()) as u32,
+            seq_nr,
+            ack_nr: self.ack_nr,
+            selective_ack: None,
+            payload,
+        }
+    }
+
+    /// The bytes received and not read yet, out of order ones included.
+    fn buffered(&self) -> usize {
+        self.recv_buf.len() + self.out_of_order.values().map(Vec::len).sum::<usize>()
+    }
+
+    /// A STATE packet acknowledging what was received, out of order packets included.
+    fn ack(&self, now: Instant) -> Packet {
+        let mut ack = self.packet(PacketType::State, self.seq_nr, vec![], now);
+        if !self.out_of_order.is_empty() {
+            let bits: Vec<usize> = self
+                .out_of_order
+                .keys()
+                .map(|seq_nr| seq_nr.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize)
+                .filter(|&bit| bit < MAX_SELECTIVE_ACK * 8)
+                .collect();
+            let len = (bits.iter().max().unwrap_or(&0) / 32 + 1) * 4;
+            let mut mask = vec![0u8; len];
+            for bit in bits {
+                mask[bit / 8] |= 1 << (bit % 8);
+            }
+            ack.selective_ack = Some(mask);
+        }
+        ack
+    }
+
+    /// Sends the packet at `index` of the packets in flight, again if it was sent before.
+    fn transmit(&mut self, index: usize, now: Instant) -> Packet {
+        let sent = &mut self.in_flight[index];
+        sent.sent_at = now;
+        sent.transmissions += 1;
+        sent.resend = false;
+        let (ty, seq_nr, payload) = (sent.ty, sent.seq_nr, sent.payload.clone());
+        let mut packet = self.packet(ty, seq_nr, payload, now);
+        if ty == PacketType::Syn {
+            packet.connection_id = self.recv_id;
+        }
+        packet
+    }
+
+    /// Sends the lost packets, then the buffered bytes, as far as the windows allow. At
+    /// least one packet is always allowed in flight so that a full window of the other
+    /// side gets probed.
+    fn flush(&mut self, now: Instant) -> Vec<Packet> {
+        if self.state == State::Closed {
+            return vec![];
+        }
+        let window = std::cmp::min(self.window as usize, self.peer_window as usize);
+        let mut in_flight: usize = self
+            .in_flight
+            .iter()
+            .filter(|p| !p.resend)
+            .map(Sent::size)
+            .sum();
+        let mut packets = vec![];
+        let mut full = false;
+        for i in 0..self.in_flight.len() {
+            if !self.in_flight[i].resend {
+                continue;
+            }
+            let size = self.in_flight[i].size();
+            if in_flight > 0 && in_flight + size > window {
+                full = true;
+                break;
+            }
+            in_flight += size;
+            packets.push(self.transmit(i, now));
+        }
+
+        if self.state == State::Connected && !full {
+            while !self.send_buf.is_empty() {
+                let (len, probe) = self.mtu.next_payload(self.send_buf.len());
+                if in_flight > 0 && in_flight + HEADER_LEN + len > window {
+                    break;
+                }
+                if probe {
+                    self.mtu.probe = Some((self.seq_nr, HEADER_LEN + len));
+                }
+                in_flight += HEADER_LEN + len;
+                let payload = self.send_buf.drain(..len).collect();
+                packets.push(self.send_new(PacketType::Data, payload, now));
+            }
+            if self.closing && !self.fin_sent && self.send_buf.is_empty() {
+                self.fin_sent = true;
+                packets.push(self.send_new(PacketType::Fin, vec![], now));
+            }
+        }
+
+        if !packets.is_empty() {
+            self.timeout_at.get_or_insert(now + self.rto);
+            if let Some(waker) = self.write_waker.take() {
+                waker.wake();
+            }
+        }
+        packets
+    }
+
+    fn send_new(&mut self, ty: PacketType, payload: Vec<u8>, now: Instant) -> Packet {
+        self.in_flight.push_back(Sent {
+            seq_nr: self.seq_nr,
+            ty,
+            payload,
+            sent_at: now,
+            transmissions: 0,
+            resend: false,
+        });
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.transmit(self.in_flight.len() - 1, now)
+    }
+
+    /// Marks a packet in flight for sending again. Returns whether that is a sign of
+    /// congestion, rather than of a probe too large for the path.
+    fn mark_lost(&mut self, index: usize) -> bool {
+        let sent = &mut self.in_flight[index];
+        sent.resend = true;
+        !self.mtu.lost(sent.seq_nr)
+    }
+
+    fn on_ack(&mut self, packet: &Packet, now: Instant) {
+        let mut acked = vec![];
+        while let Some(sent) = self.in_flight.front() {
+            if seq_before(packet.ack_nr, sent.seq_nr) {
+                break;
+            }
+            acked.extend(self.in_flight.pop_front());
+        }
+        let selectively_acked = packet.selectively_acked();
+        if !selectively_acked.is_empty() {
+            let (sacked, kept) = self
+                .in_flight
+                .drain(..)
+                .partition(|p| selectively_acked.contains(&p.seq_nr));
+            self.in_flight = kept;
+            acked.extend(sacked);
+        }
+
+        let mut congestion = false;
+        for i in 0..self.in_flight.len() {
+            let sent = &self.in_flight[i];
+            let acked_after = selectively_acked
+                .iter()
+                .filter(|&&seq_nr| seq_before(sent.seq_nr, seq_nr))
+                .count();
+            if acked_after >= DUPLICATE_ACKS && sent.transmissions == 1 && !sent.resend {
+                congestion |= self.mark_lost(i);
+            }
+        }
+        if acked.is_empty() && packet.ack_nr == self.last_ack && !self.in_flight.is_empty() {
+            self.duplicate_acks += 1;
+            let front = &self.in_flight[0];
+            if self.duplicate_acks == DUPLICATE_ACKS && front.transmissions == 1 && !front.resend {
+                congestion |= self.mark_lost(0);
+            }
+        } else {
+            self.duplicate_acks = 0;
+        }
+        self.last_ack = packet.ack_nr;
+
+        let mut bytes_acked = 0;
+        for sent in acked {
+            bytes_acked += sent.size();
+            if sent.transmissions == 1 {
+                self.update_rtt(now.duration_since(sent.sent_at));
+                self.mtu.acked(sent.seq_nr);
+            }
+        }
+        if congestion {
+            self.on_congestion(now);
+        }
+        if bytes_acked > 0 {
+            self.timeouts = 0;
+            // zero until the other side received a packet of ours
+            if packet.timestamp_diff != 0 {
+                self.base_delay.add(packet.timestamp_diff, now);
+                let delay = self.base_delay.queuing_delay(packet.timestamp_diff);
+                self.grow_window(bytes_acked, delay);
+            }
+            self.timeout_at = if self.in_flight.is_empty() {
+                None
+            } else {
+                Some(now + self.rto)
+            };
+        }
+    }
+
+    /// Adjusts the window to the queuing delay (LEDBAT): it grows while the delay is
+    /// below the target and shrinks above it, by as much as the delay is off target.
+    /// It doubles each round trip until the delay first reaches the target.
+    fn grow_window(&mut self, bytes_acked: usize, delay: u32) {
+        let off_target = (TARGET_DELAY - delay as f64) / TARGET_DELAY;
+        let bytes_acked = bytes_acked as f64;
+        if self.slow_start && off_target > 0.0 {
+            self.window += bytes_acked;
+        } else {
+            self.slow_start = false;
+            let window_factor = bytes_acked / self.window.max(bytes_acked);
+            self.window += MAX_WINDOW_INCREASE * off_target * window_factor;
+        }
+        self.window = self.window.clamp(MIN_WINDOW, MAX_WINDOW);
+    }
+
+    /// Halves the window, at most once per round trip.
+    fn on_congestion(&mut self, now: Instant) {
+        let rtt = self.rtt.map_or(self.rto, |(rtt, _)| rtt);
+        if self
+            .last_decrease
+            .is_none_or(|at| now.duration_since(at) >= rtt)
+        {
+            self.window = (self.window / 2.0).max(MIN_WINDOW);
+            self.last_decrease = Some(now);
+        }
+        self.slow_start = false;
+    }
+
+    /// Updates the timeout from a round trip sample, as TCP does (RFC 6298).
+    fn update_rtt(&mut self, sample: Duration) {
+        let (rtt, variation) = match self.rtt {
+            None => (sample, sample / 2),
+            Some((rtt, variation)) => {
+                let delta = rtt.abs_diff(sample);
+                (rtt * 7 / 8 + sample / 8, variation * 3 / 4 + delta / 4)
+            }
+        };
+        self.rtt = Some((rtt, variation));
+        self.rto = (rtt + variation * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// Buffers the payload of a DATA or FIN packet. The advertised window is only advisory,
+    /// so data past `RECV_BUF_LEN` is dropped without being acknowledged, and is sent again
+    /// once the reader made room. The next packet in order is only held to the bytes waiting
+    /// for the reader, so that out of order ones can't keep it out forever.
+    fn on_data(&mut self, seq_nr: u16, payload: Vec<u8>) {
+        let ahead = seq_nr.wrapping_sub(self.ack_nr);
+        if !seq_before(self.ack_nr, seq_nr) || ahead > MAX_OUT_OF_ORDER || self.eof {
+            return;
+        }
+        let buffered = match ahead {
+            1 => self.recv_buf.len(),
+            _ => self.buffered(),
+        };
+        if buffered + payload.len() > RECV_BUF_LEN {
+            return;
+        }
+        self.out_of_order.insert(seq_nr, payload);
+        let mut delivered = false;
+        while let Some(payload) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+            self.ack_nr = self.ack_nr.wrapping_add(1);
+            self.recv_buf.extend(payload);
+            delivered = true;
+            if self.remote_fin == Some(self.ack_nr) {
+                self.eof = true;
+                self.out_of_order.clear();
+            }
+        }
+        if delivered {
+            if let Some(waker) = self.read_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    fn fail(&mut self, kind: io::ErrorKind) {
+        self.state = State::Closed;
+        self.error = Some(kind);
+        self.in_flight.clear();
+        self.timeout_at = None;
+        if let Some(connected) = self.connected.take() {
+            let error = match kind {
+                io::ErrorKind::TimedOut => Error::Timeout,
+                _ => Error::ConnectionClosed,
+            };
+            let _ = connected.send(Err(error));
+        }
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_congestion_control() {
+    let now = Instant::now();
+    let addr = SocketAddr::from(([127, 0, 0, 1], 6881));
+    let (connected, _) = oneshot::channel();
+    let (mut connection, syn) = Connection::connect(addr, 7, now, now, connected);
+    assert_eq!(
+        (syn.ty, syn.connection_id, syn.seq_nr),
+        (PacketType::Syn, 7, 1)
+    );
+
+    // the SYN is sent again on timeout, with the timeout doubled
+    assert!(connection.on_tick(now + INITIAL_RTO / 2).is_empty());
+    let later = now + INITIAL_RTO;
+    assert_eq!(
+        connection.on_tick(later),
+        vec![Packet {
+            timestamp: connection.micros(later),
+            ..syn.clone()
+        }]
+    );
+    assert_eq!(connection.rto, INITIAL_RTO * 2);
+
+    let (mut other, ack) = Connection::accept(addr, &syn, now, now);
+    assert_eq!((ack.connection_id, ack.ack_nr), (7, 1));
+    assert!(connection.on_packet(ack, later).is_empty());
+    assert_eq!(connection.state, State::Connected);
+    assert_eq!(connection.window, MIN_WINDOW);
+
+    // LEDBAT grows the window below the target delay, and shrinks it above
+    connection.slow_start = false;
+    connection.window = 10_000.0;
+    connection.grow_window(10_000, 0);
+    assert_eq!(connection.window, 10_000.0 + MAX_WINDOW_INCREASE);
+    connection.grow_window(5_000, 150_000);
+    let expected = 13_000.0 - MAX_WINDOW_INCREASE * 0.5 * 5_000.0 / 13_000.0;
+    assert!((connection.window - expected).abs() < 1e-6);
+    connection.window = 20_000.0;
+    connection.on_congestion(later);
+    connection.on_congestion(later);
+    assert_eq!(connection.window, 10_000.0);
+
+    // the first packets are probes of the MTU, and fill the window
+    let data = vec![7u8; 10_000];
+    let waker = futures_util::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let packets = match connection.poll_write(&mut cx, &data, later) {
+        Poll::Ready(Ok((10_000, packets))) => packets,
+        _ => panic!("write failed"),
+    };
+    let sizes: Vec<usize> = packets.iter().map(|p| p.payload.len()).collect();
+    let probe = (576 - IPV4_OVERHEAD + ETHERNET_MTU - IPV4_OVERHEAD) / 2 - HEADER_LEN;
+    assert_eq!(sizes[0], probe);
+    assert_eq!(sizes[1], 576 - IPV4_OVERHEAD - HEADER_LEN);
+    let sent: usize = sizes.iter().map(|s| s + HEADER_LEN).sum();
+    assert!(sent <= 10_000 && sent + 576 > 10_000);
+
+    // the second packet is lost: acknowledged past it, it is sent again
+    let mut acks = vec![];
+    for packet in packets
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != 1)
+        .map(|(_, p)| p)
+    {
+        acks.extend(other.on_packet(packet.clone(), later));
+    }
+    let acks: Vec<Packet> = acks
+        .into_iter()
+        .filter(|p| p.ty == PacketType::State)
+        .collect();
+    assert_eq!(
+        acks.last().unwrap().selectively_acked().len(),
+        packets.len() - 2
+    );
+    let mut resent = vec![];
+    for ack in acks {
+        resent.extend(connection.on_packet(ack, later));
+    }
+    assert!(resent
+        .iter()
+        .any(|p| p.seq_nr == packets[1].seq_nr && p.payload == packets[1].payload));
+    assert_eq!(connection.mtu.floor, HEADER_LEN + probe);
+}
+
+#[test]
+fn test_receive_buffer_limit() {
+    let now = Instant::now();
+    let addr = SocketAddr::from(([127, 0, 0, 1], 6881));
+    let (connected, _) = oneshot::channel();
+    let (connection, syn) = Connection::connect(addr, 7, now, now, connected);
+    let (mut other, _) = Connection::accept(addr, &syn, now, now);
+
+    // the peer ignores the window, data past the buffer is not acknowledged
+    let chunk = 1000;
+    let data = |seq_nr| connection.packet(PacketType::Data, seq_nr, vec![7u8; chunk], now);
+    let mut seq_nr = syn.seq_nr;
+    for _ in 0..RECV_BUF_LEN / chunk {
+        seq_nr = seq_nr.wrapping_add(1);
+        let acks = other.on_packet(data(seq_nr), now);
+        assert_eq!(acks[0].ack_nr, seq_nr);
+    }
+    let acks = other.on_packet(data(seq_nr.wrapping_add(1)), now);
+    assert_eq!(acks[0].ack_nr, seq_nr);
+    assert_eq!(acks[0].wnd_size as usize, RECV_BUF_LEN % chunk);
+    assert_eq!(other.buffered(), RECV_BUF_LEN / chunk * chunk);
+
+    // once the reader made room, the data sent again is accepted
+    let waker = futures_util::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut buf = [0u8; 4096];
+    let mut read = ReadBuf::new(&mut buf);
+    assert!(other.poll_read(&mut cx, &mut read).is_ready());
+    let acks = other.on_packet(data(seq_nr.wrapping_add(1)), now);
+    assert_eq!(acks[0].ack_nr, seq_nr.wrapping_add(1));
+}
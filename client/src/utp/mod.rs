@@ -0,0 +1,465 @@
+use crate::error::Error;
+use connection::Connection;
+use log::{debug, info};
+use packet::{Packet, PacketType};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+
+mod connection;
+pub mod packet;
+
+/// Large enough for any packet within an ethernet frame, and then some.
+const RECV_BUF_SIZE: usize = 8192;
+/// How often the timeouts of the connections are checked.
+const TICK_INTERVAL: Duration = Duration::from_millis(50);
+/// Connections accepted and not yet taken by `accept`.
+const ACCEPT_BACKLOG: usize = 32;
+/// Datagrams of other protocols waiting to be read, the next ones are dropped.
+const OTHER_QUEUE_LEN: usize = 64;
+
+/// Connections by remote address and the connection id of the packets they receive.
+type Connections = HashMap<(SocketAddr, u16), Arc<Mutex<Connection>>>;
+type Outgoing = mpsc::UnboundedSender<(Packet, SocketAddr)>;
+/// Datagrams received on a shared port that are not uTP packets, with their sender.
+pub type OtherPackets = mpsc::Receiver<(Vec<u8>, SocketAddr)>;
+
+struct Shared {
+    /// Origin of the packet timestamps.
+    epoch: Instant,
+    connections: Mutex<Connections>,
+    outgoing: Outgoing,
+    /// Share of the packets dropped instead of sent.
+    #[cfg(test)]
+    loss: Mutex<f64>,
+}
+
+/// A UDP socket carrying uTP connections (BEP 29), both the ones it makes and the ones
+/// it accepts. The connections are driven by a background task for as long as the
+/// `UtpSocket` lives.
+pub struct UtpSocket {
+    shared: Arc<Shared>,
+    socket: Arc<UdpSocket>,
+    local_addr: SocketAddr,
+    incoming: tokio::sync::Mutex<mpsc::Receiver<UtpStream>>,
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl UtpSocket {
+    pub async fn bind(addr: SocketAddr) -> Result<UtpSocket, Error> {
+        UtpSocket::bind_with(addr, None).await
+    }
+
+    /// Binds a socket whose port is shared with another protocol, e.g. the DHT: the
+    /// datagrams that are not uTP packets are handed to the returned receiver, and the
+    /// other protocol sends on `udp_socket`.
+    pub async fn bind_shared(addr: SocketAddr) -> Result<(UtpSocket, OtherPackets), Error> {
+        let (other_tx, other) = mpsc::channel(OTHER_QUEUE_LEN);
+        let socket = UtpSocket::bind_with(addr, Some(other_tx)).await?;
+        Ok((socket, other))
+    }
+
+    async fn bind_with(
+        addr: SocketAddr,
+        other: Option<mpsc::Sender<(Vec<u8>, SocketAddr)>>,
+    ) -> Result<UtpSocket, Error> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let local_addr = socket.local_addr()?;
+        info!("utp listening on {}", local_addr);
+
+        let (outgoing, outgoing_rx) = mpsc::unbounded_channel();
+        let shared = Arc::new(Shared {
+            epoch: Instant::now(),
+            connections: Mutex::new(HashMap::new()),
+            outgoing,
+            #[cfg(test)]
+            loss: Mutex::new(0.0),
+        });
+        let (incoming_tx, incoming) = mpsc::channel(ACCEPT_BACKLOG);
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        tokio::spawn(run(
+            socket.clone(),
+            shared.clone(),
+            outgoing_rx,
+            incoming_tx,
+            other,
+            shutdown_rx,
+        ));
+
+        Ok(UtpSocket {
+            shared,
+            socket,
+            local_addr,
+            incoming: tokio::sync::Mutex::new(incoming),
+            _shutdown: shutdown_tx,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The UDP socket, to send the datagrams of a protocol sharing the port.
+    pub fn udp_socket(&self) -> Arc<UdpSocket> {
+        self.socket.clone()
+    }
+
+    /// Connects to `addr`, waiting for it to acknowledge the connection.
+    pub async fn connect(&self, addr: SocketAddr) -> Result<UtpStream, Error> {
+        let (connected_tx, connected) = oneshot::channel();
+        let connection = {
+            let mut connections = self.shared.connections.lock().unwrap();
+            let recv_id = loop {
+                let id = rand::random();
+                if !connections.contains_key(&(addr, id)) {
+                    break id;
+                }
+            };
+            let (connection, syn) = Connection::connect(
+                addr,
+                recv_id,
+                self.shared.epoch,
+                Instant::now(),
+                connected_tx,
+            );
+            let _ = self.shared.outgoing.send((syn, addr));
+            let connection = Arc::new(Mutex::new(connection));
+            connections.insert((addr, recv_id), connection.clone());
+            connection
+        };
+
+        let result = connected.await.unwrap_or(Err(Error::ConnectionClosed));
+        if let Err(e) = result {
+            connection.lock().unwrap().dropped = true;
+            return Err(e);
+        }
+        debug!("utp connected to {}", addr);
+        Ok(UtpStream {
+            connection,
+            outgoing: self.shared.outgoing.clone(),
+            peer_addr: addr,
+        })
+    }
+
+    /// Waits for a connection from another peer.
+    pub async fn accept(&self) -> Result<UtpStream, Error> {
+        let mut incoming = self.incoming.lock().await;
+        incoming.recv().await.ok_or(Error::ConnectionClosed)
+    }
+
+    /// Drops `loss` of the packets sent, to check recovery from losses.
+    #[cfg(test)]
+    fn set_loss(&self, loss: f64) {
+        *self.shared.loss.lock().unwrap() = loss;
+    }
+}
+
+/// A uTP connection, read and written like a `TcpStream`. Shutting it down sends the
+/// remaining bytes followed by a FIN; dropping it does the same.
+pub struct UtpStream {
+    connection: Arc<Mutex<Connection>>,
+    outgoing: Outgoing,
+    peer_addr: SocketAddr,
+}
+
+impl fmt::Debug for UtpStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UtpStream")
+            .field("peer_addr", &self.peer_addr)
+            .finish()
+    }
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    fn send(&self, packets: Vec<Packet>) {
+        for packet in packets {
+            let _ = self.outgoing.send((packet, self.peer_addr));
+        }
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.connection.lock().unwrap().poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = self
+            .connection
+            .lock()
+            .unwrap()
+            .poll_write(cx, buf, Instant::now());
+        match written {
+            Poll::Ready(Ok((len, packets))) => {
+                self.send(packets);
+                Poll::Ready(Ok(len))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let packets = self.connection.lock().unwrap().close(Instant::now());
+        self.send(packets);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let packets = {
+            let mut connection = self.connection.lock().unwrap();
+            connection.dropped = true;
+            connection.close(Instant::now())
+        };
+        self.send(packets);
+    }
+}
+
+/// Dispatches the packets received to their connection, sends the packets of the
+/// connections and checks their timeouts. Datagrams that are not uTP packets go to
+/// `other` when the port is shared.
+async fn run(
+    socket: Arc<UdpSocket>,
+    shared: Arc<Shared>,
+    mut outgoing: mpsc::UnboundedReceiver<(Packet, SocketAddr)>,
+    incoming: mpsc::Sender<UtpStream>,
+    other: Option<mpsc::Sender<(Vec<u8>, SocketAddr)>>,
+    mut shutdown: oneshot::Receiver<()>,
+) {
+    let mut buf = vec![0u8; RECV_BUF_SIZE];
+    let mut tick = tokio::time::interval(TICK_INTERVAL);
+    loop {
+        let packets = tokio::select! {
+            _ = &mut shutdown => break,
+            received = socket.recv_from(&mut buf) => match received {
+                Ok((len, from)) => match Packet::decode(&buf[..len]) {
+                    Ok(packet) => on_packet(&shared, packet, from, &incoming),
+                    Err(e) => {
+                        match other.as_ref() {
+                            // e.g. a DHT message, bencoded dictionaries are never valid
+                            // uTP headers
+                            Some(other) => {
+                                let _ = other.try_send((buf[..len].to_vec(), from));
+                            }
+                            None => debug!("invalid utp packet from {}: {}", from, e),
+                        }
+                        continue;
+                    }
+                },
+                Err(e) => {
+                    // e.g. ICMP port unreachable reported by the previous send
+                    debug!("utp socket error: {}", e);
+                    continue;
+                }
+            },
+            Some(packet) = outgoing.recv() => vec![packet],
+            _ = tick.tick() => on_tick(&shared),
+        };
+
+        for (packet, addr) in packets {
+            #[cfg(test)]
+            {
+                if rand::random::<f64>() < *shared.loss.lock().unwrap() {
+                    continue;
+                }
+            }
+            if let Err(e) = socket.send_to(&packet.encode(), addr).await {
+                debug!("failed to send utp packet to {}: {}", addr, e);
+            }
+        }
+    }
+}
+
+fn on_packet(
+    shared: &Shared,
+    packet: Packet,
+    from: SocketAddr,
+    incoming: &mpsc::Sender<UtpStream>,
+) -> Vec<(Packet, SocketAddr)> {
+    let now = Instant::now();
+    let mut connections = shared.connections.lock().unwrap();
+    // the id of a SYN is the one its connection sends with, we receive on the next one
+    let key = match packet.ty {
+        PacketType::Syn => (from, packet.connection_id.wrapping_add(1)),
+        _ => (from, packet.connection_id),
+    };
+    if let Some(connection) = connections.get(&key) {
+        let packets = connection.lock().unwrap().on_packet(packet, now);
+        return packets.into_iter().map(|p| (p, from)).collect();
+    }
+
+    match packet.ty {
+        PacketType::Syn => {
+            let (connection, ack) = Connection::accept(from, &packet, shared.epoch, now);
+            let connection = Arc::new(Mutex::new(connection));
+            let stream = UtpStream {
+                connection: connection.clone(),
+                outgoing: shared.outgoing.clone(),
+                peer_addr: from,
+            };
+            if incoming.try_send(stream).is_err() {
+                debug!("dropping utp connection from {}, too many pending", from);
+                return vec![];
+            }
+            debug!("utp connection from {}", from);
+            connections.insert(key, connection);
+            vec![(ack, from)]
+        }
+        PacketType::Data | PacketType::Fin => {
+            debug!("utp packet from {} for an unknown connection", from);
+            let reset = Packet {
+                ty: PacketType::Reset,
+                connection_id: packet.connection_id,
+                timestamp: 0,
+                timestamp_diff: 0,
+                wnd_size: 0,
+                seq_nr: rand::random(),
+                ack_nr: packet.seq_nr,
+                selective_ack: None,
+                payload: vec![],
+            };
+            vec![(reset, from)]
+        }
+        PacketType::State | PacketType::Reset => vec![],
+    }
+}
+
+/// Sends the packets whose timeout expired, and forgets the connections closed.
+fn on_tick(shared: &Shared) -> Vec<(Packet, SocketAddr)> {
+    let now = Instant::now();
+    let mut connections = shared.connections.lock().unwrap();
+    let mut packets = vec![];
+    connections.retain(|_, connection| {
+        let mut connection = connection.lock().unwrap();
+        let addr = connection.addr;
+        packets.extend(connection.on_tick(now).into_iter().map(|p| (p, addr)));
+        !connection.is_finished()
+    });
+    packets
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_transfer_with_losses() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let a = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let b = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    a.set_loss(0.1);
+    b.set_loss(0.1);
+
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+    let sent = data.clone();
+    let b_addr = b.local_addr();
+    let client = tokio::spawn(async move {
+        let mut stream = a.connect(b_addr).await.unwrap();
+        stream.write_all(&sent).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut echo = vec![];
+        stream.read_to_end(&mut echo).await.unwrap();
+        echo
+    });
+
+    let mut stream = b.accept().await.unwrap();
+    let mut received = vec![];
+    stream.read_to_end(&mut received).await.unwrap();
+    assert!(received == data);
+    stream.write_all(&received[..1000]).await.unwrap();
+    drop(stream);
+    assert_eq!(client.await.unwrap(), &data[..1000]);
+
+    // a stray packet gets the connection reset
+    b.set_loss(0.0);
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut stream = Box::pin(b.connect(socket.local_addr().unwrap()));
+    let mut buf = [0u8; 64];
+    let (len, from) = tokio::select! {
+        _ = &mut stream => panic!("connected without an answer"),
+        received = socket.recv_from(&mut buf) => received.unwrap(),
+    };
+    let mut reset = Packet::decode(&buf[..len]).unwrap();
+    assert_eq!(reset.ty, PacketType::Syn);
+    reset.ty = PacketType::Reset;
+    socket.send_to(&reset.encode(), from).await.unwrap();
+    let data = Packet {
+        ty: PacketType::Data,
+        connection_id: 1234,
+        ..reset
+    };
+    socket.send_to(&data.encode(), from).await.unwrap();
+    let (len, _) = socket.recv_from(&mut buf).await.unwrap();
+    assert_eq!(Packet::decode(&buf[..len]).unwrap().ty, PacketType::Reset);
+    assert!(stream.await.is_err());
+}
+
+#[tokio::test]
+async fn test_encrypted_peer_connection() {
+    use crate::peer::message::{Message, PeerCodec};
+    use crate::peer::mse::{self, EncryptionPolicy};
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_util::codec::Framed;
+
+    let a = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let b = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let info_hash = [9u8; 20];
+    let b_addr = b.local_addr();
+    let client = tokio::spawn(async move {
+        let stream = a.connect(b_addr).await.unwrap();
+        let stream = mse::initiate(stream, &info_hash, EncryptionPolicy::Forced)
+            .await
+            .unwrap();
+        let mut framed = Framed::new(stream, PeerCodec);
+        framed.send(Message::Have(3)).await.unwrap();
+        framed.next().await.unwrap().unwrap()
+    });
+
+    let stream = b.accept().await.unwrap();
+    let (stream, found) = mse::accept(stream, vec![], &[info_hash], EncryptionPolicy::Forced)
+        .await
+        .unwrap();
+    assert_eq!(found, info_hash);
+    assert!(stream.is_encrypted());
+    let mut framed = Framed::new(stream, PeerCodec);
+    assert_eq!(framed.next().await.unwrap().unwrap(), Message::Have(3));
+    framed.send(Message::Interested).await.unwrap();
+    assert_eq!(client.await.unwrap(), Message::Interested);
+}
@@ -0,0 +1,167 @@
+use crate::error::Error;
+use byteorder::{BigEndian, ByteOrder};
+
+pub const HEADER_LEN: usize = 20;
+const VERSION: u8 = 1;
+/// Extension carrying a bitmask of the packets received past `ack_nr + 1`.
+const EXTENSION_SELECTIVE_ACK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+/// A uTP packet (BEP 29). Timestamps are in microseconds, from an arbitrary epoch of the
+/// sender, so that only differences between them make sense.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub ty: PacketType,
+    pub connection_id: u16,
+    pub timestamp: u32,
+    /// The delay measured on the last packet received from the other side.
+    pub timestamp_diff: u32,
+    /// Bytes the sender can still buffer.
+    pub wnd_size: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    /// Bit `i` acknowledges packet `ack_nr + 2 + i`, least significant bit of each byte
+    /// first. The length is a multiple of 4.
+    pub selective_ack: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; HEADER_LEN];
+        buf[0] = (self.ty as u8) << 4 | VERSION;
+        if self.selective_ack.is_some() {
+            buf[1] = EXTENSION_SELECTIVE_ACK;
+        }
+        BigEndian::write_u16(&mut buf[2..4], self.connection_id);
+        BigEndian::write_u32(&mut buf[4..8], self.timestamp);
+        BigEndian::write_u32(&mut buf[8..12], self.timestamp_diff);
+        BigEndian::write_u32(&mut buf[12..16], self.wnd_size);
+        BigEndian::write_u16(&mut buf[16..18], self.seq_nr);
+        BigEndian::write_u16(&mut buf[18..20], self.ack_nr);
+        if let Some(mask) = self.selective_ack.as_ref() {
+            buf.push(0);
+            buf.push(mask.len() as u8);
+            buf.extend_from_slice(mask);
+        }
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+
+    /// Parses a packet, skipping the extensions other than selective acks.
+    pub fn decode(buf: &[u8]) -> Result<Packet, Error> {
+        if buf.len() < HEADER_LEN {
+            return Err(Error::Utp("packet too short"));
+        }
+        if buf[0] & 0x0f != VERSION {
+            return Err(Error::Utp("unsupported version"));
+        }
+        let ty = match buf[0] >> 4 {
+            0 => PacketType::Data,
+            1 => PacketType::Fin,
+            2 => PacketType::State,
+            3 => PacketType::Reset,
+            4 => PacketType::Syn,
+            _ => return Err(Error::Utp("unknown packet type")),
+        };
+
+        let mut selective_ack = None;
+        let mut extension = buf[1];
+        let mut offset = HEADER_LEN;
+        while extension != 0 {
+            if buf.len() < offset + 2 {
+                return Err(Error::Utp("truncated extension"));
+            }
+            let (next, len) = (buf[offset], buf[offset + 1] as usize);
+            let data = buf
+                .get(offset + 2..offset + 2 + len)
+                .ok_or(Error::Utp("truncated extension"))?;
+            if extension == EXTENSION_SELECTIVE_ACK {
+                if len == 0 || len % 4 != 0 {
+                    return Err(Error::Utp("invalid selective ack"));
+                }
+                selective_ack = Some(data.to_vec());
+            }
+            extension = next;
+            offset += 2 + len;
+        }
+
+        Ok(Packet {
+            ty,
+            connection_id: BigEndian::read_u16(&buf[2..4]),
+            timestamp: BigEndian::read_u32(&buf[4..8]),
+            timestamp_diff: BigEndian::read_u32(&buf[8..12]),
+            wnd_size: BigEndian::read_u32(&buf[12..16]),
+            seq_nr: BigEndian::read_u16(&buf[16..18]),
+            ack_nr: BigEndian::read_u16(&buf[18..20]),
+            selective_ack,
+            payload: buf[offset..].to_vec(),
+        })
+    }
+
+    /// The packets acknowledged by the selective ack, past `ack_nr + 1`.
+    pub fn selectively_acked(&self) -> Vec<u16> {
+        let mask = match self.selective_ack.as_ref() {
+            Some(mask) => mask,
+            None => return vec![],
+        };
+        (0..mask.len() * 8)
+            .filter(|i| mask[i / 8] & (1 << (i % 8)) != 0)
+            .map(|i| self.ack_nr.wrapping_add(2).wrapping_add(i as u16))
+            .collect()
+    }
+}
+
+//////////////////////////////////////////////////////////////////////
+/// Tests
+//////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_encode_decode() {
+    let packet = Packet {
+        ty: PacketType::State,
+        connection_id: 0x1234,
+        timestamp: 1_000_000,
+        timestamp_diff: 2_500,
+        wnd_size: 1 << 20,
+        seq_nr: 7,
+        ack_nr: 0xffff,
+        selective_ack: Some(vec![0b0000_0101, 0, 0, 0x80]),
+        payload: vec![],
+    };
+    let bytes = packet.encode();
+    assert_eq!(bytes.len(), HEADER_LEN + 6);
+    assert_eq!(bytes[0], 0x21);
+    assert_eq!(&bytes[2..4], &[0x12, 0x34]);
+    assert_eq!(Packet::decode(&bytes).unwrap(), packet);
+    // bits 0, 2 and 31 count from ack_nr + 2, wrapping around
+    assert_eq!(packet.selectively_acked(), vec![1, 3, 32]);
+
+    let data = Packet {
+        ty: PacketType::Data,
+        selective_ack: None,
+        payload: b"hello".to_vec(),
+        ..packet
+    };
+    assert_eq!(Packet::decode(&data.encode()).unwrap(), data);
+
+    // unknown extensions are skipped
+    let mut bytes = data.encode();
+    bytes[1] = 9;
+    bytes.splice(HEADER_LEN..HEADER_LEN, vec![0, 2, 0xaa, 0xbb]);
+    assert_eq!(Packet::decode(&bytes).unwrap(), data);
+
+    assert!(Packet::decode(&bytes[..10]).is_err());
+    bytes[0] = 0x22;
+    assert!(Packet::decode(&bytes).is_err());
+    bytes[0] = 0x51;
+    assert!(Packet::decode(&bytes).is_err());
+}